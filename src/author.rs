@@ -1,5 +1,7 @@
-use chrono::{DateTime, FixedOffset, Utc};
+use crate::error::{Error, Result};
+use chrono::{DateTime, FixedOffset, TimeZone};
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Author {
     name: String,
     email: String,
@@ -11,8 +13,82 @@ impl Author {
         Author { name, email, time }
     }
 
+    /// Parses an author from the format written by `to_str`:
+    /// "{NAME} <{EMAIL}> {SECONDS} {OFFSET}".
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || Error::CorruptObject(format!("invalid author line: {}", s));
+        let (name, rest) = s.split_once(" <").ok_or_else(invalid)?;
+        let (email, rest) = rest.split_once("> ").ok_or_else(invalid)?;
+        let (seconds, offset) = rest.split_once(' ').ok_or_else(invalid)?;
+
+        let seconds: i64 = seconds.parse().map_err(|_| invalid())?;
+        if offset.len() != 5 {
+            return Err(invalid());
+        }
+        let hours: i32 = offset[1..3].parse().map_err(|_| invalid())?;
+        let minutes: i32 = offset[3..5].parse().map_err(|_| invalid())?;
+        let mut offset_seconds = hours * 3600 + minutes * 60;
+        match &offset[0..1] {
+            "+" => (),
+            "-" => offset_seconds = -offset_seconds,
+            _ => return Err(invalid()),
+        }
+        let zone = FixedOffset::east_opt(offset_seconds).ok_or_else(invalid)?;
+        let time = zone
+            .timestamp_opt(seconds, 0)
+            .single()
+            .ok_or_else(invalid)?;
+
+        Ok(Author {
+            name: name.to_string(),
+            email: email.to_string(),
+            time,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    pub fn time(&self) -> DateTime<FixedOffset> {
+        self.time
+    }
+
+    /// Formats the time as git does within log output, such as
+    /// "Fri Jan 1 01:01:01 2021 +0000".
+    pub fn display_time(&self) -> String {
+        self.time.format("%a %b %-d %H:%M:%S %Y %z").to_string()
+    }
+
     pub fn to_str(&self) -> String {
-        let timestamp = DateTime::<Utc>::from(self.time).format("%s %z");
+        let timestamp = self.time.format("%s %z");
         format!("{} <{}> {}", self.name, self.email, timestamp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let time = DateTime::parse_from_rfc3339("2021-01-01T01:01:01-07:30")?;
+        let author = Author::new("Sean".to_string(), "sean@zombo.com".to_string(), time);
+        assert_eq!(author.to_str(), "Sean <sean@zombo.com> 1609489861 -0730");
+        assert_eq!(Author::parse(&author.to_str())?, author);
+        assert_eq!(author.display_time(), "Fri Jan 1 01:01:01 2021 -0730");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Author::parse("Sean sean@zombo.com 1609485061 +0000").is_err());
+        assert!(Author::parse("Sean <sean@zombo.com> soon +0000").is_err());
+        assert!(Author::parse("Sean <sean@zombo.com> 1609485061 0000").is_err());
+    }
+}
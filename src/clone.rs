@@ -0,0 +1,176 @@
+//! Cloning from repositories on the local filesystem.
+//!
+//! Objects are copied (or hard linked) directly between object
+//! directories, rather than being transferred as a pack.
+
+use crate::config::Config;
+use crate::database::Database;
+use crate::error::{Error, Result};
+use crate::refs::Refs;
+use std::path::{Path, PathBuf};
+
+// The prefix of URLs naming local repositories.
+const FILE_URL_PREFIX: &str = "file://";
+
+/// A repository on the local filesystem to clone from.
+pub struct Source {
+    url: String,
+    git_dir: PathBuf,
+    bare: bool,
+}
+
+impl Source {
+    /// Locates the repository named by `url`: either a path (to a workspace
+    /// or to a bare repository) or a "file://" URL. Relative paths are
+    /// relative to `cwd`.
+    pub fn locate(url: &str, cwd: &Path) -> Result<Self> {
+        let missing = || Error::Fatal(format!("repository '{}' does not exist", url));
+        let path = cwd.join(url.strip_prefix(FILE_URL_PREFIX).unwrap_or(url));
+        let path = std::fs::canonicalize(path).map_err(|_| missing())?;
+        let is_git_dir = |dir: &Path| dir.join("HEAD").is_file() && dir.join("objects").is_dir();
+        let (git_dir, bare) = if is_git_dir(&path.join(".git")) {
+            (path.join(".git"), false)
+        } else if is_git_dir(&path) {
+            // A path to the ".git" directory of a workspace is not bare.
+            let bare = path.file_name() != Some(std::ffi::OsStr::new(".git"));
+            (path.clone(), bare)
+        } else {
+            return Err(missing());
+        };
+
+        // As in git, local paths are recorded in full.
+        let url = if url.starts_with(FILE_URL_PREFIX) {
+            url.to_string()
+        } else {
+            path.to_str()
+                .ok_or_else(|| Error::invalid_path(&path, "not valid unicode"))?
+                .to_string()
+        };
+        Ok(Source { url, git_dir, bare })
+    }
+
+    /// Returns the URL to record as "remote.origin.url".
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns true if the source was named by a "file://" URL, which (as
+    /// in git) means its objects are never hard linked.
+    pub fn is_file_url(&self) -> bool {
+        self.url.starts_with(FILE_URL_PREFIX)
+    }
+
+    /// Returns true if the source has no workspace.
+    pub fn is_bare(&self) -> bool {
+        self.bare
+    }
+
+    /// Returns the git directory of the source, such as "<path>/.git".
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub fn database(&self) -> Database {
+        Database::new(self.git_dir.join("objects"))
+    }
+
+    pub fn refs(&self) -> Refs {
+        Refs::new(&self.git_dir)
+    }
+
+    pub fn config(&self) -> Result<Config> {
+        Config::load(self.git_dir.join("config"))
+    }
+}
+
+/// Returns the directory git clones `url` into by default: the last
+/// component of its path without any ".git" suffix, such as "repo" for both
+/// "/src/repo.git" and "/src/repo/.git".
+pub fn default_directory(url: &str) -> String {
+    let path = url.trim_end_matches('/').trim_end_matches("/.git");
+    let name = path.rsplit(['/', ':']).next().unwrap_or(path);
+    name.strip_suffix(".git").unwrap_or(name).to_string()
+}
+
+/// Copies every object file (loose objects, and any packs) beneath the
+/// object directory `source` into `dest`, returning the number of files
+/// copied.
+///
+/// With `link`, files are hard linked instead, falling back to copying
+/// when that fails (such as across filesystems). Objects are immutable, so
+/// sharing them is safe. Files which already exist in `dest` and temporary
+/// files are skipped.
+pub fn copy_objects(source: &Path, dest: &Path, link: bool) -> Result<usize> {
+    let mut count = 0;
+    if !source.is_dir() {
+        return Ok(count);
+    }
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let target = dest.join(&name);
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            count += copy_objects(&entry.path(), &target, link)?;
+            continue;
+        }
+        if name.to_string_lossy().starts_with("tmp_") || target.exists() {
+            continue;
+        }
+        if !link || std::fs::hard_link(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::os::unix::fs::MetadataExt;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_default_directory() {
+        assert_eq!(default_directory("/src/repo"), "repo");
+        assert_eq!(default_directory("/src/repo/"), "repo");
+        assert_eq!(default_directory("/src/repo.git"), "repo");
+        assert_eq!(default_directory("/src/repo/.git"), "repo");
+        assert_eq!(default_directory("file:///src/repo.git"), "repo");
+        assert_eq!(default_directory("../repo"), "repo");
+    }
+
+    #[test]
+    fn test_locate_and_copy() -> Result<()> {
+        let dir = TempDir::new("test_clone")?;
+        let bare = dir.path().join("bare.git");
+        std::fs::create_dir_all(bare.join("objects/ce"))?;
+        std::fs::write(bare.join("HEAD"), "ref: refs/heads/master\n")?;
+        std::fs::write(bare.join("objects/ce/0136"), "object")?;
+        std::fs::write(bare.join("objects/tmp_obj_1_0"), "partial")?;
+
+        let source = Source::locate("bare.git", dir.path())?;
+        assert_eq!(source.git_dir(), std::fs::canonicalize(&bare)?);
+        assert_eq!(source.url(), source.git_dir().to_str().unwrap());
+        assert!(!source.is_file_url());
+        assert!(source.is_bare());
+        let url = format!("file://{}", bare.display());
+        assert!(Source::locate(&url, dir.path())?.is_file_url());
+        assert!(Source::locate("missing", dir.path()).is_err());
+
+        let copied = dir.path().join("copied");
+        assert_eq!(copy_objects(&bare.join("objects"), &copied, false)?, 1);
+        assert_eq!(std::fs::read_to_string(copied.join("ce/0136"))?, "object");
+        assert!(!copied.join("tmp_obj_1_0").exists());
+        assert_eq!(std::fs::metadata(copied.join("ce/0136"))?.nlink(), 1);
+        // Existing objects are left alone.
+        assert_eq!(copy_objects(&bare.join("objects"), &copied, false)?, 0);
+
+        let linked = dir.path().join("linked");
+        assert_eq!(copy_objects(&bare.join("objects"), &linked, true)?, 1);
+        assert_eq!(std::fs::metadata(linked.join("ce/0136"))?.nlink(), 2);
+        Ok(())
+    }
+}
@@ -1,122 +1,396 @@
 use crate::author::Author;
+use crate::clone;
 use crate::commit::Commit;
-use crate::database::{Blob, Database, Storable};
+use crate::config::Config;
+use crate::database::{Database, Object, ObjectID, Storable};
+use crate::diff;
+use crate::editor;
 use crate::entry::{Entry, Mode};
-use crate::refs::Refs;
+use crate::error::{Error, Result};
+use crate::fetch;
+use crate::fsck;
+use crate::gc;
+use crate::hooks;
+use crate::http;
+use crate::index::{Index, IndexEntry};
+use crate::init;
+use crate::lockfile::LockFile;
+use crate::merge;
+use crate::protocol::NULL_OID;
+use crate::push;
+use crate::rebase::{self, RebaseState, TodoItem};
+use crate::refs::{Refs, CHERRY_PICK_HEAD, HEAD, ORIG_HEAD, REVERT_HEAD};
+use crate::refspec::Refspec;
+use crate::repository::Repository;
+use crate::revision::{self, Revision};
+use crate::sequencer::{self, Action, Sequencer, Step};
+use crate::server;
+use crate::stash::Stash;
+use crate::submodule::{Submodule, Submodules, GITMODULES};
 use crate::tree::Tree;
-use crate::workspace::Workspace;
-use anyhow::{anyhow, Result};
-use std::fs::{canonicalize, create_dir_all, OpenOptions};
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use crate::workspace::{Workspace, WorkspacePath};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::canonicalize;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 pub struct InitArgs<'a> {
     pub path: Option<&'a str>,
     pub cwd: PathBuf,
+    /// Creates a repository without a workspace.
+    pub bare: bool,
+    /// The branch HEAD refers to, from "--initial-branch".
+    pub initial_branch: Option<&'a str>,
+    /// The value of "init.defaultBranch", used without "--initial-branch".
+    pub default_branch: Option<String>,
+    /// The template directory, if any; an empty path means none.
+    pub template: Option<PathBuf>,
+    pub shared: init::Shared,
+    pub separate_git_dir: Option<&'a str>,
 }
 
 pub fn init(args: InitArgs) -> Result<()> {
     // Either acquire the user-supplied path or pick a default.
-    let mut path = match args.path {
-        Some(path) => PathBuf::from(path),
-        None => args.cwd,
+    let path = match args.path {
+        Some(path) => args.cwd.join(path),
+        None => args.cwd.clone(),
+    };
+    let cwd = &args.cwd;
+    let separate_git_dir = args.separate_git_dir.map(|dir| cwd.join(dir));
+    let options = init::Options {
+        bare: args.bare,
+        initial_branch: args
+            .initial_branch
+            .map(|branch| branch.to_string())
+            .or(args.default_branch),
+        separate_git_dir,
+        template: args.template,
+        shared: args.shared,
     };
+    let initialized = init::init(&path, &options)?;
 
-    path.push(".git");
-    let dirs = ["objects", "refs"];
-    for dir in dirs.iter() {
-        path.push(dir);
-        create_dir_all(&path)?;
-        path.pop();
+    if initialized.reinitialized {
+        if let Some(branch) = args.initial_branch {
+            eprintln!("warning: re-init: ignored --initial-branch={}", branch);
+        }
     }
-
-    std::fs::write(path.join("HEAD"), "ref: refs/heads/master\n")?;
-
+    let action = if initialized.reinitialized {
+        "Reinitialized existing"
+    } else {
+        "Initialized empty"
+    };
+    let shared = if args.shared == init::Shared::Umask {
+        ""
+    } else {
+        "shared "
+    };
     println!(
-        "Initialized empty Rit repository in {}",
-        canonicalize(&path)?.as_path().display()
+        "{} {}Rit repository in {}",
+        action,
+        shared,
+        canonicalize(&initialized.git_dir)?.as_path().display()
     );
     Ok(())
 }
 
 pub struct CommitArgs<'a> {
     pub cwd: PathBuf,
-    pub message: Option<&'a str>,
+    /// Paragraphs of the message, from each "-m".
+    pub message: Vec<&'a str>,
+    /// A file holding the message, or "-" for standard input.
+    pub file: Option<&'a str>,
+    /// The file whose contents start the message when none is given
+    /// ("commit.template").
+    pub template: Option<PathBuf>,
+    /// Whether to open the editor ("-e" or "--no-edit"); by default, it
+    /// opens unless a message is given.
+    pub edit: Option<bool>,
+    pub cleanup: editor::Cleanup,
+    pub comment_char: editor::CommentChar,
+    /// The editor used for the message.
+    pub editor: String,
+    /// Skips the pre-commit and commit-msg hooks.
+    pub no_verify: bool,
+    /// As git's "-a": commits modifications and deletions of the files in
+    /// the index, rather than the entire workspace.
+    pub all: bool,
+    /// Commits only these paths (relative to the root), leaving the rest of
+    /// the commit as it was in HEAD.
+    pub paths: Vec<&'a str>,
+    /// Replaces HEAD with the new commit, which keeps its parents and (by
+    /// default) its author and message.
+    pub amend: bool,
+    /// With `amend`, takes the author from the identity below.
+    pub reset_author: bool,
+    /// Allows a commit with the same tree as its parent.
+    pub allow_empty: bool,
     pub name: String,
     pub email: String,
     pub time: chrono::DateTime<chrono::FixedOffset>,
 }
 
 pub fn commit(args: CommitArgs) -> Result<()> {
-    let root_path = args.cwd;
-    let git_path = root_path.as_path().join(".git");
-    let db_path = git_path.as_path().join("objects");
+    let repo = Repository::open(&args.cwd)?;
+    let workspace = repo.workspace();
+    let refs = repo.refs();
+    let mut index = repo.load_index()?;
 
-    let workspace = Workspace::new(&root_path);
-    let database = Database::new(db_path);
-    let refs = Refs::new(&git_path);
+    let head = repo.head()?;
+    let amended = match (&head, args.amend) {
+        (Some(head), true) => Some(repo.database().load_commit(head)?),
+        (None, true) => {
+            return Err(Error::Fatal("You have nothing to amend.".to_string()));
+        }
+        (_, false) => None,
+    };
+    let parents = match &amended {
+        Some(amended) => amended.parents().to_vec(),
+        None => head.iter().cloned().collect(),
+    };
 
-    let files = workspace.list_files()?;
+    // As in git, the message comes from "-m", "-F", the amended commit or
+    // the template (in that order), and the editor opens unless it came
+    // from "-m" or "-F".
+    let (message, source) = if !args.message.is_empty() {
+        let mut message = String::new();
+        for paragraph in &args.message {
+            if !message.is_empty() {
+                message.push('\n');
+            }
+            message.push_str(paragraph);
+            if !message.ends_with('\n') {
+                message.push('\n');
+            }
+        }
+        (message, Some("message"))
+    } else if let Some(file) = args.file {
+        let mut message = String::new();
+        let read = if file == "-" {
+            std::io::stdin().lock().read_to_string(&mut message)
+        } else {
+            std::fs::File::open(args.cwd.join(file))
+                .and_then(|mut file| file.read_to_string(&mut message))
+        };
+        read.map_err(|e| Error::Fatal(format!("could not read log file '{}': {}", file, e)))?;
+        (message, Some("message"))
+    } else if let Some(amended) = &amended {
+        (amended.message().to_string(), Some("commit"))
+    } else if let Some(template) = &args.template {
+        let message = std::fs::read_to_string(template)
+            .map_err(|e| Error::Fatal(format!("could not read '{}': {}", template.display(), e)))?;
+        (message, Some("template"))
+    } else {
+        (String::new(), None)
+    };
+    let editing = args.edit.unwrap_or(source != Some("message"));
+    let cleanup = args.cleanup.resolve(editing);
+    let comment_char = args.comment_char.resolve(&message);
+    let hooks = if editing {
+        repo.hooks()?
+    } else {
+        repo.hooks()?.with_env("GIT_EDITOR", ":")
+    };
 
-    println!("COMMIT: file list: {:#?}", files);
+    let mut paths = Vec::new();
+    for path in &args.paths {
+        paths.push(WorkspacePath::new(path)?.as_partial_path().to_path_buf());
+    }
+    let selected = |path: &Path| paths.is_empty() || paths.iter().any(|p| path.starts_with(p));
 
-    // XXX wrong invocation
-    Tree::build(&workspace, files.clone()).unwrap();
+    let files = workspace.list_files()?;
 
-    let mut entries = Vec::new();
-    for file in files {
-        if workspace.full_path(&file).is_dir() {
-            println!("Ignoring {:#?}", file);
-            // XXX: Ignoring directories
-            continue;
-        }
-        println!("Reading data for: {:#?}", file);
-        let data = workspace.read_file(&file)?;
+    tracing::trace!(count = files.len(), "listed workspace files");
 
-        // Calculate the OID, and ensuure the entry exists in the object
-        // store if it does not already exist there.
-        let blob = Blob::new(data);
-        database.store(&blob)?;
+    // By default, the commit captures the entire workspace, so the index is
+    // rebuilt to match it. Otherwise, only the selected files within the
+    // index are (re)staged, or removed if they no longer exist.
+    let recorded: BTreeMap<PathBuf, Mode> = index
+        .entries()
+        .map(|entry| (entry.path().to_path_buf(), *entry.mode()))
+        .collect();
+    let files: Vec<_> = files
+        .into_iter()
+        .filter(|file| {
+            let path = file.as_partial_path();
+            selected(path) && (!args.all || recorded.contains_key(path))
+        })
+        .collect();
+    for path in &paths {
+        let known = files
+            .iter()
+            .any(|file| file.as_partial_path().starts_with(path))
+            || recorded.keys().any(|recorded| recorded.starts_with(path));
+        if !known {
+            return Err(Error::Fatal(format!(
+                "pathspec '{}' did not match any files",
+                path.display()
+            )));
+        }
+    }
+    if args.all || !paths.is_empty() {
+        for path in recorded.keys().filter(|path| selected(path)) {
+            index.remove(path);
+        }
+    } else {
+        index.clear();
+    }
 
-        // Identify if the entry is executable or not.
-        let metadata = workspace.metadata(&file)?;
-        let mode = if metadata.permissions().mode() & 0o111 != 0 {
-            Mode::ReadWriteExecute
-        } else {
-            Mode::ReadWrite
-        };
+    // Calculate the OIDs, and ensure the entries exist in the object store
+    // if they do not already exist there.
+    for (file, metadata, oid) in repo.store_files(files)? {
+        // Identify if the entry is executable (or a symlink) or not.
+        let recorded = recorded.get(file.as_partial_path()).copied();
+        let mode = workspace.mode(&metadata, recorded);
 
-        entries.push(Entry::new(file, blob.oid(), mode));
+        index.add(IndexEntry::new(file, oid, &metadata).with_mode(mode));
     }
 
-    let tree = Tree::new(entries);
-    database.store(&tree)?;
+    // As in git, the index about to be committed is staged within the lock
+    // of the index, which only replaces it once HEAD has moved. The hooks
+    // see it through GIT_INDEX_FILE, and what pre-commit stages is
+    // committed too.
+    let mut lock = LockFile::new(repo.git_path().join("index"))?;
+    let staging = lock.staging_path()?;
+    index.write_to(lock.writer())?;
+    let staged_hooks = hooks
+        .clone()
+        .with_env("GIT_INDEX_FILE", &staging.to_string_lossy());
+    if !args.no_verify && !staged_hooks.run(hooks::PRE_COMMIT, &[], b"")? {
+        return Err(Error::HookDeclined(hooks::PRE_COMMIT));
+    }
+    let index = Index::load(&staging)?;
+    let tree = if paths.is_empty() {
+        write_index_tree(repo.database(), &index)?
+    } else {
+        // The selected paths are committed on top of HEAD, whatever else
+        // the index holds.
+        let mut entries: Vec<Entry> = match &head {
+            Some(head) => repo.walk_tree(head)?.into_values().collect(),
+            None => Vec::new(),
+        };
+        entries.retain(|entry| !selected(entry.path()));
+        entries.extend(
+            index
+                .entries()
+                .filter(|entry| selected(entry.path()))
+                .map(|entry| entry.to_entry()),
+        );
+        Tree::build(entries, |tree| repo.database().store(tree))?
+    };
 
-    let parent = refs.read_head().ok();
+    if !args.allow_empty {
+        let empty = match parents.first() {
+            Some(parent) => repo.database().load_commit(parent)?.tree() == &tree.oid(),
+            None => tree.entries().is_empty(),
+        };
+        if empty && amended.is_some() {
+            return Err(Error::Fatal(
+                "You asked to amend the most recent commit, but doing so would make\n\
+                 it empty. You can repeat your command with --allow-empty, or you can\n\
+                 remove the commit entirely with \"rit reset HEAD^\"."
+                    .to_string(),
+            ));
+        } else if empty {
+            return Err(Error::Fatal("nothing to commit".to_string()));
+        }
+    }
 
-    let author = Author::new(args.name, args.email, args.time);
-    let message = args
-        .message
-        .ok_or_else(|| anyhow!("No commit message"))?
-        .to_string();
+    let committer = Author::new(args.name, args.email, args.time);
+    let author = match &amended {
+        Some(amended) if !args.reset_author => amended.author().clone(),
+        _ => committer.clone(),
+    };
 
-    let commit = Commit::new(&parent, &tree.oid(), author, message);
-    database.store(&commit)?;
-    refs.update_head(&commit.oid())?;
+    // The message is edited within a file, by the user and the hooks, with
+    // a summary of the commit commented out below it.
+    let message_path = repo.git_path().join("COMMIT_EDITMSG");
+    let mut text = if cleanup == editor::Cleanup::Verbatim {
+        message.clone()
+    } else {
+        editor::cleanup(&message, editor::Cleanup::Whitespace, comment_char)
+    };
+    if editing {
+        text.push('\n');
+        text.push_str(&match cleanup {
+            editor::Cleanup::Strip => editor::comment(
+                &format!(
+                    "Please enter the commit message for your changes. Lines starting\n\
+                     with '{}' will be ignored, and an empty message aborts the commit.\n",
+                    comment_char
+                ),
+                comment_char,
+            ),
+            editor::Cleanup::Scissors => editor::cut_line(comment_char),
+            _ => editor::comment(
+                &format!(
+                    "Please enter the commit message for your changes. Lines starting\n\
+                     with '{}' will be kept; you may remove them yourself if you want to.\n\
+                     An empty message aborts the commit.\n",
+                    comment_char
+                ),
+                comment_char,
+            ),
+        });
+        text.push_str(&editor::comment(
+            &commit_status(&repo, head.as_ref(), parents.first(), &tree)?,
+            comment_char,
+        ));
+    }
+    std::fs::write(&message_path, text)?;
+    let path = message_path.to_string_lossy();
+    let mut hook_args = vec![path.as_ref()];
+    hook_args.extend(source);
+    if amended.is_some() && source == Some("commit") {
+        hook_args.push(HEAD);
+    }
+    if !staged_hooks.run(hooks::PREPARE_COMMIT_MSG, &hook_args, b"")? {
+        return Err(Error::HookDeclined(hooks::PREPARE_COMMIT_MSG));
+    }
+    if editing {
+        editor::edit(&args.editor, &message_path)?;
+    }
+    if !args.no_verify && !staged_hooks.run(hooks::COMMIT_MSG, &[&path], b"")? {
+        return Err(Error::HookDeclined(hooks::COMMIT_MSG));
+    }
+    let edited = editor::cleanup(
+        &std::fs::read_to_string(&message_path)?,
+        cleanup,
+        comment_char,
+    );
+    if edited.trim().is_empty() {
+        return Err(Error::Fatal(
+            "Aborting commit due to empty commit message.".to_string(),
+        ));
+    }
+    if editing
+        && source == Some("template")
+        && edited == editor::cleanup(&message, cleanup, comment_char)
+    {
+        return Err(Error::Fatal(
+            "Aborting commit; you did not edit the message.".to_string(),
+        ));
+    }
 
-    let head_path = git_path.join("HEAD");
-    let mut head = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(&head_path)?;
-    head.write_all(commit.oid().as_str().as_bytes())?;
+    let commit = repo.create_commit(parents.clone(), tree.oid(), author, committer, edited)?;
 
-    let root_msg = if parent.is_some() {
+    let root_msg = if parents.is_empty() {
         "(root-commit) "
     } else {
         ""
     };
+    let kind = if amended.is_some() {
+        " (amend)"
+    } else if parents.is_empty() {
+        " (initial)"
+    } else {
+        ""
+    };
+    refs.update_head(
+        &commit.oid(),
+        commit.committer(),
+        &format!("commit{}: {}", kind, commit.title_line()),
+    )?;
+    lock.commit()?;
 
     println!(
         "[{}{}] {}",
@@ -128,190 +402,4129 @@ pub fn commit(args: CommitArgs) -> Result<()> {
             .next()
             .unwrap_or("<No commit message>"),
     );
+    // Like every post- hook, its outcome is ignored.
+    hooks.run(hooks::POST_COMMIT, &[], b"")?;
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use anyhow::{anyhow, Result};
-    use directory_compare::directory_compare;
-    use std::env;
-    use std::ffi::OsStr;
-    use std::fs::{create_dir_all, write};
-    use std::process::Command;
-    use tempdir::TempDir;
-    use test_process_executor::Executor;
+// Describes the commit of `tree` on top of `parent`, made while HEAD is at
+// `head`, as git's status does within the commit message template.
+fn commit_status(
+    repo: &Repository,
+    head: Option<&ObjectID>,
+    parent: Option<&ObjectID>,
+    tree: &Tree,
+) -> Result<String> {
+    let mut status = String::from("\n");
+    match repo.refs().current_ref()?.strip_prefix("refs/heads/") {
+        Some(branch) => status.push_str(&format!("On branch {}\n", branch)),
+        None => status.push_str(&format!(
+            "HEAD detached at {}\n",
+            head.map(|oid| oid.as_str()[..7].to_string())
+                .unwrap_or_default()
+        )),
+    }
+    let mut previous = match parent {
+        Some(parent) => repo.walk_tree(parent)?,
+        None => {
+            status.push_str("\nInitial commit\n\n");
+            BTreeMap::new()
+        }
+    };
+    let mut changes = BTreeMap::new();
+    for (path, entry) in repo.walk_tree(&tree.oid())? {
+        let label = match previous.remove(&path) {
+            None => "new file:",
+            Some(old) if old.oid() == entry.oid() && old.mode() == entry.mode() => continue,
+            Some(old) if (*old.mode() == Mode::Symlink) != (*entry.mode() == Mode::Symlink) => {
+                "typechange:"
+            }
+            Some(_) => "modified:",
+        };
+        changes.insert(path, label);
+    }
+    for path in previous.into_keys() {
+        changes.insert(path, "deleted:");
+    }
+    if !changes.is_empty() {
+        status.push_str("Changes to be committed:\n");
+        for (path, label) in changes {
+            status.push_str(&format!("\t{:<12}{}\n", label, path.display()));
+        }
+        status.push('\n');
+    }
+    Ok(status)
+}
 
-    const AUTHOR_NAME: &str = "Sean";
-    const AUTHOR_EMAIL: &str = "sean@zombo.com";
-    const DATE: &str = "2021-01-01T01:01:01+00:00";
-    const MESSAGE: &str = "message";
+/// Describes how much state `reset` rewrites.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Only moves the current branch.
+    Soft,
+    /// Moves the current branch and rewrites the index.
+    Mixed,
+    /// Moves the current branch and rewrites both the index and workspace.
+    Hard,
+}
 
-    fn init_golden(dir: &TempDir) {
-        Command::new("git")
-            .args(&["init", &dir.path().as_os_str().to_string_lossy()])
-            .output()
-            .expect("failed to execute git init");
+impl ResetMode {
+    fn name(&self) -> &'static str {
+        match self {
+            ResetMode::Soft => "soft",
+            ResetMode::Mixed => "mixed",
+            ResetMode::Hard => "hard",
+        }
     }
+}
 
-    fn init_manually(dir: &TempDir) {
-        init(InitArgs {
-            path: Some(dir.path().as_os_str().to_str().unwrap()),
-            cwd: env::current_dir().unwrap(),
-        })
-        .unwrap();
+pub struct ResetArgs<'a> {
+    pub cwd: PathBuf,
+    pub mode: ResetMode,
+    pub revision: Option<&'a str>,
+    pub paths: Vec<&'a str>,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
+
+pub fn reset(args: ResetArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let workspace = repo.workspace();
+    let database = repo.database();
+    let refs = repo.refs();
+    let mut index = repo.load_index()?;
+
+    let mut revision = args.revision;
+    let mut paths = args.paths;
+
+    // Like git, accept "reset <path>" when <path> does not name a revision.
+    if let Some(rev) = revision {
+        let resolved = repo.resolve(rev);
+        if resolved.is_err() && repo.root_path().join(rev).exists() {
+            paths.insert(0, rev);
+            revision = None;
+        }
     }
 
-    // TODO: We have utilities to make test file generation easiser; see
-    // workspace tests
-    fn create_test_files(dir: &TempDir) {
-        write(dir.path().join("file.txt"), "file contents").unwrap();
-        create_dir_all(dir.path().join("subdir")).unwrap();
-        create_dir_all(dir.path().join("subdir/nested")).unwrap();
-        write(dir.path().join("subdir/file.txt"), "hi").unwrap();
-        write(dir.path().join("subdir/nested/file.txt"), "hello").unwrap();
+    let oid = match revision {
+        Some(rev) => repo.resolve(rev)?,
+        None => refs.read_head()?,
+    };
+    let commit = database.load_commit(&oid)?;
+
+    if !paths.is_empty() {
+        if args.mode != ResetMode::Mixed {
+            return Err(Error::Fatal(format!(
+                "Cannot do {} reset with paths.",
+                args.mode.name()
+            )));
+        }
+        let target = database.load_tree_list(commit.tree())?;
+        for path in paths {
+            let path = WorkspacePath::new(path)?;
+            let path = path.as_partial_path();
+            index.remove(path);
+            for (target_path, entry) in target.range(path.to_path_buf()..) {
+                if !target_path.starts_with(path) {
+                    break;
+                }
+                index.add(IndexEntry::from_tree_entry(entry)?);
+            }
+        }
+        return index.write();
+    }
+    if args.mode == ResetMode::Soft && index.has_conflicts() {
+        return Err(Error::Fatal(format!(
+            "Cannot do a {} reset in the middle of a merge.",
+            args.mode.name()
+        )));
     }
 
-    // Helper structure representing the "pristine" git impl for comparison.
-    struct GoldenGit {
-        dir: TempDir,
-        executor: Executor<String, String>,
+    let previous = refs.read_head().ok();
+    if let Some(previous) = &previous {
+        refs.update_ref(ORIG_HEAD, previous)?;
     }
+    let identity = Author::new(args.name, args.email, args.time);
+    refs.update_head(
+        &oid,
+        &identity,
+        &format!("reset: moving to {}", revision.unwrap_or(HEAD)),
+    )?;
 
-    impl GoldenGit {
-        fn git_dir(dir: &TempDir) -> String {
-            dir.path()
-                .join(".git")
-                .to_str()
-                .ok_or_else(|| anyhow!("Cannot convert path to str"))
-                .unwrap()
-                .to_string()
+    match args.mode {
+        ResetMode::Soft => (),
+        ResetMode::Mixed => {
+            index.clear();
+            for entry in database.load_tree_list(commit.tree())?.values() {
+                index.add(IndexEntry::from_tree_entry(entry)?);
+            }
+            index.write()?;
         }
-
-        fn git_worktree(dir: &TempDir) -> String {
-            dir.path()
-                .as_os_str()
-                .to_str()
-                .ok_or_else(|| anyhow!("Cannot convert path to str"))
-                .unwrap()
-                .to_string()
+        ResetMode::Hard => {
+            let previous_tree = match &previous {
+                Some(previous) => Some(database.load_commit(previous)?.tree().clone()),
+                None => None,
+            };
+            reset_workspace(
+                workspace,
+                database,
+                &mut index,
+                previous_tree,
+                commit.tree(),
+            )?;
+            index.write()?;
+            println!(
+                "HEAD is now at {} {}",
+                oid.as_short_str(),
+                commit.title_line()
+            );
         }
+    }
+    remove_branch_state(&repo)
+}
 
-        fn new() -> Self {
-            let dir = TempDir::new("git-golden").unwrap();
-            init_golden(&dir);
+// Forgets any cherry-pick or revert in progress, as git does once HEAD is
+// reset.
+fn remove_branch_state(repo: &Repository) -> Result<()> {
+    for name in [CHERRY_PICK_HEAD, REVERT_HEAD].iter() {
+        repo.refs().delete_ref(name)?;
+    }
+    Sequencer::new(repo.git_path()).quit()
+}
 
-            let executor = Executor::new(vec![
-                ("GIT_AUTHOR_NAME".to_string(), AUTHOR_NAME.to_string()),
-                ("GIT_AUTHOR_EMAIL".to_string(), AUTHOR_EMAIL.to_string()),
-                ("GIT_AUTHOR_DATE".to_string(), DATE.to_string()),
-                ("GIT_COMMITTER_NAME".to_string(), AUTHOR_NAME.to_string()),
-                ("GIT_COMMITTER_EMAIL".to_string(), AUTHOR_EMAIL.to_string()),
-                ("GIT_COMMITTER_DATE".to_string(), DATE.to_string()),
-                ("GIT_WORK_TREE".to_string(), GoldenGit::git_worktree(&dir)),
-                ("GIT_DIR".to_string(), GoldenGit::git_dir(&dir)),
-            ]);
+// Rewrites the index and workspace to match the tree `target`.
+//
+// Files tracked by either the index or `previous_tree` which are absent from
+// the target are removed; untracked files are left alone.
+fn reset_workspace(
+    workspace: &Workspace,
+    database: &Database,
+    index: &mut Index,
+    previous_tree: Option<ObjectID>,
+    target: &ObjectID,
+) -> Result<()> {
+    let target = database.load_tree_list(target)?;
 
-            GoldenGit { dir, executor }
-        }
+    let mut tracked: BTreeSet<PathBuf> = index.entries().map(|e| e.path().to_path_buf()).collect();
+    if let Some(previous_tree) = previous_tree {
+        tracked.extend(database.load_tree_list(&previous_tree)?.into_keys());
+    }
+    for path in tracked.iter().filter(|path| !target.contains_key(*path)) {
+        workspace.remove_file(&WorkspacePath::new(path)?)?;
+    }
 
-        fn path(&self) -> &std::path::Path {
-            self.dir.path()
-        }
+    let mut updated = Vec::new();
+    for (path, entry) in target {
+        let path = WorkspacePath::new(path)?;
 
-        fn populate_test_files(&self) {
-            create_test_files(&self.dir);
+        // Skip rewriting files which are known to be unchanged.
+        if let Some(existing) = index.entry(path.as_partial_path()) {
+            let unchanged = existing.oid() == entry.oid()
+                && existing.mode() == entry.mode()
+                && workspace
+                    .metadata(&path)
+                    .map(|metadata| existing.stat_matches(&metadata))
+                    .unwrap_or(false);
+            if unchanged {
+                updated.push(existing.clone());
+                continue;
+            }
         }
 
-        fn run<I, S>(&self, args: I)
-        where
-            I: IntoIterator<Item = S>,
-            S: AsRef<OsStr>,
-        {
-            self.executor.run(args)
-        }
+        merge::write_entry(workspace, database, &path, &entry)?;
+        let metadata = workspace.metadata(&path)?;
+        updated
+            .push(IndexEntry::new(path, entry.oid().clone(), &metadata).with_mode(*entry.mode()));
     }
 
-    #[test]
-    fn test_init() -> Result<()> {
-        let git_env = GoldenGit::new();
-        git_env.run(vec!["git", "init"]);
+    index.clear();
+    for entry in updated {
+        index.add(entry);
+    }
+    Ok(())
+}
 
-        let test_dir = TempDir::new("git-under-test")?;
-        init_manually(&test_dir);
+// Stores the tree described by the (unconflicted) index.
+fn write_index_tree(database: &Database, index: &Index) -> Result<Tree> {
+    if index.has_conflicts() {
+        return Err(Error::Fatal(
+            "The index contains unresolved conflicts".to_string(),
+        ));
+    }
+    let entries = index.entries().map(|entry| entry.to_entry()).collect();
+    Tree::build(entries, |tree| database.store(tree))
+}
 
-        // Compare the outputs for known paths.
-        directory_compare(
-            &mut vec![".git/HEAD", ".git/objects", ".git/refs"].into_iter(),
-            git_env.path(),
-            test_dir.path(),
-        )
-        .map_err(|e| anyhow!(e))?;
+// Returns the short name of the checked-out branch, as used within
+// generated messages.
+fn branch_name(refs: &Refs) -> Result<String> {
+    let current = refs.current_ref()?;
+    Ok(match current.strip_prefix("refs/heads/") {
+        Some(branch) => branch.to_string(),
+        None => "(no branch)".to_string(),
+    })
+}
 
-        Ok(())
-    }
+/// The operations supported by `stash`.
+pub enum StashCommand<'a> {
+    /// Saves local changes, and resets the workspace to HEAD.
+    Push { message: Option<&'a str> },
+    /// Applies a stash entry, then drops it if it applied cleanly.
+    Pop { index: usize },
+    /// Applies a stash entry to the workspace.
+    Apply { index: usize },
+    /// Lists the stash entries.
+    List,
+    /// Removes a stash entry.
+    Drop { index: usize },
+    /// Shows the changes recorded in a stash entry.
+    Show { index: usize, patch: bool },
+}
 
-    /*
-    #[test]
-    fn test_commit() -> Result<()> {
-        let git_env = GoldenGit::new();
-        git_env.run(vec!["git", "init"]);
-        git_env.populate_test_files();
-        git_env.run(vec!["git", "add", "file.txt"].into_iter());
-        git_env.run(vec!["git", "commit", "-m", MESSAGE].into_iter());
+pub struct StashArgs<'a> {
+    pub cwd: PathBuf,
+    pub command: StashCommand<'a>,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
 
-        let test_dir = TempDir::new("git-under-test")?;
-        init_manually(&test_dir);
-        create_test_files(&test_dir);
+pub fn stash(args: StashArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let workspace = repo.workspace();
+    let database = repo.database();
+    let refs = repo.refs();
+    let mut index = repo.load_index()?;
+    let stack = Stash::new(refs);
+    let identity = Author::new(args.name, args.email, args.time);
 
-        commit(CommitArgs {
-            cwd: test_dir.path().to_path_buf(),
-            message: Some(MESSAGE),
-            name: AUTHOR_NAME.to_string(),
-            email: AUTHOR_EMAIL.to_string(),
-            time: chrono::DateTime::parse_from_rfc3339(DATE)?,
-        })
-        .unwrap();
+    match args.command {
+        StashCommand::Push { message } => {
+            let head = refs
+                .read_head()
+                .map_err(|_| Error::Fatal("You do not have the initial commit yet".to_string()))?;
+            let head_commit = database.load_commit(&head)?;
+            let branch = branch_name(refs)?;
+            let subject = format!(
+                "{}: {} {}",
+                branch,
+                head.as_short_str(),
+                head_commit.title_line()
+            );
 
-        directory_compare(
-            &mut vec![
-                // 75..53: "file.txt"
-                ".git/objects/75/4bb844fb01df2613c0c1fe26eaa701ce46e853",
-                // d5..e7: Tree of files (only file.txt).
-                //                ".git/objects/d5/fc9eda85155890f7d5424130ab8684251b65e7",
-                // 6f..10: Commit object (message, timestamp, tree).
-                // TODO: Time?
-                //                ".git/objects/6f/7f57f6e269b85accf32468d9b6a31bfe206510",
-                ".git/refs",
-            ]
-            .into_iter(),
-            git_env.path(),
-            test_dir.path(),
-        )?;
+            let index_tree = write_index_tree(database, &index)?;
 
-        Ok(())
+            // The workspace tree captures the current contents of every
+            // tracked file.
+            let mut entries = Vec::new();
+            let threshold = repo.big_file_threshold()?;
+            for entry in index.entries() {
+                if entry.matches_workspace(workspace, threshold)? {
+                    entries.push(entry.to_entry());
+                    continue;
+                }
+                let path = WorkspacePath::new(entry.path())?;
+                let metadata = match workspace.metadata(&path) {
+                    Ok(metadata) if !metadata.is_dir() || *entry.mode() == Mode::Gitlink => {
+                        metadata
+                    }
+                    // Deleted files are omitted.
+                    _ => continue,
+                };
+                let oid = repo.store_file(&path, &metadata, threshold)?;
+                let mode = workspace.mode(&metadata, Some(*entry.mode()));
+                entries.push(Entry::new(path, oid, mode));
+            }
+            let workspace_tree = Tree::build(entries, |tree| database.store(tree))?;
+
+            if index_tree.oid() == *head_commit.tree()
+                && workspace_tree.oid() == *head_commit.tree()
+            {
+                println!("No local changes to save");
+                return Ok(());
+            }
+
+            let index_commit = Commit::new(
+                vec![head.clone()],
+                index_tree.oid(),
+                identity.clone(),
+                identity.clone(),
+                format!("index on {}\n", subject),
+            );
+            database.store(&index_commit)?;
+
+            let message = match message {
+                Some(message) => format!("On {}: {}", branch, message),
+                None => format!("WIP on {}", subject),
+            };
+            let stash_commit = Commit::new(
+                vec![head, index_commit.oid()],
+                workspace_tree.oid(),
+                identity.clone(),
+                identity.clone(),
+                format!("{}\n", message),
+            );
+            database.store(&stash_commit)?;
+            stack.push(&stash_commit.oid(), &identity, &message)?;
+
+            reset_workspace(workspace, database, &mut index, None, head_commit.tree())?;
+            index.write()?;
+            println!("Saved working directory and index state {}", message);
+        }
+        StashCommand::Apply { index: n } => {
+            stash_apply(&repo, &mut index, &stack.get(n)?)?;
+        }
+        StashCommand::Pop { index: n } => {
+            let oid = stack.get(n)?;
+            stash_apply(&repo, &mut index, &oid).map_err(|e| {
+                let kept = "The stash entry is kept in case you need it again.";
+                match e {
+                    Error::Conflict(message) => Error::Conflict(format!("{}\n{}", message, kept)),
+                    e => {
+                        eprintln!("{}", kept);
+                        e
+                    }
+                }
+            })?;
+            stack.drop(n)?;
+            println!("Dropped refs/stash@{{{}}} ({})", n, oid.as_str());
+        }
+        StashCommand::List => {
+            for (n, entry) in stack.list()?.iter().enumerate() {
+                println!("stash@{{{}}}: {}", n, entry.message);
+            }
+        }
+        StashCommand::Drop { index: n } => {
+            let oid = stack.drop(n)?;
+            println!("Dropped refs/stash@{{{}}} ({})", n, oid.as_str());
+        }
+        StashCommand::Show { index: n, patch } => {
+            let stash_commit = database.load_commit(&stack.get(n)?)?;
+            let base = stash_commit
+                .parent()
+                .ok_or_else(|| Error::Fatal("Stash entry has no base commit".to_string()))?;
+            let base_tree = database.load_tree_list(database.load_commit(base)?.tree())?;
+            let stash_tree = database.load_tree_list(stash_commit.tree())?;
+            let changes = diff::tree_changes(&base_tree, &stash_tree);
+
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            if patch {
+                for change in &changes {
+                    diff::write_patch(database, change, &mut out)?;
+                }
+            } else {
+                diff::write_stat(database, &changes, &mut out)?;
+            }
+        }
     }
-    */
+    Ok(())
+}
 
-    #[test]
-    fn test_commit_nested() -> Result<()> {
-        let test_dir = TempDir::new("git-under-test")?;
-        init_manually(&test_dir);
-        create_test_files(&test_dir);
+// Applies the changes recorded in a stash commit onto the workspace.
+//
+// Like git (without "--index"), new files are staged but other changes
+// are left unstaged.
+fn stash_apply(repo: &Repository, index: &mut Index, oid: &ObjectID) -> Result<()> {
+    let workspace = repo.workspace();
+    let database = repo.database();
+    if index.has_conflicts() {
+        return Err(Error::Fatal(
+            "Cannot apply a stash in the middle of a merge".to_string(),
+        ));
+    }
+    let stash_commit = database.load_commit(oid)?;
+    let base = stash_commit
+        .parent()
+        .ok_or_else(|| Error::Fatal("Stash entry has no base commit".to_string()))?;
 
-        commit(CommitArgs {
-            cwd: test_dir.path().to_path_buf(),
-            message: Some(MESSAGE),
-            name: AUTHOR_NAME.to_string(),
-            email: AUTHOR_EMAIL.to_string(),
-            time: chrono::DateTime::parse_from_rfc3339(DATE)?,
-        })
-        .unwrap();
+    let base = database.load_tree_list(database.load_commit(base)?.tree())?;
+    let ours = index
+        .entries()
+        .map(|entry| (entry.path().to_path_buf(), entry.to_entry()))
+        .collect();
+    let theirs = database.load_tree_list(stash_commit.tree())?;
+
+    let result = merge::merge_trees(
+        database,
+        &base,
+        &ours,
+        &theirs,
+        "Updated upstream",
+        "Stashed changes",
+    )?;
+    merge::check_workspace(workspace, repo.big_file_threshold()?, index, &ours, &result)?;
+    for message in &result.messages {
+        println!("{}", message);
+    }
 
-        // TODO: Actual test here
+    for path in merge::write_workspace(workspace, database, &ours, &result)? {
+        if ours.contains_key(&path) || result.conflicts.contains_key(&path) {
+            continue;
+        }
+        let entry = &result.entries[&path];
+        let path = WorkspacePath::new(&path)?;
+        let metadata = workspace.metadata(&path)?;
+        index.add(IndexEntry::new(path, entry.oid().clone(), &metadata).with_mode(*entry.mode()));
+    }
+    merge::add_conflicts(index, &result)?;
+    index.write()?;
+
+    if !result.is_clean() {
+        return Err(Error::Conflict(
+            "Conflicts in index. Resolve them before continuing.".to_string(),
+        ));
+    }
+    Ok(())
+}
 
+/// The ways of driving a cherry-pick or revert.
+pub enum SequenceCommand<'a> {
+    /// Applies the named commits, in order.
+    Start {
+        revisions: Vec<&'a str>,
+        options: sequencer::Options,
+    },
+    /// Commits the resolved conflicts, then applies the remaining commits.
+    Continue,
+    /// Cancels the operation, returning to the original HEAD.
+    Abort,
+}
+
+pub struct SequenceArgs<'a> {
+    pub cwd: PathBuf,
+    pub command: SequenceCommand<'a>,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// Applies the changes introduced by existing commits onto HEAD.
+pub fn cherry_pick(args: SequenceArgs) -> Result<()> {
+    sequence(args, Action::Pick)
+}
+
+/// Applies the inverse of the changes introduced by existing commits onto
+/// HEAD.
+pub fn revert(args: SequenceArgs) -> Result<()> {
+    sequence(args, Action::Revert)
+}
+
+fn sequence(args: SequenceArgs, action: Action) -> Result<()> {
+    let identity = Author::new(args.name, args.email, args.time);
+    let repo = Replayer::open(&args.cwd, identity)?;
+    let mut runner = SequenceRunner {
+        sequencer: Sequencer::new(repo.repository.git_path()),
+        repo,
+    };
+
+    match args.command {
+        SequenceCommand::Start { revisions, options } => {
+            let repo = &runner.repo;
+            let head = repo.refs().read_head().map_err(|_| {
+                Error::Fatal(format!(
+                    "Cannot {} on an unborn branch",
+                    action.command_name()
+                ))
+            })?;
+            let mut todo = Vec::new();
+            for revision in revisions {
+                let oid = Revision::parse(revision)?.resolve(repo.refs(), repo.database())?;
+                let commit = repo.database().load_commit(&oid)?;
+                todo.push(Step::new(action, oid, commit.title_line()));
+            }
+            runner.sequencer.start(&head, &options, &todo)?;
+        }
+        SequenceCommand::Continue => {
+            if !runner.sequencer.in_progress() {
+                return Err(Error::Fatal(
+                    "No cherry-pick or revert in progress".to_string(),
+                ));
+            }
+            runner.commit_resolution()?;
+        }
+        SequenceCommand::Abort => return runner.abort(),
+    }
+    runner.run()
+}
+
+// The repository state needed to replay commits onto HEAD, shared by
+// cherry-pick, revert and rebase.
+struct Replayer {
+    repository: Repository,
+    index: Index,
+    identity: Author,
+}
+
+impl Replayer {
+    fn open(root_path: &std::path::Path, identity: Author) -> Result<Self> {
+        let repository = Repository::open(root_path)?;
+        Ok(Replayer {
+            index: repository.load_index()?,
+            repository,
+            identity,
+        })
+    }
+
+    fn database(&self) -> &Database {
+        self.repository.database()
+    }
+
+    fn refs(&self) -> &Refs {
+        self.repository.refs()
+    }
+
+    fn workspace(&self) -> &Workspace {
+        self.repository.workspace()
+    }
+
+    // Returns the flattened tree of the (only) parent of `commit`.
+    fn parent_tree(&self, commit: &Commit, command: &str) -> Result<BTreeMap<PathBuf, Entry>> {
+        match commit.parents() {
+            [] => Ok(BTreeMap::new()),
+            [parent] => self
+                .database()
+                .load_tree_list(self.database().load_commit(parent)?.tree()),
+            _ => Err(Error::Fatal(format!(
+                "Commit {} is a merge, which cannot be used with {}",
+                commit.oid().as_str(),
+                command
+            ))),
+        }
+    }
+
+    // Applies the changes from `base` to `theirs` onto HEAD, updating the
+    // index and workspace.
+    //
+    // Returns the merged tree, or `None` if conflicts were recorded in the
+    // index.
+    fn merge_onto_head(
+        &mut self,
+        base: &BTreeMap<PathBuf, Entry>,
+        theirs: &BTreeMap<PathBuf, Entry>,
+        theirs_label: &str,
+        command: &str,
+    ) -> Result<Option<ObjectID>> {
+        let head = self.refs().read_head()?;
+        let ours = self
+            .database()
+            .load_tree_list(self.database().load_commit(&head)?.tree())?;
+        let staged = self.index.has_conflicts()
+            || self.index.entries().count() != ours.len()
+            || self.index.entries().any(|entry| {
+                ours.get(entry.path())
+                    .map(|tree_entry| {
+                        tree_entry.oid() != entry.oid() || tree_entry.mode() != entry.mode()
+                    })
+                    .unwrap_or(true)
+            });
+        if staged {
+            return Err(Error::Conflict(format!(
+                "Your local changes would be overwritten by {}.\n\
+                 Please commit your changes or stash them to proceed.",
+                command
+            )));
+        }
+
+        let result =
+            merge::merge_trees(self.database(), base, &ours, theirs, "HEAD", theirs_label)?;
+        merge::check_workspace(
+            self.workspace(),
+            self.repository.big_file_threshold()?,
+            &self.index,
+            &ours,
+            &result,
+        )?;
+        for message in &result.messages {
+            println!("{}", message);
+        }
+
+        let written = merge::write_workspace(self.workspace(), self.database(), &ours, &result)?;
+        for path in ours.keys() {
+            if !result.entries.contains_key(path) {
+                self.index.remove(path);
+            }
+        }
+        for path in written {
+            if result.conflicts.contains_key(&path) {
+                continue;
+            }
+            let entry = &result.entries[&path];
+            let path = WorkspacePath::new(&path)?;
+            let metadata = self.workspace().metadata(&path)?;
+            self.index.add(
+                IndexEntry::new(path, entry.oid().clone(), &metadata).with_mode(*entry.mode()),
+            );
+        }
+        merge::add_conflicts(&mut self.index, &result)?;
+        self.index.write()?;
+
+        if !result.is_clean() {
+            return Ok(None);
+        }
+        let tree = Tree::build(result.entries.into_values().collect(), |tree| {
+            self.database().store(tree)
+        })?;
+        Ok(Some(tree.oid()))
+    }
+
+    // Stages the workspace version of each conflicted path, returning the
+    // tree described by the resulting index.
+    //
+    // As rit has no "add", this is how conflict resolutions are recorded.
+    fn stage_resolutions(&mut self) -> Result<ObjectID> {
+        let threshold = self.repository.big_file_threshold()?;
+        for path in self.index.conflicted_paths() {
+            self.index.remove(&path);
+            let path = WorkspacePath::new(&path)?;
+            let metadata = match self.workspace().metadata(&path) {
+                Ok(metadata) if !metadata.is_dir() => metadata,
+                // The conflict was resolved by deleting the file.
+                _ => continue,
+            };
+            let oid = self.repository.store_file(&path, &metadata, threshold)?;
+            self.index.add(IndexEntry::new(path, oid, &metadata));
+        }
+        self.index.write()?;
+        Ok(write_index_tree(self.database(), &self.index)?.oid())
+    }
+
+    // Creates a commit, and moves HEAD to it.
+    fn commit(
+        &self,
+        parents: Vec<ObjectID>,
+        tree: ObjectID,
+        author: Author,
+        message: String,
+        reflog_message: &str,
+    ) -> Result<Commit> {
+        let commit = Commit::new(parents, tree, author, self.identity.clone(), message);
+        self.database().store(&commit)?;
+        self.refs()
+            .update_head(&commit.oid(), &self.identity, reflog_message)?;
+        Ok(commit)
+    }
+
+    // Rewrites the index and workspace to match the commit `target`, which
+    // replaces the tree of the current HEAD.
+    fn reset_hard(&mut self, target: &ObjectID) -> Result<()> {
+        let current_tree = match self.refs().read_head() {
+            Ok(current) => Some(self.database().load_commit(&current)?.tree().clone()),
+            Err(_) => None,
+        };
+        let target = self.database().load_commit(target)?;
+        reset_workspace(
+            self.repository.workspace(),
+            self.repository.database(),
+            &mut self.index,
+            current_tree,
+            target.tree(),
+        )?;
+        self.index.write()
+    }
+}
+
+// Drives a cherry-pick or revert.
+struct SequenceRunner {
+    repo: Replayer,
+    sequencer: Sequencer,
+}
+
+impl SequenceRunner {
+    // Applies each remaining step, stopping at the first conflict.
+    fn run(&mut self) -> Result<()> {
+        let options = self.sequencer.options()?;
+        loop {
+            let mut todo = self.sequencer.todo()?;
+            if todo.is_empty() {
+                break;
+            }
+            // The step is consumed before it is applied, so "--continue"
+            // resumes with the step which follows it.
+            let step = todo.remove(0);
+            self.sequencer.write_todo(&todo)?;
+            self.apply(&step, &options)?;
+        }
+        self.sequencer.quit()
+    }
+
+    fn apply(&mut self, step: &Step, options: &sequencer::Options) -> Result<()> {
+        let command = step.action.command_name();
+        let commit = self.repo.database().load_commit(&step.oid)?;
+        let short = step.oid.as_short_str();
+        let parent_tree = self.repo.parent_tree(&commit, command)?;
+        let commit_tree = self.repo.database().load_tree_list(commit.tree())?;
+
+        let (base, theirs, theirs_label, message) = match step.action {
+            Action::Pick => {
+                let mut message = commit.message().to_string();
+                if options.record_origin {
+                    message = format!(
+                        "{}\n\n(cherry picked from commit {})\n",
+                        message.trim_end(),
+                        step.oid.as_str()
+                    );
+                }
+                let label = format!("{}... {}", short, commit.title_line());
+                (parent_tree, commit_tree, label, message)
+            }
+            Action::Revert => {
+                let message = format!(
+                    "Revert \"{}\"\n\nThis reverts commit {}.\n",
+                    commit.title_line(),
+                    step.oid.as_str()
+                );
+                let label = format!("parent of {}... {}", short, commit.title_line());
+                (commit_tree, parent_tree, label, message)
+            }
+        };
+
+        let head = self.repo.refs().read_head()?;
+        let head_tree = self.repo.database().load_commit(&head)?.tree().clone();
+        let tree = match self
+            .repo
+            .merge_onto_head(&base, &theirs, &theirs_label, command)?
+        {
+            Some(tree) => tree,
+            None => {
+                self.repo
+                    .refs()
+                    .update_ref(pending_ref(step.action), &step.oid)?;
+                self.sequencer.save_message(&message)?;
+                return Err(Error::Conflict(format!(
+                    "Could not {} {}... {}\n\
+                     After resolving the conflicts, run \"rit {} --continue\".",
+                    step.action.as_str(),
+                    short,
+                    commit.title_line(),
+                    command
+                )));
+            }
+        };
+        if tree == head_tree {
+            return Err(Error::Fatal(format!(
+                "The previous {} is now empty.\n\
+                 Run \"rit {} --continue\" to skip it.",
+                command, command
+            )));
+        }
+        let author = match step.action {
+            Action::Pick => commit.author().clone(),
+            Action::Revert => self.repo.identity.clone(),
+        };
+        self.commit(head, tree, author, message, step.action)
+    }
+
+    // Commits the index once the conflicts of an interrupted step have been
+    // resolved.
+    fn commit_resolution(&mut self) -> Result<()> {
+        let tree = self.repo.stage_resolutions()?;
+        for action in [Action::Pick, Action::Revert].iter() {
+            let pending = match self.repo.refs().read_ref(pending_ref(*action))? {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let author = match action {
+                Action::Pick => self.repo.database().load_commit(&pending)?.author().clone(),
+                Action::Revert => self.repo.identity.clone(),
+            };
+            let head = self.repo.refs().read_head()?;
+            let message = self.sequencer.message()?;
+            self.commit(head, tree.clone(), author, message, *action)?;
+            self.repo.refs().delete_ref(pending_ref(*action))?;
+        }
+        Ok(())
+    }
+
+    fn commit(
+        &self,
+        parent: ObjectID,
+        tree: ObjectID,
+        author: Author,
+        message: String,
+        action: Action,
+    ) -> Result<()> {
+        let title = message.lines().next().unwrap_or("").to_string();
+        let commit = self.repo.commit(
+            vec![parent],
+            tree,
+            author,
+            message,
+            &format!("{}: {}", action.command_name(), title),
+        )?;
+        println!(
+            "[{} {}] {}",
+            branch_name(self.repo.refs())?,
+            commit.oid().as_short_str(),
+            commit.title_line()
+        );
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        let head = self.sequencer.head()?;
+        self.repo.reset_hard(&head)?;
+        self.repo.refs().update_head(
+            &head,
+            &self.repo.identity,
+            &format!("reset: moving to {}", head.as_str()),
+        )?;
+        for action in [Action::Pick, Action::Revert].iter() {
+            self.repo.refs().delete_ref(pending_ref(*action))?;
+        }
+        self.sequencer.quit()
+    }
+}
+
+// Returns the reference which records the commit of an interrupted step.
+fn pending_ref(action: Action) -> &'static str {
+    match action {
+        Action::Pick => CHERRY_PICK_HEAD,
+        Action::Revert => REVERT_HEAD,
+    }
+}
+
+/// The ways of driving a rebase.
+pub enum RebaseCommand<'a> {
+    /// Replays the commits of the current branch which are not in
+    /// `upstream` onto `onto` (or `upstream` itself).
+    Start {
+        upstream: &'a str,
+        onto: Option<&'a str>,
+        /// Lets the user edit the todo list before it is applied.
+        interactive: bool,
+    },
+    /// Commits the resolved conflicts, then replays the remaining commits.
+    Continue,
+    /// Discards the commit which could not be applied, then replays the
+    /// remaining commits.
+    Skip,
+    /// Cancels the rebase, returning to the original branch.
+    Abort,
+}
+
+pub struct RebaseArgs<'a> {
+    pub cwd: PathBuf,
+    pub command: RebaseCommand<'a>,
+    /// The editor used for commit messages.
+    pub editor: String,
+    /// The editor used for the todo list.
+    pub sequence_editor: String,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
+
+pub fn rebase(args: RebaseArgs) -> Result<()> {
+    let identity = Author::new(args.name, args.email, args.time);
+    let repo = Replayer::open(&args.cwd, identity)?;
+    let mut runner = RebaseRunner {
+        state: RebaseState::new(repo.repository.git_path()),
+        repo,
+        editor: args.editor,
+    };
+
+    match args.command {
+        RebaseCommand::Start {
+            upstream,
+            onto,
+            interactive,
+        } => {
+            if !runner.start(upstream, onto, interactive, &args.sequence_editor)? {
+                return Ok(());
+            }
+        }
+        RebaseCommand::Continue => runner.commit_resolution()?,
+        RebaseCommand::Skip => runner.skip()?,
+        RebaseCommand::Abort => return runner.abort(),
+    }
+    runner.run()
+}
+
+// Drives a rebase.
+struct RebaseRunner {
+    repo: Replayer,
+    state: RebaseState,
+    editor: String,
+}
+
+impl RebaseRunner {
+    // Records the todo list and checks out the new base.
+    //
+    // Returns false if there is nothing to do.
+    fn start(
+        &mut self,
+        upstream: &str,
+        onto: Option<&str>,
+        interactive: bool,
+        sequence_editor: &str,
+    ) -> Result<bool> {
+        if self.state.in_progress() {
+            return Err(Error::Fatal(
+                "It seems that there is already a rebase in progress.\n\
+                 Use \"rit rebase (--continue | --skip | --abort)\"."
+                    .to_string(),
+            ));
+        }
+        let repo = &self.repo;
+        let head = repo.refs().read_head()?;
+        let upstream_oid = Revision::parse(upstream)?.resolve(repo.refs(), repo.database())?;
+        let onto_name = onto.unwrap_or(upstream);
+        let onto = Revision::parse(onto_name)?.resolve(repo.refs(), repo.database())?;
+        let head_name = match repo.refs().current_ref()? {
+            name if name == HEAD => "detached HEAD".to_string(),
+            name => name,
+        };
+
+        let head_tree = repo
+            .database()
+            .load_tree_list(repo.database().load_commit(&head)?.tree())?;
+        let mut dirty =
+            repo.index.has_conflicts() || repo.index.entries().count() != head_tree.len();
+        let threshold = repo.repository.big_file_threshold()?;
+        for entry in repo.index.entries() {
+            dirty = dirty
+                || head_tree.get(entry.path()) != Some(&entry.to_entry())
+                || !entry.matches_workspace(repo.workspace(), threshold)?;
+        }
+        if dirty {
+            return Err(Error::Fatal(
+                "Cannot rebase: You have unstaged changes.\n\
+                 Please commit or stash them."
+                    .to_string(),
+            ));
+        }
+
+        let up_to_date = onto == upstream_oid
+            && revision::commit_range(repo.database(), Some(&head), &upstream_oid)?.is_empty();
+        if up_to_date && !interactive {
+            println!("Current branch {} is up to date.", head_name);
+            return Ok(false);
+        }
+        let hooks = repo.repository.hooks()?;
+        if !hooks.run(hooks::PRE_REBASE, &[upstream], b"")? {
+            return Err(Error::Fatal(
+                "The pre-rebase hook refused to rebase.".to_string(),
+            ));
+        }
+
+        // Like git, merges are dropped rather than replayed.
+        let mut todo = Vec::new();
+        for oid in revision::commit_range(repo.database(), Some(&upstream_oid), &head)? {
+            let commit = repo.database().load_commit(&oid)?;
+            if commit.parents().len() <= 1 {
+                todo.push(TodoItem::Commit {
+                    action: rebase::Action::Pick,
+                    oid,
+                    title: commit.title_line().to_string(),
+                });
+            }
+        }
+        self.state.start(&head_name, &onto, &head, &todo)?;
+
+        if interactive {
+            let edited = self.edit_todo(&todo, &upstream_oid, &head, &onto, sequence_editor);
+            todo = match edited {
+                Ok(todo) => todo,
+                Err(e) => {
+                    self.state.finish()?;
+                    return Err(e);
+                }
+            };
+        }
+
+        // As in git, ORIG_HEAD lets the rebase be undone once finished.
+        self.repo.refs().update_ref(ORIG_HEAD, &head)?;
+        self.repo.reset_hard(&onto)?;
+        self.repo.refs().update_logged_ref(
+            HEAD,
+            &onto,
+            &self.repo.identity,
+            &format!("rebase (start): checkout {}", onto_name),
+        )?;
+        hooks.run(
+            hooks::POST_CHECKOUT,
+            &[&head.as_str(), &onto.as_str(), "1"],
+            b"",
+        )?;
+        Ok(!todo.is_empty())
+    }
+
+    // Lets the user rewrite the todo list, returning the validated result.
+    fn edit_todo(
+        &self,
+        todo: &[TodoItem],
+        upstream: &ObjectID,
+        head: &ObjectID,
+        onto: &ObjectID,
+        sequence_editor: &str,
+    ) -> Result<Vec<TodoItem>> {
+        let summary = format!(
+            "Rebase {}..{} onto {} ({} commands)",
+            upstream.as_short_str(),
+            head.as_short_str(),
+            onto.as_short_str(),
+            todo.len()
+        );
+        self.state.write_todo_with_help(todo, &summary)?;
+        editor::edit(sequence_editor, &self.state.todo_path())?;
+
+        let todo = self.state.todo(self.repo.database())?;
+        if todo.is_empty() {
+            return Err(Error::Fatal("Nothing to do".to_string()));
+        }
+        let first = todo.iter().find_map(|item| match item {
+            TodoItem::Commit { action, .. } if *action != rebase::Action::Drop => Some(*action),
+            _ => None,
+        });
+        if let Some(action @ (rebase::Action::Squash | rebase::Action::Fixup)) = first {
+            return Err(Error::Fatal(format!(
+                "Cannot '{}' without a previous commit",
+                action.as_str()
+            )));
+        }
+        self.state.write_todo(&todo)?;
+        Ok(todo)
+    }
+
+    // Applies each remaining item, stopping at the first failure.
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let mut todo = self.state.todo(self.repo.database())?;
+            if todo.is_empty() {
+                break;
+            }
+            // The item is consumed before it is applied, so "--continue"
+            // resumes with the item which follows it.
+            let item = todo.remove(0);
+            self.state.write_todo(&todo)?;
+            self.state.append_done(&item)?;
+            match item {
+                TodoItem::Commit { action, oid, .. } => self.replay(action, &oid)?,
+                TodoItem::Exec(command) => self.exec(&command)?,
+            }
+        }
+        self.finish()
+    }
+
+    fn replay(&mut self, action: rebase::Action, oid: &ObjectID) -> Result<()> {
+        if action == rebase::Action::Drop {
+            return Ok(());
+        }
+        let commit = self.repo.database().load_commit(oid)?;
+        let head = self.repo.refs().read_head()?;
+
+        // Commits which already follow HEAD are reused as-is.
+        if action == rebase::Action::Pick && commit.parent() == Some(&head) {
+            self.repo.reset_hard(oid)?;
+            return self.repo.refs().update_head(
+                oid,
+                &self.repo.identity,
+                &format!("rebase (pick): {}", commit.title_line()),
+            );
+        }
+
+        let base = self.repo.parent_tree(&commit, "rebase")?;
+        let theirs = self.repo.database().load_tree_list(commit.tree())?;
+        let label = format!("{} ({})", oid.as_short_str(), commit.title_line());
+        let head_message = self
+            .repo
+            .database()
+            .load_commit(&head)?
+            .message()
+            .to_string();
+        let message = match action {
+            rebase::Action::Squash => format!(
+                "# This is a combination of commits.\n\
+                 # The first commit's message is:\n\n{}\n\
+                 # This is the next commit message:\n\n{}",
+                head_message,
+                commit.message()
+            ),
+            rebase::Action::Fixup => head_message,
+            _ => commit.message().to_string(),
+        };
+
+        match self
+            .repo
+            .merge_onto_head(&base, &theirs, &label, "rebase")?
+        {
+            Some(tree) => self.commit(action, &commit, tree, message),
+            None => {
+                self.state.save_message(&message)?;
+                Err(Error::Conflict(format!(
+                    "Could not apply {}... {}\n\
+                     Resolve all conflicts manually, then run \"rit rebase --continue\".\n\
+                     You can instead skip this commit with \"rit rebase --skip\",\n\
+                     or stop the rebase with \"rit rebase --abort\".",
+                    oid.as_short_str(),
+                    commit.title_line()
+                )))
+            }
+        }
+    }
+
+    // Records the result of replaying `commit` as `tree`.
+    fn commit(
+        &self,
+        action: rebase::Action,
+        commit: &Commit,
+        tree: ObjectID,
+        message: String,
+    ) -> Result<()> {
+        let head = self.repo.refs().read_head()?;
+        let head_commit = self.repo.database().load_commit(&head)?;
+        let message = match action {
+            rebase::Action::Reword | rebase::Action::Squash => editor::edit_message(
+                &self.editor,
+                &self.repo.repository.git_path().join("COMMIT_EDITMSG"),
+                &message,
+            )?,
+            _ => message,
+        };
+        let reflog_message = format!(
+            "rebase ({}): {}",
+            action.as_str(),
+            message.lines().next().unwrap_or("")
+        );
+
+        match action {
+            rebase::Action::Squash | rebase::Action::Fixup => {
+                // Replaces HEAD, which the commit is melded into.
+                self.repo.commit(
+                    head_commit.parents().to_vec(),
+                    tree,
+                    head_commit.author().clone(),
+                    message,
+                    &reflog_message,
+                )?;
+            }
+            _ if tree == *head_commit.tree() => {
+                println!(
+                    "dropping {} {} -- patch contents already upstream",
+                    commit.oid().as_str(),
+                    commit.title_line()
+                );
+            }
+            _ => {
+                self.repo.commit(
+                    vec![head],
+                    tree,
+                    commit.author().clone(),
+                    message,
+                    &reflog_message,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn exec(&mut self, command: &str) -> Result<()> {
+        println!("Executing: {}", command);
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(self.repo.repository.root_path())
+            .status()?;
+        // The command may have changed the index (by committing, for
+        // example).
+        self.repo.index = self.repo.repository.load_index()?;
+        if !status.success() {
+            return Err(Error::Fatal(format!(
+                "Execution failed: {}\n\
+                 You can fix the problem, and then run\n\n  rit rebase --continue",
+                command
+            )));
+        }
+        Ok(())
+    }
+
+    // Commits the index once the conflicts of an interrupted item have been
+    // resolved.
+    fn commit_resolution(&mut self) -> Result<()> {
+        let message = match self.state.message()? {
+            Some(message) => message,
+            None => {
+                // Ensures a rebase is in progress.
+                self.state.head_name()?;
+                return Ok(());
+            }
+        };
+        let tree = self.repo.stage_resolutions()?;
+        let stopped = self
+            .state
+            .done(self.repo.database())?
+            .into_iter()
+            .rev()
+            .find_map(|item| match item {
+                TodoItem::Commit { action, oid, .. } => Some((action, oid)),
+                TodoItem::Exec(_) => None,
+            });
+        let (action, oid) =
+            stopped.ok_or_else(|| Error::Fatal("No rebase step to continue".to_string()))?;
+        let commit = self.repo.database().load_commit(&oid)?;
+        self.commit(action, &commit, tree, message)?;
+        self.state.clear_message()
+    }
+
+    fn skip(&mut self) -> Result<()> {
+        self.state.head_name()?;
+        if self.state.message()?.is_some() {
+            let head = self.repo.refs().read_head()?;
+            self.repo.reset_hard(&head)?;
+            self.state.clear_message()?;
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        let head_name = self.state.head_name()?;
+        let orig_head = self.state.orig_head()?;
+        self.repo.reset_hard(&orig_head)?;
+        self.repo.refs().update_logged_ref(
+            HEAD,
+            &orig_head,
+            &self.repo.identity,
+            &format!("rebase (abort): returning to {}", head_name),
+        )?;
+        if head_name.starts_with("refs/") {
+            self.repo.refs().set_symref(HEAD, &head_name)?;
+        }
+        self.state.finish()
+    }
+
+    // Moves the rebased branch to the final commit, and checks it out.
+    fn finish(&mut self) -> Result<()> {
+        let head = self.repo.refs().read_head()?;
+        let head_name = self.state.head_name()?;
+        let onto = self.state.onto()?;
+        if head_name.starts_with("refs/") {
+            self.repo.refs().update_logged_ref(
+                &head_name,
+                &head,
+                &self.repo.identity,
+                &format!("rebase (finish): {} onto {}", head_name, onto.as_str()),
+            )?;
+            self.repo.refs().update_logged_ref(
+                HEAD,
+                &head,
+                &self.repo.identity,
+                &format!("rebase (finish): returning to {}", head_name),
+            )?;
+            self.repo.refs().set_symref(HEAD, &head_name)?;
+        }
+        self.state.finish()?;
+        println!("Successfully rebased and updated {}.", head_name);
+        Ok(())
+    }
+}
+
+/// The operations supported by `submodule`.
+pub enum SubmoduleCommand<'a> {
+    /// Clones the repository at `url` into `path` (by default, named after
+    /// the URL), and describes it within ".gitmodules".
+    Add { url: &'a str, path: Option<&'a str> },
+    /// Registers the URLs of submodules within ".git/config".
+    Init { paths: Vec<&'a str> },
+    /// Clones missing submodules, and checks out the recorded commits.
+    Update { init: bool, paths: Vec<&'a str> },
+    /// Shows the commit checked out by each submodule.
+    Status { paths: Vec<&'a str> },
+    /// Runs a shell command within each checked-out submodule.
+    Foreach { command: &'a str },
+}
+
+pub struct SubmoduleArgs<'a> {
+    pub cwd: PathBuf,
+    pub command: SubmoduleCommand<'a>,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// Manages submodules. Only URLs naming local repositories are supported.
+pub fn submodule(args: SubmoduleArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let workspace = repo.workspace();
+    let index = repo.load_index()?;
+    let mut submodules = Submodules::load(repo.root_path())?;
+    let identity = Author::new(args.name, args.email, args.time);
+
+    // The submodules recorded by the index, along with their commits,
+    // restricted to `paths` (if any are given).
+    let recorded = |paths: &[&str]| -> Result<Vec<(WorkspacePath, ObjectID)>> {
+        let paths = paths
+            .iter()
+            .map(|path| WorkspacePath::new(path.trim_end_matches('/')))
+            .collect::<Result<Vec<_>>>()?;
+        index
+            .entries()
+            .filter(|entry| *entry.mode() == Mode::Gitlink)
+            .filter(|entry| {
+                paths.is_empty()
+                    || paths
+                        .iter()
+                        .any(|path| entry.path().starts_with(path.as_partial_path()))
+            })
+            .map(|entry| Ok((WorkspacePath::new(entry.path())?, entry.oid().clone())))
+            .collect()
+    };
+    // Looks up the ".gitmodules" entry for a recorded submodule.
+    let describe = |path: &WorkspacePath| -> Result<Submodule> {
+        submodules
+            .find_by_path(path.as_partial_path())
+            .ok_or_else(|| {
+                Error::Fatal(format!(
+                    "No url found for submodule path '{}' in {}",
+                    path.as_partial_path().display(),
+                    GITMODULES
+                ))
+            })
+    };
+
+    match args.command {
+        SubmoduleCommand::Add { url, path } => {
+            let path = match path {
+                Some(path) => path.trim_end_matches('/').to_string(),
+                None => clone::default_directory(url),
+            };
+            let path = WorkspacePath::new(path)?;
+            if index.entry(path.as_partial_path()).is_some() {
+                return Err(Error::Fatal(format!(
+                    "'{}' already exists in the index",
+                    path.as_partial_path().display()
+                )));
+            }
+            if workspace.full_path(&path).exists() {
+                return Err(Error::Fatal(format!(
+                    "'{}' already exists and is not a valid git repo",
+                    path.as_partial_path().display()
+                )));
+            }
+            // Like a clone, the submodule starts on its remote's branch.
+            let source = clone::Source::locate(url, repo.root_path())?;
+            let full_path = workspace.full_path(&path);
+            eprintln!("Cloning into '{}'...", full_path.display());
+            clone_repository(&source, &full_path, false, &identity)?;
+
+            let name = path.as_partial_path().to_string_lossy().to_string();
+            let submodule = Submodule {
+                name,
+                path: path.as_partial_path().to_path_buf(),
+                url: url.to_string(),
+            };
+            submodules.add(&submodule)?;
+            register_submodule(&repo, &submodule)?;
+        }
+        SubmoduleCommand::Init { paths } => {
+            for (path, _) in recorded(&paths)? {
+                register_submodule(&repo, &describe(&path)?)?;
+            }
+        }
+        SubmoduleCommand::Update { init, paths } => {
+            for (path, oid) in recorded(&paths)? {
+                let submodule = describe(&path)?;
+                if init {
+                    register_submodule(&repo, &submodule)?;
+                }
+                // Uninitialized submodules are skipped.
+                let url = match repo.config()?.get(&submodule.variable("url")) {
+                    Some(url) => url.to_string(),
+                    None => continue,
+                };
+                let source = clone::Source::locate(&url, repo.root_path())?;
+                let full_path = workspace.full_path(&path);
+                let cloned = workspace.nested_git_dir(&path).is_none();
+                let sub_repo = if cloned {
+                    eprintln!("Cloning into '{}'...", full_path.display());
+                    clone_repository(&source, &full_path, false, &identity)?
+                } else {
+                    Repository::open(&full_path)?
+                };
+                let previous = sub_repo.refs().read_ref(HEAD)?;
+                if !cloned && previous.as_ref() == Some(&oid) {
+                    continue;
+                }
+
+                // Fetch the recorded commit, if it is missing.
+                if !sub_repo.database().contains(&oid)? {
+                    sub_repo.database().import(&source.database())?;
+                }
+                let tree = sub_repo.database().load_commit(&oid)?.tree().clone();
+                let previous_tree = match &previous {
+                    Some(previous) => {
+                        Some(sub_repo.database().load_commit(previous)?.tree().clone())
+                    }
+                    None => None,
+                };
+                let mut sub_index = sub_repo.load_index()?;
+                reset_workspace(
+                    sub_repo.workspace(),
+                    sub_repo.database(),
+                    &mut sub_index,
+                    previous_tree,
+                    &tree,
+                )?;
+                sub_index.write()?;
+                sub_repo.refs().update_logged_ref(
+                    HEAD,
+                    &oid,
+                    &identity,
+                    &format!("checkout: moving to {}", oid.as_str()),
+                )?;
+                println!(
+                    "Submodule path '{}': checked out '{}'",
+                    path.as_partial_path().display(),
+                    oid.as_str()
+                );
+            }
+        }
+        SubmoduleCommand::Status { paths } => {
+            for (path, oid) in recorded(&paths)? {
+                let (prefix, oid) = match workspace.submodule_head(&path)? {
+                    None => ('-', oid),
+                    Some(head) if head == oid => (' ', oid),
+                    Some(head) => ('+', head),
+                };
+                println!(
+                    "{}{} {}",
+                    prefix,
+                    oid.as_str(),
+                    path.as_partial_path().display()
+                );
+            }
+        }
+        SubmoduleCommand::Foreach { command } => {
+            for (path, oid) in recorded(&[])? {
+                if workspace.nested_git_dir(&path).is_none() {
+                    continue;
+                }
+                let sm_path = path.as_partial_path().to_string_lossy().to_string();
+                let name = submodules
+                    .find_by_path(path.as_partial_path())
+                    .map(|submodule| submodule.name)
+                    .unwrap_or_else(|| sm_path.clone());
+                println!("Entering '{}'", sm_path);
+                std::io::stdout().flush()?;
+                let status = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .current_dir(workspace.full_path(&path))
+                    .env("name", name)
+                    .env("sm_path", &sm_path)
+                    .env("displaypath", &sm_path)
+                    .env("sha1", oid.as_str())
+                    .env("toplevel", repo.root_path())
+                    .status()?;
+                if !status.success() {
+                    return Err(Error::Fatal(format!(
+                        "run_command returned non-zero status for {}",
+                        sm_path
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Records the URL of a submodule within ".git/config", unless it is already
+// there, resolving relative URLs.
+fn register_submodule(repo: &Repository, submodule: &Submodule) -> Result<()> {
+    let mut config = repo.config()?;
+    let variable = submodule.variable("url");
+    if config.get(&variable).is_some() {
+        return Ok(());
+    }
+    let url = submodule.resolve_url(repo.root_path());
+    let url = url
+        .to_str()
+        .ok_or_else(|| Error::invalid_path(&url, "not valid unicode"))?;
+    config.set(&variable, url)?;
+    config.save(repo.git_path().join("config"))?;
+    println!(
+        "Submodule '{}' ({}) registered for path '{}'",
+        submodule.name,
+        url,
+        submodule.path.display()
+    );
+    Ok(())
+}
+
+// Creates a repository at `dest` (which must be empty, if it exists) from
+// the objects and references of `source`, and checks out the branch (or
+// detached HEAD) `source` has checked out. With `link`, objects are hard
+// linked rather than copied.
+//
+// If the clone fails, whatever it created is removed again: `dest` itself,
+// unless it already existed.
+fn clone_repository(
+    source: &clone::Source,
+    dest: &Path,
+    link: bool,
+    identity: &Author,
+) -> Result<Repository> {
+    let existed = dest.exists();
+    let not_empty = existed && (!dest.is_dir() || std::fs::read_dir(dest)?.next().is_some());
+    if not_empty {
+        return Err(Error::Fatal(format!(
+            "destination path '{}' already exists and is not an empty directory.",
+            dest.display()
+        )));
+    }
+    let result = populate_clone(source, dest, link, identity);
+    if result.is_err() {
+        let removed = if existed {
+            std::fs::read_dir(dest)
+                .and_then(|mut entries| entries.try_for_each(|entry| remove_all(&entry?.path())))
+        } else {
+            std::fs::remove_dir_all(dest)
+        };
+        if let Err(e) = removed {
+            eprintln!("warning: could not remove '{}': {}", dest.display(), e);
+        }
+    }
+    result
+}
+
+// Removes a file, or a directory and everything within it.
+fn remove_all(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() && !path.is_symlink() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+// Fills in a clone at `dest`, which is empty.
+fn populate_clone(
+    source: &clone::Source,
+    dest: &Path,
+    link: bool,
+    identity: &Author,
+) -> Result<Repository> {
+    let repo = Repository::init(dest)?;
+    clone::copy_objects(
+        &source.git_dir().join("objects"),
+        &repo.git_path().join("objects"),
+        link && !source.is_file_url(),
+    )?;
+
+    // Branches become remote-tracking branches, while tags are kept as
+    // they are.
+    let source_refs = source.refs();
+    let refs = repo.refs();
+    for (name, oid) in source_refs.list_refs()? {
+        if let Some(branch) = name.strip_prefix("refs/heads/") {
+            refs.update_ref(&format!("refs/remotes/origin/{}", branch), &oid)?;
+        } else if name.starts_with("refs/tags/") {
+            refs.update_ref(&name, &oid)?;
+        }
+    }
+
+    let mut config = repo.config()?;
+    config.set("remote.origin.url", source.url())?;
+    config.set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+    let current = source_refs.current_ref()?;
+    let head = source_refs.read_ref(HEAD)?;
+    if let Some(branch) = current.strip_prefix("refs/heads/") {
+        refs.set_symref(HEAD, &current)?;
+        config.set(&format!("branch.{}.remote", branch), "origin")?;
+        config.set(&format!("branch.{}.merge", branch), &current)?;
+        if head.is_some() {
+            refs.set_symref(
+                "refs/remotes/origin/HEAD",
+                &format!("refs/remotes/origin/{}", branch),
+            )?;
+        }
+    }
+    config.save(repo.git_path().join("config"))?;
+
+    let head = match head {
+        Some(head) => head,
+        None => {
+            eprintln!("warning: You appear to have cloned an empty repository.");
+            return Ok(repo);
+        }
+    };
+    let message = format!("clone: from {}", source.url());
+    if current == HEAD {
+        refs.update_logged_ref(HEAD, &head, identity, &message)?;
+    } else {
+        refs.update_head(&head, identity, &message)?;
+    }
+    let tree = repo.database().load_commit(&head)?.tree().clone();
+    let mut index = repo.load_index()?;
+    reset_workspace(repo.workspace(), repo.database(), &mut index, None, &tree)?;
+    index.write()?;
+    repo.hooks()?
+        .run(hooks::POST_CHECKOUT, &[NULL_OID, &head.as_str(), "1"], b"")?;
+    Ok(repo)
+}
+
+pub struct CloneArgs<'a> {
+    pub cwd: PathBuf,
+    /// The path (or "file://" URL) of the repository to clone.
+    pub source: &'a str,
+    /// Where to clone into; defaults to a directory named after `source`.
+    pub directory: Option<&'a str>,
+    /// Hard links objects from the source rather than copying them.
+    pub local: bool,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// Clones a repository on the local filesystem, setting it up as the
+/// "origin" remote.
+pub fn clone(args: CloneArgs) -> Result<()> {
+    let source = clone::Source::locate(args.source, &args.cwd)?;
+    let directory = match args.directory {
+        Some(directory) => directory.to_string(),
+        None => clone::default_directory(args.source),
+    };
+    let identity = Author::new(args.name, args.email, args.time);
+    eprintln!("Cloning into '{}'...", directory);
+    clone_repository(&source, &args.cwd.join(&directory), args.local, &identity)?;
+    eprintln!("done.");
+    Ok(())
+}
+
+pub struct FetchArgs<'a> {
+    pub cwd: PathBuf,
+    /// The remote (or URL) to fetch from; defaults to the current branch's
+    /// remote, or "origin".
+    pub remote: Option<&'a str>,
+    /// Refspecs overriding the remote's configured ones.
+    pub refspecs: Vec<&'a str>,
+    pub options: fetch::Options,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// Downloads objects and references from another repository, reporting
+/// each updated reference as git does.
+pub fn fetch(args: FetchArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let config = repo.config()?;
+    let identity = Author::new(args.name, args.email, args.time);
+    let name = match args.remote {
+        Some(name) => name.to_string(),
+        None => default_remote(&repo, &config)?,
+    };
+    let remote = fetch::Remote::load(&config, &name, &args.cwd)?;
+    let refspecs = args
+        .refspecs
+        .iter()
+        .map(|spec| spec.parse())
+        .collect::<Result<Vec<Refspec>>>()?;
+
+    // As in git, reflog entries name the command as it was run.
+    let action = std::iter::once("fetch")
+        .chain(args.remote)
+        .chain(args.refspecs.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut progress = |message: &str| eprint!("remote: {}", message);
+    let fetched = fetch::fetch(
+        &repo,
+        &remote,
+        &refspecs,
+        args.options,
+        &identity,
+        &action,
+        &mut progress,
+    )?;
+
+    for name in &fetched.ignored {
+        eprintln!("warning: ignoring ref with broken name {}", name);
+    }
+    let width = fetch::summary_width(&fetched.updates);
+    let shown: Vec<_> = fetched.updates.iter().filter(|u| u.is_shown()).collect();
+    if !shown.is_empty() {
+        eprintln!("From {}", remote.url());
+    }
+    for update in shown {
+        eprintln!("{}", update.summary(width));
+    }
+    if fetched.updates.iter().any(|update| update.is_rejected()) {
+        return Err(Error::Conflict(
+            "some local refs could not be updated".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Returns the remote of the current branch, or "origin".
+fn default_remote(repo: &Repository, config: &Config) -> Result<String> {
+    let current = repo.refs().current_ref()?;
+    Ok(current
+        .strip_prefix("refs/heads/")
+        .and_then(|branch| config.get(&format!("branch.{}.remote", branch)))
+        .unwrap_or("origin")
+        .to_string())
+}
+
+pub struct PushArgs<'a> {
+    pub cwd: PathBuf,
+    /// The remote (or URL) to push to; defaults to the current branch's
+    /// remote, or "origin".
+    pub remote: Option<&'a str>,
+    /// Refspecs overriding the remote's configured ones.
+    pub refspecs: Vec<&'a str>,
+    pub options: push::Options,
+    pub name: String,
+    pub email: String,
+    pub time: chrono::DateTime<chrono::FixedOffset>,
+}
+
+/// Uploads objects and updates references in another repository,
+/// reporting each reference as git does.
+pub fn push(args: PushArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let config = repo.config()?;
+    let identity = Author::new(args.name, args.email, args.time);
+    let name = match args.remote {
+        Some(name) => name.to_string(),
+        None => default_remote(&repo, &config)?,
+    };
+    let remote = fetch::Remote::load(&config, &name, &args.cwd)?;
+    let refspecs = args
+        .refspecs
+        .iter()
+        .map(|spec| spec.parse())
+        .collect::<Result<Vec<Refspec>>>()?;
+
+    let pushed = push::push(&repo, &remote, &refspecs, &args.options, &identity)?;
+    let shown: Vec<_> = pushed.updates.iter().filter(|u| u.is_shown()).collect();
+    if shown.is_empty() {
+        eprintln!("Everything up-to-date");
+    } else {
+        eprintln!("To {}", remote.url());
+    }
+    for update in shown {
+        eprintln!("{}", update.summary());
+    }
+    if pushed.updates.iter().any(|update| update.is_rejected()) {
+        return Err(Error::Conflict(format!(
+            "failed to push some refs to '{}'",
+            remote.url()
+        )));
+    }
+    Ok(())
+}
+
+pub struct UploadPackArgs<'a> {
+    pub cwd: PathBuf,
+    /// The repository to serve.
+    pub directory: &'a str,
+    /// The value of GIT_PROTOCOL, which selects the protocol version.
+    pub protocol: Option<String>,
+    pub options: server::Options,
+}
+
+/// Serves a fetch from a repository over standard input and output.
+pub fn upload_pack(args: UploadPackArgs) -> Result<()> {
+    let source = clone::Source::locate(args.directory, &args.cwd)?;
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut output = std::io::BufWriter::new(stdout.lock());
+    server::upload_pack(
+        &source,
+        args.protocol.as_deref(),
+        args.options,
+        &mut stdin.lock(),
+        &mut output,
+    )
+}
+
+pub struct ReceivePackArgs<'a> {
+    pub cwd: PathBuf,
+    /// The repository to update.
+    pub directory: &'a str,
+    pub options: server::Options,
+}
+
+/// Serves a push to a repository over standard input and output.
+pub fn receive_pack(args: ReceivePackArgs) -> Result<()> {
+    let source = clone::Source::locate(args.directory, &args.cwd)?;
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut output = std::io::BufWriter::new(stdout.lock());
+    server::receive_pack(&source, args.options, &mut stdin.lock(), &mut output)
+}
+
+pub struct HttpBackendArgs {
+    /// The CGI variables describing the request, along with
+    /// GIT_PROJECT_ROOT and GIT_HTTP_EXPORT_ALL.
+    pub vars: HashMap<String, String>,
+}
+
+/// Answers a smart HTTP request as a CGI program, reading its body from
+/// standard input and writing the response to standard output.
+pub fn http_backend(args: HttpBackendArgs) -> Result<()> {
+    let root = args
+        .vars
+        .get("GIT_PROJECT_ROOT")
+        .ok_or_else(|| Error::Fatal("GIT_PROJECT_ROOT is not set".to_string()))?;
+    let backend = http::Backend::new(
+        Path::new(root),
+        args.vars.contains_key("GIT_HTTP_EXPORT_ALL"),
+    );
+
+    let stdin = std::io::stdin();
+    let mut body = vec![];
+    match args
+        .vars
+        .get("CONTENT_LENGTH")
+        .filter(|length| !length.is_empty())
+    {
+        Some(length) => {
+            let length: usize = length
+                .parse()
+                .map_err(|_| Error::Fatal(format!("invalid CONTENT_LENGTH '{}'", length)))?;
+            // Larger bodies are refused once read this far.
+            let length = length.min(http::MAX_BODY_LENGTH + 1);
+            stdin.lock().take(length as u64).read_to_end(&mut body)?;
+            if body.len() < length {
+                return Err(Error::Fatal("truncated request body".to_string()));
+            }
+        }
+        None => {
+            stdin
+                .lock()
+                .take(http::MAX_BODY_LENGTH as u64 + 1)
+                .read_to_end(&mut body)?;
+        }
+    }
+    let request = http::Request::from_cgi(&args.vars, body);
+    let stdout = std::io::stdout();
+    let mut output = std::io::BufWriter::new(stdout.lock());
+    backend.handle(&request).write_cgi(&mut output)
+}
+
+pub struct ServeArgs<'a> {
+    pub cwd: PathBuf,
+    /// The address to listen on for HTTP, such as "127.0.0.1:8080".
+    pub http: &'a str,
+    /// The directory whose repositories are served.
+    pub directory: Option<&'a str>,
+}
+
+/// Serves every repository beneath a directory over smart HTTP, until
+/// interrupted.
+pub fn serve(args: ServeArgs) -> Result<()> {
+    let listener = std::net::TcpListener::bind(args.http)
+        .map_err(|e| Error::Fatal(format!("unable to listen on {}: {}", args.http, e)))?;
+    let root = match args.directory {
+        Some(directory) => args.cwd.join(directory),
+        None => args.cwd,
+    };
+    println!(
+        "Serving {} on http://{}/",
+        root.display(),
+        listener.local_addr()?
+    );
+    std::io::stdout().flush()?;
+    http::serve(listener, http::Backend::new(&root, true))
+}
+
+pub struct FsckArgs {
+    pub cwd: PathBuf,
+    /// Whether to report unreachable objects which nothing refers to.
+    pub dangling: bool,
+}
+
+/// Verifies the objects within the repository, and the connections between
+/// them, failing if the repository is broken.
+pub fn fsck(args: FsckArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let problems = fsck::check(&repo)?;
+    for problem in &problems {
+        match problem {
+            fsck::Problem::Dangling { .. } if !args.dangling => (),
+            fsck::Problem::Dangling { .. } | fsck::Problem::Missing { .. } => {
+                println!("{}", problem)
+            }
+            _ => eprintln!("{}", problem),
+        }
+    }
+    // Like git, each problem has been reported, so only the exit status
+    // remains.
+    match fsck::exit_code(&problems) {
+        0 => Ok(()),
+        code => Err(Error::Exit(code)),
+    }
+}
+
+pub struct PruneArgs<'a> {
+    pub cwd: PathBuf,
+    /// Only objects older than this (such as "2.weeks.ago") are removed;
+    /// defaults to gc.pruneExpire.
+    pub expire: Option<&'a str>,
+    /// Lists the objects which would be removed, without removing them.
+    pub dry_run: bool,
+    /// Lists the objects as they are removed.
+    pub verbose: bool,
+}
+
+/// Removes unreachable objects, along with stale temporary files.
+pub fn prune(args: PruneArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let now = std::time::SystemTime::now();
+    let expire = match args.expire {
+        Some(expire) => gc::parse_expire(expire, now)?,
+        None => gc::prune_expire(&repo, now)?,
+    };
+    let pruned = gc::prune(&repo, expire, args.dry_run)?;
+    if args.dry_run || args.verbose {
+        for (oid, type_name) in pruned.objects {
+            println!("{} {}", oid.as_str(), type_name);
+        }
+    }
+    Ok(())
+}
+
+pub struct GcArgs<'a> {
+    pub cwd: PathBuf,
+    /// Overrides gc.pruneExpire.
+    pub prune: Option<&'a str>,
+}
+
+/// Cleans up the repository. As rit does not pack objects, this only
+/// prunes unreachable objects older than gc.pruneExpire.
+pub fn gc(args: GcArgs) -> Result<()> {
+    prune(PruneArgs {
+        cwd: args.cwd,
+        expire: args.prune,
+        dry_run: false,
+        verbose: false,
+    })
+}
+
+pub struct ShowArgs<'a> {
+    pub cwd: PathBuf,
+    /// Revisions naming the objects to show, such as "HEAD" or
+    /// "HEAD~1:src/main.rs".
+    pub objects: Vec<&'a str>,
+}
+
+/// Shows commits (with their changes), trees, blobs and annotated tags.
+pub fn show(args: ShowArgs) -> Result<()> {
+    let repo = Repository::open(&args.cwd)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let mut shown_commit = false;
+    for name in args.objects {
+        let oid = repo.resolve(name)?;
+        show_object(repo.database(), name, &oid, &mut shown_commit, &mut out)?;
+    }
+    Ok(())
+}
+
+// Writes a single object. `shown_commit` tracks whether a commit has been
+// written already, as consecutive commits are separated by a blank line.
+fn show_object(
+    database: &Database,
+    name: &str,
+    oid: &ObjectID,
+    shown_commit: &mut bool,
+    out: &mut impl Write,
+) -> Result<()> {
+    match database.load(oid)? {
+        Object::Commit(commit) => {
+            if *shown_commit {
+                writeln!(out)?;
+            }
+            write_commit(database, oid, &commit, out)?;
+            *shown_commit = true;
+        }
+        Object::Tree(tree) => {
+            writeln!(out, "tree {}\n", name)?;
+            for entry in tree.entries() {
+                let suffix = if *entry.mode() == Mode::Directory {
+                    "/"
+                } else {
+                    ""
+                };
+                writeln!(out, "{}{}", entry.path().display(), suffix)?;
+            }
+        }
+        Object::Blob(blob) => out.write_all(blob.data())?,
+        Object::Tag(tag) => {
+            writeln!(out, "tag {}", tag.name())?;
+            if let Some(tagger) = tag.tagger() {
+                writeln!(out, "Tagger: {} <{}>", tagger.name(), tagger.email())?;
+                writeln!(out, "Date:   {}", tagger.display_time())?;
+            }
+            writeln!(out)?;
+            writeln!(out, "{}", tag.message().trim_end())?;
+            writeln!(out)?;
+            let target = tag.object().as_str();
+            show_object(database, &target, tag.object(), shown_commit, out)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes the header and message of a commit, followed by its changes
+// relative to its parent (or a combined diff, for merges).
+fn write_commit(
+    database: &Database,
+    oid: &ObjectID,
+    commit: &Commit,
+    out: &mut impl Write,
+) -> Result<()> {
+    writeln!(out, "commit {}", oid.as_str())?;
+    if commit.parents().len() > 1 {
+        let parents: Vec<String> = commit.parents().iter().map(|p| p.as_short_str()).collect();
+        writeln!(out, "Merge: {}", parents.join(" "))?;
+    }
+    let author = commit.author();
+    writeln!(out, "Author: {} <{}>", author.name(), author.email())?;
+    writeln!(out, "Date:   {}", author.display_time())?;
+    writeln!(out)?;
+    for line in commit.message().lines() {
+        writeln!(out, "    {}", line)?;
+    }
+
+    let tree = database.load_tree_list(commit.tree())?;
+    let mut parent_trees = Vec::new();
+    for parent in commit.parents() {
+        parent_trees.push(database.load_tree_list(database.load_commit(parent)?.tree())?);
+    }
+
+    let mut patch = Vec::new();
+    if parent_trees.len() <= 1 {
+        let empty = BTreeMap::new();
+        let parent_tree = parent_trees.first().unwrap_or(&empty);
+        for change in diff::tree_changes(parent_tree, &tree) {
+            diff::write_patch(database, &change, &mut patch)?;
+        }
+    } else {
+        // Only paths which differ from every parent are shown.
+        for (path, entry) in &tree {
+            let parents: Vec<Option<Entry>> = parent_trees
+                .iter()
+                .map(|parent_tree| parent_tree.get(path).cloned())
+                .collect();
+            if parents.iter().all(|parent| parent.as_ref() != Some(entry)) {
+                diff::write_combined_patch(database, path, &parents, entry, &mut patch)?;
+            }
+        }
+    }
+    if !patch.is_empty() {
+        writeln!(out)?;
+        out.write_all(&patch)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Result};
+    use directory_compare::directory_compare;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+    use tempdir::TempDir;
+    use test_process_executor::Executor;
+
+    const AUTHOR_NAME: &str = "Sean";
+    const AUTHOR_EMAIL: &str = "sean@zombo.com";
+    const DATE: &str = "2021-01-01T01:01:01+00:00";
+    const MESSAGE: &str = "message";
+
+    fn init_golden(dir: &TempDir) {
+        Command::new("git")
+            .args(["init", &dir.path().as_os_str().to_string_lossy()])
+            .output()
+            .expect("failed to execute git init");
+    }
+
+    fn init_args(dir: &TempDir) -> InitArgs<'_> {
+        InitArgs {
+            path: Some(dir.path().as_os_str().to_str().unwrap()),
+            cwd: env::current_dir().unwrap(),
+            bare: false,
+            initial_branch: None,
+            default_branch: None,
+            template: None,
+            shared: init::Shared::Umask,
+            separate_git_dir: None,
+        }
+    }
+
+    fn init_manually(dir: &TempDir) {
+        init(init_args(dir)).unwrap();
+    }
+
+    // TODO: We have utilities to make test file generation easiser; see
+    // workspace tests
+    fn create_test_files(dir: &TempDir) {
+        create_test_files_at(dir.path());
+    }
+
+    fn create_test_files_at(root: &std::path::Path) {
+        write(root.join("file.txt"), "file contents").unwrap();
+        create_dir_all(root.join("subdir")).unwrap();
+        create_dir_all(root.join("subdir/nested")).unwrap();
+        write(root.join("subdir/file.txt"), "hi").unwrap();
+        write(root.join("subdir/nested/file.txt"), "hello").unwrap();
+    }
+
+    // Helper structure representing the "pristine" git impl for comparison.
+    struct GoldenGit {
+        dir: TempDir,
+        executor: Executor<String, String>,
+    }
+
+    impl GoldenGit {
+        fn git_dir(dir: &TempDir) -> String {
+            dir.path()
+                .join(".git")
+                .to_str()
+                .ok_or_else(|| anyhow!("Cannot convert path to str"))
+                .unwrap()
+                .to_string()
+        }
+
+        fn git_worktree(dir: &TempDir) -> String {
+            dir.path()
+                .as_os_str()
+                .to_str()
+                .ok_or_else(|| anyhow!("Cannot convert path to str"))
+                .unwrap()
+                .to_string()
+        }
+
+        fn new() -> Self {
+            let dir = TempDir::new("git-golden").unwrap();
+            init_golden(&dir);
+
+            let executor = Executor::new(vec![
+                ("GIT_AUTHOR_NAME".to_string(), AUTHOR_NAME.to_string()),
+                ("GIT_AUTHOR_EMAIL".to_string(), AUTHOR_EMAIL.to_string()),
+                ("GIT_AUTHOR_DATE".to_string(), DATE.to_string()),
+                ("GIT_COMMITTER_NAME".to_string(), AUTHOR_NAME.to_string()),
+                ("GIT_COMMITTER_EMAIL".to_string(), AUTHOR_EMAIL.to_string()),
+                ("GIT_COMMITTER_DATE".to_string(), DATE.to_string()),
+                ("GIT_WORK_TREE".to_string(), GoldenGit::git_worktree(&dir)),
+                ("GIT_DIR".to_string(), GoldenGit::git_dir(&dir)),
+            ]);
+
+            GoldenGit { dir, executor }
+        }
+
+        fn path(&self) -> &std::path::Path {
+            self.dir.path()
+        }
+
+        fn populate_test_files(&self) {
+            create_test_files(&self.dir);
+        }
+
+        fn run<I, S>(&self, args: I)
+        where
+            I: IntoIterator<Item = S>,
+            S: AsRef<OsStr>,
+        {
+            self.executor.run(args)
+        }
+    }
+
+    #[test]
+    fn test_init() -> Result<()> {
+        let git_env = GoldenGit::new();
+        git_env.run(vec!["git", "init"]);
+
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+
+        // Compare the outputs for known paths.
+        directory_compare(
+            &mut vec![
+                ".git/HEAD",
+                ".git/config",
+                ".git/description",
+                ".git/info/exclude",
+                ".git/objects/info",
+                ".git/objects/pack",
+                ".git/refs/heads",
+                ".git/refs/tags",
+            ]
+            .into_iter(),
+            git_env.path(),
+            test_dir.path(),
+        )
+        .map_err(|e| anyhow!(e))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_bare() -> Result<()> {
+        let golden = TempDir::new("git-golden")?;
+        git_output(
+            golden.path(),
+            &[
+                "init",
+                "-q",
+                "--bare",
+                "-b",
+                "main",
+                "--shared=0640",
+                "repo.git",
+            ],
+        );
+
+        let test_dir = TempDir::new("git-under-test")?;
+        init(InitArgs {
+            path: Some("repo.git"),
+            cwd: test_dir.path().to_path_buf(),
+            bare: true,
+            initial_branch: Some("main"),
+            default_branch: None,
+            template: None,
+            shared: "0640".parse()?,
+            separate_git_dir: None,
+        })?;
+
+        directory_compare(
+            &mut vec![
+                "repo.git/HEAD",
+                "repo.git/config",
+                "repo.git/description",
+                "repo.git/info/exclude",
+                "repo.git/objects/info",
+                "repo.git/objects/pack",
+                "repo.git/refs/heads",
+                "repo.git/refs/tags",
+            ]
+            .into_iter(),
+            golden.path(),
+            test_dir.path(),
+        )
+        .map_err(|e| anyhow!(e))?;
+        for path in ["repo.git/HEAD", "repo.git/config", "repo.git/refs"].iter() {
+            let mode = |root: &Path| -> Result<u32> {
+                Ok(std::fs::metadata(root.join(path))?.permissions().mode() & 0o7777)
+            };
+            assert_eq!(mode(golden.path())?, mode(test_dir.path())?, "{}", path);
+        }
+        Ok(())
+    }
+
+    /*
+    #[test]
+    fn test_commit() -> Result<()> {
+        let git_env = GoldenGit::new();
+        git_env.run(vec!["git", "init"]);
+        git_env.populate_test_files();
+        git_env.run(vec!["git", "add", "file.txt"].into_iter());
+        git_env.run(vec!["git", "commit", "-m", MESSAGE].into_iter());
+
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+
+        commit(commit_args(test_dir.path(), MESSAGE))
+        .unwrap();
+
+        directory_compare(
+            &mut vec![
+                // 75..53: "file.txt"
+                ".git/objects/75/4bb844fb01df2613c0c1fe26eaa701ce46e853",
+                // d5..e7: Tree of files (only file.txt).
+                //                ".git/objects/d5/fc9eda85155890f7d5424130ab8684251b65e7",
+                // 6f..10: Commit object (message, timestamp, tree).
+                // TODO: Time?
+                //                ".git/objects/6f/7f57f6e269b85accf32468d9b6a31bfe206510",
+                ".git/refs",
+            ]
+            .into_iter(),
+            git_env.path(),
+            test_dir.path(),
+        )?;
+
+        Ok(())
+    }
+    */
+
+    // Runs git within `dir`, returning its stdout.
+    fn git_output(dir: &std::path::Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", &format!("user.name={}", AUTHOR_NAME)])
+            .args(["-c", &format!("user.email={}", AUTHOR_EMAIL)])
+            .args(args)
+            .output()
+            .expect("failed to execute git");
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    // Installs an executable hook within the repository at `dir`.
+    fn write_hook(dir: &Path, name: &str, script: &str) -> Result<()> {
+        let path = dir.join(".git/hooks").join(name);
+        write(&path, format!("#!/bin/sh\n{}", script))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_hooks() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        let dir = test_dir.path();
+        let commit_args = |message, no_verify| CommitArgs {
+            no_verify,
+            ..commit_args(dir, message)
+        };
+
+        // The pre-commit hook sees the index about to be committed.
+        write_hook(
+            dir,
+            "pre-commit",
+            "git diff --cached --name-only > .git/staged\nexit 1\n",
+        )?;
+        let result = commit(commit_args("first\n", false));
+        assert!(matches!(result, Err(Error::HookDeclined("pre-commit"))));
+        assert_eq!(
+            std::fs::read_to_string(dir.join(".git/staged"))?,
+            "file.txt\nsubdir/file.txt\nsubdir/nested/file.txt\n"
+        );
+        assert!(Refs::new(dir.join(".git")).read_head().is_err());
+
+        write_hook(dir, "prepare-commit-msg", "echo \"$2\" > .git/source\n")?;
+        write_hook(dir, "commit-msg", "echo 'Signed-off-by: A' >> \"$1\"\n")?;
+        write_hook(dir, "post-commit", "git rev-parse HEAD > .git/committed\n")?;
+        commit(commit_args("first\n", true))?;
+        let head = git_output(dir, &["rev-parse", "HEAD"]);
+        assert_eq!(git_output(dir, &["log", "-1", "--format=%B"]), "first\n\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join(".git/source"))?,
+            "message\n"
+        );
+        assert_eq!(std::fs::read_to_string(dir.join(".git/committed"))?, head);
+
+        // The commit-msg hook may edit the message, or refuse it.
+        write_hook(dir, "pre-commit", "exit 0\n")?;
+        write(dir.join("file.txt"), "second")?;
+        commit(commit_args("second\n", false))?;
+        assert_eq!(
+            git_output(dir, &["log", "-1", "--format=%B"]),
+            "second\nSigned-off-by: A\n\n"
+        );
+        write_hook(dir, "commit-msg", "exit 1\n")?;
+        write(dir.join("file.txt"), "third")?;
+        let result = commit(commit_args("third\n", false));
+        assert!(matches!(result, Err(Error::HookDeclined("commit-msg"))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_message() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        let dir = test_dir.path();
+        let seen = dir.join(".git/seen");
+        let message = |dir: &Path| git_output(dir, &["log", "-1", "--format=%B"]);
+
+        // Without a message, the editor opens with a summary of the commit.
+        let args = CommitArgs {
+            message: vec![],
+            editor: format!(
+                "f() {{ cp \"$1\" {}; printf 'Title \\n\\n\\n# note\\n' > \"$1\"; }}; f",
+                seen.display()
+            ),
+            ..commit_args(dir, "")
+        };
+        commit(args)?;
+        assert_eq!(
+            std::fs::read_to_string(&seen)?,
+            "\n\
+             # Please enter the commit message for your changes. Lines starting\n\
+             # with '#' will be ignored, and an empty message aborts the commit.\n\
+             #\n\
+             # On branch master\n\
+             #\n\
+             # Initial commit\n\
+             #\n\
+             # Changes to be committed:\n\
+             #\tnew file:   file.txt\n\
+             #\tnew file:   subdir/file.txt\n\
+             #\tnew file:   subdir/nested/file.txt\n\
+             #\n"
+        );
+        assert_eq!(message(dir), "Title\n\n");
+
+        // Paragraphs from "-m" may be edited, keeping comments with the
+        // "whitespace" cleanup.
+        write(dir.join("file.txt"), "second")?;
+        std::fs::remove_file(dir.join("subdir/file.txt"))?;
+        let args = CommitArgs {
+            message: vec!["First", "Second\n"],
+            edit: Some(true),
+            cleanup: editor::Cleanup::Whitespace,
+            comment_char: editor::CommentChar::Char(';'),
+            editor: format!("f() {{ cp \"$1\" {}; }}; f", seen.display()),
+            ..commit_args(dir, "")
+        };
+        commit(args)?;
+        assert_eq!(
+            std::fs::read_to_string(&seen)?,
+            "First\n\nSecond\n\n\
+             ; Please enter the commit message for your changes. Lines starting\n\
+             ; with ';' will be kept; you may remove them yourself if you want to.\n\
+             ; An empty message aborts the commit.\n\
+             ;\n\
+             ; On branch master\n\
+             ; Changes to be committed:\n\
+             ;\tmodified:   file.txt\n\
+             ;\tdeleted:    subdir/file.txt\n\
+             ;\n"
+        );
+        let edited = std::fs::read_to_string(&seen)?;
+        assert_eq!(message(dir), edited + "\n");
+
+        // A message from a file is not edited, and may be kept verbatim.
+        write(dir.join("file.txt"), "third")?;
+        write(dir.join(".git/message"), "  Spaced  \n\n\n# kept\n")?;
+        let args = CommitArgs {
+            message: vec![],
+            file: Some(".git/message"),
+            cleanup: editor::Cleanup::Verbatim,
+            editor: "false".to_string(),
+            ..commit_args(dir, "")
+        };
+        commit(args)?;
+        assert_eq!(message(dir), "  Spaced  \n\n\n# kept\n\n");
+
+        // Commits are refused with an empty message, or an unedited
+        // template.
+        write(dir.join("file.txt"), "fourth")?;
+        let result = commit(commit_args(dir, "\n"));
+        assert!(matches!(result, Err(Error::Fatal(_))));
+        write(dir.join(".git/template"), "Template\n")?;
+        let args = CommitArgs {
+            message: vec![],
+            template: Some(dir.join(".git/template")),
+            ..commit_args(dir, "")
+        };
+        let result = commit(args);
+        assert!(matches!(result, Err(Error::Fatal(message)) if message.contains("did not edit")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_amend() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        let dir = test_dir.path();
+        let log = |dir: &Path| git_output(dir, &["log", "--format=%an %cn %s"]);
+
+        let result = commit(CommitArgs {
+            amend: true,
+            ..commit_args(dir, "nothing")
+        });
+        assert!(matches!(result, Err(Error::Fatal(_))));
+        let result = commit(commit_args(dir, "empty"));
+        assert!(matches!(result, Err(Error::Fatal(_))));
+
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+
+        // Amending keeps the author, and the message unless one is given.
+        write(dir.join("file.txt"), "amended")?;
+        commit(CommitArgs {
+            amend: true,
+            name: "Other".to_string(),
+            ..commit_args(dir, "amended")
+        })?;
+        assert_eq!(log(dir), format!("{} Other amended\n", AUTHOR_NAME));
+        assert_eq!(
+            git_output(dir, &["reflog", "-1", "--format=%gs"]),
+            "commit (amend): amended\n"
+        );
+        commit(CommitArgs {
+            message: vec![],
+            amend: true,
+            reset_author: true,
+            name: "Other".to_string(),
+            ..commit_args(dir, "")
+        })?;
+        assert_eq!(log(dir), "Other Other amended\n");
+        assert_eq!(git_output(dir, &["show", "HEAD:file.txt"]), "amended");
+
+        // Commits (and amendments) must change their parent's tree.
+        write(dir.join("file.txt"), "second")?;
+        commit_all(&test_dir, "second");
+        let result = commit(commit_args(dir, "third"));
+        assert!(matches!(result, Err(Error::Fatal(_))));
+        commit(CommitArgs {
+            allow_empty: true,
+            ..commit_args(dir, "third")
+        })?;
+        let result = commit(CommitArgs {
+            amend: true,
+            ..commit_args(dir, "third")
+        });
+        assert!(matches!(result, Err(Error::Fatal(message)) if message.contains("--allow-empty")));
+        assert_eq!(
+            log(dir),
+            format!(
+                "{0} {0} third\n{0} {0} second\nOther Other amended\n",
+                AUTHOR_NAME
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_paths() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        let dir = test_dir.path();
+        commit_all(&test_dir, "first");
+        let changes = |dir: &Path| git_output(dir, &["show", "--name-status", "--format=", "HEAD"]);
+
+        write(dir.join("file.txt"), "changed")?;
+        write(dir.join("subdir/file.txt"), "changed")?;
+        std::fs::remove_file(dir.join("subdir/nested/file.txt"))?;
+        write(dir.join("new.txt"), "new")?;
+
+        // Only the named paths are committed.
+        let result = commit(CommitArgs {
+            paths: vec!["missing"],
+            ..commit_args(dir, "missing")
+        });
+        assert!(matches!(result, Err(Error::Fatal(_))));
+        commit(CommitArgs {
+            paths: vec!["subdir"],
+            ..commit_args(dir, "subdir")
+        })?;
+        assert_eq!(
+            changes(dir),
+            "M\tsubdir/file.txt\nD\tsubdir/nested/file.txt\n"
+        );
+        assert_eq!(
+            git_output(dir, &["status", "--porcelain"]),
+            " M file.txt\n?? new.txt\n"
+        );
+
+        // An aborted commit leaves the index untouched.
+        let index = std::fs::read(dir.join(".git/index"))?;
+        let result = commit(CommitArgs {
+            all: true,
+            ..commit_args(dir, "")
+        });
+        assert!(matches!(result, Err(Error::Fatal(_))));
+        write_hook(dir, "pre-commit", "exit 1\n")?;
+        let result = commit(CommitArgs {
+            paths: vec!["file.txt"],
+            ..commit_args(dir, "file")
+        });
+        assert!(matches!(result, Err(Error::HookDeclined("pre-commit"))));
+        std::fs::remove_file(dir.join(".git/hooks/pre-commit"))?;
+        assert_eq!(std::fs::read(dir.join(".git/index"))?, index);
+        assert!(!dir.join(".git/index.lock").exists());
+
+        // "-a" leaves untracked files out.
+        commit(CommitArgs {
+            all: true,
+            ..commit_args(dir, "all")
+        })?;
+        assert_eq!(changes(dir), "M\tfile.txt\n");
+        assert_eq!(git_output(dir, &["status", "--porcelain"]), "?? new.txt\n");
+
+        commit_all(&test_dir, "new");
+        assert_eq!(changes(dir), "A\tnew.txt\n");
+        Ok(())
+    }
+
+    fn commit_args<'a>(dir: &Path, message: &'a str) -> CommitArgs<'a> {
+        CommitArgs {
+            cwd: dir.to_path_buf(),
+            message: vec![message],
+            file: None,
+            template: None,
+            edit: None,
+            cleanup: editor::Cleanup::default(),
+            comment_char: editor::CommentChar::default(),
+            editor: ":".to_string(),
+            no_verify: false,
+            all: false,
+            paths: vec![],
+            amend: false,
+            reset_author: false,
+            allow_empty: false,
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        }
+    }
+
+    fn commit_all(dir: &TempDir, message: &str) -> ObjectID {
+        commit(commit_args(dir.path(), message)).unwrap();
+        Refs::new(dir.path().join(".git")).read_head().unwrap()
+    }
+
+    fn reset_args<'a>(
+        dir: &TempDir,
+        mode: ResetMode,
+        revision: Option<&'a str>,
+        paths: Vec<&'a str>,
+    ) -> ResetArgs<'a> {
+        ResetArgs {
+            cwd: dir.path().to_path_buf(),
+            mode,
+            revision,
+            paths,
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_commit_nested() -> Result<()> {
+        let git_env = GoldenGit::new();
+        git_env.populate_test_files();
+        git_env.run(vec!["git", "add", "."]);
+        git_env.run(vec!["git", "commit", "-m", MESSAGE]);
+
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+
+        commit(commit_args(test_dir.path(), MESSAGE)).unwrap();
+
+        // Git should find identical trees (and IDs) in both repositories.
+        let ls_tree = ["ls-tree", "-r", "-t", "HEAD"];
+        assert_eq!(
+            git_output(git_env.path(), &ls_tree),
+            git_output(test_dir.path(), &ls_tree)
+        );
+        // The index written by the commit should match the workspace.
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_symlinks() -> Result<()> {
+        let create_links = |root: &std::path::Path| -> Result<()> {
+            create_test_files_at(root);
+            std::os::unix::fs::symlink("file.txt", root.join("link"))?;
+            std::os::unix::fs::symlink("subdir", root.join("dir-link"))?;
+            std::os::unix::fs::symlink("missing", root.join("subdir/dangling"))?;
+            Ok(())
+        };
+        let git_env = GoldenGit::new();
+        create_links(git_env.path())?;
+        git_env.run(vec!["git", "add", "."]);
+        git_env.run(vec!["git", "commit", "-m", MESSAGE]);
+
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_links(test_dir.path())?;
+        let first = commit_all(&test_dir, MESSAGE);
+
+        let ls_tree = ["ls-tree", "-r", "-t", "HEAD"];
+        assert_eq!(
+            git_output(git_env.path(), &ls_tree),
+            git_output(test_dir.path(), &ls_tree)
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+
+        // Checking out recreates the links, rather than copies of their
+        // targets.
+        std::fs::remove_file(test_dir.path().join("link"))?;
+        std::fs::remove_file(test_dir.path().join("subdir/dangling"))?;
+        std::fs::remove_file(test_dir.path().join("dir-link"))?;
+        write(test_dir.path().join("dir-link"), "replaced")?;
+        commit_all(&test_dir, "second");
+        reset(reset_args(
+            &test_dir,
+            ResetMode::Hard,
+            Some(&first.as_str()),
+            vec![],
+        ))?;
+        assert_eq!(
+            std::fs::read_link(test_dir.path().join("link"))?,
+            std::path::Path::new("file.txt")
+        );
+        assert_eq!(
+            std::fs::read_link(test_dir.path().join("subdir/dangling"))?,
+            std::path::Path::new("missing")
+        );
+        assert!(test_dir.path().join("dir-link/file.txt").is_file());
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+
+        // Without core.symlinks, links become files holding their target,
+        // but are still recorded as links.
+        write(
+            test_dir.path().join(".git/config"),
+            "[core]\n\tsymlinks = false\n",
+        )?;
+        std::fs::remove_file(test_dir.path().join("link"))?;
+        reset(reset_args(&test_dir, ResetMode::Hard, None, vec![]))?;
+        let link = test_dir.path().join("link");
+        assert!(!std::fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(std::fs::read_to_string(&link)?, "file.txt");
+        commit(CommitArgs {
+            allow_empty: true,
+            ..commit_args(test_dir.path(), "third")
+        })?;
+        let third = Refs::new(test_dir.path().join(".git")).read_head()?;
+        let database = Database::new(test_dir.path().join(".git/objects"));
+        assert_eq!(
+            database.load_commit(&third)?.tree(),
+            database.load_commit(&first)?.tree()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsck_matches_git() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+        let fsck_args = || FsckArgs {
+            cwd: test_dir.path().to_path_buf(),
+            dangling: true,
+        };
+        fsck(fsck_args())?;
+
+        // Replace the contents of a blob with those of another.
+        let blob = git_output(test_dir.path(), &["rev-parse", "HEAD:file.txt"]);
+        let blob = blob.trim();
+        let path = test_dir
+            .path()
+            .join(".git/objects")
+            .join(&blob[..2])
+            .join(&blob[2..]);
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(b"blob 5\0other")?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+        std::fs::write(&path, encoder.finish()?)?;
+
+        let git = Command::new("git")
+            .arg("fsck")
+            .current_dir(test_dir.path())
+            .output()?;
+        let problems: Vec<String> = fsck::check(&Repository::open(test_dir.path())?)?
+            .iter()
+            .map(|problem| format!("{}\n", problem))
+            .collect();
+        assert_eq!(
+            problems.concat(),
+            format!(
+                "{}{}",
+                String::from_utf8(git.stderr)?,
+                String::from_utf8(git.stdout)?
+            )
+        );
+        match fsck(fsck_args()) {
+            Err(err) => {
+                assert!(err.is_silent());
+                assert_eq!(Some(err.exit_code()), git.status.code());
+            }
+            Ok(()) => panic!("Expected fsck to fail"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone() -> Result<()> {
+        let source = GoldenGit::new();
+        source.populate_test_files();
+        source.run(vec!["git", "add", "."]);
+        source.run(vec!["git", "commit", "-m", MESSAGE]);
+        source.run(vec!["git", "tag", "v1"]);
+        source.run(vec!["git", "branch", "topic"]);
+        write(source.path().join("file.txt"), "changed")?;
+        source.run(vec!["git", "commit", "-am", "second"]);
+
+        let dir = TempDir::new("git-under-test")?;
+        let golden = dir.path().join("golden");
+        let output = Command::new("git")
+            .arg("clone")
+            .arg(source.path())
+            .arg(&golden)
+            .output()?;
+        assert!(output.status.success());
+
+        let clone_args = |directory: &'static str, local: bool| CloneArgs {
+            cwd: dir.path().to_path_buf(),
+            source: source.path().to_str().unwrap(),
+            directory: Some(directory),
+            local,
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        };
+        clone(clone_args("copied", false))?;
+        let copied = dir.path().join("copied");
+
+        let for_each_ref = [
+            "for-each-ref",
+            "--format=%(refname) %(objectname) %(symref)",
+        ];
+        assert_eq!(
+            git_output(&golden, &for_each_ref),
+            git_output(&copied, &for_each_ref)
+        );
+        for name in [
+            "remote.origin.url",
+            "remote.origin.fetch",
+            "branch.master.merge",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                git_output(&golden, &["config", name]),
+                git_output(&copied, &["config", name])
+            );
+        }
+        assert_eq!(std::fs::read_to_string(copied.join("file.txt"))?, "changed");
+        assert_eq!(git_output(&copied, &["status", "--porcelain"]), "");
+        assert_eq!(git_output(&copied, &["fsck"]), "");
+
+        // The destination must be empty, and objects may be shared.
+        assert!(clone(clone_args("copied", false)).is_err());
+        clone(clone_args("linked", true))?;
+        let object = |root: &std::path::Path| {
+            let head = git_output(root, &["rev-parse", "HEAD"]);
+            root.join(".git/objects")
+                .join(&head[..2])
+                .join(head[2..].trim())
+        };
+        use std::os::unix::fs::MetadataExt;
+        let linked = dir.path().join("linked");
+        assert_eq!(
+            std::fs::metadata(object(&linked))?.ino(),
+            std::fs::metadata(object(source.path()))?.ino()
+        );
+        assert_ne!(
+            std::fs::metadata(object(&copied))?.ino(),
+            std::fs::metadata(object(source.path()))?.ino()
+        );
+
+        // Packed objects are read from the copied packs.
+        source.run(vec!["git", "gc", "-q"]);
+        clone(clone_args("packed", false))?;
+        let packed = dir.path().join("packed");
+        assert_eq!(std::fs::read_to_string(packed.join("file.txt"))?, "changed");
+        assert_eq!(git_output(&packed, &["status", "--porcelain"]), "");
+        assert_eq!(git_output(&packed, &["fsck"]), "");
+
+        // A failed clone leaves nothing behind.
+        for pack in std::fs::read_dir(source.path().join(".git/objects/pack"))? {
+            std::fs::remove_file(pack?.path())?;
+        }
+        assert!(clone(clone_args("broken", false)).is_err());
+        assert!(!dir.path().join("broken").exists());
+        let empty = dir.path().join("empty");
+        std::fs::create_dir(&empty)?;
+        assert!(clone(clone_args("empty", false)).is_err());
+        assert_eq!(std::fs::read_dir(&empty)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch() -> Result<()> {
+        let source = GoldenGit::new();
+        source.populate_test_files();
+        source.run(vec!["git", "add", "."]);
+        source.run(vec!["git", "commit", "-m", MESSAGE]);
+        source.run(vec!["git", "branch", "topic"]);
+
+        let dir = TempDir::new("git-under-test")?;
+        for name in ["golden", "fetched"].iter() {
+            let output = Command::new("git")
+                .arg("clone")
+                .arg(source.path())
+                .arg(dir.path().join(name))
+                .output()?;
+            assert!(output.status.success());
+        }
+        let golden = dir.path().join("golden");
+        let fetched = dir.path().join("fetched");
+
+        // Advance master, rewrite topic and add a branch and a tag.
+        write(source.path().join("file.txt"), "changed")?;
+        source.run(vec!["git", "commit", "-am", "second"]);
+        source.run(vec!["git", "tag", "-a", "v1", "-m", "v1"]);
+        source.run(vec!["git", "checkout", "-q", "topic"]);
+        write(source.path().join("file.txt"), "rewritten")?;
+        source.run(vec!["git", "commit", "-am", "third"]);
+        source.run(vec!["git", "branch", "new"]);
+        source.run(vec!["git", "checkout", "-q", "master"]);
+
+        let fetch_args = |refspecs: Vec<&'static str>| FetchArgs {
+            cwd: fetched.clone(),
+            remote: Some("origin"),
+            refspecs,
+            options: fetch::Options::default(),
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        };
+        git_output(&golden, &["fetch", "origin"]);
+        fetch(fetch_args(vec![]))?;
+
+        let for_each_ref = ["for-each-ref", "--format=%(refname) %(objectname)"];
+        let reflog = ["reflog", "show", "--format=%gs", "origin/master"];
+        for args in [&for_each_ref[..], &reflog[..]].iter() {
+            assert_eq!(git_output(&golden, args), git_output(&fetched, args));
+        }
+        assert_eq!(
+            std::fs::read_to_string(golden.join(".git/FETCH_HEAD"))?,
+            std::fs::read_to_string(fetched.join(".git/FETCH_HEAD"))?
+        );
+        assert_eq!(git_output(&fetched, &["fsck"]), "");
+        // The fetched pack is kept whole, rather than unpacked.
+        let head = git_output(&fetched, &["rev-parse", "origin/master"]);
+        assert!(!fetched.join(".git/objects").join(&head[..2]).exists());
+        assert_eq!(
+            git_output(&fetched, &["count-objects", "-v"])
+                .lines()
+                .find(|line| line.starts_with("packs: ")),
+            Some("packs: 1")
+        );
+
+        // Without "+", rewritten branches are rejected.
+        source.run(vec!["git", "branch", "-f", "topic", "master"]);
+        let topic = git_output(&fetched, &["rev-parse", "origin/topic"]);
+        let result = fetch(fetch_args(vec![
+            "refs/heads/topic:refs/remotes/origin/topic",
+        ]));
+        assert!(matches!(result, Err(Error::Conflict(_))));
+        assert_eq!(git_output(&fetched, &["rev-parse", "origin/topic"]), topic);
+
+        // References with invalid names are ignored, rather than written
+        // outside the git directory.
+        let master = git_output(&fetched, &["rev-parse", "origin/master"]);
+        let lines: String = [
+            format!("{} refs/heads/master\n", master.trim()),
+            format!("{} refs/heads/../../../escaped\n", master.trim()),
+        ]
+        .iter()
+        .map(|line| format!("{:04x}{}", line.len() + 4, line))
+        .collect();
+        let server = dir.path().join("server.sh");
+        write(
+            &server,
+            format!(
+                "#!/bin/sh\nprintf '000eversion 2\\n000cls-refs\\n0000{}0000'\ncat >/dev/null\n",
+                lines.replace('\n', "\\n")
+            ),
+        )?;
+        std::fs::set_permissions(&server, std::fs::Permissions::from_mode(0o755))?;
+        git_output(
+            &fetched,
+            &[
+                "config",
+                "remote.origin.uploadpack",
+                server.to_str().unwrap(),
+            ],
+        );
+        fetch(fetch_args(vec![]))?;
+        assert!(!fetched.join(".git/escaped").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_push() -> Result<()> {
+        let source = GoldenGit::new();
+        source.populate_test_files();
+        source.run(vec!["git", "add", "."]);
+        source.run(vec!["git", "commit", "-m", MESSAGE]);
+        source.run(vec!["git", "branch", "topic"]);
+
+        let dir = TempDir::new("git-under-test")?;
+        let remote = dir.path().join("remote.git");
+        let work = dir.path().join("work");
+        for args in [
+            vec!["clone", "--bare", source.path().to_str().unwrap()],
+            vec!["clone", remote.to_str().unwrap()],
+        ]
+        .iter()
+        {
+            let output = Command::new("git")
+                .args(args)
+                .arg(if args[1] == "--bare" { &remote } else { &work })
+                .output()?;
+            assert!(output.status.success());
+        }
+
+        let push_args = |refspecs: Vec<&'static str>, options: push::Options| PushArgs {
+            cwd: work.clone(),
+            remote: Some("origin"),
+            refspecs,
+            options,
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        };
+        let rev_parse = |dir: &Path, rev: &str| git_output(dir, &["rev-parse", rev]);
+
+        // A fast-forward, a new branch and a tag.
+        write(work.join("file.txt"), "changed")?;
+        git_output(&work, &["commit", "-qam", "second"]);
+        git_output(&work, &["tag", "-a", "v1", "-m", "v1"]);
+        push(push_args(vec![], push::Options::default()))?;
+        push(push_args(
+            vec!["master:new", "v1"],
+            push::Options::default(),
+        ))?;
+        for (local, remote_ref) in [("master", "master"), ("master", "new"), ("v1", "v1")].iter() {
+            assert_eq!(rev_parse(&work, local), rev_parse(&remote, remote_ref));
+        }
+        assert_eq!(rev_parse(&work, "origin/new"), rev_parse(&work, "master"));
+        assert_eq!(
+            git_output(&work, &["reflog", "show", "--format=%gs", "origin/master"]),
+            "update by push\n"
+        );
+        assert_eq!(git_output(&remote, &["fsck"]), "");
+
+        // Rewritten branches are rejected, unless forced while the remote
+        // is as expected.
+        git_output(&work, &["checkout", "-q", "topic"]);
+        write(work.join("file.txt"), "rewritten")?;
+        git_output(&work, &["commit", "-qam", "third"]);
+        git_output(&work, &["checkout", "-q", "master"]);
+        let result = push(push_args(vec!["topic:master"], push::Options::default()));
+        assert!(matches!(result, Err(Error::Conflict(_))));
+        let stale = push::Options {
+            force: false,
+            leases: vec!["master:topic".parse()?],
+            ..push::Options::default()
+        };
+        let result = push(push_args(vec!["topic:master"], stale));
+        assert!(matches!(result, Err(Error::Conflict(_))));
+        assert_ne!(rev_parse(&remote, "master"), rev_parse(&work, "topic"));
+        let lease = push::Options {
+            force: false,
+            leases: vec!["".parse()?],
+            ..push::Options::default()
+        };
+        push(push_args(vec!["topic:master"], lease))?;
+        assert_eq!(rev_parse(&remote, "master"), rev_parse(&work, "topic"));
+
+        // Deleting a branch also deletes its remote-tracking branch.
+        push(push_args(vec![":topic"], push::Options::default()))?;
+        assert_eq!(
+            git_output(&remote, &["for-each-ref", "--format=%(refname)"]),
+            "refs/heads/master\nrefs/heads/new\nrefs/tags/v1\n"
+        );
+        assert!(!work.join(".git/refs/remotes/origin/topic").exists());
+        assert_eq!(git_output(&remote, &["fsck"]), "");
+
+        // The pre-push hook reads each update, and may refuse them all.
+        write_hook(
+            &work,
+            "pre-push",
+            "echo \"$1\" > .git/pushed\ncat >> .git/pushed\nexit 1\n",
+        )?;
+        let result = push(push_args(vec!["master:refused"], push::Options::default()));
+        assert!(matches!(result, Err(Error::Conflict(_))));
+        assert_eq!(
+            std::fs::read_to_string(work.join(".git/pushed"))?,
+            format!(
+                "origin\nrefs/heads/master {} refs/heads/refused {}\n",
+                rev_parse(&work, "master").trim(),
+                NULL_OID
+            )
+        );
+        assert!(!remote.join("refs/heads/refused").exists());
+        let options = push::Options {
+            no_verify: true,
+            ..push::Options::default()
+        };
+        push(push_args(vec!["master:refused"], options.clone()))?;
+        assert!(remote.join("refs/heads/refused").exists());
+
+        // Updates the remote refuses are reported, and leave their
+        // remote-tracking branches as they were.
+        let hook = remote.join("hooks/update");
+        write(&hook, "#!/bin/sh\ntest \"$1\" != refs/heads/new\n")?;
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))?;
+        write(work.join("file.txt"), "fourth")?;
+        git_output(&work, &["commit", "-qam", "fourth"]);
+        let new = rev_parse(&work, "origin/new");
+        let repo = Repository::open(&work)?;
+        let origin = fetch::Remote::load(&repo.config()?, "origin", &work)?;
+        let refspecs = vec!["master:accepted".parse()?, "master:new".parse()?];
+        let identity = Author::new(
+            AUTHOR_NAME.to_string(),
+            AUTHOR_EMAIL.to_string(),
+            chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        );
+        let pushed = push::push(&repo, &origin, &refspecs, &options, &identity)?;
+        let summaries: Vec<String> = pushed.updates.iter().map(|u| u.summary()).collect();
+        assert_eq!(
+            summaries,
+            vec![
+                " * [new branch]      master -> accepted",
+                " ! [remote rejected] master -> new (hook declined)",
+            ]
+        );
+        assert_eq!(rev_parse(&remote, "accepted"), rev_parse(&work, "master"));
+        assert_eq!(
+            rev_parse(&work, "origin/accepted"),
+            rev_parse(&work, "master")
+        );
+        assert_eq!(rev_parse(&remote, "new"), new);
+        assert_eq!(rev_parse(&work, "origin/new"), new);
+        Ok(())
+    }
+
+    fn submodule_args<'a>(
+        dir: &std::path::Path,
+        command: SubmoduleCommand<'a>,
+    ) -> SubmoduleArgs<'a> {
+        SubmoduleArgs {
+            cwd: dir.to_path_buf(),
+            command,
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_submodule() -> Result<()> {
+        let lib = TempDir::new("git-under-test-lib")?;
+        init_manually(&lib);
+        create_test_files(&lib);
+        let lib_first = commit_all(&lib, "first");
+
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        write(test_dir.path().join("file.txt"), "file contents")?;
+        let url = lib.path().to_str().unwrap();
+        submodule(submodule_args(
+            test_dir.path(),
+            SubmoduleCommand::Add {
+                url,
+                path: Some("vendor/lib"),
+            },
+        ))?;
+        assert!(test_dir.path().join("vendor/lib/subdir/file.txt").is_file());
+        commit_all(&test_dir, "add lib");
+
+        // The submodule is recorded as a commit, and not recursed into.
+        assert_eq!(
+            git_output(test_dir.path(), &["ls-tree", "-r", "HEAD"]),
+            format!(
+                "100644 blob {}\t.gitmodules\n\
+                 100644 blob 754bb844fb01df2613c0c1fe26eaa701ce46e853\tfile.txt\n\
+                 160000 commit {}\tvendor/lib\n",
+                git_output(test_dir.path(), &["hash-object", ".gitmodules"]).trim(),
+                lib_first.as_str()
+            )
+        );
+        assert_eq!(
+            git_output(
+                test_dir.path(),
+                &["config", "-f", ".gitmodules", "submodule.vendor/lib.url"]
+            ),
+            format!("{}\n", url)
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+
+        // A new commit in the library is not picked up by the superproject
+        // until the submodule moves to it.
+        write(lib.path().join("file.txt"), "changed")?;
+        let lib_second = commit_all(&lib, "second");
+        std::fs::remove_dir_all(test_dir.path().join("vendor"))?;
+        submodule(submodule_args(
+            test_dir.path(),
+            SubmoduleCommand::Update {
+                init: true,
+                paths: vec![],
+            },
+        ))?;
+        let sub_path = test_dir.path().join("vendor/lib");
+        assert_eq!(
+            git_output(&sub_path, &["rev-parse", "HEAD"]),
+            format!("{}\n", lib_first.as_str())
+        );
+        assert_eq!(
+            std::fs::read_to_string(sub_path.join("file.txt"))?,
+            "file contents"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+
+        // Committing within the submodule records the new commit.
+        git_output(&sub_path, &["checkout", "-q", &lib_second.as_str()]);
+        commit_all(&test_dir, "update lib");
+        assert_eq!(
+            git_output(test_dir.path(), &["rev-parse", "HEAD:vendor/lib"]),
+            format!("{}\n", lib_second.as_str())
+        );
+        reset(reset_args(
+            &test_dir,
+            ResetMode::Hard,
+            Some("HEAD~1"),
+            vec![],
+        ))?;
+        submodule(submodule_args(
+            test_dir.path(),
+            SubmoduleCommand::Update {
+                init: false,
+                paths: vec!["vendor/lib"],
+            },
+        ))?;
+        assert_eq!(
+            std::fs::read_to_string(sub_path.join("file.txt"))?,
+            "file contents"
+        );
+
+        let out = test_dir.path().join("foreach.txt");
+        submodule(submodule_args(
+            test_dir.path(),
+            SubmoduleCommand::Foreach {
+                command: &format!("echo \"$name $sm_path $sha1\" > {}", out.display()),
+            },
+        ))?;
+        assert_eq!(
+            std::fs::read_to_string(&out)?,
+            format!("vendor/lib vendor/lib {}\n", lib_first.as_str())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_hard() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        let first = commit_all(&test_dir, "first");
+
+        write(test_dir.path().join("file.txt"), "changed")?;
+        write(test_dir.path().join("new.txt"), "new")?;
+        std::fs::remove_dir_all(test_dir.path().join("subdir/nested"))?;
+        let second = commit_all(&test_dir, "second");
+
+        write(test_dir.path().join("untracked.txt"), "untracked")?;
+        reset(reset_args(
+            &test_dir,
+            ResetMode::Hard,
+            Some("HEAD~1"),
+            vec![],
+        ))?;
+
+        let refs = Refs::new(test_dir.path().join(".git"));
+        assert_eq!(refs.read_head()?, first);
+        assert_eq!(refs.read_ref(ORIG_HEAD)?, Some(second));
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "file contents"
+        );
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("subdir/nested/file.txt"))?,
+            "hello"
+        );
+        assert!(!test_dir.path().join("new.txt").exists());
+        assert!(test_dir.path().join("untracked.txt").exists());
+        assert_eq!(
+            git_output(test_dir.path(), &["status", "--porcelain"]),
+            "?? untracked.txt\n"
+        );
+
+        let reflog = std::fs::read_to_string(test_dir.path().join(".git/logs/HEAD"))?;
+        assert!(reflog.ends_with("\treset: moving to HEAD~1\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_soft_then_mixed() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        let first = commit_all(&test_dir, "first");
+        write(test_dir.path().join("file.txt"), "changed")?;
+        commit_all(&test_dir, "second");
+
+        // A soft reset leaves the second commit's contents staged.
+        reset(reset_args(
+            &test_dir,
+            ResetMode::Soft,
+            Some("HEAD^"),
+            vec![],
+        ))?;
+        let refs = Refs::new(test_dir.path().join(".git"));
+        assert_eq!(refs.read_head()?, first);
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--cached", "--name-only"]),
+            "file.txt\n"
+        );
+
+        // A mixed reset unstages it, without touching the workspace.
+        reset(reset_args(&test_dir, ResetMode::Mixed, None, vec![]))?;
+        assert_eq!(refs.read_head()?, first);
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--cached", "--name-only"]),
+            ""
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--name-only"]),
+            "file.txt\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "changed"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_paths() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        let head = commit_all(&test_dir, "first");
+
+        write(test_dir.path().join("file.txt"), "changed")?;
+        write(test_dir.path().join("subdir/file.txt"), "changed")?;
+        git_output(test_dir.path(), &["add", "."]);
+
+        // Paths may follow "--", or be given alone if they are not revisions.
+        reset(reset_args(
+            &test_dir,
+            ResetMode::Mixed,
+            None,
+            vec!["subdir"],
+        ))?;
+        reset(reset_args(
+            &test_dir,
+            ResetMode::Mixed,
+            Some("file.txt"),
+            vec![],
+        ))?;
+
+        assert_eq!(Refs::new(test_dir.path().join(".git")).read_head()?, head);
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--cached", "--name-only"]),
+            ""
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--name-only"]),
+            "file.txt\nsubdir/file.txt\n"
+        );
+
+        assert!(reset(reset_args(
+            &test_dir,
+            ResetMode::Hard,
+            None,
+            vec!["file.txt"]
+        ))
+        .is_err());
+        Ok(())
+    }
+
+    fn stash_args<'a>(dir: &TempDir, command: StashCommand<'a>) -> StashArgs<'a> {
+        StashArgs {
+            cwd: dir.path().to_path_buf(),
+            command,
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_stash_push_and_pop() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        let head = commit_all(&test_dir, "first");
+
+        write(test_dir.path().join("file.txt"), "changed")?;
+        write(test_dir.path().join("new.txt"), "new")?;
+        git_output(test_dir.path(), &["add", "new.txt"]);
+        std::fs::remove_file(test_dir.path().join("subdir/file.txt"))?;
+
+        stash(stash_args(&test_dir, StashCommand::Push { message: None }))?;
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        // Git understands the stash layout.
+        assert_eq!(
+            git_output(test_dir.path(), &["stash", "list"]),
+            format!(
+                "stash@{{0}}: WIP on master: {} first\n",
+                head.as_short_str()
+            )
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["stash", "show", "--name-status"]),
+            "M\tfile.txt\nA\tnew.txt\nD\tsubdir/file.txt\n"
+        );
+
+        stash(stash_args(&test_dir, StashCommand::Pop { index: 0 }))?;
+        assert_eq!(
+            git_output(test_dir.path(), &["status", "--porcelain"]),
+            " M file.txt\nA  new.txt\n D subdir/file.txt\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["stash", "list"]), "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_stash_pop_from_git() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+
+        write(test_dir.path().join("file.txt"), "older")?;
+        git_output(test_dir.path(), &["stash", "push", "-m", "older"]);
+        write(test_dir.path().join("subdir/file.txt"), "newer")?;
+        git_output(test_dir.path(), &["stash"]);
+
+        stash(stash_args(&test_dir, StashCommand::Pop { index: 1 }))?;
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "older"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["stash", "list"])
+                .lines()
+                .count(),
+            1
+        );
+        stash(stash_args(&test_dir, StashCommand::Drop { index: 0 }))?;
+        assert_eq!(git_output(test_dir.path(), &["stash", "list"]), "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_stash_apply_conflict() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+
+        write(test_dir.path().join("file.txt"), "stashed")?;
+        stash(stash_args(
+            &test_dir,
+            StashCommand::Push {
+                message: Some("mine"),
+            },
+        ))?;
+        write(test_dir.path().join("file.txt"), "committed")?;
+        commit_all(&test_dir, "second");
+
+        assert!(stash(stash_args(&test_dir, StashCommand::Pop { index: 0 })).is_err());
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "<<<<<<< Updated upstream\ncommitted\n=======\nstashed\n>>>>>>> Stashed changes\n"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--name-only", "--diff-filter=U"]),
+            "file.txt\n"
+        );
+        // A conflicted pop keeps the entry.
+        assert_eq!(
+            git_output(test_dir.path(), &["stash", "list"]),
+            "stash@{0}: On master: mine\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stash_refuses_to_overwrite() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+
+        write(test_dir.path().join("file.txt"), "stashed")?;
+        stash(stash_args(&test_dir, StashCommand::Push { message: None }))?;
+        write(test_dir.path().join("file.txt"), "local")?;
+
+        assert!(stash(stash_args(&test_dir, StashCommand::Apply { index: 0 })).is_err());
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "local"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stash_pop_keeps_error() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+
+        write(test_dir.path().join("file.txt"), "stashed")?;
+        stash(stash_args(&test_dir, StashCommand::Push { message: None }))?;
+        let oid = git_output(test_dir.path(), &["rev-parse", "stash"]);
+        let path = format!(".git/objects/{}/{}", &oid[..2], oid[2..].trim());
+        std::fs::remove_file(test_dir.path().join(path))?;
+
+        // Errors other than conflicts keep their type, and the entry.
+        let result = stash(stash_args(&test_dir, StashCommand::Pop { index: 0 }));
+        assert!(matches!(result, Err(Error::ObjectNotFound(_))));
+        assert_eq!(git_output(test_dir.path(), &["rev-parse", "stash"]), oid);
+        Ok(())
+    }
+
+    fn sequence_args<'a>(dir: &TempDir, command: SequenceCommand<'a>) -> SequenceArgs<'a> {
+        SequenceArgs {
+            cwd: dir.path().to_path_buf(),
+            command,
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        }
+    }
+
+    fn start<'a>(revisions: Vec<&'a str>, record_origin: bool) -> SequenceCommand<'a> {
+        SequenceCommand::Start {
+            revisions,
+            options: sequencer::Options { record_origin },
+        }
+    }
+
+    // Creates a "topic" branch with two commits, and a diverging commit on
+    // "master" which conflicts with the first of them.
+    fn create_diverged_branches(dir: &TempDir) -> ObjectID {
+        init_manually(dir);
+        create_test_files(dir);
+        commit_all(dir, "first");
+        git_output(dir.path(), &["checkout", "-q", "-b", "topic"]);
+        write(dir.path().join("file.txt"), "topic\n").unwrap();
+        commit_all(dir, "one");
+        write(dir.path().join("subdir/file.txt"), "topic\n").unwrap();
+        commit_all(dir, "two");
+        git_output(dir.path(), &["checkout", "-q", "master"]);
+        write(dir.path().join("file.txt"), "master\n").unwrap();
+        commit_all(dir, "master")
+    }
+
+    #[test]
+    fn test_cherry_pick() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        create_diverged_branches(&test_dir);
+
+        cherry_pick(sequence_args(&test_dir, start(vec!["topic"], true)))?;
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("subdir/file.txt"))?,
+            "topic\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        let topic = git_output(test_dir.path(), &["rev-parse", "topic"]);
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "-1", "--format=%B"]),
+            format!("two\n\n(cherry picked from commit {})\n\n", topic.trim())
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "--format=%s"]),
+            "two\nmaster\nfirst\n"
+        );
+        assert!(!test_dir.path().join(".git/sequencer").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_revert() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+        write(test_dir.path().join("file.txt"), "second")?;
+        write(test_dir.path().join("new.txt"), "new")?;
+        let second = commit_all(&test_dir, "second");
+
+        revert(sequence_args(&test_dir, start(vec!["HEAD"], false)))?;
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "file contents"
+        );
+        assert!(!test_dir.path().join("new.txt").exists());
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "-1", "--format=%B"]),
+            format!(
+                "Revert \"second\"\n\nThis reverts commit {}.\n\n",
+                second.as_str()
+            )
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--stat", "HEAD~2", "HEAD"]),
+            ""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cherry_pick_conflict_and_continue() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        create_diverged_branches(&test_dir);
+        let one = git_output(test_dir.path(), &["rev-parse", "--short", "topic~1"]);
+
+        let result = cherry_pick(sequence_args(
+            &test_dir,
+            start(vec!["topic~1", "topic"], false),
+        ));
+        assert!(result.is_err());
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            format!(
+                "<<<<<<< HEAD\nmaster\n=======\ntopic\n>>>>>>> {}... one\n",
+                one.trim()
+            )
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--name-only", "--diff-filter=U"]),
+            "file.txt\n"
+        );
+        let stages: Vec<String> = git_output(test_dir.path(), &["ls-files", "-u"])
+            .lines()
+            .map(|line| line.split_whitespace().nth(2).unwrap().to_string())
+            .collect();
+        assert_eq!(stages, vec!["1", "2", "3"]);
+        assert!(test_dir.path().join(".git/CHERRY_PICK_HEAD").exists());
+
+        write(test_dir.path().join("file.txt"), "resolved\n")?;
+        cherry_pick(sequence_args(&test_dir, SequenceCommand::Continue))?;
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "--format=%s"]),
+            "two\none\nmaster\nfirst\n"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["show", "HEAD~1:file.txt"]),
+            "resolved\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        assert!(!test_dir.path().join(".git/CHERRY_PICK_HEAD").exists());
+        assert!(!test_dir.path().join(".git/sequencer").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cherry_pick_abort() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        let master = create_diverged_branches(&test_dir);
+
+        assert!(cherry_pick(sequence_args(&test_dir, start(vec!["topic~1"], false))).is_err());
+        // A second sequence cannot start until the first finishes.
+        assert!(revert(sequence_args(&test_dir, start(vec!["HEAD"], false))).is_err());
+
+        cherry_pick(sequence_args(&test_dir, SequenceCommand::Abort))?;
+        assert_eq!(Refs::new(test_dir.path().join(".git")).read_head()?, master);
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "master\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        assert!(!test_dir.path().join(".git/CHERRY_PICK_HEAD").exists());
+        assert!(!test_dir.path().join(".git/sequencer").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_revert_with_separate_git_dir() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        let git_dir = TempDir::new("git-dir")?;
+        init(InitArgs {
+            separate_git_dir: git_dir.path().to_str(),
+            ..init_args(&test_dir)
+        })?;
+        create_test_files(&test_dir);
+        commit(commit_args(test_dir.path(), "first"))?;
+        write(test_dir.path().join("file.txt"), "changed")?;
+        commit(commit_args(test_dir.path(), "second"))?;
+
+        revert(sequence_args(&test_dir, start(vec!["HEAD"], false)))?;
+        assert_eq!(
+            git_output(test_dir.path(), &["diff", "--stat", "HEAD~2", "HEAD"]),
+            ""
+        );
+        assert!(!git_dir.path().join("sequencer").exists());
+        assert!(!test_dir.path().join(".git").is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_ends_cherry_pick() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        let master = create_diverged_branches(&test_dir);
+
+        assert!(cherry_pick(sequence_args(&test_dir, start(vec!["topic~1"], false))).is_err());
+        match reset(reset_args(&test_dir, ResetMode::Soft, None, vec![])) {
+            Err(Error::Fatal(message)) => {
+                assert_eq!(message, "Cannot do a soft reset in the middle of a merge.")
+            }
+            _ => panic!("Expected a soft reset to be refused"),
+        }
+
+        reset(reset_args(&test_dir, ResetMode::Hard, None, vec![]))?;
+        assert_eq!(Refs::new(test_dir.path().join(".git")).read_head()?, master);
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        assert!(!test_dir.path().join(".git/CHERRY_PICK_HEAD").exists());
+        assert!(!test_dir.path().join(".git/MERGE_MSG").exists());
+        assert!(!test_dir.path().join(".git/sequencer").exists());
+        assert!(cherry_pick(sequence_args(&test_dir, SequenceCommand::Continue)).is_err());
+        Ok(())
+    }
+
+    fn rebase_args<'a>(dir: &TempDir, command: RebaseCommand<'a>) -> RebaseArgs<'a> {
+        RebaseArgs {
+            cwd: dir.path().to_path_buf(),
+            command,
+            editor: ":".to_string(),
+            sequence_editor: ":".to_string(),
+            name: AUTHOR_NAME.to_string(),
+            email: AUTHOR_EMAIL.to_string(),
+            time: chrono::DateTime::parse_from_rfc3339(DATE).unwrap(),
+        }
+    }
+
+    fn rebase_onto(upstream: &str) -> RebaseCommand<'_> {
+        RebaseCommand::Start {
+            upstream,
+            onto: None,
+            interactive: false,
+        }
+    }
+
+    #[test]
+    fn test_rebase() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+        git_output(test_dir.path(), &["checkout", "-q", "-b", "topic"]);
+        write(test_dir.path().join("a.txt"), "a")?;
+        commit_all(&test_dir, "add a");
+        write(test_dir.path().join("subdir/file.txt"), "topic")?;
+        commit_all(&test_dir, "change subdir");
+        git_output(test_dir.path(), &["checkout", "-q", "master"]);
+        write(test_dir.path().join("file.txt"), "master")?;
+        let master = commit_all(&test_dir, "master");
+        git_output(test_dir.path(), &["checkout", "-q", "topic"]);
+        let topic = git_output(test_dir.path(), &["rev-parse", "topic"]);
+
+        rebase(rebase_args(&test_dir, rebase_onto("master")))?;
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "--format=%s"]),
+            "change subdir\nadd a\nmaster\nfirst\n"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["rev-parse", "ORIG_HEAD"]),
+            topic
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["symbolic-ref", "HEAD"]),
+            "refs/heads/topic\n"
+        );
+        assert_eq!(
+            Refs::new(test_dir.path().join(".git")).read_ref("master")?,
+            Some(master)
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "master"
+        );
+        assert!(!test_dir.path().join(".git/rebase-merge").exists());
+
+        // Rebasing again has no effect.
+        let head = git_output(test_dir.path(), &["rev-parse", "HEAD"]);
+        rebase(rebase_args(&test_dir, rebase_onto("master")))?;
+        assert_eq!(git_output(test_dir.path(), &["rev-parse", "HEAD"]), head);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_with_separate_git_dir() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        let git_dir = TempDir::new("git-dir")?;
+        init(InitArgs {
+            separate_git_dir: git_dir.path().to_str(),
+            ..init_args(&test_dir)
+        })?;
+        create_test_files(&test_dir);
+        commit(commit_args(test_dir.path(), "first"))?;
+        git_output(test_dir.path(), &["checkout", "-q", "-b", "topic"]);
+        write(test_dir.path().join("a.txt"), "a")?;
+        commit(commit_args(test_dir.path(), "add a"))?;
+        git_output(test_dir.path(), &["checkout", "-q", "master"]);
+        write(test_dir.path().join("file.txt"), "master")?;
+        commit(commit_args(test_dir.path(), "master"))?;
+        git_output(test_dir.path(), &["checkout", "-q", "topic"]);
+
+        rebase(rebase_args(&test_dir, rebase_onto("master")))?;
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "--format=%s"]),
+            "add a\nmaster\nfirst\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        assert!(!git_dir.path().join("rebase-merge").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_conflict_and_continue() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        create_diverged_branches(&test_dir);
+        git_output(test_dir.path(), &["checkout", "-q", "topic"]);
+
+        assert!(rebase(rebase_args(&test_dir, rebase_onto("master"))).is_err());
+        let one = git_output(test_dir.path(), &["rev-parse", "--short", "topic~1"]);
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            format!(
+                "<<<<<<< HEAD\nmaster\n=======\ntopic\n>>>>>>> {} (one)\n",
+                one.trim()
+            )
+        );
+        // The branch is untouched until the rebase completes.
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "-1", "--format=%s", "topic"]),
+            "two\n"
+        );
+
+        write(test_dir.path().join("file.txt"), "resolved\n")?;
+        rebase(rebase_args(&test_dir, RebaseCommand::Continue))?;
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "--format=%s", "topic"]),
+            "two\none\nmaster\nfirst\n"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["symbolic-ref", "HEAD"]),
+            "refs/heads/topic\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "resolved\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_hooks() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        let master = create_diverged_branches(&test_dir);
+        git_output(test_dir.path(), &["checkout", "-q", "topic"]);
+        let topic = Refs::new(test_dir.path().join(".git")).read_head()?;
+
+        write_hook(
+            test_dir.path(),
+            "pre-rebase",
+            "echo \"$1\" > .git/upstream\nexit 1\n",
+        )?;
+        let result = rebase(rebase_args(&test_dir, rebase_onto("master")));
+        assert!(matches!(result, Err(Error::Fatal(_))));
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join(".git/upstream"))?,
+            "master\n"
+        );
+        assert_eq!(Refs::new(test_dir.path().join(".git")).read_head()?, topic);
+
+        write_hook(test_dir.path(), "pre-rebase", "exit 0\n")?;
+        write_hook(
+            test_dir.path(),
+            "post-checkout",
+            "echo \"$@\" > .git/checkout\n",
+        )?;
+        // The first commit conflicts, once the new base is checked out.
+        let result = rebase(rebase_args(&test_dir, rebase_onto("master")));
+        assert!(matches!(result, Err(Error::Conflict(_))));
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join(".git/checkout"))?,
+            format!("{} {} 1\n", topic.as_str(), master.as_str())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_skip_and_abort() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        create_diverged_branches(&test_dir);
+        git_output(test_dir.path(), &["checkout", "-q", "topic"]);
+        let topic = Refs::new(test_dir.path().join(".git")).read_head()?;
+
+        assert!(rebase(rebase_args(&test_dir, rebase_onto("master"))).is_err());
+        assert!(rebase(rebase_args(&test_dir, rebase_onto("master"))).is_err());
+        rebase(rebase_args(&test_dir, RebaseCommand::Abort))?;
+        assert_eq!(Refs::new(test_dir.path().join(".git")).read_head()?, topic);
+        assert_eq!(
+            git_output(test_dir.path(), &["symbolic-ref", "HEAD"]),
+            "refs/heads/topic\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "topic\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        assert!(!test_dir.path().join(".git/rebase-merge").exists());
+
+        assert!(rebase(rebase_args(&test_dir, rebase_onto("master"))).is_err());
+        rebase(rebase_args(&test_dir, RebaseCommand::Skip))?;
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "--format=%s"]),
+            "two\nmaster\nfirst\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("file.txt"))?,
+            "master\n"
+        );
+        assert_eq!(git_output(test_dir.path(), &["status", "--porcelain"]), "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_todo() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        commit_all(&test_dir, "first");
+        let mut commits = Vec::new();
+        for name in ["a", "b", "c", "d", "e"].iter() {
+            write(test_dir.path().join(format!("{}.txt", name)), *name)?;
+            commits.push(commit_all(&test_dir, &format!("add {}", name)).as_short_str());
+        }
+
+        // Scripts drive the rebase by replacing the todo list.
+        let todo_dir = TempDir::new("todo")?;
+        let todo = todo_dir.path().join("todo");
+        write(
+            &todo,
+            format!(
+                "reword {} add a\n\
+                 squash {} add b\n\
+                 exec echo exec > exec.txt\n\
+                 pick {} add d\n\
+                 fixup {} add c\n\
+                 drop {} add e\n",
+                commits[0], commits[1], commits[3], commits[2], commits[4]
+            ),
+        )?;
+        let mut args = rebase_args(
+            &test_dir,
+            RebaseCommand::Start {
+                upstream: "HEAD~5",
+                onto: None,
+                interactive: true,
+            },
+        );
+        args.sequence_editor = format!("cp {}", todo.display());
+        args.editor = "sed -i s/add/Add/".to_string();
+        rebase(args)?;
+
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "--format=%s"]),
+            "add d\nAdd a\nfirst\n"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["log", "-1", "--format=%B", "HEAD~1"]),
+            "Add a\n\nAdd b\n\n"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["ls-tree", "--name-only", "HEAD"]),
+            "a.txt\nb.txt\nc.txt\nd.txt\nfile.txt\nsubdir\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(test_dir.path().join("exec.txt"))?,
+            "exec\n"
+        );
+        assert_eq!(
+            git_output(test_dir.path(), &["status", "--porcelain"]),
+            "?? exec.txt\n"
+        );
+
+        // Removing every line cancels the rebase.
+        let head = git_output(test_dir.path(), &["rev-parse", "HEAD"]);
+        write(&todo, "# nothing\n")?;
+        let mut args = rebase_args(
+            &test_dir,
+            RebaseCommand::Start {
+                upstream: "HEAD~1",
+                onto: None,
+                interactive: true,
+            },
+        );
+        args.sequence_editor = format!("cp {}", todo.display());
+        assert!(rebase(args).is_err());
+        assert_eq!(git_output(test_dir.path(), &["rev-parse", "HEAD"]), head);
+        assert!(!test_dir.path().join(".git/rebase-merge").exists());
+        Ok(())
+    }
+
+    // Returns what "rit show" writes for `name`.
+    fn show_output(dir: &TempDir, name: &str) -> String {
+        let git_path = dir.path().join(".git");
+        let database = Database::new(git_path.join("objects"));
+        let refs = Refs::new(&git_path);
+        let oid = Revision::parse(name)
+            .unwrap()
+            .resolve(&refs, &database)
+            .unwrap();
+        let mut out = Vec::new();
+        show_object(&database, name, &oid, &mut false, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_show() -> Result<()> {
+        let test_dir = TempDir::new("git-under-test")?;
+        init_manually(&test_dir);
+        create_test_files(&test_dir);
+        write(test_dir.path().join("file.txt"), "a\nb\nc\n")?;
+        commit_all(&test_dir, "first\n\nbody\n");
+
+        git_output(test_dir.path(), &["checkout", "-q", "-b", "side"]);
+        write(test_dir.path().join("file.txt"), "a\nb\nT\n")?;
+        git_output(test_dir.path(), &["commit", "-q", "-am", "side"]);
+        git_output(test_dir.path(), &["checkout", "-q", "master"]);
+        write(test_dir.path().join("file.txt"), "M\nb\nc\n")?;
+        write(test_dir.path().join("new.txt"), "new\n")?;
+        git_output(test_dir.path(), &["add", "-A"]);
+        git_output(test_dir.path(), &["commit", "-q", "-m", "main"]);
+        // Resolve the merge with lines from neither side.
+        git_output(
+            test_dir.path(),
+            &["merge", "-q", "--no-commit", "--no-ff", "side"],
+        );
+        write(test_dir.path().join("file.txt"), "X\nb\nY\n")?;
+        git_output(test_dir.path(), &["commit", "-q", "-am", "merge"]);
+        git_output(test_dir.path(), &["tag", "-a", "v1", "-m", "Release"]);
+
+        for name in [
+            "HEAD",
+            "HEAD^",
+            "HEAD^2",
+            "HEAD~2",
+            "HEAD:",
+            "HEAD:subdir",
+            "HEAD^:file.txt",
+            "v1",
+        ] {
+            assert_eq!(
+                show_output(&test_dir, name),
+                git_output(test_dir.path(), &["show", name]),
+                "rit show {}",
+                name
+            );
+        }
         Ok(())
     }
 }
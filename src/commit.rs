@@ -1,40 +1,121 @@
 use crate::author::Author;
 use crate::database::{ObjectID, Storable};
+use crate::error::{Error, Result};
+use std::str::FromStr;
 
 pub struct Commit {
+    tree: ObjectID,
+    parents: Vec<ObjectID>,
+    author: Author,
+    committer: Author,
     message: String,
     data: Vec<u8>,
 }
 
 impl Commit {
-    pub fn new(parent: &Option<ObjectID>, oid: &ObjectID, author: Author, message: String) -> Self {
-        let parent_msg = if let Some(parent) = parent {
-            format!("parent {}\n", parent.as_str())
-        } else {
-            "".to_string()
-        };
+    pub fn new(
+        parents: Vec<ObjectID>,
+        tree: ObjectID,
+        author: Author,
+        committer: Author,
+        message: String,
+    ) -> Self {
+        let parent_msg: String = parents
+            .iter()
+            .map(|parent| format!("parent {}\n", parent.as_str()))
+            .collect();
         let data = format!(
-            "{}\
-             tree {}\n\
+            "tree {}\n\
+             {}\
              author {}\n\
              committer {}\n\
              \n\
              {}",
+            tree.as_str(),
             parent_msg,
-            oid.as_str(),
-            author.to_str(),
             author.to_str(),
+            committer.to_str(),
             message
         )
         .as_bytes()
         .to_vec();
 
-        Commit { message, data }
+        Commit {
+            tree,
+            parents,
+            author,
+            committer,
+            message,
+            data,
+        }
+    }
+
+    /// Parses a commit from its stored representation.
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let text = std::str::from_utf8(&data)
+            .map_err(|_| Error::CorruptObject("commit is not valid UTF-8".to_string()))?;
+        let (headers, message) = text.split_once("\n\n").unwrap_or((text, ""));
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        for line in headers.lines() {
+            // Continuation lines (such as those in signatures) are not
+            // interpreted.
+            let (key, value) = match line.split_once(' ') {
+                Some(header) if !line.starts_with(' ') => header,
+                _ => continue,
+            };
+            match key {
+                "tree" => tree = Some(ObjectID::from_str(value)?),
+                "parent" => parents.push(ObjectID::from_str(value)?),
+                "author" => author = Some(Author::parse(value)?),
+                "committer" => committer = Some(Author::parse(value)?),
+                _ => (),
+            }
+        }
+
+        let missing = |header| Error::CorruptObject(format!("commit is missing a {}", header));
+        Ok(Commit {
+            tree: tree.ok_or_else(|| missing("tree"))?,
+            parents,
+            author: author.ok_or_else(|| missing("author"))?,
+            committer: committer.ok_or_else(|| missing("committer"))?,
+            message: message.to_string(),
+            data,
+        })
+    }
+
+    pub fn tree(&self) -> &ObjectID {
+        &self.tree
+    }
+
+    pub fn parents(&self) -> &[ObjectID] {
+        &self.parents
+    }
+
+    /// Returns the first parent of the commit, if one exists.
+    pub fn parent(&self) -> Option<&ObjectID> {
+        self.parents.first()
+    }
+
+    pub fn author(&self) -> &Author {
+        &self.author
+    }
+
+    pub fn committer(&self) -> &Author {
+        &self.committer
     }
 
     pub fn message(&self) -> &str {
         &self.message
     }
+
+    /// Returns the first line of the commit message.
+    pub fn title_line(&self) -> &str {
+        self.message.lines().next().unwrap_or("")
+    }
 }
 
 impl Storable for Commit {
@@ -45,3 +126,34 @@ impl Storable for Commit {
         &self.data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_parse_roundtrip() -> Result<()> {
+        let time = chrono::DateTime::parse_from_rfc3339("2021-01-01T01:01:01+00:00")?;
+        let author = Author::new("Sean".to_string(), "sean@zombo.com".to_string(), time);
+        let tree = ObjectID::from_str("4b825dc642cb6eb9a060e54bf8d69288fbee4904")?;
+        let parent = ObjectID::from_str("ce013625030ba8dba906f756967f9e9ca394464a")?;
+        let commit = Commit::new(
+            vec![parent.clone()],
+            tree.clone(),
+            author.clone(),
+            author.clone(),
+            "title\n\nbody\n".to_string(),
+        );
+
+        let parsed = Commit::parse(commit.data().clone())?;
+        assert_eq!(parsed.tree(), &tree);
+        assert_eq!(parsed.parents(), &[parent]);
+        assert_eq!(parsed.author(), &author);
+        assert_eq!(parsed.committer(), &author);
+        assert_eq!(parsed.message(), "title\n\nbody\n");
+        assert_eq!(parsed.title_line(), "title");
+        assert_eq!(parsed.oid(), commit.oid());
+        Ok(())
+    }
+}
@@ -0,0 +1,446 @@
+use crate::error::{Error, Result};
+use crate::lockfile::LockFile;
+use std::io::Write;
+use std::path::Path;
+
+// A single "key = value" line within a config file.
+#[derive(Debug, Clone, PartialEq)]
+struct Variable {
+    // Section names and keys are case-insensitive, so are kept lowercase.
+    section: String,
+    subsection: Option<String>,
+    key: String,
+    // A key with no "=" is a shorthand for true.
+    value: Option<String>,
+    // The index of the line holding the variable.
+    line: usize,
+}
+
+// A "[section]" or `[section "subsection"]` header.
+#[derive(Debug, Clone, PartialEq)]
+struct Header {
+    section: String,
+    subsection: Option<String>,
+    line: usize,
+}
+
+/// The settings within a git config file, such as ".git/config".
+///
+/// Variables are named as in "git config": "core.bare", or
+/// "remote.origin.url" for those within a subsection. When a variable is
+/// set more than once, the last value wins.
+///
+/// Changes made with `set` keep the rest of the file (including comments)
+/// intact when saved.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    lines: Vec<String>,
+    headers: Vec<Header>,
+    variables: Vec<Variable>,
+}
+
+impl Config {
+    /// Reads a config file, which is treated as empty if missing.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        match std::fs::read_to_string(path.as_ref()) {
+            Ok(contents) => Config::parse(&contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Parses the contents of a config file.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut headers = Vec::new();
+        let mut variables = Vec::new();
+        let mut section: Option<(String, Option<String>)> = None;
+        for (n, line) in contents.lines().enumerate() {
+            let invalid = || Error::Fatal(format!("bad config line {}", n + 1));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let end = header.find(']').ok_or_else(invalid)?;
+                let (name, subsection) = parse_section(&header[..end]).ok_or_else(invalid)?;
+                headers.push(Header {
+                    section: name.clone(),
+                    subsection: subsection.clone(),
+                    line: n,
+                });
+                section = Some((name, subsection));
+                continue;
+            }
+            let (name, subsection) = section.clone().ok_or_else(invalid)?;
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(parse_value(value).ok_or_else(invalid)?)),
+                None => (strip_comment(line).trim(), None),
+            };
+            if !valid_key(key) {
+                return Err(invalid());
+            }
+            variables.push(Variable {
+                section: name,
+                subsection,
+                key: key.to_ascii_lowercase(),
+                value,
+                line: n,
+            });
+        }
+        Ok(Config {
+            lines: contents.lines().map(|line| line.to_string()).collect(),
+            headers,
+            variables,
+        })
+    }
+
+    /// Writes the config to `path`, replacing its previous contents.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut lock = LockFile::new(path)?;
+        lock.writer().write_all(self.to_string().as_bytes())?;
+        lock.commit()
+    }
+
+    /// Sets a variable, such as "remote.origin.url", replacing its last
+    /// value if already set.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let invalid = || Error::Fatal(format!("invalid config key: {}", name));
+        let (section, subsection, key) = split_name(name).ok_or_else(invalid)?;
+        let original_key = &name[name.len() - key.len()..];
+        if !valid_key(&key) {
+            return Err(invalid());
+        }
+        let line = format!("\t{} = {}", original_key, quote_value(value));
+        if let Some(variable) = self.find(name) {
+            let n = variable.line;
+            self.lines[n] = line;
+        } else {
+            // Adds to the end of the last matching section, or a new one.
+            let end_of_section = self
+                .headers
+                .iter()
+                .rev()
+                .find(|h| h.section == section && h.subsection.as_deref() == subsection)
+                .map(|header| {
+                    self.variables
+                        .iter()
+                        .filter(|v| v.line > header.line)
+                        .take_while(|v| {
+                            v.section == section && v.subsection.as_deref() == subsection
+                        })
+                        .map(|v| v.line)
+                        .last()
+                        .unwrap_or(header.line)
+                });
+            match end_of_section {
+                Some(n) => self.lines.insert(n + 1, line),
+                None => {
+                    let header = match subsection {
+                        Some(subsection) => format!(
+                            "[{} \"{}\"]",
+                            section,
+                            subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                        ),
+                        None => format!("[{}]", section),
+                    };
+                    self.lines.push(header);
+                    self.lines.push(line);
+                }
+            }
+        }
+        *self = Config::parse(&self.to_string())?;
+        Ok(())
+    }
+
+    /// Returns the names of the subsections of `section`, such as each
+    /// remote's name for "remote", in the order they first appear.
+    pub fn subsections(&self, section: &str) -> Vec<&str> {
+        let section = section.to_ascii_lowercase();
+        let mut names: Vec<&str> = Vec::new();
+        for header in self.headers.iter().filter(|h| h.section == section) {
+            if let Some(name) = header.subsection.as_deref() {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    // Returns the last setting of a variable.
+    fn find(&self, name: &str) -> Option<&Variable> {
+        let (section, subsection, key) = split_name(name)?;
+        self.variables
+            .iter()
+            .rev()
+            .find(|v| v.section == section && v.subsection.as_deref() == subsection && v.key == key)
+    }
+
+    /// Returns the value of a variable, such as "core.editor".
+    ///
+    /// A variable given without a value (which means true) is returned as
+    /// an empty string.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.find(name)
+            .map(|variable| variable.value.as_deref().unwrap_or(""))
+    }
+
+    /// Returns every value of a variable which may be set more than once,
+    /// such as "remote.origin.fetch", in the order they appear.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        let (section, subsection, key) = match split_name(name) {
+            Some(parts) => parts,
+            None => return vec![],
+        };
+        self.variables
+            .iter()
+            .filter(|v| {
+                v.section == section && v.subsection.as_deref() == subsection && v.key == key
+            })
+            .map(|variable| variable.value.as_deref().unwrap_or(""))
+            .collect()
+    }
+
+    /// Returns a boolean variable, accepting the same spellings as git.
+    pub fn get_bool(&self, name: &str) -> Result<Option<bool>> {
+        let value = match self.find(name) {
+            Some(Variable { value: None, .. }) => return Ok(Some(true)),
+            Some(Variable {
+                value: Some(value), ..
+            }) => value,
+            None => return Ok(None),
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(Some(true)),
+            "false" | "no" | "off" | "0" | "" => Ok(Some(false)),
+            _ => Err(Error::Fatal(format!(
+                "bad boolean config value '{}' for '{}'",
+                value, name
+            ))),
+        }
+    }
+
+    /// Returns an integer variable, which may have a "k", "m" or "g"
+    /// suffix (as in "512m").
+    pub fn get_int(&self, name: &str) -> Result<Option<u64>> {
+        let value = match self.get(name) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let invalid = || {
+            Error::Fatal(format!(
+                "bad numeric config value '{}' for '{}'",
+                value, name
+            ))
+        };
+        let (digits, scale) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+            Some('k') => (&value[..value.len() - 1], 1 << 10),
+            Some('m') => (&value[..value.len() - 1], 1 << 20),
+            Some('g') => (&value[..value.len() - 1], 1 << 30),
+            _ => (value, 1),
+        };
+        let n: u64 = digits.parse().map_err(|_| invalid())?;
+        n.checked_mul(scale).map(Some).ok_or_else(invalid)
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// Formats a value so that `parse_value` returns it unchanged.
+fn quote_value(value: &str) -> String {
+    let mut quoted = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';'])
+        || value.contains("  ");
+    if needs_quotes {
+        format!("\"{}\"", quoted)
+    } else {
+        quoted
+    }
+}
+
+// Splits "remote.origin.url" into its section, subsection and key. Section
+// and key are lowercased.
+fn split_name(name: &str) -> Option<(String, Option<&str>, String)> {
+    let (section, rest) = name.split_once('.')?;
+    let (subsection, key) = match rest.rsplit_once('.') {
+        Some((subsection, key)) => (Some(subsection), key),
+        None => (None, rest),
+    };
+    Some((
+        section.to_ascii_lowercase(),
+        subsection,
+        key.to_ascii_lowercase(),
+    ))
+}
+
+// Parses a section header (without brackets), such as `remote "origin"`.
+fn parse_section(header: &str) -> Option<(String, Option<String>)> {
+    let header = header.trim();
+    match header.split_once(char::is_whitespace) {
+        Some((name, subsection)) => {
+            let subsection = subsection.trim().strip_prefix('"')?.strip_suffix('"')?;
+            let subsection = subsection.replace("\\\"", "\"").replace("\\\\", "\\");
+            Some((name.to_ascii_lowercase(), Some(subsection)))
+        }
+        // The deprecated `[section.subsection]` syntax.
+        None => match header.split_once('.') {
+            Some((name, subsection)) => Some((
+                name.to_ascii_lowercase(),
+                Some(subsection.to_ascii_lowercase()),
+            )),
+            None => Some((header.to_ascii_lowercase(), None)),
+        },
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// Removes a trailing comment from a line with no quoted text.
+fn strip_comment(line: &str) -> &str {
+    match line.find(['#', ';']) {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+// Parses the text following "=": unquoting, unescaping and dropping any
+// comment. Returns None for an unterminated quote or unknown escape.
+fn parse_value(text: &str) -> Option<String> {
+    let mut value = String::new();
+    // Whitespace is only kept between other characters, or when quoted.
+    let mut pending_space = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => break,
+            '\\' => {
+                let escaped = match chars.next()? {
+                    'n' => '\n',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    c @ ('"' | '\\') => c,
+                    _ => return None,
+                };
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(escaped);
+            }
+            c if c.is_whitespace() && !quoted => pending_space.push(c),
+            c => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(c);
+            }
+        }
+    }
+    if quoted {
+        return None;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let config = Config::parse(
+            "# comment\n\
+             [core]\n\
+             \tbare = false\n\
+             \tBigFileThreshold = 1m ; trailing comment\n\
+             \tfilemode\n\
+             [remote \"origin\"]\n\
+             \turl = \"https://example.com/a b.git\"\n\
+             \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+             \tfetch = ^refs/heads/wip\n\
+             [core]\n\
+             \tbare = true\n\
+             \teditor = vim -c \"set tw=72\"\n",
+        )?;
+        assert_eq!(config.get("core.bare"), Some("true"));
+        assert_eq!(config.get_bool("core.bare")?, Some(true));
+        assert_eq!(config.get_bool("core.fileMode")?, Some(true));
+        assert_eq!(config.get_bool("core.missing")?, None);
+        assert_eq!(config.get_int("core.bigfilethreshold")?, Some(1 << 20));
+        assert_eq!(
+            config.get("remote.origin.url"),
+            Some("https://example.com/a b.git")
+        );
+        assert_eq!(config.get("remote.Origin.url"), None);
+        assert_eq!(
+            config.get_all("remote.origin.fetch"),
+            vec!["+refs/heads/*:refs/remotes/origin/*", "^refs/heads/wip"]
+        );
+        assert!(config.get_all("remote.upstream.fetch").is_empty());
+        assert_eq!(config.get("core.editor"), Some("vim -c set tw=72"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set() -> Result<()> {
+        let mut config = Config::parse(
+            "# keep me\n\
+             [core]\n\
+             \tbare = false\n\
+             [remote \"origin\"]\n\
+             \turl = /old\n",
+        )?;
+        config.set("core.bare", "true")?;
+        config.set("core.logAllRefUpdates", "true")?;
+        config.set("remote.origin.url", "/new path")?;
+        config.set("submodule.lib/a.url", "../a; b")?;
+        assert_eq!(
+            config.to_string(),
+            "# keep me\n\
+             [core]\n\
+             \tbare = true\n\
+             \tlogAllRefUpdates = true\n\
+             [remote \"origin\"]\n\
+             \turl = /new path\n\
+             [submodule \"lib/a\"]\n\
+             \turl = \"../a; b\"\n"
+        );
+        let reparsed = Config::parse(&config.to_string())?;
+        assert_eq!(reparsed.get("submodule.lib/a.url"), Some("../a; b"));
+        assert_eq!(reparsed.subsections("Submodule"), vec!["lib/a"]);
+        assert!(config.set("core", "x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Config::parse("key = value\n").is_err());
+        assert!(Config::parse("[core\n").is_err());
+        assert!(Config::parse("[core]\n\tkey = \"open\n").is_err());
+        assert!(Config::parse("[core]\n\t1key = x\n").is_err());
+
+        let config = Config::parse("[core]\n\tbare = maybe\n\tsize = 1x\n").unwrap();
+        assert!(config.get_bool("core.bare").is_err());
+        assert!(config.get_int("core.size").is_err());
+    }
+}
@@ -1,41 +1,37 @@
-use anyhow::{anyhow, Result};
+use crate::commit::Commit;
+use crate::entry::{Entry, Mode};
+use crate::error::{Error, Result};
+use crate::pack;
+use crate::store::{DiskStore, MemoryStore, ObjectStore};
+use crate::tag::Tag;
+use crate::tree::Tree;
+use crate::workspace::WorkspacePath;
 use sha1::{Digest, Sha1};
-use std::fs::{create_dir_all, rename, OpenOptions};
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub struct ObjectID {
     id: Vec<u8>,
 }
 
 impl ObjectID {
     fn new(storable: &(impl Storable + ?Sized)) -> Self {
-        let mut hasher = Sha1::new();
-        hasher.update(storable.encoded_raw());
-        ObjectID {
-            id: hasher.finalize().as_mut_slice().to_vec(),
-        }
-    }
-
-    /// Creates an ObjectID from a hexadecimal encoded string.
-    pub fn from_str<S: AsRef<str>>(s: S) -> Result<Self> {
-        let id = hex::decode(s.as_ref())?;
-        if id.len() != sha1::Sha1::output_size() {
-            return Err(anyhow!("Invalid ObjectID length"));
-        }
-        Ok(ObjectID { id })
+        let data = storable.data();
+        let mut hasher = ObjectHasher::new(storable.type_name(), data.len() as u64);
+        hasher.update(data);
+        hasher.finish()
     }
 
-    /*
     /// Creates an ObjectID from a raw byte sequence.
     pub fn from_bytes(b: &[u8]) -> Result<Self> {
         if b.len() != sha1::Sha1::output_size() {
-            return Err(anyhow!("Invalid ObjectID length"));
+            return Err(Error::InvalidObjectId(hex::encode(b)));
         }
         Ok(ObjectID { id: b.to_vec() })
     }
-    */
 
     pub fn as_bytes(&self) -> &[u8] {
         self.id.as_slice()
@@ -44,6 +40,82 @@ impl ObjectID {
     pub fn as_str(&self) -> String {
         hex::encode(self.as_bytes())
     }
+
+    /// Returns the abbreviated form of the ID, as displayed by git.
+    pub fn as_short_str(&self) -> String {
+        self.as_str()[..7].to_string()
+    }
+}
+
+impl FromStr for ObjectID {
+    type Err = Error;
+
+    /// Creates an ObjectID from a hexadecimal encoded string.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidObjectId(s.to_string());
+        let id = hex::decode(s).map_err(|_| invalid())?;
+        if id.len() != sha1::Sha1::output_size() {
+            return Err(invalid());
+        }
+        Ok(ObjectID { id })
+    }
+}
+
+// Returns the header which precedes the contents of an object, both when
+// hashed and when stored: "{TYPE} {SIZE}\0".
+pub(crate) fn object_header(type_name: &str, size: u64) -> String {
+    format!("{} {}\0", type_name, size)
+}
+
+/// Computes the ID of an object incrementally, so that its contents need
+/// not be held in memory at once.
+pub struct ObjectHasher {
+    hasher: Sha1,
+}
+
+impl ObjectHasher {
+    /// Starts hashing an object of the given type, whose contents will be
+    /// `size` bytes long.
+    pub fn new(type_name: &str, size: u64) -> Self {
+        let mut hasher = Sha1::new();
+        hasher.update(object_header(type_name, size).as_bytes());
+        ObjectHasher { hasher }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finish(self) -> ObjectID {
+        ObjectID {
+            id: self.hasher.finalize().as_slice().to_vec(),
+        }
+    }
+
+    /// Hashes an object whose `size` bytes of contents are read from
+    /// `reader`, without holding them in memory.
+    pub fn hash_stream(type_name: &str, size: u64, reader: &mut dyn Read) -> Result<ObjectID> {
+        let mut hasher = ObjectHasher::new(type_name, size);
+        let total = std::io::copy(&mut reader.take(size), &mut hasher)?;
+        if total != size {
+            return Err(Error::CorruptObject(format!(
+                "short read while hashing object: expected {} bytes, got {}",
+                size, total
+            )));
+        }
+        Ok(hasher.finish())
+    }
+}
+
+impl Write for ObjectHasher {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 /// An entity which may be stored within the git object database.
@@ -54,92 +126,230 @@ pub trait Storable {
     // Returns a byte representation of the underlying data.
     fn data(&self) -> &Vec<u8>;
 
-    // TODO: Could be fancy, use types to enforce compression.
-
-    // TODO: We re-invoked "encoded_raw" pretty frequently; might be
-    // worth restructuring this code to reduce the number of invocations.
-
-    fn encoded_raw(&self) -> Vec<u8> {
-        let data = self.data();
-        let mut content = Vec::new();
-        content.extend_from_slice(self.type_name().as_bytes());
-        content.extend_from_slice(b" ");
-        content.extend_from_slice(format!("{}\0", data.len()).as_bytes());
-        content.extend_from_slice(data);
-        content
+    /// Returns the ID of the object.
+    fn oid(&self) -> ObjectID {
+        ObjectID::new(self)
     }
+}
 
-    /// Serializes the object to a byte steam.
-    fn serialize(&self) -> Result<Vec<u8>> {
-        let content = self.encoded_raw();
+/// Any object which may be parsed out of the object store.
+pub enum Object {
+    Blob(Blob),
+    Tree(Tree),
+    Commit(Commit),
+    Tag(Tag),
+}
 
-        // Use zlib to compress the file so it uses less on-disk storage.
-        let compression = flate2::Compression::fast();
-        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), compression);
-        encoder.write_all(&content)?;
-        let compressed = encoder.finish()?;
+impl Object {
+    /// Parses an object from its type name and (uncompressed) contents.
+    pub fn parse(type_name: &str, data: Vec<u8>) -> Result<Self> {
+        match type_name {
+            "blob" => Ok(Object::Blob(Blob::new(data))),
+            "tree" => Ok(Object::Tree(Tree::parse(&data)?)),
+            "commit" => Ok(Object::Commit(Commit::parse(data)?)),
+            "tag" => Ok(Object::Tag(Tag::parse(data)?)),
+            _ => Err(Error::CorruptObject(format!(
+                "unknown object type: {}",
+                type_name
+            ))),
+        }
+    }
 
-        Ok(compressed)
+    // Returns the error for this object (named `oid`) being used where an
+    // object of type `expected` is needed.
+    pub(crate) fn wrong_type(&self, oid: &ObjectID, expected: &'static str) -> Error {
+        Error::WrongObjectType {
+            oid: oid.as_str(),
+            expected,
+            actual: self.type_name().to_string(),
+        }
     }
 
-    /// Returns the ID of the object.
-    fn oid(&self) -> ObjectID {
-        ObjectID::new(self)
+    pub fn type_name(&self) -> &str {
+        match self {
+            Object::Blob(blob) => blob.type_name(),
+            Object::Tree(tree) => tree.type_name(),
+            Object::Commit(commit) => commit.type_name(),
+            Object::Tag(tag) => tag.type_name(),
+        }
     }
 }
 
-/// Utility for storing Blob objects within git.
+/// Utility for storing objects within git.
+///
+/// Objects are kept by an `ObjectStore`: loose files and packs on disk by
+/// default, or any other backend passed to `with_store`.
 pub struct Database {
-    root: PathBuf,
+    store: Box<dyn ObjectStore>,
 }
 
 impl Database {
     /// Generates a new database object around the provided
     /// git database path.
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Database::with_store(DiskStore::new(path))
+    }
+
+    /// Generates a database which holds its objects in memory.
+    pub fn in_memory() -> Self {
+        Database::with_store(MemoryStore::new())
+    }
+
+    /// Generates a database around an arbitrary backend.
+    pub fn with_store(store: impl ObjectStore + 'static) -> Self {
         Database {
-            root: PathBuf::from(path.as_ref()),
+            store: Box::new(store),
         }
     }
 
+    /// Returns the backend holding the objects.
+    pub fn backend(&self) -> &dyn ObjectStore {
+        self.store.as_ref()
+    }
+
     /// Add a new object to the Git object store.
     pub fn store(&self, object: &impl Storable) -> Result<()> {
-        let content = object.serialize()?;
-        let object_id = object.oid().as_str();
-        let prefix = &object_id[0..2];
-        let suffix = &object_id[2..];
-
-        // First two characters of the object ID form a directory.
-        // The latter characters of the object ID form the regular file name.
-        let mut path = self.root.clone();
-        path.push(&prefix);
-        create_dir_all(&path)?;
-        let temp_filename = format!("{}.tmp", suffix);
-        let mut final_path = path.clone();
-        final_path.push(suffix);
-        // Someone else already made this object. Since the object store
-        // *should* be immutable, this means it already has the content we want
-        // too.
-        if final_path.exists() {
+        let oid = object.oid();
+        if self.store.has(&oid)? {
             return Ok(());
         }
+        self.store.write(&oid, object.type_name(), object.data())?;
+        tracing::debug!(
+            oid = %oid.as_str(),
+            kind = object.type_name(),
+            size = object.data().len(),
+            "write"
+        );
+        Ok(())
+    }
+
+    /// Adds an object whose `size` bytes of contents are read from
+    /// `reader`, returning its ID. Unlike `store`, the contents need not
+    /// fit in memory.
+    pub fn store_stream(
+        &self,
+        type_name: &str,
+        size: u64,
+        reader: &mut dyn std::io::Read,
+    ) -> Result<ObjectID> {
+        let oid = self.store.write_stream(type_name, size, reader)?;
+        tracing::debug!(oid = %oid.as_str(), kind = type_name, size, "write");
+        Ok(oid)
+    }
 
-        let mut tmp_path = path;
-        tmp_path.push(&temp_filename);
+    /// Adds every object within a pack received from another repository,
+    /// returning their IDs in the order they appear in the pack.
+    ///
+    /// Stores which can keep packs whole are given the pack, completed and
+    /// indexed; others are given each object in turn.
+    pub fn store_pack(&self, pack: &[u8]) -> Result<Vec<ObjectID>> {
+        let indexed = pack::index(self, pack)?;
+        if indexed.oids.is_empty() {
+            return Ok(vec![]);
+        }
+        if !self.store.write_pack(&indexed.pack, &indexed.index)? {
+            pack::unpack(self, pack)?;
+        }
+        Ok(indexed.oids)
+    }
 
-        // Create the file exclusively so we won't clobber anyone else
-        // generating this object.
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(&tmp_path)?;
-        file.write_all(&content)?;
+    /// Returns true if the object `oid` exists.
+    pub fn contains(&self, oid: &ObjectID) -> Result<bool> {
+        self.store.has(oid)
+    }
+
+    /// Reads the type name and uncompressed contents of an object.
+    pub fn read_raw(&self, oid: &ObjectID) -> Result<(String, Vec<u8>)> {
+        let (type_name, data) = self.store.read(oid)?;
+        tracing::debug!(oid = %oid.as_str(), kind = %type_name, size = data.len(), "read");
+        Ok((type_name, data))
+    }
+
+    /// Reads and parses an object from the store.
+    pub fn load(&self, oid: &ObjectID) -> Result<Object> {
+        let (type_name, data) = self.read_raw(oid)?;
+        Object::parse(&type_name, data)
+    }
+
+    /// Loads an object, which is expected to be a commit.
+    pub fn load_commit(&self, oid: &ObjectID) -> Result<Commit> {
+        match self.load(oid)? {
+            Object::Commit(commit) => Ok(commit),
+            object => Err(object.wrong_type(oid, "commit")),
+        }
+    }
+
+    /// Loads an object, which is expected to be a tree.
+    pub fn load_tree(&self, oid: &ObjectID) -> Result<Tree> {
+        match self.load(oid)? {
+            Object::Tree(tree) => Ok(tree),
+            object => Err(object.wrong_type(oid, "tree")),
+        }
+    }
+
+    /// Loads an object, which is expected to be a blob.
+    pub fn load_blob(&self, oid: &ObjectID) -> Result<Blob> {
+        match self.load(oid)? {
+            Object::Blob(blob) => Ok(blob),
+            object => Err(object.wrong_type(oid, "blob")),
+        }
+    }
 
-        rename(tmp_path, final_path)?;
+    /// Recursively reads a tree, returning a map of every non-tree entry
+    /// keyed by its path relative to the root of the tree.
+    pub fn load_tree_list(&self, oid: &ObjectID) -> Result<BTreeMap<PathBuf, Entry>> {
+        let mut list = BTreeMap::new();
+        self.load_tree_list_r(oid, Path::new(""), &mut list)?;
+        Ok(list)
+    }
 
+    // Recursive helper for load_tree_list.
+    fn load_tree_list_r(
+        &self,
+        oid: &ObjectID,
+        prefix: &Path,
+        list: &mut BTreeMap<PathBuf, Entry>,
+    ) -> Result<()> {
+        for entry in self.load_tree(oid)?.entries() {
+            let path = prefix.join(entry.path());
+            if *entry.mode() == Mode::Directory {
+                self.load_tree_list_r(entry.oid(), &path, list)?;
+            } else {
+                list.insert(
+                    path.clone(),
+                    Entry::new(
+                        WorkspacePath::new(path)?,
+                        entry.oid().clone(),
+                        *entry.mode(),
+                    ),
+                );
+            }
+        }
         Ok(())
     }
+
+    /// Returns all object IDs in the store beginning with the hexadecimal
+    /// `prefix`.
+    pub fn prefix_match(&self, prefix: &str) -> Result<Vec<ObjectID>> {
+        self.store.prefix_match(prefix)
+    }
+
+    /// Copies every object of `other` which is missing from this database,
+    /// returning the number copied.
+    pub fn import(&self, other: &Database) -> Result<usize> {
+        let mut count = 0;
+        for oid in other.backend().iter()? {
+            let oid = oid?;
+            if self.store.has(&oid)? {
+                continue;
+            }
+            let (type_name, data) = other.read_raw(&oid)?;
+            self.store.write(&oid, &type_name, &data)?;
+            count += 1;
+        }
+        tracing::debug!(count, "import");
+        Ok(count)
+    }
 }
 
 /// Encapsulates the contents of a file.
@@ -160,4 +370,78 @@ impl Blob {
     pub fn new(data: Vec<u8>) -> Self {
         Blob { data }
     }
+
+    /// Consumes the blob, returning its contents.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_store_and_load_blob() -> Result<()> {
+        let dir = TempDir::new("test_store_and_load_blob")?;
+        let database = Database::new(dir.path());
+
+        let blob = Blob::new(b"hello\n".to_vec());
+        database.store(&blob)?;
+        // Matches "git hash-object" for the same content.
+        assert_eq!(
+            blob.oid().as_str(),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+
+        let loaded = database.load_blob(&blob.oid())?;
+        assert_eq!(loaded.data(), blob.data());
+        assert!(database.load_tree(&blob.oid()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_match() -> Result<()> {
+        let dir = TempDir::new("test_prefix_match")?;
+        let database = Database::new(dir.path());
+
+        let blob = Blob::new(b"hello\n".to_vec());
+        database.store(&blob)?;
+
+        assert_eq!(database.prefix_match("ce0136")?, vec![blob.oid()]);
+        assert!(database.prefix_match("ce0137")?.is_empty());
+        assert!(database.prefix_match("00")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory() -> Result<()> {
+        let database = Database::in_memory();
+        let blob = Blob::new(b"hello\n".to_vec());
+        database.store(&blob)?;
+        let tree = Tree::build(
+            vec![Entry::new(
+                WorkspacePath::new("dir/hello.txt")?,
+                blob.oid(),
+                Mode::ReadWrite,
+            )],
+            |tree| database.store(tree),
+        )?;
+
+        assert!(database.contains(&blob.oid())?);
+        assert_eq!(database.backend().iter()?.count(), 3);
+        let files = database.load_tree_list(&tree.oid())?;
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            vec![Path::new("dir/hello.txt")]
+        );
+        assert_eq!(database.load_blob(&blob.oid())?.data(), blob.data());
+
+        let copy = Database::in_memory();
+        assert_eq!(copy.import(&database)?, 3);
+        assert_eq!(copy.import(&database)?, 0);
+        assert_eq!(copy.load_tree_list(&tree.oid())?, files);
+        Ok(())
+    }
 }
@@ -0,0 +1,732 @@
+use crate::database::Database;
+use crate::entry::{Entry, Mode};
+use crate::error::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Number of unchanged lines surrounding each change within a hunk.
+const CONTEXT: usize = 3;
+// Git treats a file as binary if a NUL byte appears within this prefix.
+const BINARY_CHECK_LEN: usize = 8000;
+// Total width available for a diffstat line.
+const STAT_WIDTH: usize = 80;
+
+/// A single step in transforming one sequence into another.
+///
+/// Indices refer to positions within the "old" (a) and "new" (b) sequences.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Computes a minimal edit script from `a` to `b`, using Myers' algorithm.
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace = Vec::new();
+
+    // Walk outwards over the number of edits (d), recording the furthest
+    // reaching path along each diagonal (k = x - y).
+    'search: for d in 0..=max as isize {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    // Backtrack through the recorded paths to recover the edits.
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let index = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal((x - 1) as usize, (y - 1) as usize));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert((y - 1) as usize));
+            } else {
+                edits.push(Edit::Delete((x - 1) as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.reverse();
+    edits
+}
+
+/// Splits data into lines, each retaining its trailing newline (if any).
+pub fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|b| *b == b'\n').collect()
+}
+
+/// Returns true if git would consider the data to be binary.
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(BINARY_CHECK_LEN)].contains(&b'\0')
+}
+
+/// A group of nearby edits, displayed together with surrounding context.
+pub struct Hunk {
+    a_start: usize,
+    b_start: usize,
+    edits: Vec<Edit>,
+}
+
+impl Hunk {
+    /// Groups an edit script into hunks with `context` lines of context.
+    pub fn build(edits: &[Edit], context: usize) -> Vec<Hunk> {
+        let mut hunks = Vec::new();
+        let changes: Vec<usize> = edits
+            .iter()
+            .enumerate()
+            .filter(|(_, edit)| !matches!(edit, Edit::Equal(_, _)))
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut i = 0;
+        while i < changes.len() {
+            let start = changes[i].saturating_sub(context);
+            let mut end = changes[i] + 1;
+            // Absorb subsequent changes whose context would overlap.
+            while i + 1 < changes.len() && changes[i + 1] <= end + 2 * context {
+                i += 1;
+                end = changes[i] + 1;
+            }
+            let end = (end + context).min(edits.len());
+            i += 1;
+
+            // Line numbers are derived from the edits preceding the hunk.
+            let a_start = edits[..start]
+                .iter()
+                .filter(|e| !matches!(e, Edit::Insert(_)))
+                .count();
+            let b_start = edits[..start]
+                .iter()
+                .filter(|e| !matches!(e, Edit::Delete(_)))
+                .count();
+            hunks.push(Hunk {
+                a_start,
+                b_start,
+                edits: edits[start..end].to_vec(),
+            });
+        }
+        hunks
+    }
+
+    /// Returns the hunk header, such as "@@ -1,3 +1,4 @@".
+    pub fn header(&self) -> String {
+        let a_len = self
+            .edits
+            .iter()
+            .filter(|e| !matches!(e, Edit::Insert(_)))
+            .count();
+        let b_len = self
+            .edits
+            .iter()
+            .filter(|e| !matches!(e, Edit::Delete(_)))
+            .count();
+        format!(
+            "@@ -{} +{} @@",
+            Hunk::range(self.a_start, a_len),
+            Hunk::range(self.b_start, b_len)
+        )
+    }
+
+    // Formats a line range as git does: empty ranges refer to the line
+    // before them, and single-line ranges omit their length.
+    fn range(start: usize, len: usize) -> String {
+        match len {
+            0 => format!("{},0", start),
+            1 => format!("{}", start + 1),
+            _ => format!("{},{}", start + 1, len),
+        }
+    }
+
+    pub fn edits(&self) -> &[Edit] {
+        &self.edits
+    }
+}
+
+/// A path which differs between two trees.
+pub struct Change {
+    pub path: PathBuf,
+    pub old: Option<Entry>,
+    pub new: Option<Entry>,
+}
+
+/// Compares two flattened trees (as returned by `Database::load_tree_list`),
+/// returning the paths which differ in content or mode.
+pub fn tree_changes(old: &BTreeMap<PathBuf, Entry>, new: &BTreeMap<PathBuf, Entry>) -> Vec<Change> {
+    let paths: BTreeSet<&PathBuf> = old.keys().chain(new.keys()).collect();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let (old, new) = (old.get(path), new.get(path));
+            if old == new {
+                return None;
+            }
+            Some(Change {
+                path: path.clone(),
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .collect()
+}
+
+// Loads the contents of one side of a change.
+fn side_data(database: &Database, entry: &Option<Entry>) -> Result<Vec<u8>> {
+    match entry {
+        Some(entry) => entry_data(database, entry),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Loads the contents of an entry. As in git, a submodule is shown as the
+// commit it records.
+fn entry_data(database: &Database, entry: &Entry) -> Result<Vec<u8>> {
+    if *entry.mode() == Mode::Gitlink {
+        return Ok(format!("Subproject commit {}\n", entry.oid().as_str()).into_bytes());
+    }
+    Ok(database.load_blob(entry.oid())?.into_data())
+}
+
+fn short_oid(entry: &Option<Entry>) -> String {
+    entry
+        .as_ref()
+        .map(|entry| entry.oid().as_short_str())
+        .unwrap_or_else(|| "0000000".to_string())
+}
+
+/// Writes a change in git's unified patch format.
+pub fn write_patch(database: &Database, change: &Change, out: &mut impl Write) -> Result<()> {
+    let path = change.path.display();
+    writeln!(out, "diff --git a/{} b/{}", path, path)?;
+    match (&change.old, &change.new) {
+        (None, Some(new)) => writeln!(out, "new file mode {}", new.mode().as_str())?,
+        (Some(old), None) => writeln!(out, "deleted file mode {}", old.mode().as_str())?,
+        (Some(old), Some(new)) if old.mode() != new.mode() => {
+            writeln!(out, "old mode {}", old.mode().as_str())?;
+            writeln!(out, "new mode {}", new.mode().as_str())?;
+        }
+        _ => (),
+    }
+
+    let old_oid = change.old.as_ref().map(|e| e.oid());
+    let new_oid = change.new.as_ref().map(|e| e.oid());
+    if old_oid == new_oid {
+        // Only the mode changed.
+        return Ok(());
+    }
+    let mode_suffix = match (&change.old, &change.new) {
+        (Some(old), Some(new)) if old.mode() == new.mode() => format!(" {}", old.mode().as_str()),
+        _ => String::new(),
+    };
+    writeln!(
+        out,
+        "index {}..{}{}",
+        short_oid(&change.old),
+        short_oid(&change.new),
+        mode_suffix
+    )?;
+
+    let old_data = side_data(database, &change.old)?;
+    let new_data = side_data(database, &change.new)?;
+    let old_name = match change.old {
+        Some(_) => format!("a/{}", path),
+        None => "/dev/null".to_string(),
+    };
+    let new_name = match change.new {
+        Some(_) => format!("b/{}", path),
+        None => "/dev/null".to_string(),
+    };
+    if is_binary(&old_data) || is_binary(&new_data) {
+        writeln!(out, "Binary files {} and {} differ", old_name, new_name)?;
+        return Ok(());
+    }
+    writeln!(out, "--- {}", old_name)?;
+    writeln!(out, "+++ {}", new_name)?;
+    write_hunks(&old_data, &new_data, out)
+}
+
+/// Writes the hunks transforming `old` into `new`.
+pub fn write_hunks(old: &[u8], new: &[u8], out: &mut impl Write) -> Result<()> {
+    let (a, b) = (lines(old), lines(new));
+    for hunk in Hunk::build(&diff(&a, &b), CONTEXT) {
+        writeln!(out, "{}", hunk.header())?;
+        for edit in hunk.edits() {
+            let (marker, line) = match *edit {
+                Edit::Equal(i, _) => (b' ', a[i]),
+                Edit::Delete(i) => (b'-', a[i]),
+                Edit::Insert(j) => (b'+', b[j]),
+            };
+            out.write_all(&[marker])?;
+            out.write_all(line)?;
+            if !line.ends_with(b"\n") {
+                out.write_all(b"\n\\ No newline at end of file\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// A line of a combined diff, with a marker for each parent.
+struct CombinedLine<'a> {
+    markers: Vec<u8>,
+    text: &'a [u8],
+    // Whether the line exists in each parent, and in the result.
+    in_parents: Vec<bool>,
+    in_result: bool,
+}
+
+impl CombinedLine<'_> {
+    fn is_change(&self) -> bool {
+        self.markers.iter().any(|marker| *marker != b' ')
+    }
+}
+
+/// Writes the combined diff of a merge result against each of its parents,
+/// in the style of "git diff --cc".
+///
+/// Only paths which differ from every parent are worth showing, and hunks
+/// in which the result matches any one parent are omitted.
+pub fn write_combined_patch(
+    database: &Database,
+    path: &Path,
+    parents: &[Option<Entry>],
+    result: &Entry,
+    out: &mut impl Write,
+) -> Result<()> {
+    let parent_data = parents
+        .iter()
+        .map(|parent| side_data(database, parent))
+        .collect::<Result<Vec<_>>>()?;
+    let result_data = entry_data(database, result)?;
+    let binary = is_binary(&result_data) || parent_data.iter().any(|data| is_binary(data));
+
+    let result_lines = lines(&result_data);
+    let parent_lines: Vec<Vec<&[u8]>> = parent_data.iter().map(|data| lines(data)).collect();
+    let rows = if binary {
+        Vec::new()
+    } else {
+        combine(&parent_lines, &result_lines)
+    };
+
+    let mut hunks = Vec::new();
+    let changes: Vec<usize> = (0..rows.len()).filter(|i| rows[*i].is_change()).collect();
+    let mut i = 0;
+    while i < changes.len() {
+        let start = changes[i].saturating_sub(CONTEXT);
+        let mut end = changes[i] + 1;
+        while i + 1 < changes.len() && changes[i + 1] <= end + 2 * CONTEXT {
+            i += 1;
+            end = changes[i] + 1;
+        }
+        let end = (end + CONTEXT).min(rows.len());
+        i += 1;
+
+        let interesting =
+            (0..parents.len()).all(|p| rows[start..end].iter().any(|row| row.markers[p] != b' '));
+        if interesting {
+            hunks.push(start..end);
+        }
+    }
+    if !binary && hunks.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out, "diff --cc {}", path.display())?;
+    let parent_oids: Vec<String> = parents.iter().map(short_oid).collect();
+    writeln!(
+        out,
+        "index {}..{}",
+        parent_oids.join(","),
+        result.oid().as_short_str()
+    )?;
+    if binary {
+        writeln!(out, "Binary files differ")?;
+        return Ok(());
+    }
+    let a_name = match parents.iter().any(|parent| parent.is_some()) {
+        true => format!("a/{}", path.display()),
+        false => "/dev/null".to_string(),
+    };
+    writeln!(out, "--- {}", a_name)?;
+    writeln!(out, "+++ b/{}", path.display())?;
+
+    let marker = "@".repeat(parents.len() + 1);
+    for hunk in hunks {
+        write!(out, "{}", marker)?;
+        for p in 0..parents.len() {
+            let before = rows[..hunk.start]
+                .iter()
+                .filter(|row| row.in_parents[p])
+                .count();
+            let len = rows[hunk.clone()]
+                .iter()
+                .filter(|row| row.in_parents[p])
+                .count();
+            write!(out, " -{}", combined_range(before, len))?;
+        }
+        let before = rows[..hunk.start]
+            .iter()
+            .filter(|row| row.in_result)
+            .count();
+        let len = rows[hunk.clone()]
+            .iter()
+            .filter(|row| row.in_result)
+            .count();
+        writeln!(out, " +{} {}", combined_range(before, len), marker)?;
+
+        for row in &rows[hunk] {
+            out.write_all(&row.markers)?;
+            out.write_all(row.text)?;
+            if !row.text.ends_with(b"\n") {
+                out.write_all(b"\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Formats a line range within a combined diff header, which (unlike a
+// unified diff) always includes the length.
+fn combined_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+// Interleaves the lines of `result` with the lines each parent lost, marking
+// which parents each line was added to or removed from.
+fn combine<'a>(parents: &[Vec<&'a [u8]>], result: &[&'a [u8]]) -> Vec<CombinedLine<'a>> {
+    let n = parents.len();
+    // For each parent: the lines removed before each result line (with an
+    // extra slot for the end), and whether each result line was added.
+    let mut lost: Vec<Vec<Vec<&'a [u8]>>> = vec![vec![Vec::new(); result.len() + 1]; n];
+    let mut added = vec![vec![false; result.len()]; n];
+    for (p, parent) in parents.iter().enumerate() {
+        let mut next = 0;
+        for edit in diff(parent, result) {
+            match edit {
+                Edit::Equal(_, j) => next = j + 1,
+                Edit::Delete(i) => lost[p][next].push(parent[i]),
+                Edit::Insert(j) => {
+                    added[p][j] = true;
+                    next = j + 1;
+                }
+            }
+        }
+    }
+
+    let mut rows = Vec::new();
+    for j in 0..=result.len() {
+        // Lines lost by several parents are shown once, with a marker for
+        // each of them.
+        let mut removed: Vec<CombinedLine> = Vec::new();
+        for (p, parent_lost) in lost.iter().enumerate() {
+            let mut search_from = 0;
+            for line in &parent_lost[j] {
+                let existing = removed[search_from..]
+                    .iter()
+                    .position(|row| row.text == *line && row.markers[p] == b' ');
+                match existing {
+                    Some(offset) => {
+                        let row = &mut removed[search_from + offset];
+                        row.markers[p] = b'-';
+                        row.in_parents[p] = true;
+                        search_from += offset + 1;
+                    }
+                    None => {
+                        let mut row = CombinedLine {
+                            markers: vec![b' '; n],
+                            text: line,
+                            in_parents: vec![false; n],
+                            in_result: false,
+                        };
+                        row.markers[p] = b'-';
+                        row.in_parents[p] = true;
+                        removed.push(row);
+                        search_from = removed.len();
+                    }
+                }
+            }
+        }
+        rows.extend(removed);
+
+        if j < result.len() {
+            let markers: Vec<u8> = (0..n)
+                .map(|p| if added[p][j] { b'+' } else { b' ' })
+                .collect();
+            let in_parents = (0..n).map(|p| !added[p][j]).collect();
+            rows.push(CombinedLine {
+                markers,
+                text: result[j],
+                in_parents,
+                in_result: true,
+            });
+        }
+    }
+    rows
+}
+
+// Counts of lines added and removed by a change, or the sizes of both sides
+// for binary files.
+enum Stat {
+    Text { insertions: usize, deletions: usize },
+    Binary { old_size: usize, new_size: usize },
+}
+
+/// Writes a summary of the number of lines changed in each file, in the
+/// style of "git diff --stat".
+pub fn write_stat(database: &Database, changes: &[Change], out: &mut impl Write) -> Result<()> {
+    let mut stats = Vec::new();
+    for change in changes {
+        let old_data = side_data(database, &change.old)?;
+        let new_data = side_data(database, &change.new)?;
+        let stat = if is_binary(&old_data) || is_binary(&new_data) {
+            Stat::Binary {
+                old_size: old_data.len(),
+                new_size: new_data.len(),
+            }
+        } else {
+            let edits = diff(&lines(&old_data), &lines(&new_data));
+            Stat::Text {
+                insertions: edits
+                    .iter()
+                    .filter(|e| matches!(e, Edit::Insert(_)))
+                    .count(),
+                deletions: edits
+                    .iter()
+                    .filter(|e| matches!(e, Edit::Delete(_)))
+                    .count(),
+            }
+        };
+        stats.push((change.path.as_path(), stat));
+    }
+    write_stat_lines(&stats, out)
+}
+
+fn write_stat_lines(stats: &[(&Path, Stat)], out: &mut impl Write) -> Result<()> {
+    let name_width = stats
+        .iter()
+        .map(|(path, _)| path.display().to_string().len())
+        .max()
+        .unwrap_or(0);
+    let max_change = stats
+        .iter()
+        .map(|(_, stat)| match stat {
+            Stat::Text {
+                insertions,
+                deletions,
+            } => insertions + deletions,
+            Stat::Binary { .. } => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    let count_width = max_change.to_string().len();
+    let graph_width = STAT_WIDTH
+        .saturating_sub(name_width + count_width + 4)
+        .max(10);
+    // Scale the graph down (as git does) only when it would overflow.
+    let scale = |n: usize| {
+        if max_change <= graph_width || n == 0 {
+            n
+        } else {
+            1 + n * (graph_width - 1) / max_change
+        }
+    };
+
+    let (mut total_insertions, mut total_deletions) = (0, 0);
+    for (path, stat) in stats {
+        let name = path.display().to_string();
+        match stat {
+            Stat::Text {
+                insertions,
+                deletions,
+            } => {
+                total_insertions += insertions;
+                total_deletions += deletions;
+                let (mut plus, minus) = (scale(*insertions), scale(*deletions));
+                if max_change > graph_width && plus + minus > graph_width {
+                    plus -= 1;
+                }
+                let graph = "+".repeat(plus) + &"-".repeat(minus);
+                let graph = if graph.is_empty() {
+                    graph
+                } else {
+                    format!(" {}", graph)
+                };
+                writeln!(
+                    out,
+                    " {:name_width$} | {:>count_width$}{}",
+                    name,
+                    insertions + deletions,
+                    graph,
+                    name_width = name_width,
+                    count_width = count_width,
+                )?;
+            }
+            Stat::Binary { old_size, new_size } => {
+                writeln!(
+                    out,
+                    " {:name_width$} | Bin {} -> {} bytes",
+                    name,
+                    old_size,
+                    new_size,
+                    name_width = name_width
+                )?;
+            }
+        }
+    }
+
+    let plural =
+        |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+    let mut summary = format!(" {}", plural(stats.len(), "file changed", "files changed"));
+    if total_insertions > 0 || total_deletions == 0 {
+        summary += &format!(
+            ", {}",
+            plural(total_insertions, "insertion(+)", "insertions(+)")
+        );
+    }
+    if total_deletions > 0 || total_insertions == 0 {
+        summary += &format!(
+            ", {}",
+            plural(total_deletions, "deletion(-)", "deletions(-)")
+        );
+    }
+    writeln!(out, "{}", summary)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn apply(a: &[&str], b: &[&str], edits: &[Edit]) -> Vec<String> {
+        edits
+            .iter()
+            .filter_map(|edit| match *edit {
+                Edit::Equal(i, j) => {
+                    assert_eq!(a[i], b[j]);
+                    Some(a[i].to_string())
+                }
+                Edit::Insert(j) => Some(b[j].to_string()),
+                Edit::Delete(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_minimal() {
+        let a = ["A", "B", "C", "A", "B", "B", "A"];
+        let b = ["C", "B", "A", "B", "A", "C"];
+        let edits = diff(&a, &b);
+        assert_eq!(apply(&a, &b, &edits), b);
+        // The classic example from Myers' paper has an edit distance of 5.
+        let changes = edits
+            .iter()
+            .filter(|e| !matches!(e, Edit::Equal(_, _)))
+            .count();
+        assert_eq!(changes, 5);
+    }
+
+    #[test]
+    fn test_diff_empty() {
+        let empty: [&str; 0] = [];
+        assert!(diff(&empty, &empty).is_empty());
+        assert_eq!(diff(&empty, &["a"]), vec![Edit::Insert(0)]);
+        assert_eq!(diff(&["a"], &empty), vec![Edit::Delete(0)]);
+    }
+
+    #[test]
+    fn test_hunks() -> Result<()> {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n";
+        let mut out = Vec::new();
+        write_hunks(old.as_bytes(), new.as_bytes(), &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "@@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n\
+             @@ -9,4 +9,4 @@\n 9\n 10\n 11\n-12\n+twelve\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hunks_missing_newline() -> Result<()> {
+        let mut out = Vec::new();
+        write_hunks(b"", b"new", &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "@@ -0,0 +1 @@\n+new\n\\ No newline at end of file\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stat_lines() -> Result<()> {
+        let mut out = Vec::new();
+        write_stat_lines(
+            &[
+                (
+                    Path::new("file.txt"),
+                    Stat::Text {
+                        insertions: 1,
+                        deletions: 1,
+                    },
+                ),
+                (
+                    Path::new("a/long/name.txt"),
+                    Stat::Text {
+                        insertions: 10,
+                        deletions: 0,
+                    },
+                ),
+            ],
+            &mut out,
+        )?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            " file.txt        |  2 +-\n \
+             a/long/name.txt | 10 ++++++++++\n \
+             2 files changed, 11 insertions(+), 1 deletion(-)\n"
+        );
+        Ok(())
+    }
+}
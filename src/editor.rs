@@ -0,0 +1,308 @@
+use crate::error::{Error, Result};
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+// Used when no editor has been configured.
+const DEFAULT_EDITOR: &str = "vi";
+
+// The characters "core.commentChar = auto" chooses between, in order.
+const COMMENT_CHARS: &str = "#;@!$%^&|:";
+
+// Without its comment character, the line below which "scissors" cleanup
+// discards a message.
+const CUT_LINE: &str = " ------------------------ >8 ------------------------";
+
+/// Returns the editor used for commit messages, following the same
+/// precedence as git: GIT_EDITOR, then `configured` ("core.editor"), then
+/// VISUAL and EDITOR.
+pub fn editor(configured: Option<&str>) -> String {
+    env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| configured.map(String::from))
+        .or_else(|| env::var("VISUAL").ok())
+        .or_else(|| env::var("EDITOR").ok())
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string())
+}
+
+/// Returns the editor used for rebase todo lists: GIT_SEQUENCE_EDITOR, then
+/// `configured` ("sequence.editor"), then the commit message `editor`.
+pub fn sequence_editor(configured: Option<&str>, editor: &str) -> String {
+    env::var("GIT_SEQUENCE_EDITOR")
+        .ok()
+        .or_else(|| configured.map(String::from))
+        .unwrap_or_else(|| editor.to_string())
+}
+
+/// How a commit message is cleaned up before it is recorded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cleanup {
+    /// Removes comments, along with surrounding and repeated blank lines
+    /// and trailing whitespace.
+    Strip,
+    /// Like `Strip`, but keeps comments.
+    Whitespace,
+    /// Leaves the message untouched.
+    Verbatim,
+    /// Like `Whitespace`, but also removes everything from the scissors
+    /// line onwards.
+    Scissors,
+    /// `Strip` if the message is edited, and `Whitespace` otherwise.
+    #[default]
+    Default,
+}
+
+impl Cleanup {
+    /// Returns the mode in effect when the message is (or is not) edited.
+    pub fn resolve(self, editing: bool) -> Self {
+        match self {
+            Cleanup::Default if editing => Cleanup::Strip,
+            Cleanup::Default => Cleanup::Whitespace,
+            // The scissors line only appears in the editor.
+            Cleanup::Scissors if !editing => Cleanup::Whitespace,
+            mode => mode,
+        }
+    }
+}
+
+impl FromStr for Cleanup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strip" => Ok(Cleanup::Strip),
+            "whitespace" => Ok(Cleanup::Whitespace),
+            "verbatim" => Ok(Cleanup::Verbatim),
+            "scissors" => Ok(Cleanup::Scissors),
+            "default" => Ok(Cleanup::Default),
+            _ => Err(Error::Fatal(format!("Invalid cleanup mode {}", s))),
+        }
+    }
+}
+
+/// The character starting comment lines in commit messages
+/// ("core.commentChar").
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommentChar {
+    Char(char),
+    /// Picks a character which starts none of the message's lines.
+    Auto,
+}
+
+impl Default for CommentChar {
+    fn default() -> Self {
+        CommentChar::Char('#')
+    }
+}
+
+impl CommentChar {
+    /// Returns the character to use for comments alongside `message`.
+    pub fn resolve(self, message: &str) -> char {
+        match self {
+            CommentChar::Char(c) => c,
+            CommentChar::Auto => COMMENT_CHARS
+                .chars()
+                .find(|&c| !message.lines().any(|line| line.starts_with(c)))
+                .unwrap_or('#'),
+        }
+    }
+}
+
+impl FromStr for CommentChar {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            return Ok(CommentChar::Auto);
+        }
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(CommentChar::Char(c)),
+            _ => Err(Error::Fatal(
+                "core.commentChar should only be one character".to_string(),
+            )),
+        }
+    }
+}
+
+/// Returns `text` as lines commented out with `comment_char`, as git writes
+/// hints into messages for the user to edit.
+pub fn comment(text: &str, comment_char: char) -> String {
+    text.lines()
+        .map(|line| match line {
+            "" => format!("{}\n", comment_char),
+            line if line.starts_with('\t') => format!("{}{}\n", comment_char, line),
+            line => format!("{} {}\n", comment_char, line),
+        })
+        .collect()
+}
+
+/// Returns the scissors line (and its explanation) for `comment_char`,
+/// below which "scissors" cleanup discards a message.
+pub fn cut_line(comment_char: char) -> String {
+    comment(
+        &format!(
+            "{}\nDo not modify or remove the line above.\nEverything below it will be ignored.\n",
+            CUT_LINE.trim_start()
+        ),
+        comment_char,
+    )
+}
+
+/// Opens `path` within `editor`, waiting for it to exit.
+///
+/// As in git, the editor is run by the shell so that it may include
+/// arguments, and ":" leaves the file untouched.
+pub fn edit(editor: &str, path: &Path) -> Result<()> {
+    if editor == ":" {
+        return Ok(());
+    }
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        return Err(Error::Fatal(format!(
+            "There was a problem with the editor '{}'.",
+            editor
+        )));
+    }
+    Ok(())
+}
+
+/// Lets the user edit `message` by writing it to `path`, returning the
+/// result with comment lines and surrounding blank lines removed.
+pub fn edit_message(editor: &str, path: &Path, message: &str) -> Result<String> {
+    std::fs::write(path, message)?;
+    edit(editor, path)?;
+    let edited = std::fs::read_to_string(path)?;
+    let message = cleanup(&edited, Cleanup::Strip, '#');
+    if message.is_empty() {
+        return Err(Error::Fatal(
+            "Aborting commit due to empty commit message.".to_string(),
+        ));
+    }
+    Ok(message)
+}
+
+/// Cleans up `message` as `mode` (which should have been resolved)
+/// describes, with `comment_char` starting comment lines.
+pub fn cleanup(message: &str, mode: Cleanup, comment_char: char) -> String {
+    let message = match mode {
+        Cleanup::Verbatim => return message.to_string(),
+        Cleanup::Scissors => {
+            let cut = format!("{}{}", comment_char, CUT_LINE);
+            match message.lines().position(|line| line == cut) {
+                Some(position) => message
+                    .lines()
+                    .take(position)
+                    .map(|line| format!("{}\n", line))
+                    .collect(),
+                None => message.to_string(),
+            }
+        }
+        _ => message.to_string(),
+    };
+    let comments = match mode {
+        Cleanup::Strip | Cleanup::Default => Some(comment_char),
+        _ => None,
+    };
+    strip_space(&message, comments)
+}
+
+// Removes trailing whitespace, leading and trailing blank lines and lines
+// starting with `comment_char` (if given), and collapses runs of blank lines
+// (as "git stripspace" does).
+fn strip_space(message: &str, comment_char: Option<char>) -> String {
+    let mut text = String::new();
+    let mut blank = false;
+    let comment = |line: &str| comment_char.is_some_and(|c| line.starts_with(c));
+    for line in message.lines().filter(|line| !comment(line)) {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = !text.is_empty();
+            continue;
+        }
+        if blank {
+            text.push('\n');
+            blank = false;
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_cleanup() -> Result<()> {
+        let message = "\ntitle  \n# comment\n\n\nbody\n\n; other\n";
+        assert_eq!(
+            cleanup(message, Cleanup::Strip, '#'),
+            "title\n\nbody\n\n; other\n"
+        );
+        assert_eq!(
+            cleanup(message, Cleanup::Strip, ';'),
+            "title\n# comment\n\nbody\n"
+        );
+        assert_eq!(
+            cleanup(message, Cleanup::Whitespace, '#'),
+            "title\n# comment\n\nbody\n\n; other\n"
+        );
+        assert_eq!(cleanup(message, Cleanup::Verbatim, '#'), message);
+        assert_eq!(cleanup("# only\n# comments\n", Cleanup::Strip, '#'), "");
+
+        let scissors = format!("title\n# kept\n\n{}\ndiscarded\n", cut_line('#'));
+        assert_eq!(
+            cleanup(&scissors, Cleanup::Scissors, '#'),
+            "title\n# kept\n"
+        );
+        assert_eq!(
+            cleanup(&scissors, Cleanup::Scissors, ';'),
+            scissors.trim_end().to_string() + "\n"
+        );
+
+        assert_eq!(Cleanup::Default.resolve(true), Cleanup::Strip);
+        assert_eq!(Cleanup::Default.resolve(false), Cleanup::Whitespace);
+        assert_eq!(Cleanup::Scissors.resolve(false), Cleanup::Whitespace);
+        assert_eq!("verbatim".parse::<Cleanup>()?, Cleanup::Verbatim);
+        assert!("other".parse::<Cleanup>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment_char() -> Result<()> {
+        assert_eq!(";".parse::<CommentChar>()?.resolve("# title\n"), ';');
+        assert!("##".parse::<CommentChar>().is_err());
+        let auto = "auto".parse::<CommentChar>()?;
+        assert_eq!(auto.resolve("title\n"), '#');
+        assert_eq!(auto.resolve("#1 title\n;\n"), '@');
+        assert_eq!(
+            comment("Changes:\n\tnew file:   a\n\n", ';'),
+            "; Changes:\n;\tnew file:   a\n;\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_message() -> Result<()> {
+        let dir = TempDir::new("test_edit_message")?;
+        let path = dir.path().join("COMMIT_EDITMSG");
+
+        assert_eq!(edit_message(":", &path, "title\n# hint\n")?, "title\n");
+        assert_eq!(
+            edit_message("sed -i s/title/edited/", &path, "title\n")?,
+            "edited\n"
+        );
+        assert!(edit_message("false", &path, "title\n").is_err());
+        assert!(edit_message(":", &path, "# empty\n").is_err());
+        Ok(())
+    }
+}
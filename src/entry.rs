@@ -1,13 +1,21 @@
 use crate::database::ObjectID;
-use crate::workspace::{Workspace, WorkspacePath};
+use crate::error::{Error, Result};
+use crate::workspace::WorkspacePath;
+use std::fs::Metadata;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub enum Mode {
     ReadWriteExecute,
     ReadWrite,
     Directory,
+    /// A symbolic link, whose blob holds the link target.
+    Symlink,
+    /// A submodule, whose ID is a commit within another repository.
+    Gitlink,
 }
 
 impl Mode {
@@ -15,14 +23,82 @@ impl Mode {
         match *self {
             Mode::ReadWriteExecute => "100755",
             Mode::ReadWrite => "100644",
-            Mode::Directory => "040000",
+            // Git omits the leading zero when writing trees.
+            Mode::Directory => "40000",
+            Mode::Symlink => "120000",
+            Mode::Gitlink => "160000",
+        }
+    }
+
+    /// Returns the numeric mode, as stored in the index.
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            Mode::ReadWriteExecute => 0o100755,
+            Mode::ReadWrite => 0o100644,
+            Mode::Directory => 0o40000,
+            Mode::Symlink => 0o120000,
+            Mode::Gitlink => 0o160000,
+        }
+    }
+
+    /// Parses the numeric mode, as stored in the index.
+    pub fn from_u32(mode: u32) -> Result<Self> {
+        match mode {
+            0o100755 => Ok(Mode::ReadWriteExecute),
+            0o100644 => Ok(Mode::ReadWrite),
+            0o40000 => Ok(Mode::Directory),
+            0o120000 => Ok(Mode::Symlink),
+            0o160000 => Ok(Mode::Gitlink),
+            _ => Err(Error::CorruptIndex(format!("unknown mode {:o}", mode))),
+        }
+    }
+
+    /// Identifies the mode of a file from its metadata, which should not
+    /// follow symlinks (see `Workspace::metadata`).
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        if metadata.file_type().is_symlink() {
+            Mode::Symlink
+        } else if metadata.is_dir() {
+            Mode::Directory
+        } else if metadata.permissions().mode() & 0o111 != 0 {
+            Mode::ReadWriteExecute
+        } else {
+            Mode::ReadWrite
+        }
+    }
+
+    /// Returns true if a file with `metadata` may have this mode.
+    ///
+    /// When core.symlinks is false, symlinks are checked out as regular
+    /// files holding the link target, so these also match `Symlink`.
+    /// Submodules are checked out as directories.
+    pub fn matches_metadata(&self, metadata: &Metadata) -> bool {
+        let mode = Mode::from_metadata(metadata);
+        *self == mode
+            || (*self == Mode::Symlink && mode == Mode::ReadWrite)
+            || (*self == Mode::Gitlink && mode == Mode::Directory)
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    /// Parses the octal representation of a mode, as stored in a tree.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "100755" => Ok(Mode::ReadWriteExecute),
+            "100644" => Ok(Mode::ReadWrite),
+            "40000" | "040000" => Ok(Mode::Directory),
+            "120000" => Ok(Mode::Symlink),
+            "160000" => Ok(Mode::Gitlink),
+            _ => Err(Error::CorruptObject(format!("unknown mode {}", s))),
         }
     }
 }
 
 /// An Entry contains the information necessary to represent
 /// a line within a tree.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Entry {
     path: WorkspacePath,
     oid: ObjectID,
@@ -35,7 +111,7 @@ impl Entry {
     }
 
     pub fn path(&self) -> &Path {
-        self.path.as_partial_path().as_ref()
+        self.path.as_partial_path()
     }
 
     pub fn path_bytes(&self) -> &[u8] {
@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+
+/// The errors returned by rit.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Neither the directory nor any of its parents contain a repository.
+    #[error("not a rit repository (or any of the parent directories): {}", .0.display())]
+    NotARepository(PathBuf),
+    /// A lock is already held, usually by another rit process.
+    #[error(
+        "Unable to create '{}': File exists.\n\n\
+         Another rit process seems to be running in this repository.",
+        .0.display()
+    )]
+    LockContention(PathBuf),
+    /// A string which is not a full, hexadecimal object ID.
+    #[error("invalid object ID: '{0}'")]
+    InvalidObjectId(String),
+    #[error("object {0} not found")]
+    ObjectNotFound(String),
+    /// An abbreviated object ID which matches several objects.
+    #[error("short object ID {prefix} is ambiguous: {}", .candidates.join(", "))]
+    AmbiguousObjectId {
+        prefix: String,
+        candidates: Vec<String>,
+    },
+    /// An object which could not be parsed.
+    #[error("corrupt object: {0}")]
+    CorruptObject(String),
+    #[error("object {oid} is a {actual}, not a {expected}")]
+    WrongObjectType {
+        oid: String,
+        expected: &'static str,
+        actual: String,
+    },
+    /// An index file which could not be parsed.
+    #[error("corrupt index: {0}")]
+    CorruptIndex(String),
+    /// A revision expression which could not be parsed.
+    #[error("invalid revision: '{0}'")]
+    InvalidRevision(String),
+    /// A revision which does not name an object.
+    #[error("not a valid object name: '{0}'")]
+    UnknownRevision(String),
+    /// A path within a "rev:path" revision which does not exist.
+    #[error("path '{0}' does not exist in the revision")]
+    PathNotFound(String),
+    /// A reference (or reflog entry) which is missing or malformed.
+    #[error("{0} is not a valid reference")]
+    InvalidRef(String),
+    /// HEAD names a branch with no commits.
+    #[error("HEAD does not refer to a commit")]
+    UnbornHead,
+    #[error("invalid path '{}': {reason}", .path.display())]
+    InvalidPath { path: PathBuf, reason: &'static str },
+    /// The operation stopped because of conflicts which need resolving.
+    #[error("{0}")]
+    Conflict(String),
+    /// A hook, such as "pre-commit", refused to let the operation continue.
+    #[error("the {0} hook declined")]
+    HookDeclined(&'static str),
+    /// Any other reason a command could not proceed, such as an operation
+    /// already being in progress.
+    #[error("{0}")]
+    Fatal(String),
+    /// A command which has already reported what went wrong, and exits
+    /// with the given status, as fsck does.
+    #[error("exit status {0}")]
+    Exit(i32),
+}
+
+impl Error {
+    /// Returns the exit status used by git for this error: 1 when an
+    /// operation stops for the user to resolve conflicts (or is refused by
+    /// a hook), 141 when the reader of the output went away (as git is
+    /// killed by SIGPIPE), and 128 for fatal errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Conflict(_) | Error::HookDeclined(_) => 1,
+            Error::Exit(code) => *code,
+            _ if self.is_broken_pipe() => 141,
+            _ => 128,
+        }
+    }
+
+    /// Returns true if the output was closed by its reader, as when piped
+    /// into "head". Like git, rit then exits without reporting it.
+    pub fn is_broken_pipe(&self) -> bool {
+        matches!(self, Error::Io(e) if e.kind() == std::io::ErrorKind::BrokenPipe)
+    }
+
+    /// Returns true if the error should not be reported, as it already has
+    /// been or (like a broken pipe) is of no interest.
+    pub fn is_silent(&self) -> bool {
+        matches!(self, Error::Exit(_)) || self.is_broken_pipe()
+    }
+
+    /// Returns the prefix git uses when reporting this error.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Error::Conflict(_) | Error::HookDeclined(_) => "error",
+            _ => "fatal",
+        }
+    }
+
+    // Shorthand for invalid paths.
+    pub(crate) fn invalid_path<P: Into<PathBuf>>(path: P, reason: &'static str) -> Self {
+        Error::InvalidPath {
+            path: path.into(),
+            reason,
+        }
+    }
+}
+
+/// The result type used throughout rit.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let err = Error::WrongObjectType {
+            oid: "ce01362".to_string(),
+            expected: "commit",
+            actual: "blob".to_string(),
+        };
+        assert_eq!(err.to_string(), "object ce01362 is a blob, not a commit");
+        assert_eq!(err.exit_code(), 128);
+        assert_eq!(err.prefix(), "fatal");
+
+        let err = Error::Conflict("could not apply ce01362... title".to_string());
+        assert_eq!(err.to_string(), "could not apply ce01362... title");
+        assert_eq!(err.exit_code(), 1);
+
+        let err: Error = std::io::Error::from(std::io::ErrorKind::NotFound).into();
+        assert!(matches!(err, Error::Io(_)));
+        assert!(!err.is_broken_pipe());
+
+        let err: Error = std::io::Error::from(std::io::ErrorKind::BrokenPipe).into();
+        assert!(err.is_broken_pipe());
+        assert!(err.is_silent());
+        assert_eq!(err.exit_code(), 141);
+
+        let err = Error::Exit(3);
+        assert!(err.is_silent());
+        assert_eq!(err.exit_code(), 3);
+    }
+}
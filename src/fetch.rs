@@ -0,0 +1,678 @@
+//! Fetching objects and references from another repository.
+//!
+//! The remote's references are listed, mapped to local references by
+//! refspecs, and any missing objects are downloaded as a pack, which is
+//! kept whole in the object database. Local references are then updated
+//! together, in a single transaction.
+
+use crate::author::Author;
+use crate::clone::Source;
+use crate::config::Config;
+use crate::database::{Database, ObjectID};
+use crate::error::{Error, Result};
+use crate::lockfile::LockFile;
+use crate::protocol::{Connection, RemoteRef, DEFAULT_RECEIVE_PACK, DEFAULT_UPLOAD_PACK};
+use crate::refs::{short_name, HEAD};
+use crate::refspec::Refspec;
+use crate::repository::Repository;
+use crate::revision::{self, Revision};
+use std::collections::{BinaryHeap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+/// The reference recording what was last fetched.
+pub const FETCH_HEAD: &str = "FETCH_HEAD";
+
+// The width of the summary column of update lines, which fits an
+// abbreviated range such as "ce01362...4b825dc".
+const SUMMARY_WIDTH: usize = 17;
+
+// The minimum width of the remote reference column of update lines.
+const MIN_REF_WIDTH: usize = 10;
+
+/// Which tags are fetched besides those matched by refspecs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagMode {
+    /// Tags which point at fetched (or existing) objects, as by default.
+    Follow,
+    /// Every tag, as with "--tags".
+    All,
+    /// No other tags, as with "--no-tags".
+    None,
+}
+
+/// A repository to fetch from, as configured by "remote.<name>.*".
+pub struct Remote {
+    name: String,
+    url: String,
+    source: Source,
+    refspecs: Vec<Refspec>,
+    push_refspecs: Vec<Refspec>,
+    upload_pack: String,
+    receive_pack: String,
+    tags: Option<TagMode>,
+}
+
+impl Remote {
+    /// Loads the remote `name` from `config`. A name which is not a
+    /// configured remote is taken to be a URL, relative to `cwd`, with no
+    /// refspecs.
+    pub fn load(config: &Config, name: &str, cwd: &Path) -> Result<Self> {
+        let variable = |key: &str| format!("remote.{}.{}", name, key);
+        let url = config.get(&variable("url")).unwrap_or(name).to_string();
+        let refspecs = |key: &str| {
+            config
+                .get_all(&variable(key))
+                .into_iter()
+                .map(|spec| spec.parse())
+                .collect::<Result<Vec<_>>>()
+        };
+        let tags = match config.get(&variable("tagOpt")) {
+            Some("--tags") => Some(TagMode::All),
+            Some("--no-tags") => Some(TagMode::None),
+            _ => None,
+        };
+        Ok(Remote {
+            name: name.to_string(),
+            source: Source::locate(&url, cwd)?,
+            url,
+            refspecs: refspecs("fetch")?,
+            push_refspecs: refspecs("push")?,
+            upload_pack: config
+                .get(&variable("uploadpack"))
+                .unwrap_or(DEFAULT_UPLOAD_PACK)
+                .to_string(),
+            receive_pack: config
+                .get(&variable("receivepack"))
+                .unwrap_or(DEFAULT_RECEIVE_PACK)
+                .to_string(),
+            tags,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the configured "remote.<name>.fetch" refspecs.
+    pub fn refspecs(&self) -> &[Refspec] {
+        &self.refspecs
+    }
+
+    /// Returns the configured "remote.<name>.push" refspecs.
+    pub fn push_refspecs(&self) -> &[Refspec] {
+        &self.push_refspecs
+    }
+
+    /// Returns the git directory of the remote repository.
+    pub fn git_dir(&self) -> &Path {
+        self.source.git_dir()
+    }
+
+    /// Returns the program run to push to the remote, as configured by
+    /// "remote.<name>.receivepack".
+    pub fn receive_pack(&self) -> &str {
+        &self.receive_pack
+    }
+
+    /// Returns the remote-tracking reference which the fetch refspecs map
+    /// the remote reference `name` to, if any.
+    pub fn tracking_ref(&self, name: &str) -> Option<String> {
+        let (negative, positive): (Vec<&Refspec>, Vec<&Refspec>) =
+            self.refspecs.iter().partition(|spec| spec.is_negative());
+        if negative.iter().any(|spec| spec.matches(name)) {
+            return None;
+        }
+        positive.iter().find_map(|spec| spec.map(name))
+    }
+}
+
+/// Options controlling a fetch.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Allows updates which are not fast-forwards, as if every refspec
+    /// started with "+".
+    pub force: bool,
+    /// Overrides "remote.<name>.tagOpt".
+    pub tags: Option<TagMode>,
+}
+
+/// The outcome of fetching a single reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The local reference was created.
+    New,
+    /// The local reference already matched.
+    UpToDate,
+    FastForward,
+    /// A branch was moved by force.
+    Forced,
+    /// A tag was moved by force.
+    TagUpdate,
+    /// A branch was left alone, as the change was not a fast-forward.
+    NonFastForward,
+    /// A tag was left alone, as tags only move by force.
+    WouldClobberTag,
+    /// The reference was only recorded in FETCH_HEAD.
+    FetchHead,
+}
+
+/// A remote reference which was fetched, along with the local reference
+/// (if any) it was stored in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefUpdate {
+    pub remote: String,
+    pub local: Option<String>,
+    pub old: Option<ObjectID>,
+    pub new: ObjectID,
+    pub status: Status,
+}
+
+// Describes what a reference is, such as "branch" for "refs/heads/main".
+fn kind(name: &str) -> &'static str {
+    if name.starts_with("refs/tags/") {
+        "tag"
+    } else if name.starts_with("refs/heads/") || name == HEAD {
+        "branch"
+    } else {
+        "ref"
+    }
+}
+
+impl RefUpdate {
+    /// Returns true if the local reference was left alone, which is an
+    /// error.
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self.status,
+            Status::NonFastForward | Status::WouldClobberTag
+        )
+    }
+
+    /// Returns true if the update is reported by default.
+    pub fn is_shown(&self) -> bool {
+        self.status != Status::UpToDate
+    }
+
+    /// Formats the update as git does, such as
+    /// " * [new branch]      main       -> origin/main", with the remote
+    /// name padded to `width`.
+    pub fn summary(&self, width: usize) -> String {
+        let range = |separator: &str| match &self.old {
+            Some(old) => format!(
+                "{}{}{}",
+                old.as_short_str(),
+                separator,
+                self.new.as_short_str()
+            ),
+            None => String::new(),
+        };
+        let (code, summary, reason) = match self.status {
+            Status::New => ('*', format!("[new {}]", kind(&self.remote)), None),
+            Status::UpToDate => ('=', "[up to date]".to_string(), None),
+            Status::FastForward => (' ', range(".."), None),
+            Status::Forced => ('+', range("..."), Some("forced update")),
+            Status::TagUpdate => ('t', "[tag update]".to_string(), None),
+            Status::NonFastForward => ('!', "[rejected]".to_string(), Some("non-fast-forward")),
+            Status::WouldClobberTag => (
+                '!',
+                "[rejected]".to_string(),
+                Some("would clobber existing tag"),
+            ),
+            Status::FetchHead => ('*', kind(&self.remote).to_string(), None),
+        };
+        let local = match &self.local {
+            Some(local) => short_name(local),
+            None => FETCH_HEAD,
+        };
+        let mut line = format!(
+            " {} {:<summary_width$} {:<width$} -> {}",
+            code,
+            summary,
+            short_name(&self.remote),
+            local,
+            summary_width = SUMMARY_WIDTH,
+            width = width
+        );
+        if let Some(reason) = reason {
+            line.push_str(&format!("  ({})", reason));
+        }
+        line
+    }
+
+    // Returns the reflog message describing the update, if it changes the
+    // local reference.
+    fn reflog_message(&self) -> Option<&'static str> {
+        match self.status {
+            Status::New => Some(match kind(&self.remote) {
+                "tag" => "storing tag",
+                "branch" => "storing head",
+                _ => "storing ref",
+            }),
+            Status::FastForward => Some("fast-forward"),
+            Status::Forced => Some("forced-update"),
+            Status::TagUpdate => Some("updating tag"),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the width of the remote reference column when displaying
+/// `updates`, which fits every shown name.
+pub fn summary_width(updates: &[RefUpdate]) -> usize {
+    updates
+        .iter()
+        .filter(|update| update.is_shown())
+        .map(|update| short_name(&update.remote).len())
+        .fold(MIN_REF_WIDTH, usize::max)
+}
+
+/// The result of a fetch.
+pub struct Fetched {
+    /// Every reference fetched, in the order git reports them.
+    pub updates: Vec<RefUpdate>,
+    /// The number of objects downloaded.
+    pub objects: usize,
+    /// The advertised references which were skipped, as their names are
+    /// invalid.
+    pub ignored: Vec<String>,
+}
+
+// A remote reference to fetch, and where to store it.
+struct Mapping<'a> {
+    remote: &'a RemoteRef,
+    local: Option<String>,
+    force: bool,
+    // Whether the reference is recorded in FETCH_HEAD, and if so whether it
+    // is a candidate for merging (as by "git pull").
+    fetch_head: bool,
+    for_merge: bool,
+}
+
+/// Fetches the references matched by `refspecs` (or by the remote's
+/// configured refspecs, if none are given) from `remote`, along with the
+/// objects they need.
+///
+/// Local references are updated in a single transaction, logged with
+/// `action` (such as "fetch origin"), and every fetched reference is
+/// recorded in FETCH_HEAD. Progress messages from the remote are passed to
+/// `progress`.
+pub fn fetch(
+    repo: &Repository,
+    remote: &Remote,
+    refspecs: &[Refspec],
+    options: Options,
+    identity: &Author,
+    action: &str,
+    progress: &mut dyn FnMut(&str),
+) -> Result<Fetched> {
+    let database = repo.database();
+    let refs = repo.refs();
+    let given = !refspecs.is_empty();
+    let mut refspecs = if given {
+        refspecs.to_vec()
+    } else {
+        remote.refspecs.clone()
+    };
+    let tags = options.tags.or(remote.tags).unwrap_or(TagMode::Follow);
+    if tags == TagMode::All {
+        refspecs.push("refs/tags/*:refs/tags/*".parse()?);
+    }
+    // With nothing else to fetch, the remote's HEAD is fetched.
+    if refspecs.iter().all(|spec| spec.is_negative()) {
+        refspecs.push(HEAD.parse()?);
+    }
+    let (negative, positive): (Vec<&Refspec>, Vec<&Refspec>) =
+        refspecs.iter().partition(|spec| spec.is_negative());
+    let excluded = |name: &str| negative.iter().any(|spec| spec.matches(name));
+
+    let mut connection = Connection::spawn(&remote.upload_pack, remote.source.git_dir())?;
+    let mut prefixes: Vec<String> = positive.iter().flat_map(|spec| spec.prefixes()).collect();
+    if tags == TagMode::Follow {
+        prefixes.push("refs/tags/".to_string());
+    }
+    let prefixes: Vec<&str> = prefixes.iter().map(|prefix| prefix.as_str()).collect();
+    // References with invalid names could escape the git directory.
+    let (remote_refs, ignored): (Vec<RemoteRef>, Vec<RemoteRef>) = connection
+        .ls_refs(&prefixes)?
+        .into_iter()
+        .partition(|remote_ref| Revision::valid_ref_name(&remote_ref.name));
+    let ignored: Vec<String> = ignored
+        .into_iter()
+        .map(|remote_ref| remote_ref.name)
+        .collect();
+
+    let mut mappings: Vec<Mapping> = Vec::new();
+    for spec in &positive {
+        let mut matched = false;
+        for remote_ref in &remote_refs {
+            // Globs only match real references, not symbolic ones.
+            let symbolic = spec.is_glob() && remote_ref.symref_target.is_some();
+            if !spec.matches(&remote_ref.name) || symbolic || excluded(&remote_ref.name) {
+                continue;
+            }
+            matched = true;
+            let local = spec.map(&remote_ref.name);
+            if mappings
+                .iter()
+                .any(|m| m.remote.name == remote_ref.name && m.local == local)
+            {
+                continue;
+            }
+            mappings.push(Mapping {
+                remote: remote_ref,
+                local,
+                force: spec.is_force() || options.force,
+                fetch_head: true,
+                for_merge: given,
+            });
+        }
+        if given && !matched && !spec.is_glob() {
+            return Err(Error::Fatal(format!(
+                "couldn't find remote ref {}",
+                spec.source()
+            )));
+        }
+    }
+
+    if given {
+        // References named on the command line also update the matching
+        // remote-tracking branches.
+        let configured: Vec<Mapping> = mappings
+            .iter()
+            .flat_map(|mapping| {
+                remote
+                    .refspecs
+                    .iter()
+                    .filter(|spec| !spec.is_negative())
+                    .filter_map(move |spec| {
+                        let local = spec.map(&mapping.remote.name)?;
+                        Some(Mapping {
+                            remote: mapping.remote,
+                            local: Some(local),
+                            force: spec.is_force() || options.force,
+                            fetch_head: false,
+                            for_merge: false,
+                        })
+                    })
+            })
+            .filter(|mapping| {
+                !remote
+                    .refspecs
+                    .iter()
+                    .any(|spec| spec.is_negative() && spec.matches(&mapping.remote.name))
+            })
+            .collect();
+        for mapping in configured {
+            if !mappings.iter().any(|m| m.local == mapping.local) {
+                mappings.push(mapping);
+            }
+        }
+    } else {
+        // The branch's configured upstream is the one to merge.
+        let current = refs.current_ref()?;
+        if let Some(branch) = current.strip_prefix("refs/heads/") {
+            let config = repo.config()?;
+            let variable = |key: &str| format!("branch.{}.{}", branch, key);
+            if config.get(&variable("remote")) == Some(remote.name.as_str()) {
+                let merge = config.get(&variable("merge"));
+                if let Some(mapping) = mappings
+                    .iter_mut()
+                    .find(|m| Some(m.remote.name.as_str()) == merge)
+                {
+                    mapping.for_merge = true;
+                }
+            }
+        }
+    }
+
+    // Tags are followed if they point at objects which are fetched, or
+    // which are already present.
+    let mut candidates = Vec::new();
+    if tags == TagMode::Follow {
+        for remote_ref in &remote_refs {
+            let name = &remote_ref.name;
+            if !name.starts_with("refs/tags/")
+                || mappings.iter().any(|m| m.remote.name == *name)
+                || refs.read_ref_exact(name)?.is_some()
+            {
+                continue;
+            }
+            let target = remote_ref.peeled.as_ref().unwrap_or(&remote_ref.oid);
+            if database.contains(target)? {
+                mappings.push(follow(remote_ref));
+            } else {
+                candidates.push(remote_ref);
+            }
+        }
+    }
+
+    let mut wants = Vec::new();
+    for mapping in &mappings {
+        let oid = &mapping.remote.oid;
+        if !wants.contains(oid) && !database.contains(oid)? {
+            wants.push(oid.clone());
+        }
+    }
+    let mut objects = 0;
+    if !wants.is_empty() {
+        let haves = history(repo)?;
+        let pack = connection.fetch(&wants, &haves, progress)?;
+        objects = database.store_pack(&pack)?.len();
+    }
+    connection.close()?;
+    for remote_ref in candidates {
+        if database.contains(&remote_ref.oid)? {
+            mappings.push(follow(remote_ref));
+        }
+    }
+
+    let mut updates = Vec::with_capacity(mappings.len());
+    let mut transaction = refs.transaction();
+    for mapping in &mappings {
+        let old = match &mapping.local {
+            Some(local) => refs.read_ref_exact(local)?,
+            None => None,
+        };
+        let update = ref_update(database, old, mapping)?;
+        if let (Some(local), Some(message)) = (&update.local, update.reflog_message()) {
+            // As in git, only branches are logged.
+            let message = format!("{}: {}", action, message);
+            let logged = !local.starts_with("refs/tags/");
+            transaction.update(
+                local,
+                update.old.as_ref(),
+                &update.new,
+                Some(message.as_str()).filter(|_| logged),
+            );
+        }
+        updates.push(update);
+    }
+    transaction.commit(identity)?;
+    write_fetch_head(repo, &remote.url, &mappings)?;
+    Ok(Fetched {
+        updates,
+        objects,
+        ignored,
+    })
+}
+
+// Maps a followed tag to the same name locally.
+fn follow(remote_ref: &RemoteRef) -> Mapping<'_> {
+    Mapping {
+        remote: remote_ref,
+        local: Some(remote_ref.name.clone()),
+        force: false,
+        fetch_head: true,
+        for_merge: false,
+    }
+}
+
+// Decides how the local reference of `mapping`, currently `old`, changes.
+fn ref_update(database: &Database, old: Option<ObjectID>, mapping: &Mapping) -> Result<RefUpdate> {
+    let new = mapping.remote.oid.clone();
+    let status = match (&mapping.local, &old) {
+        (None, _) => Status::FetchHead,
+        (Some(_), None) => Status::New,
+        (Some(_), Some(old)) if *old == new => Status::UpToDate,
+        (Some(local), Some(_)) if local.starts_with("refs/tags/") => {
+            if mapping.force {
+                Status::TagUpdate
+            } else {
+                Status::WouldClobberTag
+            }
+        }
+        (Some(_), Some(old)) => {
+            if revision::is_ancestor(database, old, &new)? {
+                Status::FastForward
+            } else if mapping.force {
+                Status::Forced
+            } else {
+                Status::NonFastForward
+            }
+        }
+    };
+    Ok(RefUpdate {
+        remote: mapping.remote.name.clone(),
+        local: mapping.local.clone(),
+        old: if mapping.local.is_some() { old } else { None },
+        new,
+        status,
+    })
+}
+
+// Returns the commits reachable from local references, most recently
+// committed first, to tell the remote what is already present.
+fn history(repo: &Repository) -> Result<Vec<ObjectID>> {
+    let database = repo.database();
+    let mut tips: Vec<ObjectID> = repo.refs().list_refs()?.into_values().collect();
+    tips.extend(repo.head()?);
+
+    let mut queue = BinaryHeap::new();
+    let mut seen = HashSet::new();
+    let mut push = |queue: &mut BinaryHeap<_>, oid: ObjectID| -> Result<()> {
+        if !seen.insert(oid.clone()) {
+            return Ok(());
+        }
+        // References to other objects (such as tags of trees) are skipped.
+        let oid = revision::peel(database, oid)?;
+        if let Ok(commit) = database.load_commit(&oid) {
+            let time = commit.committer().time().timestamp();
+            queue.push((time, oid, commit.parents().to_vec()));
+        }
+        Ok(())
+    };
+    for tip in tips {
+        push(&mut queue, tip)?;
+    }
+    let mut history = Vec::new();
+    while let Some((_, oid, parents)) = queue.pop() {
+        for parent in parents {
+            push(&mut queue, parent)?;
+        }
+        history.push(oid);
+    }
+    Ok(history)
+}
+
+// Records the fetched references in FETCH_HEAD, those to merge first, as
+// "{OID}\t[not-for-merge]\t{DESCRIPTION} of {URL}".
+fn write_fetch_head(repo: &Repository, url: &str, mappings: &[Mapping]) -> Result<()> {
+    let mut lock = LockFile::new(repo.git_path().join(FETCH_HEAD))?;
+    let recorded = mappings.iter().filter(|m| m.fetch_head);
+    let (merge, others): (Vec<&Mapping>, Vec<&Mapping>) = recorded.partition(|m| m.for_merge);
+    for mapping in merge.into_iter().chain(others) {
+        let name = &mapping.remote.name;
+        let description = match kind(name) {
+            _ if name == HEAD => url.to_string(),
+            "ref" => format!("'{}' of {}", name, url),
+            kind => format!("{} '{}' of {}", kind, short_name(name), url),
+        };
+        let marker = if mapping.for_merge {
+            ""
+        } else {
+            "not-for-merge"
+        };
+        writeln!(
+            lock.writer(),
+            "{}\t{}\t{}",
+            mapping.remote.oid.as_str(),
+            marker,
+            description
+        )?;
+    }
+    lock.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::oid;
+
+    fn update(remote: &str, local: Option<&str>, old: Option<char>, status: Status) -> RefUpdate {
+        RefUpdate {
+            remote: remote.to_string(),
+            local: local.map(|local| local.to_string()),
+            old: old.map(oid),
+            new: oid('b'),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let updates = vec![
+            update(
+                "refs/heads/main",
+                Some("refs/remotes/origin/main"),
+                Some('a'),
+                Status::FastForward,
+            ),
+            update(
+                "refs/heads/topic",
+                Some("refs/remotes/origin/topic"),
+                Some('a'),
+                Status::Forced,
+            ),
+            update(
+                "refs/heads/new",
+                Some("refs/remotes/origin/new"),
+                None,
+                Status::New,
+            ),
+            update(
+                "refs/tags/v1",
+                Some("refs/tags/v1"),
+                Some('a'),
+                Status::WouldClobberTag,
+            ),
+            update("refs/heads/main", None, None, Status::FetchHead),
+            update(
+                "refs/heads/a-very-long-name",
+                None,
+                Some('b'),
+                Status::UpToDate,
+            ),
+        ];
+        assert_eq!(summary_width(&updates), 10);
+        let lines: Vec<String> = updates[..5].iter().map(|u| u.summary(10)).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "   aaaaaaa..bbbbbbb  main       -> origin/main",
+                " + aaaaaaa...bbbbbbb topic      -> origin/topic  (forced update)",
+                " * [new branch]      new        -> origin/new",
+                " ! [rejected]        v1         -> v1  (would clobber existing tag)",
+                " * branch            main       -> FETCH_HEAD",
+            ]
+        );
+        assert!(updates[3].is_rejected());
+        assert!(!updates[5].is_shown());
+        assert_eq!(updates[2].reflog_message(), Some("storing head"));
+        assert_eq!(updates[3].reflog_message(), None);
+    }
+}
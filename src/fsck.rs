@@ -0,0 +1,783 @@
+//! Verification of the object database, as performed by `git fsck`.
+//!
+//! Every object, loose or packed, is re-hashed and its syntax checked, and
+//! each pack is checked against its index. Then the objects reachable from
+//! references, reflogs and the index are walked to find missing and
+//! dangling objects.
+
+use crate::database::{ObjectHasher, ObjectID};
+use crate::entry::Mode;
+use crate::error::Result;
+use crate::pack::{self, Pack};
+use crate::repository::Repository;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// Problems which are reported with "warning" rather than "error", as they
+// are tolerated by git.
+const WARNINGS: &[&str] = &["zeroPaddedFilemode", "missingTaggerEntry"];
+
+// The bits of git's exit status, each set by a kind of error.
+const ERROR_OBJECT: i32 = 0o1;
+const ERROR_REACHABLE: i32 = 0o2;
+const ERROR_PACK: i32 = 0o4;
+
+/// Something wrong with a repository, formatted as git does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// An object which could not be read, such as one which is not valid
+    /// zlib data.
+    Unreadable { oid: ObjectID, reason: String },
+    /// An object whose contents hash to `actual` rather than its ID,
+    /// found within the file at `path`.
+    HashMismatch {
+        oid: ObjectID,
+        actual: ObjectID,
+        path: PathBuf,
+    },
+    /// An object which is malformed. `id` names the check which failed,
+    /// such as "treeNotSorted".
+    BadObject {
+        oid: ObjectID,
+        kind: String,
+        id: &'static str,
+        message: &'static str,
+    },
+    /// An object which refers to another object which is absent (or has
+    /// the wrong type).
+    BrokenLink {
+        from_kind: String,
+        from: ObjectID,
+        to_kind: String,
+        to: ObjectID,
+    },
+    /// A pack which cannot be read, or which does not match its checksum
+    /// or index.
+    BadPack { message: String },
+    /// A reachable object which is absent.
+    Missing { kind: String, oid: ObjectID },
+    /// A reference or reflog entry naming an absent (or unreadable)
+    /// object.
+    InvalidPointer { name: String, oid: ObjectID },
+    /// An unreachable object which no other object refers to.
+    Dangling { kind: String, oid: ObjectID },
+}
+
+impl Problem {
+    /// Returns true if this problem means the repository is broken, rather
+    /// than merely untidy.
+    pub fn is_error(&self) -> bool {
+        match self {
+            Problem::BadObject { id, .. } => !WARNINGS.contains(id),
+            Problem::Dangling { .. } => false,
+            _ => true,
+        }
+    }
+
+    // Returns the bit this problem sets within the exit status, if any.
+    fn exit_bit(&self) -> i32 {
+        match self {
+            _ if !self.is_error() => 0,
+            Problem::Unreadable { .. }
+            | Problem::HashMismatch { .. }
+            | Problem::BadObject { .. } => ERROR_OBJECT,
+            Problem::BadPack { .. } => ERROR_PACK,
+            _ => ERROR_REACHABLE,
+        }
+    }
+}
+
+/// Returns the exit status of git's fsck after finding `problems`: 0 if
+/// none are errors, or else a bit for each kind of error found.
+pub fn exit_code(problems: &[Problem]) -> i32 {
+    problems
+        .iter()
+        .fold(0, |code, problem| code | problem.exit_bit())
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Unreadable { oid, reason } => write!(
+                f,
+                "error: {}: object corrupt or missing: {}",
+                oid.as_str(),
+                reason
+            ),
+            Problem::HashMismatch { actual, path, .. } => write!(
+                f,
+                "error: {}: hash-path mismatch, found at: {}",
+                actual.as_str(),
+                path.display()
+            ),
+            Problem::BadObject {
+                oid,
+                kind,
+                id,
+                message,
+            } => {
+                let level = if self.is_error() { "error" } else { "warning" };
+                write!(
+                    f,
+                    "{} in {} {}: {}: {}",
+                    level,
+                    kind,
+                    oid.as_str(),
+                    id,
+                    message
+                )
+            }
+            Problem::BrokenLink {
+                from_kind,
+                from,
+                to_kind,
+                to,
+            } => write!(
+                f,
+                "broken link from {:>7} {}\n              to {:>7} {}",
+                from_kind,
+                from.as_str(),
+                to_kind,
+                to.as_str()
+            ),
+            Problem::BadPack { message } => write!(f, "error: {}", message),
+            Problem::Missing { kind, oid } => write!(f, "missing {} {}", kind, oid.as_str()),
+            Problem::InvalidPointer { name, oid } => {
+                write!(f, "error: {}: invalid sha1 pointer {}", name, oid.as_str())
+            }
+            Problem::Dangling { kind, oid } => write!(f, "dangling {} {}", kind, oid.as_str()),
+        }
+    }
+}
+
+// An object which was read successfully, along with the objects it refers
+// to (and their expected types).
+struct Checked {
+    kind: String,
+    links: Vec<(&'static str, ObjectID)>,
+}
+
+/// Checks every object within the repository, returning the problems
+/// found in the order git reports them: problems with individual objects,
+/// then with the connections between them.
+pub fn check(repo: &Repository) -> Result<Vec<Problem>> {
+    let database = repo.database();
+    let mut problems = Vec::new();
+
+    let mut oids = database
+        .backend()
+        .iter()?
+        .collect::<Result<Vec<ObjectID>>>()?;
+    oids.sort();
+    // Like git, loose objects are named by their path from the workspace.
+    let objects_path = repo
+        .git_path()
+        .strip_prefix(repo.root_path())
+        .unwrap_or_else(|_| repo.git_path())
+        .join("objects");
+    let mut objects = BTreeMap::new();
+    let mut corrupt = BTreeSet::new();
+    for oid in oids {
+        let (kind, data) = match database.backend().read(&oid) {
+            Ok(object) => object,
+            Err(err) => {
+                corrupt.insert(oid.clone());
+                problems.push(Problem::Unreadable {
+                    reason: err.to_string(),
+                    oid,
+                });
+                continue;
+            }
+        };
+        let mut hasher = ObjectHasher::new(&kind, data.len() as u64);
+        hasher.update(&data);
+        let actual = hasher.finish();
+        if actual != oid {
+            let hex = oid.as_str();
+            let path = objects_path.join(&hex[..2]).join(&hex[2..]);
+            corrupt.insert(oid.clone());
+            problems.push(Problem::HashMismatch { oid, actual, path });
+            continue;
+        }
+
+        let mut links = Vec::new();
+        let result = match kind.as_str() {
+            "blob" => Ok(()),
+            "tree" => check_tree(&data, &mut links),
+            "commit" => check_commit(&data, &mut links),
+            "tag" => check_tag(&data, &mut links),
+            _ => Err(("badType", "unknown object type")),
+        };
+        for (id, message) in result.err().into_iter().chain(tree_warnings(&kind, &data)) {
+            problems.push(Problem::BadObject {
+                oid: oid.clone(),
+                kind: kind.clone(),
+                id,
+                message,
+            });
+        }
+        objects.insert(oid, Checked { kind, links });
+    }
+
+    // Packed objects were read through the store above, but each pack is
+    // also checked against its index.
+    for path in pack::list(&repo.git_path().join("objects").join("pack"))? {
+        match Pack::open(&path) {
+            Ok(pack) => problems.extend(
+                pack.verify()
+                    .into_iter()
+                    .map(|message| Problem::BadPack { message }),
+            ),
+            Err(err) => problems.push(Problem::BadPack {
+                message: format!("{}: {}", path.display(), err),
+            }),
+        }
+    }
+
+    // Walk everything reachable from the roots, noting absent objects. As
+    // in git, the blobs of index entries are only walked, so are reported
+    // as missing rather than as invalid pointers.
+    let mut reachable = BTreeSet::new();
+    let mut pending = Vec::new();
+    let mut missing = BTreeMap::new();
+    for (name, oid) in repo.referenced_objects()? {
+        if objects.contains_key(&oid) {
+            pending.push(oid);
+        } else {
+            problems.push(Problem::InvalidPointer { name, oid });
+        }
+    }
+    for entry in repo.load_index()?.entries() {
+        if *entry.mode() == Mode::Gitlink {
+            continue;
+        }
+        if objects.contains_key(entry.oid()) {
+            pending.push(entry.oid().clone());
+        } else {
+            missing.insert(entry.oid().clone(), "blob".to_string());
+        }
+    }
+    while let Some(oid) = pending.pop() {
+        if !reachable.insert(oid.clone()) {
+            continue;
+        }
+        let from = &objects[&oid];
+        for (to_kind, to) in &from.links {
+            match objects.get(to) {
+                Some(object) if object.kind == *to_kind => pending.push(to.clone()),
+                _ => {
+                    // An object which is present but corrupt has already
+                    // been reported, so is only missing.
+                    if !corrupt.contains(to) {
+                        problems.push(Problem::BrokenLink {
+                            from_kind: from.kind.clone(),
+                            from: oid.clone(),
+                            to_kind: to_kind.to_string(),
+                            to: to.clone(),
+                        });
+                    }
+                    if !objects.contains_key(to) {
+                        missing.insert(to.clone(), to_kind.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    // Unreachable objects are only dangling if nothing else unreachable
+    // refers to them, so that each orphaned history is reported once.
+    let unreachable: Vec<_> = objects
+        .keys()
+        .filter(|oid| !reachable.contains(*oid))
+        .collect();
+    let referenced: BTreeSet<_> = unreachable
+        .iter()
+        .flat_map(|oid| objects[*oid].links.iter().map(|(_, to)| to))
+        .collect();
+    for oid in unreachable {
+        if !referenced.contains(oid) {
+            problems.push(Problem::Dangling {
+                kind: objects[oid].kind.clone(),
+                oid: oid.clone(),
+            });
+        }
+    }
+    for (oid, kind) in missing {
+        problems.push(Problem::Missing { kind, oid });
+    }
+    Ok(problems)
+}
+
+// The result of a syntax check: the ID and description of the first
+// problem found.
+type Check = std::result::Result<(), (&'static str, &'static str)>;
+
+// Checks the entries of a tree, which are each "{MODE} {NAME}\0{ID}" with
+// a 20-byte binary ID, collecting the objects they refer to.
+fn check_tree(data: &[u8], links: &mut Vec<(&'static str, ObjectID)>) -> Check {
+    let bad = ("badTree", "could not be parsed");
+    let mut previous: Option<(Vec<u8>, bool)> = None;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|b| *b == b' ').ok_or(bad)?;
+        let nul = rest.iter().position(|b| *b == b'\0').ok_or(bad)?;
+        if nul < space || rest.len() < nul + 21 {
+            return Err(bad);
+        }
+        let mode = std::str::from_utf8(&rest[..space]).map_err(|_| bad)?;
+        let name = &rest[space + 1..nul];
+        let oid = ObjectID::from_bytes(&rest[nul + 1..nul + 21]).map_err(|_| bad)?;
+        rest = &rest[nul + 21..];
+
+        let mode = u32::from_str_radix(mode, 8).map_err(|_| bad)?;
+        let (kind, is_tree) = match mode {
+            0o100644 | 0o100755 | 0o120000 => (Some("blob"), false),
+            0o40000 => (Some("tree"), true),
+            0o160000 => (None, false),
+            // Written by old versions of git.
+            0o100664 => (Some("blob"), false),
+            _ => return Err(("badFilemode", "contains bad file modes")),
+        };
+        match name {
+            b"" => return Err(("emptyName", "contains empty pathname")),
+            b"." => return Err(("hasDot", "contains '.'")),
+            b".." => return Err(("hasDotdot", "contains '..'")),
+            _ if name.eq_ignore_ascii_case(b".git") => {
+                return Err(("hasDotgit", "contains '.git'"))
+            }
+            _ if name.contains(&b'/') => return Err(("fullPathname", "contains full pathnames")),
+            _ => (),
+        }
+
+        if let Some((previous_name, previous_is_tree)) = &previous {
+            if previous_name.as_slice() == name {
+                return Err(("duplicateEntries", "contains duplicate file entries"));
+            }
+            if tree_order(previous_name, *previous_is_tree, name, is_tree).is_gt() {
+                return Err(("treeNotSorted", "not properly sorted"));
+            }
+        }
+        previous = Some((name.to_vec(), is_tree));
+        links.extend(kind.map(|kind| (kind, oid)));
+    }
+    Ok(())
+}
+
+// Reports modes written with a leading zero, which git tolerates.
+fn tree_warnings(kind: &str, data: &[u8]) -> Option<(&'static str, &'static str)> {
+    if kind != "tree" {
+        return None;
+    }
+    let mut rest = data;
+    while let Some(nul) = rest.iter().position(|b| *b == b'\0') {
+        if rest.first() == Some(&b'0') {
+            return Some(("zeroPaddedFilemode", "contains zero-padded file modes"));
+        }
+        rest = rest.get(nul + 21..)?;
+    }
+    None
+}
+
+// Compares tree entry names as git sorts them: as though the names of
+// trees ended with "/".
+fn tree_order(a: &[u8], a_is_tree: bool, b: &[u8], b_is_tree: bool) -> std::cmp::Ordering {
+    let suffixed = |name: &[u8], is_tree| {
+        let mut name = name.to_vec();
+        if is_tree {
+            name.push(b'/');
+        }
+        name
+    };
+    suffixed(a, a_is_tree).cmp(&suffixed(b, b_is_tree))
+}
+
+// Checks the headers of a commit: a tree, any number of parents, an author
+// and a committer, in that order.
+fn check_commit(data: &[u8], links: &mut Vec<(&'static str, ObjectID)>) -> Check {
+    let mut lines = headers(data);
+    let tree = lines
+        .next()
+        .and_then(|line| line.strip_prefix("tree "))
+        .ok_or(("missingTree", "invalid format - expected 'tree' line"))?;
+    links.push((
+        "tree",
+        parse_oid(tree, "badTreeSha1", "invalid 'tree' line format - bad sha1")?,
+    ));
+
+    let mut line = lines.next();
+    while let Some(parent) = line.and_then(|line| line.strip_prefix("parent ")) {
+        links.push((
+            "commit",
+            parse_oid(
+                parent,
+                "badParentSha1",
+                "invalid 'parent' line format - bad sha1",
+            )?,
+        ));
+        line = lines.next();
+    }
+    let author = line
+        .and_then(|line| line.strip_prefix("author "))
+        .ok_or(("missingAuthor", "invalid format - expected 'author' line"))?;
+    check_ident(author)?;
+    let committer = lines
+        .next()
+        .and_then(|line| line.strip_prefix("committer "))
+        .ok_or((
+            "missingCommitter",
+            "invalid format - expected 'committer' line",
+        ))?;
+    check_ident(committer)
+}
+
+// Checks the headers of an annotated tag: the tagged object, its type, the
+// tag's name and (optionally) the tagger.
+fn check_tag(data: &[u8], links: &mut Vec<(&'static str, ObjectID)>) -> Check {
+    let mut lines = headers(data);
+    let object = lines
+        .next()
+        .and_then(|line| line.strip_prefix("object "))
+        .ok_or(("missingObject", "invalid format - expected 'object' line"))?;
+    let object = parse_oid(
+        object,
+        "badObjectSha1",
+        "invalid 'object' line format - bad sha1",
+    )?;
+    let kind = lines
+        .next()
+        .and_then(|line| line.strip_prefix("type "))
+        .ok_or(("missingTypeEntry", "invalid format - expected 'type' line"))?;
+    let kind = match kind {
+        "blob" => "blob",
+        "tree" => "tree",
+        "commit" => "commit",
+        "tag" => "tag",
+        _ => return Err(("badType", "invalid 'type' value")),
+    };
+    links.push((kind, object));
+    lines
+        .next()
+        .and_then(|line| line.strip_prefix("tag "))
+        .ok_or(("missingTagEntry", "invalid format - expected 'tag' line"))?;
+    match lines.next().and_then(|line| line.strip_prefix("tagger ")) {
+        Some(tagger) => check_ident(tagger),
+        None => Err((
+            "missingTaggerEntry",
+            "invalid format - expected 'tagger' line",
+        )),
+    }
+}
+
+// Returns the header lines of a commit or tag, which end at the first
+// blank line.
+fn headers(data: &[u8]) -> impl Iterator<Item = &str> {
+    let end = data
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map(|n| n + 1)
+        .unwrap_or(data.len());
+    std::str::from_utf8(&data[..end])
+        .unwrap_or("")
+        .lines()
+        .take_while(|line| !line.is_empty())
+}
+
+fn parse_oid(
+    hex: &str,
+    id: &'static str,
+    message: &'static str,
+) -> std::result::Result<ObjectID, (&'static str, &'static str)> {
+    // Only lowercase IDs are accepted, as git writes them.
+    if hex.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Err((id, message));
+    }
+    ObjectID::from_str(hex).map_err(|_| (id, message))
+}
+
+// Checks an identity, as written by `Author::to_str`:
+// "{NAME} <{EMAIL}> {SECONDS} {OFFSET}". The name may be empty.
+fn check_ident(ident: &str) -> Check {
+    let bad_email = ("badEmail", "invalid author/committer line - bad email");
+    let (name, rest) = ident.split_once('<').ok_or((
+        "missingEmail",
+        "invalid author/committer line - missing email",
+    ))?;
+    if name.is_empty() {
+        return Err((
+            "missingNameBeforeEmail",
+            "invalid author/committer line - missing space before email",
+        ));
+    }
+    if !name.ends_with(' ') {
+        return Err((
+            "missingSpaceBeforeEmail",
+            "invalid author/committer line - missing space before email",
+        ));
+    }
+    if name.contains('>') {
+        return Err(("badName", "invalid author/committer line - bad name"));
+    }
+    let (email, rest) = rest.split_once('>').ok_or(bad_email)?;
+    if email.contains('<') {
+        return Err(bad_email);
+    }
+    let rest = rest.strip_prefix(' ').ok_or((
+        "missingSpaceBeforeDate",
+        "invalid author/committer line - missing space before date",
+    ))?;
+
+    let bad_date = ("badDate", "invalid author/committer line - bad date");
+    let (seconds, offset) = rest.split_once(' ').ok_or(bad_date)?;
+    if seconds.is_empty() || !seconds.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad_date);
+    }
+    if seconds.len() > 1 && seconds.starts_with('0') {
+        return Err((
+            "zeroPaddedDate",
+            "invalid author/committer line - zero-padded date",
+        ));
+    }
+    if seconds.parse::<u64>().is_err() {
+        return Err((
+            "badDateOverflow",
+            "invalid author/committer line - date causes integer overflow",
+        ));
+    }
+    let offset = offset.as_bytes();
+    let valid_offset = offset.len() == 5
+        && (offset[0] == b'+' || offset[0] == b'-')
+        && offset[1..].iter().all(|b| b.is_ascii_digit());
+    if !valid_offset {
+        return Err((
+            "badTimezone",
+            "invalid author/committer line - bad time zone",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Author;
+    use crate::commit::Commit;
+    use crate::database::{Blob, Storable};
+    use crate::entry::Entry;
+    use crate::tree::Tree;
+    use crate::workspace::WorkspacePath;
+    use anyhow::Result;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn author() -> Author {
+        Author::new(
+            "Sean".to_string(),
+            "sean@zombo.com".to_string(),
+            chrono::DateTime::parse_from_rfc3339("2021-01-01T01:01:01+00:00").unwrap(),
+        )
+    }
+
+    // Writes an object without validating it, returning its ID.
+    fn write_raw(repo: &Repository, kind: &str, data: &[u8]) -> Result<ObjectID> {
+        let mut hasher = ObjectHasher::new(kind, data.len() as u64);
+        hasher.update(data);
+        let oid = hasher.finish();
+        repo.database().backend().write(&oid, kind, data)?;
+        Ok(oid)
+    }
+
+    #[test]
+    fn test_clean() -> Result<()> {
+        let dir = TempDir::new("test_fsck_clean")?;
+        let repo = Repository::init(dir.path())?;
+        let blob = Blob::new(b"hello\n".to_vec());
+        repo.database().store(&blob)?;
+        let tree = repo.write_tree(vec![Entry::new(
+            WorkspacePath::new("dir/hello.txt")?,
+            blob.oid(),
+            Mode::ReadWrite,
+        )])?;
+        let commit = repo.create_commit(vec![], tree.oid(), author(), author(), "m\n".into())?;
+        repo.refs()
+            .update_head(&commit.oid(), &author(), "commit")?;
+        assert_eq!(check(&repo)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_packed() -> Result<()> {
+        let dir = TempDir::new("test_fsck_packed")?;
+        let repo = Repository::init(dir.path())?;
+        let blob = Blob::new(b"hello\n".to_vec());
+        repo.database().store(&blob)?;
+        let tree = repo.write_tree(vec![Entry::new(
+            WorkspacePath::new("hello.txt")?,
+            blob.oid(),
+            Mode::ReadWrite,
+        )])?;
+        let commit = repo.create_commit(vec![], tree.oid(), author(), author(), "m\n".into())?;
+        repo.refs()
+            .update_head(&commit.oid(), &author(), "commit")?;
+        let status = std::process::Command::new("git")
+            .args(["gc", "-q"])
+            .current_dir(dir.path())
+            .status()?;
+        assert!(status.success());
+
+        // Only the packs hold the objects now.
+        let objects = dir.path().join(".git/objects");
+        assert!(!objects.join(&commit.oid().as_str()[..2]).exists());
+        let repo = Repository::open(dir.path())?;
+        assert_eq!(check(&repo)?, vec![]);
+
+        let path = pack::list(&objects.join("pack"))?.remove(0);
+        let mut data = std::fs::read(&path)?;
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+        std::fs::write(&path, data)?;
+        let repo = Repository::open(dir.path())?;
+        let found = check(&repo)?;
+        assert_ne!(exit_code(&found) & ERROR_PACK, 0);
+        let problems: Vec<String> = found.iter().map(|p| p.to_string()).collect();
+        let name = path.display();
+        assert!(problems.contains(&format!("error: {} SHA1 checksum mismatch", name)));
+        assert!(problems
+            .iter()
+            .any(|problem| problem.starts_with("error: index CRC mismatch for object")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_problems() -> Result<()> {
+        let dir = TempDir::new("test_fsck_problems")?;
+        let repo = Repository::init(dir.path())?;
+        let database = repo.database();
+
+        // A commit whose tree refers to a missing blob.
+        let absent = Blob::new(b"absent".to_vec()).oid();
+        let tree = Tree::new(vec![Entry::new(
+            WorkspacePath::new("file")?,
+            absent.clone(),
+            Mode::ReadWrite,
+        )]);
+        database.store(&tree)?;
+        let commit = Commit::new(vec![], tree.oid(), author(), author(), "m\n".into());
+        database.store(&commit)?;
+        repo.refs()
+            .update_head(&commit.oid(), &author(), "commit")?;
+
+        let dangling = Blob::new(b"dangling".to_vec());
+        database.store(&dangling)?;
+
+        // Unsorted, and zero-padded.
+        let mut unsorted = b"100644 b\0".to_vec();
+        unsorted.extend(dangling.oid().as_bytes());
+        unsorted.extend(b"100644 a\0");
+        unsorted.extend(dangling.oid().as_bytes());
+        let unsorted = write_raw(&repo, "tree", &unsorted)?;
+        let mut padded = b"0100644 a\0".to_vec();
+        padded.extend(dangling.oid().as_bytes());
+        let padded = write_raw(&repo, "tree", &padded)?;
+
+        let bad_author = format!(
+            "tree {}\nauthor a <a@b 1 +0000\ncommitter a <a@b> 1 +0000\n\nm\n",
+            tree.oid().as_str()
+        );
+        let bad_author = write_raw(&repo, "commit", bad_author.as_bytes())?;
+
+        // Replace the contents of an object with those of another.
+        let mismatched = Blob::new(b"original".to_vec());
+        database.store(&mismatched)?;
+        let path = dir
+            .path()
+            .join(".git/objects")
+            .join(&mismatched.oid().as_str()[..2])
+            .join(&mismatched.oid().as_str()[2..]);
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(b"blob 5\0other")?;
+        std::fs::write(&path, encoder.finish()?)?;
+
+        let found = check(&repo)?;
+        assert_eq!(exit_code(&found), ERROR_OBJECT | ERROR_REACHABLE);
+        let problems: Vec<String> = found.iter().map(|p| p.to_string()).collect();
+        let mut expected = vec![
+            format!(
+                "error: {}: hash-path mismatch, found at: .git/objects/{}/{}",
+                Blob::new(b"other".to_vec()).oid().as_str(),
+                &mismatched.oid().as_str()[..2],
+                &mismatched.oid().as_str()[2..]
+            ),
+            format!(
+                "error in tree {}: treeNotSorted: not properly sorted",
+                unsorted.as_str()
+            ),
+            format!(
+                "warning in tree {}: zeroPaddedFilemode: contains zero-padded file modes",
+                padded.as_str()
+            ),
+            format!(
+                "error in commit {}: badEmail: invalid author/committer line - bad email",
+                bad_author.as_str()
+            ),
+        ];
+        // Object problems come first, in ID order.
+        let mut object_problems = problems[..4].to_vec();
+        object_problems.sort();
+        expected.sort();
+        assert_eq!(object_problems, expected);
+        assert_eq!(
+            problems[4],
+            format!(
+                "broken link from    tree {}\n              to    blob {}",
+                tree.oid().as_str(),
+                absent.as_str()
+            )
+        );
+        let mut dangling_lines: Vec<String> = problems[5..problems.len() - 1].to_vec();
+        dangling_lines.sort();
+        let mut expected_dangling = vec![
+            format!("dangling commit {}", bad_author.as_str()),
+            format!("dangling tree {}", unsorted.as_str()),
+            format!("dangling tree {}", padded.as_str()),
+        ];
+        expected_dangling.sort();
+        assert_eq!(dangling_lines, expected_dangling);
+        assert_eq!(
+            problems.last().unwrap(),
+            &format!("missing blob {}", absent.as_str())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_ident() {
+        assert_eq!(
+            check_ident("Sean <sean@zombo.com> 1609462861 +0000"),
+            Ok(())
+        );
+        assert_eq!(check_ident(" <> 0 +0000"), Ok(()));
+        assert_eq!(
+            check_ident("Sean sean@zombo.com> 1 +0000").unwrap_err().0,
+            "missingEmail"
+        );
+        assert_eq!(
+            check_ident("Sean<a@b> 1 +0000").unwrap_err().0,
+            "missingSpaceBeforeEmail"
+        );
+        assert_eq!(
+            check_ident("Sean <a@b>1 +0000").unwrap_err().0,
+            "missingSpaceBeforeDate"
+        );
+        assert_eq!(
+            check_ident("Sean <a@b> 01 +0000").unwrap_err().0,
+            "zeroPaddedDate"
+        );
+        assert_eq!(check_ident("Sean <a@b> x +0000").unwrap_err().0, "badDate");
+        assert_eq!(
+            check_ident("Sean <a@b> 1 0000").unwrap_err().0,
+            "badTimezone"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Removal of unreachable objects, as performed by `git prune` and
+//! `git gc`.
+
+use crate::database::{Database, Object, ObjectID};
+use crate::entry::Mode;
+use crate::error::{Error, Result};
+use crate::repository::Repository;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+
+/// How old unreachable objects must be before they are pruned, unless
+/// gc.pruneExpire says otherwise. Matches git's default.
+pub const DEFAULT_PRUNE_EXPIRE: &str = "2.weeks.ago";
+
+/// Parses an expiry date such as "2.weeks.ago", "now", "never" or
+/// "2021-01-01T00:00:00+00:00", relative to `now`.
+///
+/// Returns the time before which objects may be pruned, or None if they
+/// never may be.
+pub fn parse_expire(value: &str, now: SystemTime) -> Result<Option<SystemTime>> {
+    let invalid = || Error::Fatal(format!("malformed expiration date '{}'", value));
+    match value {
+        "never" | "false" => return Ok(None),
+        "now" | "all" => return Ok(Some(now)),
+        _ => (),
+    }
+    if let Ok(date) = chrono::DateTime::parse_from_rfc3339(value) {
+        let seconds = u64::try_from(date.timestamp()).map_err(|_| invalid())?;
+        return Ok(Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)));
+    }
+
+    // Relative dates are "{N}.{UNIT}.ago", with "." or " " between words.
+    let words: Vec<&str> = value.split(['.', ' ']).collect();
+    let (count, unit) = match words.as_slice() {
+        [count, unit, "ago"] => (count, unit),
+        _ => return Err(invalid()),
+    };
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    let seconds = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let age = count.checked_mul(seconds).ok_or_else(invalid)?;
+    // Dates before the epoch are clamped to it.
+    let since_epoch = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok(Some(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(since_epoch.saturating_sub(age)),
+    ))
+}
+
+/// Returns the expiry date configured by gc.pruneExpire.
+pub fn prune_expire(repo: &Repository, now: SystemTime) -> Result<Option<SystemTime>> {
+    let config = repo.config()?;
+    let value = config.get("gc.pruneExpire").unwrap_or(DEFAULT_PRUNE_EXPIRE);
+    parse_expire(value, now)
+}
+
+/// Returns every object reachable from `roots`, following commits to their
+/// trees and parents, trees to their entries and tags to their targets.
+///
+/// Blobs are not read, so are assumed to exist. Fails if any other object
+/// is missing.
+pub fn reachable(
+    database: &Database,
+    roots: impl IntoIterator<Item = ObjectID>,
+) -> Result<BTreeSet<ObjectID>> {
+    let mut reachable = BTreeSet::new();
+    let mut pending: Vec<ObjectID> = roots.into_iter().collect();
+    while let Some(oid) = pending.pop() {
+        if !reachable.insert(oid.clone()) {
+            continue;
+        }
+        match database.load(&oid)? {
+            Object::Blob(_) => (),
+            Object::Tree(tree) => {
+                for entry in tree.entries() {
+                    match entry.mode() {
+                        Mode::Directory => pending.push(entry.oid().clone()),
+                        // Submodule commits live in another repository.
+                        Mode::Gitlink => (),
+                        _ => {
+                            reachable.insert(entry.oid().clone());
+                        }
+                    }
+                }
+            }
+            Object::Commit(commit) => {
+                pending.push(commit.tree().clone());
+                pending.extend(commit.parents().iter().cloned());
+            }
+            Object::Tag(tag) => pending.push(tag.object().clone()),
+        }
+    }
+    Ok(reachable)
+}
+
+/// What `prune` removed (or would remove).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Pruned {
+    /// The unreachable objects, with their types, sorted by ID.
+    pub objects: Vec<(ObjectID, String)>,
+    /// The number of stale temporary files.
+    pub temporaries: usize,
+}
+
+/// Removes objects which are unreachable from the references, reflogs and
+/// index, and which were written before `expire`. Stale temporary files
+/// left behind by interrupted writes are also removed.
+///
+/// With `expire` of None, nothing is removed. With `dry_run`, nothing is
+/// removed but the objects which would be are still returned.
+pub fn prune(repo: &Repository, expire: Option<SystemTime>, dry_run: bool) -> Result<Pruned> {
+    let expire = match expire {
+        Some(expire) => expire,
+        None => return Ok(Pruned::default()),
+    };
+    let database = repo.database();
+    let store = database.backend();
+
+    let roots = repo.referenced_objects()?.into_iter().map(|(_, oid)| oid);
+    let mut keep = reachable(database, roots)?;
+    keep.extend(
+        repo.load_index()?
+            .entries()
+            .map(|entry| entry.oid().clone()),
+    );
+
+    let mut pruned = Pruned::default();
+    let mut oids = store.iter()?.collect::<Result<Vec<_>>>()?;
+    oids.sort();
+    for oid in oids {
+        // Packs are kept whole.
+        if keep.contains(&oid) || store.is_packed(&oid)? {
+            continue;
+        }
+        // Stores which do not record times have no recent objects.
+        if let Some(modified) = store.modified(&oid)? {
+            if modified >= expire {
+                continue;
+            }
+        }
+        let (type_name, _) = database.read_raw(&oid)?;
+        if !dry_run {
+            store.remove(&oid)?;
+            tracing::debug!(oid = %oid.as_str(), kind = %type_name, "prune");
+        }
+        pruned.objects.push((oid, type_name));
+    }
+    if !dry_run {
+        pruned.temporaries = store.remove_stale(expire)?;
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Author;
+    use crate::database::{Blob, Storable};
+    use crate::entry::Entry;
+    use crate::workspace::WorkspacePath;
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    fn author() -> Author {
+        Author::new(
+            "Sean".to_string(),
+            "sean@zombo.com".to_string(),
+            chrono::DateTime::parse_from_rfc3339("2021-01-01T01:01:01+00:00").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_parse_expire() -> Result<()> {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(30 * 24 * 60 * 60);
+        let days_ago = |days: u64| Some(now - Duration::from_secs(days * 24 * 60 * 60));
+        assert_eq!(parse_expire("2.weeks.ago", now)?, days_ago(14));
+        assert_eq!(parse_expire("1 day ago", now)?, days_ago(1));
+        assert_eq!(
+            parse_expire("3.hours.ago", now)?,
+            Some(now - Duration::from_secs(3 * 3600))
+        );
+        assert_eq!(parse_expire("now", now)?, Some(now));
+        assert_eq!(parse_expire("never", now)?, None);
+        assert_eq!(
+            parse_expire("5.years.ago", now)?,
+            Some(SystemTime::UNIX_EPOCH)
+        );
+        assert_eq!(
+            parse_expire("1970-01-02T00:00:00+00:00", now)?,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(24 * 60 * 60))
+        );
+        assert!(parse_expire("2.fortnights.ago", now).is_err());
+        assert!(parse_expire("soon", now).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune() -> Result<()> {
+        let dir = TempDir::new("test_prune")?;
+        let repo = Repository::init(dir.path())?;
+        let database = repo.database();
+
+        let kept = Blob::new(b"kept".to_vec());
+        database.store(&kept)?;
+        let tree = repo.write_tree(vec![Entry::new(
+            WorkspacePath::new("kept.txt")?,
+            kept.oid(),
+            Mode::ReadWrite,
+        )])?;
+        let first = repo.create_commit(vec![], tree.oid(), author(), author(), "1\n".into())?;
+        repo.refs().update_head(&first.oid(), &author(), "commit")?;
+
+        // A commit only referenced by the reflog, once the branch moves
+        // back, is kept.
+        let second = repo.create_commit(
+            vec![first.oid()],
+            tree.oid(),
+            author(),
+            author(),
+            "2\n".into(),
+        )?;
+        repo.refs()
+            .update_head(&second.oid(), &author(), "commit")?;
+        repo.refs().update_ref("refs/heads/master", &first.oid())?;
+
+        let unreachable = Blob::new(b"unreachable".to_vec());
+        database.store(&unreachable)?;
+        std::fs::write(dir.path().join(".git/objects/tmp_obj_1_0"), "partial")?;
+
+        // Nothing is old enough yet.
+        let past = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!(prune(&repo, Some(past), false)?, Pruned::default());
+        assert_eq!(prune(&repo, None, false)?, Pruned::default());
+
+        let future = SystemTime::now() + Duration::from_secs(60);
+        let expected = vec![(unreachable.oid(), "blob".to_string())];
+        assert_eq!(prune(&repo, Some(future), true)?.objects, expected);
+        assert!(database.contains(&unreachable.oid())?);
+        assert_eq!(
+            prune(&repo, Some(future), false)?,
+            Pruned {
+                objects: expected,
+                temporaries: 1,
+            }
+        );
+        assert!(!database.contains(&unreachable.oid())?);
+        for oid in [kept.oid(), tree.oid(), first.oid(), second.oid()].iter() {
+            assert!(database.contains(oid)?);
+        }
+        Ok(())
+    }
+}
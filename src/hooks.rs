@@ -0,0 +1,145 @@
+//! Client-side hooks: programs within ".git/hooks" (or "core.hooksPath")
+//! which commands run at certain points, and which may refuse to let them
+//! continue.
+//!
+//! As in git, a hook runs from the root of the workspace with its standard
+//! output sent to standard error, and a hook which is missing or not
+//! executable is skipped.
+
+use crate::error::Result;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Runs before a commit is created, and may refuse it.
+pub const PRE_COMMIT: &str = "pre-commit";
+/// Runs with the file holding the initial commit message, and its source.
+pub const PREPARE_COMMIT_MSG: &str = "prepare-commit-msg";
+/// Runs with the file holding the final commit message, and may edit it
+/// or refuse the commit.
+pub const COMMIT_MSG: &str = "commit-msg";
+/// Runs once a commit has been created.
+pub const POST_COMMIT: &str = "post-commit";
+/// Runs before a rebase starts, with its upstream, and may refuse it.
+pub const PRE_REBASE: &str = "pre-rebase";
+/// Runs once HEAD has moved and the workspace has been updated, with the
+/// previous HEAD, the new HEAD, and "1" for a change of branch.
+pub const POST_CHECKOUT: &str = "post-checkout";
+/// Runs before a push, with the remote's name and URL, reading a line for
+/// each reference to update. It may refuse the push.
+pub const PRE_PUSH: &str = "pre-push";
+
+/// The hooks of a repository.
+#[derive(Clone, Debug)]
+pub struct Hooks {
+    dir: PathBuf,
+    work_dir: PathBuf,
+    env: Vec<(String, String)>,
+}
+
+impl Hooks {
+    /// Finds hooks within `dir`, to be run from `work_dir`.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, work_dir: Q) -> Self {
+        Hooks {
+            dir: dir.as_ref().to_path_buf(),
+            work_dir: work_dir.as_ref().to_path_buf(),
+            env: Vec::new(),
+        }
+    }
+
+    /// Sets an environment variable for every hook, such as GIT_INDEX_FILE.
+    pub fn with_env(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Returns the path of the hook `name`, if it exists and is executable.
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        let path = self.dir.join(name);
+        let metadata = std::fs::metadata(&path).ok()?;
+        if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Runs the hook `name` with `args`, writing `input` to its standard
+    /// input. Returns false if the hook failed, and true if it succeeded or
+    /// does not exist.
+    pub fn run(&self, name: &str, args: &[&str], input: &[u8]) -> Result<bool> {
+        let path = match self.find(name) {
+            Some(path) => path,
+            None => return Ok(true),
+        };
+        let _span = tracing::info_span!("hook", name).entered();
+        let mut child = Command::new(&path)
+            .args(args)
+            .current_dir(&self.work_dir)
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .stdin(if input.is_empty() {
+                Stdio::null()
+            } else {
+                Stdio::piped()
+            })
+            .stdout(Stdio::from(std::io::stderr()))
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A hook need not read all of its input.
+            match stdin.write_all(input) {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(child.wait()?.success())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    fn write_hook(dir: &Path, name: &str, script: &str) -> Result<()> {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}", script))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_run() -> Result<()> {
+        let dir = TempDir::new("test_hooks")?;
+        let hooks = Hooks::new(dir.path(), dir.path()).with_env("HOOK_VALUE", "set");
+
+        assert!(hooks.run(PRE_COMMIT, &[], b"")?);
+        write_hook(dir.path(), PRE_COMMIT, "exit 1\n")?;
+        assert!(!hooks.run(PRE_COMMIT, &[], b"")?);
+
+        // Hooks which are not executable are skipped.
+        std::fs::set_permissions(
+            dir.path().join(PRE_COMMIT),
+            std::fs::Permissions::from_mode(0o644),
+        )?;
+        assert_eq!(hooks.find(PRE_COMMIT), None);
+        assert!(hooks.run(PRE_COMMIT, &[], b"")?);
+
+        write_hook(
+            dir.path(),
+            PRE_PUSH,
+            "read line\necho \"$1 $2 $line $HOOK_VALUE\" > out\n",
+        )?;
+        assert!(hooks.run(PRE_PUSH, &["origin", "url"], b"refs/heads/main\n")?);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("out"))?,
+            "origin url refs/heads/main set\n"
+        );
+
+        // Input which is not read is dropped.
+        write_hook(dir.path(), PRE_PUSH, "exit 0\n")?;
+        assert!(hooks.run(PRE_PUSH, &[], &vec![b'x'; 1 << 20])?);
+        Ok(())
+    }
+}
@@ -0,0 +1,729 @@
+//! Git's "smart" HTTP protocol, served either as a CGI program (as with
+//! `git http-backend`) or by a small built-in server.
+//!
+//! Each repository beneath the project root is served at its path, with
+//! an advertisement at "<path>/info/refs?service=git-upload-pack" (or
+//! receive-pack), and each request of the session POSTed separately to
+//! "<path>/git-upload-pack". The older "dumb" protocol, which serves the
+//! repository's files directly, is not supported.
+
+use crate::clone::Source;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::protocol::{write_flush, write_packet};
+use crate::server;
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+// The file which exports a repository unless all of them are exported.
+const EXPORT_OK: &str = "git-daemon-export-ok";
+
+// Sent with every response, since advertisements and results are never
+// the same twice.
+const CACHE_CONTROL: &str = "no-cache, max-age=0, must-revalidate";
+
+// The largest request body accepted, after decompression, so that a
+// client cannot make the server allocate whatever it claims to send.
+pub(crate) const MAX_BODY_LENGTH: usize = 1 << 30;
+
+// The longest request or header line accepted, so that a client cannot
+// make the server buffer a line that never ends.
+const MAX_LINE_LENGTH: usize = 8 << 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Service {
+    UploadPack,
+    ReceivePack,
+}
+
+impl Service {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "git-upload-pack" => Some(Service::UploadPack),
+            "git-receive-pack" => Some(Service::ReceivePack),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Service::UploadPack => "git-upload-pack",
+            Service::ReceivePack => "git-receive-pack",
+        }
+    }
+
+    // Returns the variable which enables the service.
+    fn variable(self) -> &'static str {
+        match self {
+            Service::UploadPack => "http.uploadpack",
+            Service::ReceivePack => "http.receivepack",
+        }
+    }
+}
+
+/// A request, as received by the built-in server or described by CGI
+/// variables.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// The path beneath the project root, such as "/repo.git/info/refs".
+    pub path: String,
+    /// The query string, without its "?".
+    pub query: String,
+    pub content_type: Option<String>,
+    /// The encoding of the body, such as "gzip".
+    pub content_encoding: Option<String>,
+    /// The value of the "Git-Protocol" header, passed on as GIT_PROTOCOL.
+    pub protocol: Option<String>,
+    /// The authenticated user, if any, which enables pushes by default.
+    pub remote_user: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Builds a request from CGI variables, such as PATH_INFO, and the
+    /// body read from standard input.
+    pub fn from_cgi(vars: &HashMap<String, String>, body: Vec<u8>) -> Self {
+        let var = |name: &str| vars.get(name).filter(|value| !value.is_empty()).cloned();
+        Request {
+            method: var("REQUEST_METHOD").unwrap_or_else(|| "GET".to_string()),
+            path: var("PATH_INFO").unwrap_or_default(),
+            query: var("QUERY_STRING").unwrap_or_default(),
+            content_type: var("CONTENT_TYPE"),
+            content_encoding: var("HTTP_CONTENT_ENCODING"),
+            protocol: var("HTTP_GIT_PROTOCOL").or_else(|| var("GIT_PROTOCOL")),
+            remote_user: var("REMOTE_USER"),
+            body,
+        }
+    }
+
+    // Returns the value of a parameter in the query string.
+    fn parameter(&self, name: &str) -> Option<&str> {
+        self.query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if key == name {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+
+    // Returns the body, decompressed if the client compressed it.
+    fn decoded_body(&self) -> Result<Option<Vec<u8>>> {
+        match self.content_encoding.as_deref() {
+            None | Some("identity") => Ok(Some(self.body.clone())),
+            Some("gzip") | Some("x-gzip") => {
+                let mut body = vec![];
+                GzDecoder::new(self.body.as_slice())
+                    .take(MAX_BODY_LENGTH as u64 + 1)
+                    .read_to_end(&mut body)
+                    .map_err(|e| Error::Fatal(format!("bad gzip request body: {}", e)))?;
+                Ok(Some(body))
+            }
+            Some(_) => Ok(None),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: String, body: Vec<u8>) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain".to_string(),
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+
+    /// Returns the reason phrase for the status code, such as "Not Found".
+    pub fn reason(&self) -> &'static str {
+        match self.status {
+            100 => "Continue",
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /// Writes the response as the output of a CGI program.
+    pub fn write_cgi(&self, output: &mut impl Write) -> Result<()> {
+        write!(
+            output,
+            "Status: {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Cache-Control: {}\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len(),
+            CACHE_CONTROL
+        )?;
+        output.write_all(&self.body)?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Writes the response for an HTTP/1.1 client, closing the connection
+    /// afterwards.
+    pub fn write_http(&self, output: &mut impl Write) -> Result<()> {
+        write!(
+            output,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Cache-Control: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len(),
+            CACHE_CONTROL
+        )?;
+        output.write_all(&self.body)?;
+        output.flush()?;
+        Ok(())
+    }
+}
+
+/// Serves the repositories beneath a project root.
+#[derive(Clone, Debug)]
+pub struct Backend {
+    root: PathBuf,
+    export_all: bool,
+}
+
+impl Backend {
+    /// Serves the repositories beneath `root`. Unless `export_all` is set,
+    /// only those containing a "git-daemon-export-ok" file are served.
+    pub fn new(root: &Path, export_all: bool) -> Self {
+        Backend {
+            root: root.to_path_buf(),
+            export_all,
+        }
+    }
+
+    /// Answers a request. Failures within the server are reported to the
+    /// client, as with a "500 Internal Server Error" response.
+    pub fn handle(&self, request: &Request) -> Response {
+        match self.route(request) {
+            Ok(response) => response,
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+
+    fn route(&self, request: &Request) -> Result<Response> {
+        let (repository, service, advertise) = match request.path.strip_suffix("/info/refs") {
+            Some(repository) => match request.parameter("service").and_then(Service::parse) {
+                Some(service) => (repository, service, true),
+                None => return Ok(Response::error(403, "dumb HTTP is not supported")),
+            },
+            None => match request.path.rsplit_once('/') {
+                Some((repository, name)) => match Service::parse(name) {
+                    Some(service) => (repository, service, false),
+                    None => return Ok(Response::error(404, "Not Found")),
+                },
+                None => return Ok(Response::error(404, "Not Found")),
+            },
+        };
+        let method = if advertise { "GET" } else { "POST" };
+        if request.method != method && !(advertise && request.method == "HEAD") {
+            return Ok(Response::error(405, "Method Not Allowed"));
+        }
+
+        let source = match self.locate(repository) {
+            Some(source) => source,
+            None => return Ok(Response::error(404, "Repository not exported")),
+        };
+        let config = Config::load(source.git_dir().join("config"))?;
+        let enabled = config
+            .get_bool(service.variable())?
+            .unwrap_or(service == Service::UploadPack || request.remote_user.is_some());
+        if !enabled {
+            let message = format!("Service not enabled: '{}'", service.name());
+            return Ok(Response::error(403, &message));
+        }
+        let protocol = request.protocol.as_deref();
+
+        if advertise {
+            let mut body = vec![];
+            // Version 2 begins with its capabilities instead.
+            if service == Service::ReceivePack || !server::is_version_2(protocol) {
+                write_packet(
+                    &mut body,
+                    format!("# service={}\n", service.name()).as_bytes(),
+                )?;
+                write_flush(&mut body)?;
+            }
+            let options = server::Options {
+                advertise_refs: true,
+                stateless_rpc: false,
+            };
+            run(&source, service, protocol, options, &[], &mut body)?;
+            let content_type = format!("application/x-{}-advertisement", service.name());
+            return Ok(Response::ok(content_type, body));
+        }
+
+        let expected = format!("application/x-{}-request", service.name());
+        if request.content_type.as_deref() != Some(expected.as_str()) {
+            return Ok(Response::error(415, "Unsupported Media Type"));
+        }
+        let input = match request.decoded_body()? {
+            Some(input) => input,
+            None => return Ok(Response::error(415, "Unsupported Content-Encoding")),
+        };
+        if input.len() > MAX_BODY_LENGTH {
+            return Ok(Response::error(413, "Payload Too Large"));
+        }
+        let options = server::Options {
+            advertise_refs: false,
+            stateless_rpc: true,
+        };
+        let mut body = vec![];
+        // Errors the server reports to the client are in the body already.
+        if let Err(e) = run(&source, service, protocol, options, &input, &mut body) {
+            if body.is_empty() {
+                return Err(e);
+            }
+        }
+        let content_type = format!("application/x-{}-result", service.name());
+        Ok(Response::ok(content_type, body))
+    }
+
+    // Finds an exported repository beneath the root, given its path.
+    fn locate(&self, repository: &str) -> Option<Source> {
+        let relative = repository.trim_start_matches('/');
+        if relative.is_empty() || relative.split('/').any(|part| part == "..") {
+            return None;
+        }
+        let root = std::fs::canonicalize(&self.root).ok()?;
+        let source = Source::locate(relative, &root).ok()?;
+        // A symbolic link may still lead elsewhere.
+        if !source.git_dir().starts_with(&root) {
+            return None;
+        }
+        if self.export_all || source.git_dir().join(EXPORT_OK).is_file() {
+            Some(source)
+        } else {
+            None
+        }
+    }
+}
+
+fn run(
+    source: &Source,
+    service: Service,
+    protocol: Option<&str>,
+    options: server::Options,
+    mut input: &[u8],
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    match service {
+        Service::UploadPack => server::upload_pack(source, protocol, options, &mut input, output),
+        Service::ReceivePack => server::receive_pack(source, options, &mut input, output),
+    }
+}
+
+/// Serves HTTP requests from `listener` until it fails, handling each
+/// connection on its own thread.
+pub fn serve(listener: TcpListener, backend: Backend) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let backend = backend.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = serve_connection(stream, &backend) {
+                tracing::debug!(error = %e, "connection failed");
+            }
+        });
+    }
+    Ok(())
+}
+
+// Answers a single request on a connection, then closes it.
+fn serve_connection(stream: TcpStream, backend: &Backend) -> Result<()> {
+    let mut output = stream.try_clone()?;
+    let mut input = BufReader::new(stream);
+    let response = match read_request(&mut input, &mut output) {
+        Ok(Some(request)) => backend.handle(&request),
+        Ok(None) => return Ok(()),
+        Err(Rejection::TooLarge) => Response::error(413, "Payload Too Large"),
+        Err(Rejection::Invalid(e)) => Response::error(400, &e.to_string()),
+    };
+    response.write_http(&mut output)
+}
+
+// Why a request could not be read.
+#[derive(Debug, thiserror::Error)]
+enum Rejection {
+    #[error("request body too large")]
+    TooLarge,
+    #[error(transparent)]
+    Invalid(#[from] Error),
+}
+
+impl From<std::io::Error> for Rejection {
+    fn from(e: std::io::Error) -> Self {
+        Rejection::Invalid(e.into())
+    }
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::Fatal(format!("bad request: {}", message))
+}
+
+// Reads a request line, headers and body. Returns None if the client
+// closes the connection without sending anything.
+fn read_request(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> std::result::Result<Option<Request>, Rejection> {
+    let line = match read_crlf_line(input)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut words = line.split(' ');
+    let (method, target) = match (words.next(), words.next(), words.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, target)
+        }
+        _ => return Err(bad_request(&line).into()),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        ..Request::default()
+    };
+
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut expect_continue = false;
+    loop {
+        let line = read_crlf_line(input)?.ok_or_else(|| bad_request("truncated headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| bad_request(&line))?;
+        let value = value.trim().to_string();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = Some(value.parse().map_err(|_| bad_request(&line))?)
+            }
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            "content-type" => request.content_type = Some(value),
+            "content-encoding" => request.content_encoding = Some(value),
+            "git-protocol" => request.protocol = Some(value),
+            _ => (),
+        }
+    }
+
+    if content_length.is_some_and(|length| length > MAX_BODY_LENGTH) {
+        return Err(Rejection::TooLarge);
+    }
+    if expect_continue {
+        output.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        output.flush()?;
+    }
+    if chunked {
+        request.body = read_chunked(input)?;
+    } else if let Some(length) = content_length {
+        read_body(input, length, &mut request.body)?;
+    }
+    Ok(Some(request))
+}
+
+// Reads a body sent in chunks, each preceded by its length in hex, up to
+// an empty chunk and any trailing headers.
+fn read_chunked(input: &mut impl BufRead) -> std::result::Result<Vec<u8>, Rejection> {
+    let mut body = vec![];
+    loop {
+        let line = read_crlf_line(input)?.ok_or_else(|| bad_request("truncated chunk"))?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| bad_request(&line))?;
+        if size == 0 {
+            break;
+        }
+        if body
+            .len()
+            .checked_add(size)
+            .is_none_or(|end| end > MAX_BODY_LENGTH)
+        {
+            return Err(Rejection::TooLarge);
+        }
+        read_body(input, size, &mut body)?;
+        if read_crlf_line(input)? != Some(String::new()) {
+            return Err(bad_request("missing chunk terminator").into());
+        }
+    }
+    while read_crlf_line(input)?.is_some_and(|line| !line.is_empty()) {}
+    Ok(body)
+}
+
+// Appends `length` bytes of body to `body` as they arrive, rather than
+// allocating however much the client claims it will send up front.
+fn read_body(input: &mut impl BufRead, length: usize, body: &mut Vec<u8>) -> Result<()> {
+    let start = body.len();
+    (&mut *input).take(length as u64).read_to_end(body)?;
+    if body.len() - start < length {
+        return Err(bad_request("truncated body"));
+    }
+    Ok(())
+}
+
+// Reads a line ending with CRLF (or just LF), without its ending, or None
+// at the end of the input. Lines longer than MAX_LINE_LENGTH are refused.
+fn read_crlf_line(input: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    if (&mut *input)
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_line(&mut line)?
+        == 0
+    {
+        return Ok(None);
+    }
+    if line.len() > MAX_LINE_LENGTH {
+        return Err(bad_request("line too long"));
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::read_packet;
+    use crate::protocol::Packet;
+    use crate::test_helpers::git;
+    use anyhow::Result;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tempdir::TempDir;
+
+    fn get(path: &str, query: &str) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            ..Request::default()
+        }
+    }
+
+    #[test]
+    fn test_handle() -> Result<()> {
+        let dir = TempDir::new("test_handle")?;
+        git(dir.path(), &["init", "-q", "--bare", "repo.git"]);
+        git(dir.path(), &["init", "-q", "--bare", "private.git"]);
+        std::fs::write(dir.path().join("repo.git").join(EXPORT_OK), "")?;
+        let backend = Backend::new(dir.path(), false);
+
+        let response = backend.handle(&get("/repo.git/info/refs", "service=git-upload-pack"));
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.content_type,
+            "application/x-git-upload-pack-advertisement"
+        );
+        let mut body = response.body.as_slice();
+        assert_eq!(
+            read_packet(&mut body)?,
+            Packet::Data(b"# service=git-upload-pack\n".to_vec())
+        );
+        assert_eq!(read_packet(&mut body)?, Packet::Flush);
+
+        let mut request = get("/repo.git/info/refs", "service=git-upload-pack");
+        request.protocol = Some("version=2".to_string());
+        let response = backend.handle(&request);
+        assert!(response.body.starts_with(b"000eversion 2\n"));
+
+        let statuses = [
+            ("/repo.git/info/refs", "", 403),
+            ("/repo.git/info/refs", "service=git-receive-pack", 403),
+            ("/private.git/info/refs", "service=git-upload-pack", 404),
+            ("/missing.git/info/refs", "service=git-upload-pack", 404),
+            (
+                "/repo.git/../repo.git/info/refs",
+                "service=git-upload-pack",
+                404,
+            ),
+            ("/repo.git/HEAD", "", 404),
+            ("/repo.git/git-upload-pack", "", 405),
+        ];
+        for (path, query, status) in statuses.iter() {
+            assert_eq!(
+                backend.handle(&get(path, query)).status,
+                *status,
+                "{}",
+                path
+            );
+        }
+
+        let mut request = get("/repo.git/info/refs", "service=git-receive-pack");
+        request.remote_user = Some("user".to_string());
+        assert_eq!(backend.handle(&request).status, 200);
+
+        // An empty request for a version 2 command list, compressed.
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"0014command=ls-refs\n00010000")?;
+        let request = Request {
+            method: "POST".to_string(),
+            path: "/repo.git/git-upload-pack".to_string(),
+            content_type: Some("application/x-git-upload-pack-request".to_string()),
+            content_encoding: Some("gzip".to_string()),
+            protocol: Some("version=2".to_string()),
+            body: encoder.finish()?,
+            ..Request::default()
+        };
+        let response = backend.handle(&request);
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.content_type,
+            "application/x-git-upload-pack-result"
+        );
+        assert_eq!(response.body, b"0000");
+
+        let request = Request {
+            content_type: None,
+            ..request
+        };
+        assert_eq!(backend.handle(&request).status, 415);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_request() -> Result<()> {
+        let input = b"POST /repo.git/git-upload-pack?x=1 HTTP/1.1\r\n\
+                      Host: localhost\r\n\
+                      Transfer-Encoding: chunked\r\n\
+                      Git-Protocol: version=2\r\n\
+                      Expect: 100-continue\r\n\r\n\
+                      4\r\n0000\r\n3;ext\r\nabc\r\n0\r\n\r\n";
+        let mut output = vec![];
+        let request = read_request(&mut &input[..], &mut output)?.unwrap();
+        assert_eq!(output, b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/repo.git/git-upload-pack");
+        assert_eq!(request.query, "x=1");
+        assert_eq!(request.protocol.as_deref(), Some("version=2"));
+        assert_eq!(request.body, b"0000abc");
+
+        let input = b"GET / HTTP/1.0\r\nContent-Length: 2\r\n\r\nhi";
+        let request = read_request(&mut &input[..], &mut output)?.unwrap();
+        assert_eq!(request.body, b"hi");
+        assert!(read_request(&mut &b""[..], &mut output)?.is_none());
+        assert!(read_request(&mut &b"nonsense\r\n"[..], &mut output).is_err());
+
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n";
+        assert!(matches!(
+            read_request(&mut &input[..], &mut output),
+            Err(Rejection::TooLarge)
+        ));
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      1\r\na\r\nffffffffffffffff\r\n";
+        assert!(matches!(
+            read_request(&mut &input[..], &mut output),
+            Err(Rejection::TooLarge)
+        ));
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi";
+        assert!(matches!(
+            read_request(&mut &input[..], &mut output),
+            Err(Rejection::Invalid(_))
+        ));
+        let input = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", "x".repeat(8 << 10));
+        assert!(matches!(
+            read_request(&mut input.as_bytes(), &mut output),
+            Err(Rejection::Invalid(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_refuses_funny_refname() -> Result<()> {
+        let dir = TempDir::new("test_push_refuses_funny_refname")?;
+        git(dir.path(), &["init", "-q", "--bare", "repo.git"]);
+        let backend = Backend::new(dir.path(), true);
+
+        let zero = "0".repeat(40);
+        let mut body = vec![];
+        let command = format!("{} {} refs/../../escaped\0report-status", zero, zero);
+        write_packet(&mut body, command.as_bytes())?;
+        write_flush(&mut body)?;
+        let request = Request {
+            method: "POST".to_string(),
+            path: "/repo.git/git-receive-pack".to_string(),
+            content_type: Some("application/x-git-receive-pack-request".to_string()),
+            remote_user: Some("user".to_string()),
+            body,
+            ..Request::default()
+        };
+        let response = backend.handle(&request);
+        assert_eq!(response.status, 200);
+        let mut body = response.body.as_slice();
+        assert_eq!(
+            read_packet(&mut body)?,
+            Packet::Data(b"unpack ok\n".to_vec())
+        );
+        assert_eq!(
+            read_packet(&mut body)?,
+            Packet::Data(b"ng refs/../../escaped funny refname\n".to_vec())
+        );
+        assert!(!dir.path().join("escaped").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve() -> Result<()> {
+        let dir = TempDir::new("test_serve")?;
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work)?;
+        git(&work, &["init", "-q", "-b", "main"]);
+        std::fs::write(work.join("file.txt"), "contents")?;
+        git(&work, &["add", "file.txt"]);
+        git(&work, &["commit", "-q", "-m", "first"]);
+        git(dir.path(), &["clone", "-q", "--bare", "work", "repo.git"]);
+        git(
+            &dir.path().join("repo.git"),
+            &["config", "http.receivepack", "true"],
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/repo.git", listener.local_addr()?);
+        let backend = Backend::new(dir.path(), true);
+        std::thread::spawn(move || serve(listener, backend));
+
+        git(dir.path(), &["clone", "-q", &url, "copy"]);
+        let copy = dir.path().join("copy");
+        assert_eq!(std::fs::read_to_string(copy.join("file.txt"))?, "contents");
+        std::fs::write(copy.join("file.txt"), "changed")?;
+        git(&copy, &["commit", "-q", "-a", "-m", "second"]);
+        git(&copy, &["push", "-q", "origin", "main"]);
+        assert_eq!(
+            git(&copy, &["rev-parse", "main"]),
+            git(&dir.path().join("repo.git"), &["rev-parse", "main"])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,566 @@
+use crate::database::{Blob, ObjectHasher, ObjectID, Storable};
+use crate::entry::{Entry, Mode};
+use crate::error::{Error, Result};
+use crate::lockfile::LockFile;
+use crate::workspace::{Workspace, WorkspacePath};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::Metadata;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// Identifies the file as a git index ("dircache").
+const SIGNATURE: &[u8] = b"DIRC";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 12;
+// Size of an entry, excluding the path and padding.
+const ENTRY_FIXED_SIZE: usize = 62;
+const ENTRY_BLOCK: usize = 8;
+const CHECKSUM_SIZE: usize = 20;
+
+// Bits of the entry flags field.
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_SHIFT: u16 = 12;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+
+/// A single file tracked by the index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    ctime: u32,
+    ctime_nsec: u32,
+    mtime: u32,
+    mtime_nsec: u32,
+    dev: u32,
+    ino: u32,
+    mode: Mode,
+    uid: u32,
+    gid: u32,
+    size: u32,
+    oid: ObjectID,
+    stage: u8,
+    path: WorkspacePath,
+}
+
+impl IndexEntry {
+    /// Creates an entry for a file in the workspace, caching its metadata
+    /// so later changes to the file can be detected cheaply.
+    pub fn new(path: WorkspacePath, oid: ObjectID, metadata: &Metadata) -> Self {
+        IndexEntry {
+            // The index stores 32-bit fields; git truncates larger values.
+            ctime: metadata.ctime() as u32,
+            ctime_nsec: metadata.ctime_nsec() as u32,
+            mtime: metadata.mtime() as u32,
+            mtime_nsec: metadata.mtime_nsec() as u32,
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode: Mode::from_metadata(metadata),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            oid,
+            stage: 0,
+            path,
+        }
+    }
+
+    /// Creates an entry from a (non-tree) entry of a tree object, whose path
+    /// is relative to the workspace root.
+    ///
+    /// No file metadata is cached, so the entry will be treated as
+    /// potentially modified until refreshed.
+    pub fn from_tree_entry(entry: &Entry) -> Result<Self> {
+        IndexEntry::from_tree_entry_at_stage(entry, 0)
+    }
+
+    /// Creates an entry from a tree entry for a particular merge stage.
+    ///
+    /// Stage 0 is used for ordinary entries, and stages 1-3 for the base,
+    /// "ours" and "theirs" sides of a conflicted merge.
+    pub fn from_tree_entry_at_stage(entry: &Entry, stage: u8) -> Result<Self> {
+        if stage > 3 {
+            return Err(Error::CorruptIndex(format!("invalid stage {}", stage)));
+        }
+        Ok(IndexEntry {
+            ctime: 0,
+            ctime_nsec: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            dev: 0,
+            ino: 0,
+            mode: *entry.mode(),
+            uid: 0,
+            gid: 0,
+            size: 0,
+            oid: entry.oid().clone(),
+            stage,
+            path: WorkspacePath::new(entry.path())?,
+        })
+    }
+
+    /// Overrides the mode derived from the file's metadata, such as for a
+    /// symlink checked out as a regular file.
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_partial_path()
+    }
+
+    pub fn oid(&self) -> &ObjectID {
+        &self.oid
+    }
+
+    pub fn mode(&self) -> &Mode {
+        &self.mode
+    }
+
+    /// Returns true if the cached metadata matches `metadata`, meaning the
+    /// file is (very likely) unchanged since it was added.
+    pub fn stat_matches(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.size() as u32
+            && self.mode.matches_metadata(metadata)
+            && self.mtime == metadata.mtime() as u32
+            && self.mtime_nsec == metadata.mtime_nsec() as u32
+            && self.ctime == metadata.ctime() as u32
+            && self.ctime_nsec == metadata.ctime_nsec() as u32
+    }
+
+    /// Returns true if the file in the workspace has the same contents and
+    /// mode as this entry.
+    ///
+    /// Only files whose metadata changed are re-hashed, and those of at
+    /// least `threshold` bytes (see `Repository::big_file_threshold`) are
+    /// streamed rather than read into memory.
+    pub fn matches_workspace(&self, workspace: &Workspace, threshold: u64) -> Result<bool> {
+        let metadata = match workspace.metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+        if self.mode == Mode::Gitlink {
+            return Ok(metadata.is_dir()
+                && workspace.submodule_head(&self.path)? == Some(self.oid.clone()));
+        }
+        if self.stat_matches(&metadata) {
+            return Ok(true);
+        }
+        if !self.mode.matches_metadata(&metadata) || metadata.is_dir() {
+            return Ok(false);
+        }
+        if metadata.len() < threshold || metadata.file_type().is_symlink() {
+            let blob = Blob::new(workspace.read_file(&self.path)?);
+            return Ok(blob.oid() == self.oid);
+        }
+        let mut file = workspace.open_file(&self.path)?;
+        Ok(ObjectHasher::hash_stream("blob", metadata.len(), &mut file)? == self.oid)
+    }
+
+    /// Converts the index entry into a tree entry, with a path relative to
+    /// the workspace root.
+    pub fn to_entry(&self) -> Entry {
+        Entry::new(self.path.clone(), self.oid.clone(), self.mode)
+    }
+
+    fn key(&self) -> (Vec<u8>, u8) {
+        (path_key(self.path()), self.stage)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let path = path_key(self.path());
+        let mut data = Vec::new();
+        for field in [
+            self.ctime,
+            self.ctime_nsec,
+            self.mtime,
+            self.mtime_nsec,
+            self.dev,
+            self.ino,
+            self.mode.as_u32(),
+            self.uid,
+            self.gid,
+            self.size,
+        ]
+        .iter()
+        {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        data.extend_from_slice(self.oid.as_bytes());
+        let flags = ((self.stage as u16) << FLAG_STAGE_SHIFT)
+            | (path.len().min(FLAG_NAME_MASK as usize) as u16);
+        data.extend_from_slice(&flags.to_be_bytes());
+        data.extend_from_slice(&path);
+
+        // Entries are NUL-terminated, then padded to a multiple of 8 bytes.
+        data.push(b'\0');
+        while !data.len().is_multiple_of(ENTRY_BLOCK) {
+            data.push(b'\0');
+        }
+        data
+    }
+
+    // Parses an entry from the start of `data`, returning it along with the
+    // number of bytes consumed.
+    fn parse(data: &[u8]) -> Result<(Self, usize)> {
+        let truncated = || Error::CorruptIndex("truncated entry".to_string());
+        if data.len() < ENTRY_FIXED_SIZE {
+            return Err(truncated());
+        }
+        let field = |i: usize| u32::from_be_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        let flags = u16::from_be_bytes(data[60..62].try_into().unwrap());
+        let mut offset = ENTRY_FIXED_SIZE;
+        if flags & FLAG_EXTENDED != 0 {
+            // Version 3 entries carry an additional 16 bits of flags.
+            offset += 2;
+        }
+
+        let path_len = data
+            .get(offset..)
+            .ok_or_else(truncated)?
+            .iter()
+            .position(|b| *b == b'\0')
+            .ok_or_else(|| Error::CorruptIndex("unterminated entry path".to_string()))?;
+        let path = Path::new(std::ffi::OsStr::from_bytes(
+            &data[offset..offset + path_len],
+        ));
+        let entry = IndexEntry {
+            ctime: field(0),
+            ctime_nsec: field(1),
+            mtime: field(2),
+            mtime_nsec: field(3),
+            dev: field(4),
+            ino: field(5),
+            mode: Mode::from_u32(field(6))?,
+            uid: field(7),
+            gid: field(8),
+            size: field(9),
+            oid: ObjectID::from_bytes(&data[40..60])?,
+            stage: ((flags & FLAG_STAGE_MASK) >> FLAG_STAGE_SHIFT) as u8,
+            path: WorkspacePath::new(path)?,
+        };
+
+        let mut len = offset + path_len + 1;
+        while !len.is_multiple_of(ENTRY_BLOCK) {
+            len += 1;
+        }
+        if len > data.len() {
+            return Err(truncated());
+        }
+        Ok((entry, len))
+    }
+}
+
+// Entries are ordered by the raw bytes of their path, which differs from
+// the component-wise ordering of `Path`.
+fn path_key(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_bytes().to_vec()
+}
+
+/// The staging area, recording the contents of the next commit.
+///
+/// This reads and writes the same file format as git's ".git/index".
+pub struct Index {
+    path: PathBuf,
+    entries: BTreeMap<(Vec<u8>, u8), IndexEntry>,
+}
+
+impl Index {
+    /// Loads the index stored at `path`.
+    ///
+    /// A missing index file is treated as an empty index.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut index = Index {
+            path: PathBuf::from(path.as_ref()),
+            entries: BTreeMap::new(),
+        };
+        if !index.path.exists() {
+            return Ok(index);
+        }
+
+        let data = std::fs::read(&index.path)?;
+        if data.len() < HEADER_SIZE + CHECKSUM_SIZE {
+            return Err(Error::CorruptIndex("file is truncated".to_string()));
+        }
+        let (body, checksum) = data.split_at(data.len() - CHECKSUM_SIZE);
+        if Sha1::digest(body).as_slice() != checksum {
+            return Err(Error::CorruptIndex(
+                "checksum does not match its contents".to_string(),
+            ));
+        }
+        if &body[0..4] != SIGNATURE {
+            return Err(Error::CorruptIndex("invalid signature".to_string()));
+        }
+        let version = u32::from_be_bytes(body[4..8].try_into().unwrap());
+        if version != 2 && version != 3 {
+            return Err(Error::CorruptIndex(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+        let count = u32::from_be_bytes(body[8..12].try_into().unwrap());
+
+        // Any extensions following the entries are only caches, so they
+        // are safely discarded.
+        let mut offset = HEADER_SIZE;
+        for _ in 0..count {
+            let data = body
+                .get(offset..)
+                .ok_or_else(|| Error::CorruptIndex("truncated entry".to_string()))?;
+            let (entry, len) = IndexEntry::parse(data)?;
+            offset += len;
+            index.entries.insert(entry.key(), entry);
+        }
+        Ok(index)
+    }
+
+    /// Atomically replaces the on-disk index with the current contents.
+    pub fn write(&self) -> Result<()> {
+        let mut lock = LockFile::new(&self.path)?;
+        self.write_to(lock.writer())?;
+        lock.commit()
+    }
+
+    /// Writes the index, with its trailing checksum, to `out`.
+    pub fn write_to(&self, out: &mut impl Write) -> Result<()> {
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in self.entries.values() {
+            data.extend_from_slice(&entry.serialize());
+        }
+        let checksum = Sha1::digest(&data);
+        data.extend_from_slice(checksum.as_slice());
+        out.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Adds an entry, replacing any entries it conflicts with.
+    ///
+    /// A file may not share its path with a directory, so adding "a/b"
+    /// evicts "a", and adding "a" evicts "a/b".
+    pub fn add(&mut self, entry: IndexEntry) {
+        for parent in entry.path().ancestors().skip(1) {
+            self.remove_exact(parent);
+        }
+        self.remove_children(entry.path());
+        self.remove_exact(entry.path());
+        self.entries.insert(entry.key(), entry);
+    }
+
+    /// Adds one stage of a conflicted entry, keeping its other stages but
+    /// replacing any unconflicted entry at its path.
+    pub fn add_conflict(&mut self, entry: IndexEntry) {
+        for parent in entry.path().ancestors().skip(1) {
+            self.remove_exact(parent);
+        }
+        self.remove_children(entry.path());
+        self.entries.remove(&(path_key(entry.path()), 0));
+        self.entries.insert(entry.key(), entry);
+    }
+
+    /// Removes the entry at `path`, along with any entries beneath it.
+    pub fn remove(&mut self, path: &Path) {
+        self.remove_exact(path);
+        self.remove_children(path);
+    }
+
+    // Removes all stages of the entry at `path`.
+    fn remove_exact(&mut self, path: &Path) {
+        let key = path_key(path);
+        for stage in 0..=3 {
+            self.entries.remove(&(key.clone(), stage));
+        }
+    }
+
+    // Removes every entry within the directory `path`.
+    fn remove_children(&mut self, path: &Path) {
+        let mut prefix = path_key(path);
+        prefix.push(b'/');
+        let children: Vec<(Vec<u8>, u8)> = self
+            .entries
+            .range((prefix.clone(), 0)..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in children {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Removes every entry from the index.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns all entries, sorted by path and stage.
+    pub fn entries(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.values()
+    }
+
+    /// Returns the unconflicted (stage 0) entry at `path`.
+    pub fn entry(&self, path: &Path) -> Option<&IndexEntry> {
+        self.entries.get(&(path_key(path), 0))
+    }
+
+    /// Returns true if any entry is in a conflicted (non-zero) stage.
+    pub fn has_conflicts(&self) -> bool {
+        self.entries.values().any(|entry| entry.stage != 0)
+    }
+
+    /// Returns the paths which have conflicted entries, in sorted order.
+    pub fn conflicted_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .entries
+            .values()
+            .filter(|entry| entry.stage != 0)
+            .map(|entry| entry.path().to_path_buf())
+            .collect();
+        paths.dedup();
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tempdir::TempDir;
+
+    fn entry(path: &str) -> IndexEntry {
+        IndexEntry::from_tree_entry(&Entry::new(
+            WorkspacePath::new(path).unwrap(),
+            ObjectID::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap(),
+            Mode::ReadWrite,
+        ))
+        .unwrap()
+    }
+
+    fn paths(index: &Index) -> Vec<&Path> {
+        index.entries().map(|e| e.path()).collect()
+    }
+
+    #[test]
+    fn test_write_and_load() -> Result<()> {
+        let dir = TempDir::new("test_index_write_and_load")?;
+        let path = dir.path().join("index");
+
+        let mut index = Index::load(&path)?;
+        index.add(entry("b.txt"));
+        index.add(entry("a/nested.txt"));
+        index.add(entry("a-file.txt"));
+        index.write()?;
+
+        let loaded = Index::load(&path)?;
+        // Sorted by raw path bytes: '-' (0x2d) precedes '/' (0x2f).
+        assert_eq!(
+            paths(&loaded),
+            vec![
+                Path::new("a-file.txt"),
+                Path::new("a/nested.txt"),
+                Path::new("b.txt")
+            ]
+        );
+        assert_eq!(loaded.entry(Path::new("b.txt")), Some(&entry("b.txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_replaces_conflicting_paths() {
+        let mut index = Index::load("/nonexistent/index").unwrap();
+        index.add(entry("a/b/c.txt"));
+        index.add(entry("a/d.txt"));
+
+        // A file replaces a directory of the same name...
+        index.add(entry("a/b"));
+        assert_eq!(paths(&index), vec![Path::new("a/b"), Path::new("a/d.txt")]);
+
+        // ...and vice versa.
+        index.add(entry("a/b/e.txt"));
+        assert_eq!(
+            paths(&index),
+            vec![Path::new("a/b/e.txt"), Path::new("a/d.txt")]
+        );
+
+        index.remove(Path::new("a"));
+        assert!(paths(&index).is_empty());
+    }
+
+    #[test]
+    fn test_add_conflict_keeps_other_stages() -> Result<()> {
+        let mut index = Index::load("/nonexistent/index")?;
+        index.add(entry("a.txt"));
+        let tree_entry = entry("a.txt").to_entry();
+        for stage in 1..=3 {
+            index.add_conflict(IndexEntry::from_tree_entry_at_stage(&tree_entry, stage)?);
+        }
+        let stages: Vec<u8> = index.entries().map(|entry| entry.stage).collect();
+        assert_eq!(stages, vec![1, 2, 3]);
+        assert_eq!(index.entry(Path::new("a.txt")), None);
+
+        // Resolving the conflict replaces every stage.
+        index.add(entry("a.txt"));
+        assert_eq!(index.entry(Path::new("a.txt")), Some(&entry("a.txt")));
+        assert!(!index.has_conflicts());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_corruption() -> Result<()> {
+        let dir = TempDir::new("test_index_corruption")?;
+        let path = dir.path().join("index");
+        let mut index = Index::load(&path)?;
+        index.add(entry("file.txt"));
+        index.write()?;
+
+        let mut data = std::fs::read(&path)?;
+        data[HEADER_SIZE] ^= 0xff;
+        std::fs::write(&path, data)?;
+        assert!(Index::load(&path).is_err());
+        Ok(())
+    }
+
+    // Writes an index holding `count` entries whose data is `entries`,
+    // with a valid checksum.
+    fn write_raw(path: &Path, count: u32, entries: &[u8]) -> Result<()> {
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&3u32.to_be_bytes());
+        data.extend_from_slice(&count.to_be_bytes());
+        data.extend_from_slice(entries);
+        let checksum = Sha1::digest(&data);
+        data.extend_from_slice(checksum.as_slice());
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_truncated_entries() -> Result<()> {
+        let dir = TempDir::new("test_index_truncated")?;
+        let path = dir.path().join("index");
+        let entry = entry("ab").serialize();
+        assert_eq!(entry.len(), 72);
+
+        // An extended entry which ends before its extra flags.
+        let mut extended = entry[..ENTRY_FIXED_SIZE].to_vec();
+        extended[60] |= (FLAG_EXTENDED >> 8) as u8;
+        write_raw(&path, 1, &extended)?;
+        assert!(matches!(Index::load(&path), Err(Error::CorruptIndex(_))));
+
+        // An entry which ends before its padding, followed by another.
+        write_raw(&path, 2, &entry[..ENTRY_FIXED_SIZE + 3])?;
+        assert!(matches!(Index::load(&path), Err(Error::CorruptIndex(_))));
+        write_raw(&path, 1, &entry[..ENTRY_FIXED_SIZE + 3])?;
+        assert!(matches!(Index::load(&path), Err(Error::CorruptIndex(_))));
+
+        write_raw(&path, 2, &entry)?;
+        assert!(matches!(Index::load(&path), Err(Error::CorruptIndex(_))));
+        write_raw(&path, 1, &entry)?;
+        assert_eq!(paths(&Index::load(&path)?), vec![Path::new("ab")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+//! Creating repositories, as with `git init`: the git directory's skeleton,
+//! its initial config, and the permissions of a repository shared between
+//! users.
+//!
+//! Initializing an existing repository is safe: files which already exist
+//! are left alone, so only what is missing (such as new template files) is
+//! added.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::revision::Revision;
+use std::fs::{create_dir_all, read_dir};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The name of the git directory within a workspace.
+pub const GIT_DIR: &str = ".git";
+
+/// The branch HEAD refers to in a new repository, unless configured.
+pub const DEFAULT_BRANCH: &str = "master";
+
+// The contents of the template built into git, written when no template
+// directory is given.
+const DESCRIPTION: &str =
+    "Unnamed repository; edit this file 'description' to name the repository.\n";
+const EXCLUDE: &str = "\
+# git ls-files --others --exclude-from=.git/info/exclude
+# Lines that start with '#' are comments.
+# For a project mostly in C, the following would be a good set of
+# exclude patterns (uncomment them if you want to use them):
+# *.[oa]
+# *~
+";
+
+// The directories every repository has, whatever its template.
+const SKELETON: [&str; 4] = ["objects/info", "objects/pack", "refs/heads", "refs/tags"];
+
+/// Who may use a repository, as set by "core.sharedRepository".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Shared {
+    /// Permissions follow the umask of whoever creates each file.
+    #[default]
+    Umask,
+    /// Files are writable by the group of the repository.
+    Group,
+    /// As for `Group`, and files are readable by everyone.
+    Everybody,
+    /// Files have exactly these permissions, such as 0o640.
+    Mode(u32),
+}
+
+impl FromStr for Shared {
+    type Err = Error;
+
+    /// Parses "umask", "group", "all" (or "world", "everybody"), a boolean,
+    /// or an octal mode, as git does.
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "umask" | "false" | "no" | "off" | "0" => return Ok(Shared::Umask),
+            "group" | "true" | "yes" | "on" | "1" => return Ok(Shared::Group),
+            "all" | "world" | "everybody" | "2" => return Ok(Shared::Everybody),
+            _ => (),
+        }
+        let mode = u32::from_str_radix(value, 8)
+            .map_err(|_| Error::Fatal(format!("unknown value for --shared: {}", value)))?;
+        if mode & 0o600 != 0o600 {
+            return Err(Error::Fatal(format!(
+                "problem with core.sharedRepository filemode value (0{:03o}).\n\
+                 The owner of files must always have read and write permissions.",
+                mode
+            )));
+        }
+        Ok(Shared::Mode(mode & 0o666))
+    }
+}
+
+impl Shared {
+    // Returns the value recorded as "core.sharedRepository", if any.
+    fn config_value(self) -> Option<String> {
+        match self {
+            Shared::Umask => None,
+            Shared::Group => Some("1".to_string()),
+            Shared::Everybody => Some("2".to_string()),
+            Shared::Mode(mode) => Some(format!("0{:03o}", mode)),
+        }
+    }
+
+    /// Returns the permissions for a file or directory with permissions
+    /// `mode`. Directories are also made set-group-ID, so that whatever is
+    /// created within them shares their group.
+    pub fn permissions(self, mode: u32, is_dir: bool) -> u32 {
+        let mut tweak = match self {
+            Shared::Umask => return mode,
+            Shared::Group => 0o660,
+            Shared::Everybody => 0o664,
+            Shared::Mode(mode) => mode,
+        };
+        if mode & 0o200 == 0 {
+            tweak &= !0o222;
+        }
+        if mode & 0o100 != 0 {
+            // Whoever may read may also search, or execute.
+            tweak |= (tweak & 0o444) >> 2;
+        }
+        let mode = match self {
+            Shared::Mode(_) => (mode & !0o777) | tweak,
+            _ => mode | tweak,
+        };
+        if is_dir {
+            mode | 0o2000
+        } else {
+            mode
+        }
+    }
+
+    // Applies the permissions to `path` and everything beneath it.
+    fn apply(self, path: &Path) -> Result<()> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        if metadata.file_type().is_symlink() {
+            return Ok(());
+        }
+        let mode = self.permissions(metadata.permissions().mode(), metadata.is_dir());
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        if metadata.is_dir() {
+            for entry in read_dir(path)? {
+                self.apply(&entry?.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How to create a repository.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Creates a repository without a workspace, whose git directory is the
+    /// path itself.
+    pub bare: bool,
+    /// The branch HEAD refers to; defaults to "master".
+    pub initial_branch: Option<String>,
+    /// A directory whose files are copied into the git directory, or an
+    /// empty path for none. Defaults to git's own description and exclude
+    /// files, and empty hooks.
+    pub template: Option<PathBuf>,
+    pub shared: Shared,
+    /// Where to create the git directory instead, which "<path>/.git" then
+    /// names ("gitdir: <dir>").
+    pub separate_git_dir: Option<PathBuf>,
+}
+
+/// A repository created (or found) by `init`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Initialized {
+    pub git_dir: PathBuf,
+    /// Whether the repository already existed.
+    pub reinitialized: bool,
+}
+
+/// Returns the git directory of the workspace at `root`, if it has one:
+/// either "<root>/.git", or where a ".git" file names ("gitdir: <dir>").
+pub fn git_dir(root: &Path) -> Option<PathBuf> {
+    let dot_git = root.join(GIT_DIR);
+    let git_dir = if dot_git.is_file() {
+        let contents = std::fs::read_to_string(&dot_git).ok()?;
+        root.join(contents.trim().strip_prefix("gitdir:")?.trim())
+    } else {
+        dot_git
+    };
+    if git_dir.join("HEAD").is_file() {
+        Some(git_dir)
+    } else {
+        None
+    }
+}
+
+/// Creates an empty repository at `path`, or fills in whatever is missing
+/// from an existing one.
+pub fn init(path: &Path, options: &Options) -> Result<Initialized> {
+    if let Some(branch) = &options.initial_branch {
+        if !Revision::valid_ref_name(&format!("refs/heads/{}", branch)) {
+            return Err(Error::Fatal(format!(
+                "invalid initial branch name: '{}'",
+                branch
+            )));
+        }
+    }
+
+    let dot_git = path.join(GIT_DIR);
+    let git_dir = match &options.separate_git_dir {
+        Some(separate) => {
+            // As in git, an existing git directory is moved.
+            if dot_git.is_dir() {
+                std::fs::rename(&dot_git, separate)?;
+            }
+            separate.clone()
+        }
+        None if options.bare => path.to_path_buf(),
+        None => git_dir(path).unwrap_or_else(|| dot_git.clone()),
+    };
+    let reinitialized = git_dir.join("HEAD").is_file();
+
+    create_dir_all(&git_dir)?;
+    match &options.template {
+        None => {
+            write_new(&git_dir.join("description"), DESCRIPTION)?;
+            create_dir_all(git_dir.join("info"))?;
+            write_new(&git_dir.join("info/exclude"), EXCLUDE)?;
+            for dir in ["hooks", "branches"].iter() {
+                create_dir_all(git_dir.join(dir))?;
+            }
+        }
+        Some(template) if template.as_os_str().is_empty() => (),
+        Some(template) => copy_template(template, &git_dir)?,
+    }
+    for dir in SKELETON.iter() {
+        create_dir_all(git_dir.join(dir))?;
+    }
+    let branch = options.initial_branch.as_deref().unwrap_or(DEFAULT_BRANCH);
+    write_new(
+        &git_dir.join("HEAD"),
+        &format!("ref: refs/heads/{}\n", branch),
+    )?;
+
+    let config_path = git_dir.join("config");
+    let mut config = Config::load(&config_path)?;
+    let mut defaults = vec![
+        ("core.repositoryformatversion", "0".to_string()),
+        ("core.filemode", "true".to_string()),
+        ("core.bare", options.bare.to_string()),
+    ];
+    if !options.bare {
+        defaults.push(("core.logallrefupdates", "true".to_string()));
+    }
+    for (name, value) in defaults {
+        if config.get(name).is_none() {
+            config.set(name, &value)?;
+        }
+    }
+    if let Some(value) = options.shared.config_value() {
+        config.set("core.sharedrepository", &value)?;
+        config.set("receive.denyNonFastforwards", "true")?;
+    }
+    config.save(&config_path)?;
+
+    if options.separate_git_dir.is_some() {
+        create_dir_all(path)?;
+        let target = std::fs::canonicalize(&git_dir)?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| Error::invalid_path(&target, "not valid unicode"))?;
+        std::fs::write(&dot_git, format!("gitdir: {}\n", target))?;
+    }
+    options.shared.apply(&git_dir)?;
+    Ok(Initialized {
+        git_dir,
+        reinitialized,
+    })
+}
+
+// Writes a file unless it already exists.
+fn write_new(path: &Path, contents: &str) -> Result<()> {
+    if !path.exists() {
+        std::fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+// Copies the files of a template directory into the git directory, leaving
+// any which exist alone. A missing template is skipped, as in git.
+fn copy_template(template: &Path, destination: &Path) -> Result<()> {
+    let entries = match read_dir(template) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let target = destination.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            create_dir_all(&target)?;
+            copy_template(&entry.path(), &target)?;
+        } else if target.symlink_metadata().is_err() {
+            if file_type.is_symlink() {
+                std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+            } else {
+                std::fs::copy(entry.path(), &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_shared() -> Result<()> {
+        assert_eq!("group".parse::<Shared>()?, Shared::Group);
+        assert_eq!("true".parse::<Shared>()?, Shared::Group);
+        assert_eq!("world".parse::<Shared>()?, Shared::Everybody);
+        assert_eq!("umask".parse::<Shared>()?, Shared::Umask);
+        assert_eq!("0640".parse::<Shared>()?, Shared::Mode(0o640));
+        assert!("0440".parse::<Shared>().is_err());
+        assert!("nobody".parse::<Shared>().is_err());
+
+        assert_eq!(Shared::Group.permissions(0o644, false), 0o664);
+        assert_eq!(Shared::Group.permissions(0o755, true), 0o2775);
+        assert_eq!(Shared::Everybody.permissions(0o600, false), 0o664);
+        assert_eq!(Shared::Mode(0o640).permissions(0o644, false), 0o640);
+        assert_eq!(Shared::Mode(0o640).permissions(0o755, true), 0o2750);
+        assert_eq!(Shared::Group.permissions(0o444, false), 0o444);
+        Ok(())
+    }
+
+    #[test]
+    fn test_init() -> Result<()> {
+        let dir = TempDir::new("test_init")?;
+        let template = dir.path().join("template");
+        create_dir_all(template.join("hooks"))?;
+        std::fs::write(template.join("hooks/pre-commit"), "#!/bin/sh\n")?;
+        std::fs::write(template.join("description"), "from the template\n")?;
+
+        let options = Options {
+            bare: true,
+            initial_branch: Some("main".to_string()),
+            template: Some(template),
+            ..Options::default()
+        };
+        let bare = dir.path().join("bare.git");
+        let initialized = init(&bare, &options)?;
+        assert_eq!(initialized.git_dir, bare);
+        assert!(!initialized.reinitialized);
+        assert_eq!(
+            std::fs::read_to_string(bare.join("HEAD"))?,
+            "ref: refs/heads/main\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(bare.join("hooks/pre-commit"))?,
+            "#!/bin/sh\n"
+        );
+        assert!(!bare.join("info").exists());
+        let config = Config::load(bare.join("config"))?;
+        assert_eq!(config.get_bool("core.bare")?, Some(true));
+        assert_eq!(config.get("core.logallrefupdates"), None);
+
+        // Existing files are left alone.
+        std::fs::write(bare.join("description"), "edited\n")?;
+        let options = Options {
+            bare: true,
+            ..Options::default()
+        };
+        let initialized = init(&bare, &options)?;
+        assert!(initialized.reinitialized);
+        assert_eq!(
+            std::fs::read_to_string(bare.join("HEAD"))?,
+            "ref: refs/heads/main\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(bare.join("description"))?,
+            "edited\n"
+        );
+
+        let options = Options {
+            separate_git_dir: Some(dir.path().join("separate")),
+            shared: Shared::Group,
+            ..Options::default()
+        };
+        let work = dir.path().join("work");
+        let initialized = init(&work, &options)?;
+        assert_eq!(initialized.git_dir, dir.path().join("separate"));
+        assert_eq!(git_dir(&work), Some(dir.path().join("separate")));
+        let config = Config::load(dir.path().join("separate/config"))?;
+        assert_eq!(config.get("core.sharedrepository"), Some("1"));
+        let mode = std::fs::metadata(dir.path().join("separate/refs"))?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o2070, 0o2070);
+
+        let options = Options {
+            initial_branch: Some("bad..name".to_string()),
+            ..Options::default()
+        };
+        assert!(init(&dir.path().join("bad"), &options).is_err());
+        Ok(())
+    }
+}
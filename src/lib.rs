@@ -0,0 +1,46 @@
+//! Rusty git: a reimplementation of a subset of git.
+//!
+//! `Repository` is the entry point for library users; the "rit" binary is
+//! built on top of the `commands` module.
+
+pub mod author;
+pub mod clone;
+pub mod commands;
+pub mod commit;
+pub mod config;
+pub mod database;
+pub mod diff;
+pub mod editor;
+pub mod entry;
+pub mod error;
+pub mod fetch;
+pub mod fsck;
+pub mod gc;
+pub mod hooks;
+pub mod http;
+pub mod index;
+pub mod init;
+mod lockfile;
+pub mod merge;
+pub mod pack;
+pub mod protocol;
+pub mod push;
+pub mod rebase;
+pub mod refs;
+pub mod refspec;
+pub mod repository;
+pub mod revision;
+pub mod sequencer;
+pub mod server;
+pub mod stash;
+pub mod store;
+pub mod submodule;
+pub mod tag;
+#[cfg(test)]
+mod test_helpers;
+pub mod trace;
+pub mod tree;
+pub mod workspace;
+
+pub use error::{Error, Result};
+pub use repository::Repository;
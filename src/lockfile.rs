@@ -1,27 +1,33 @@
-use anyhow::{anyhow, Result};
+use crate::error::{Error, Result};
 use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
 
 /// Defines access to a cooperative filesystem lock
 /// object. Creates a lockfile by extending the provided path with a ".lock"
 /// suffix, and renaming over the previously existing file on completion.
+///
+/// A lock which is dropped without being committed is released, leaving the
+/// original file untouched.
 pub struct LockFile {
     // Original path, without the ".lock" suffix.
     path: PathBuf,
     // Connection to the currently open, ".lock" variant.
     file: File,
+    // Set once the lock has been renamed over the original path.
+    committed: bool,
 }
 
 impl LockFile {
     // Given "/path/to/foo.bar", return "/path/to/foo.bar.lock".
     fn lock_path<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+        let invalid = |reason| Error::invalid_path(path.as_ref(), reason);
         let file = path
             .as_ref()
             .file_name()
-            .ok_or_else(|| anyhow!("No file name"))?;
+            .ok_or_else(|| invalid("no file name"))?;
         let lock_file = file
             .to_str()
-            .ok_or_else(|| anyhow!("Invalid unicode"))?
+            .ok_or_else(|| invalid("not valid unicode"))?
             .to_string()
             + ".lock";
 
@@ -34,17 +40,30 @@ impl LockFile {
     /// Wraps the `path` argument in a lockfile variant.
     ///
     /// Does not mutate the object behind `path` until commit is invoked.
+    /// Fails with `Error::LockContention` if the lock is already held.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let lock_path = LockFile::lock_path(path.as_ref())?;
         let file = OpenOptions::new()
             .write(true)
             .create_new(true)
-            .open(LockFile::lock_path(path.as_ref())?)?;
+            .open(&lock_path)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => Error::LockContention(lock_path.clone()),
+                _ => e.into(),
+            })?;
+        tracing::debug!(path = %lock_path.display(), "acquired lock");
         Ok(LockFile {
             path: path.as_ref().into(),
             file,
+            committed: false,
         })
     }
 
+    /// Returns the path of the ".lock" file holding the new contents.
+    pub fn staging_path(&self) -> Result<PathBuf> {
+        LockFile::lock_path(&self.path)
+    }
+
     /// Provide access to the writer interface of the file.
     pub fn writer(&mut self) -> &mut impl std::io::Write {
         &mut self.file
@@ -52,10 +71,54 @@ impl LockFile {
 
     /// Consumes the lockfile object, atomically moving the written
     /// contents of the LockFile to the final path location.
-    pub fn commit(self) -> Result<()> {
-        Ok(std::fs::rename(
-            LockFile::lock_path(&self.path)?,
-            self.path,
-        )?)
+    pub fn commit(mut self) -> Result<()> {
+        let lock_path = LockFile::lock_path(&self.path)?;
+        std::fs::rename(&lock_path, &self.path)?;
+        self.committed = true;
+        tracing::debug!(path = %lock_path.display(), "released lock");
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        if let Ok(lock_path) = LockFile::lock_path(&self.path) {
+            let _ = std::fs::remove_file(&lock_path);
+            tracing::debug!(path = %lock_path.display(), "abandoned lock");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_contention() -> Result<()> {
+        let dir = TempDir::new("test_lockfile")?;
+        let path = dir.path().join("HEAD");
+
+        let mut lock = LockFile::new(&path)?;
+        match LockFile::new(&path) {
+            Err(Error::LockContention(held)) => assert_eq!(held, dir.path().join("HEAD.lock")),
+            _ => panic!("Expected lock contention"),
+        }
+        lock.writer().write_all(b"contents")?;
+        lock.commit()?;
+
+        assert_eq!(std::fs::read(&path)?, b"contents");
+
+        // Dropping a lock releases it without changing the file.
+        let mut lock = LockFile::new(&path)?;
+        lock.writer().write_all(b"discarded")?;
+        drop(lock);
+        assert_eq!(std::fs::read(&path)?, b"contents");
+        assert!(LockFile::new(&path).is_ok());
+        Ok(())
     }
 }
@@ -1,31 +1,322 @@
-mod author;
-mod commands;
-mod commit;
-mod database;
-mod entry;
-mod lockfile;
-mod refs;
-mod tree;
-mod workspace;
-
-use crate::commands::{commit, init, CommitArgs, InitArgs};
-use anyhow::Result;
 use clap::{App, Arg, SubCommand};
+use rit::commands::{
+    cherry_pick, clone, commit, fetch, fsck, gc, http_backend, init, prune, push, rebase,
+    receive_pack, reset, revert, serve, show, stash, submodule, upload_pack, CloneArgs, CommitArgs,
+    FetchArgs, FsckArgs, GcArgs, HttpBackendArgs, InitArgs, PruneArgs, PushArgs, RebaseArgs,
+    RebaseCommand, ReceivePackArgs, ResetArgs, ResetMode, SequenceArgs, SequenceCommand, ServeArgs,
+    ShowArgs, StashArgs, StashCommand, SubmoduleArgs, SubmoduleCommand, UploadPackArgs,
+};
+use rit::config::Config;
+use rit::init::Shared;
+use rit::repository::Repository;
+use rit::{editor, fetch, push, sequencer, server, stash, trace, Error, Result};
 use std::env;
+use std::path::PathBuf;
 
-fn main() -> Result<()> {
+// Optional argument naming a stash entry, such as "stash@{1}".
+fn stash_entry_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("stash")
+        .takes_value(true)
+        .help("The stash entry to use; defaults to stash@{0}")
+}
+
+// Optional arguments restricting a submodule command to some paths.
+fn submodule_paths_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("paths")
+        .multiple(true)
+        .help("Submodules to operate on; defaults to all of them")
+}
+
+// Parses the arguments built by `submodule_paths_arg`.
+fn submodule_paths<'a>(args: &'a clap::ArgMatches) -> Vec<&'a str> {
+    args.values_of("paths")
+        .map(|v| v.collect())
+        .unwrap_or_default()
+}
+
+// Builds the arguments shared by "cherry-pick" and "revert".
+fn sequence_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("continue")
+                .long("continue")
+                .conflicts_with_all(&["abort", "commits"])
+                .help("Continues after resolving conflicts"),
+        )
+        .arg(
+            Arg::with_name("abort")
+                .long("abort")
+                .conflicts_with("commits")
+                .help("Cancels the operation, returning to the original HEAD"),
+        )
+        .arg(
+            Arg::with_name("commits")
+                .multiple(true)
+                .required_unless_one(&["continue", "abort"])
+                .help("Commits to apply"),
+        )
+}
+
+// Parses the arguments built by `sequence_subcommand`.
+fn sequence_args<'a>(args: &'a clap::ArgMatches) -> Result<SequenceArgs<'a>> {
+    let command = if args.is_present("continue") {
+        SequenceCommand::Continue
+    } else if args.is_present("abort") {
+        SequenceCommand::Abort
+    } else {
+        SequenceCommand::Start {
+            revisions: args
+                .values_of("commits")
+                .map(|v| v.collect())
+                .unwrap_or_default(),
+            options: sequencer::Options {
+                record_origin: args.is_present("x"),
+            },
+        }
+    };
+    Ok(SequenceArgs {
+        cwd: env::current_dir()?,
+        command,
+        name: env_var("GIT_AUTHOR_NAME")?,
+        email: env_var("GIT_AUTHOR_EMAIL")?,
+        time: chrono::Local::now().into(),
+    })
+}
+
+// Builds the options shared by "upload-pack" and "receive-pack", which
+// split a session into separate requests for HTTP.
+fn server_mode_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("stateless-rpc")
+            .long("stateless-rpc")
+            .help("Serves a single request, without the advertisement"),
+        Arg::with_name("advertise-refs")
+            .long("advertise-refs")
+            .help("Only sends the advertisement"),
+    ]
+}
+
+// Parses the arguments built by `server_mode_args`.
+fn server_options(args: &clap::ArgMatches) -> server::Options {
+    server::Options {
+        advertise_refs: args.is_present("advertise-refs"),
+        stateless_rpc: args.is_present("stateless-rpc"),
+    }
+}
+
+// Loads the user's own config, from GIT_CONFIG_GLOBAL or "~/.gitconfig".
+fn global_config() -> Result<Config> {
+    let path = match env::var_os("GIT_CONFIG_GLOBAL") {
+        Some(path) => PathBuf::from(path),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".gitconfig"),
+            None => return Ok(Config::default()),
+        },
+    };
+    Config::load(path)
+}
+
+// Looks up a variable in the config of the repository containing the
+// current directory (if any), and then in the user's own config.
+fn config_value(name: &str) -> Result<Option<String>> {
+    if let Ok(repo) = Repository::discover(env::current_dir()?) {
+        if let Some(value) = repo.config()?.get(name) {
+            return Ok(Some(value.to_string()));
+        }
+    }
+    Ok(global_config()?.get(name).map(String::from))
+}
+
+// Returns the editor for commit messages, honouring "core.editor".
+fn commit_editor() -> Result<String> {
+    Ok(editor::editor(config_value("core.editor")?.as_deref()))
+}
+
+// Reads an environment variable which must be set.
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| Error::Fatal(format!("{} is not set", name)))
+}
+
+fn main() {
+    if let Err(err) = trace::init() {
+        eprintln!("warning: {}", err);
+    }
+    let code = match run() {
+        Ok(()) => 0,
+        Err(err) => {
+            if !err.is_silent() {
+                eprintln!("{}: {}", err.prefix(), err);
+            }
+            err.exit_code()
+        }
+    };
+    trace::exit(code);
+    std::process::exit(code);
+}
+
+fn run() -> Result<()> {
     let args = App::new("Rusty git (rit)")
         .version("1.0")
         .author("Sean Klein")
         .subcommands(vec![
             SubCommand::with_name("init")
                 .about("Initializes a git repo")
+                .arg(
+                    Arg::with_name("bare")
+                        .long("bare")
+                        .help("Creates a repository without a workspace"),
+                )
+                .arg(
+                    Arg::with_name("initial-branch")
+                        .short("b")
+                        .long("initial-branch")
+                        .takes_value(true)
+                        .value_name("branch-name")
+                        .help("The branch HEAD refers to; defaults to init.defaultBranch"),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .takes_value(true)
+                        .require_equals(true)
+                        .empty_values(true)
+                        .value_name("template-directory")
+                        .help("The directory whose files are copied into the new repository"),
+                )
+                .arg(
+                    Arg::with_name("shared")
+                        .long("shared")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .value_name("permissions")
+                        .help("Shares the repository: group, all, umask or an octal mode"),
+                )
+                .arg(
+                    Arg::with_name("separate-git-dir")
+                        .long("separate-git-dir")
+                        .takes_value(true)
+                        .value_name("git-dir")
+                        .help("Creates the git directory elsewhere, linked from .git"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .default_value(".")
                         .takes_value(true)
                         .help("Path to git repo which should be initialized"),
                 ),
+            SubCommand::with_name("clone")
+                .about("Clone a repository into a new directory")
+                .arg(
+                    Arg::with_name("local")
+                        .short("l")
+                        .long("local")
+                        .help("Hard links objects from the source rather than copying them"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .required(true)
+                        .help("The path (or file:// URL) of the repository to clone"),
+                )
+                .arg(
+                    Arg::with_name("directory")
+                        .help("Where to clone into; defaults to the name of <repository>"),
+                ),
+            SubCommand::with_name("fetch")
+                .about("Download objects and refs from another repository")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Allows updates which are not fast-forwards"),
+                )
+                .arg(
+                    Arg::with_name("tags")
+                        .short("t")
+                        .long("tags")
+                        .conflicts_with("no-tags")
+                        .help("Fetches every tag"),
+                )
+                .arg(
+                    Arg::with_name("no-tags")
+                        .short("n")
+                        .long("no-tags")
+                        .help("Does not follow tags pointing at fetched objects"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .help("The remote (or URL) to fetch from; defaults to origin"),
+                )
+                .arg(
+                    Arg::with_name("refspecs")
+                        .multiple(true)
+                        .help("Which refs to fetch, and where to store them"),
+                ),
+            SubCommand::with_name("push")
+                .about("Update remote refs along with associated objects")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Allows updates which are not fast-forwards"),
+                )
+                .arg(
+                    Arg::with_name("force-with-lease")
+                        .long("force-with-lease")
+                        .value_name("ref[:expect]")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .multiple(true)
+                        .help("Allows updates which are not fast-forwards if the remote refs are as expected"),
+                )
+                .arg(
+                    Arg::with_name("no-verify")
+                        .long("no-verify")
+                        .help("Bypasses the pre-push hook"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .help("The remote (or URL) to push to; defaults to origin"),
+                )
+                .arg(
+                    Arg::with_name("refspecs")
+                        .multiple(true)
+                        .help("Which refs to push, and where to"),
+                ),
+            SubCommand::with_name("upload-pack")
+                .about("Send objects packed back to git-fetch-pack")
+                .args(&server_mode_args())
+                .arg(
+                    Arg::with_name("directory")
+                        .required(true)
+                        .help("The repository to serve"),
+                ),
+            SubCommand::with_name("receive-pack")
+                .about("Receive what is pushed into the repository")
+                .args(&server_mode_args())
+                .arg(
+                    Arg::with_name("directory")
+                        .required(true)
+                        .help("The repository to update"),
+                ),
+            SubCommand::with_name("http-backend")
+                .about("Server side implementation of Git over HTTP, as a CGI program"),
+            SubCommand::with_name("serve")
+                .about("Serve repositories over smart HTTP")
+                .arg(
+                    Arg::with_name("http")
+                        .long("http")
+                        .takes_value(true)
+                        .value_name("address")
+                        .required(true)
+                        .help("The address to listen on, such as 127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::with_name("directory")
+                        .help("The directory whose repositories to serve"),
+                ),
             SubCommand::with_name("commit")
                 .about("Record changes to the repository")
                 .arg(
@@ -33,29 +324,583 @@ fn main() -> Result<()> {
                         .short("m")
                         .long("message")
                         .takes_value(true)
-                        .help("Uses the provided argument as a commit message"),
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Uses the provided argument as a paragraph of the commit message"),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .short("F")
+                        .long("file")
+                        .takes_value(true)
+                        .conflicts_with("message")
+                        .help("Takes the commit message from a file, or \"-\" for standard input"),
+                )
+                .arg(
+                    Arg::with_name("edit")
+                        .short("e")
+                        .long("edit")
+                        .help("Edits a message given by -m or -F"),
+                )
+                .arg(
+                    Arg::with_name("no-edit")
+                        .long("no-edit")
+                        .conflicts_with("edit")
+                        .help("Uses the message as given, or as amended, without editing it"),
+                )
+                .arg(
+                    Arg::with_name("cleanup")
+                        .long("cleanup")
+                        .takes_value(true)
+                        .possible_values(&["strip", "whitespace", "verbatim", "scissors", "default"])
+                        .help("How the message is cleaned up; defaults to commit.cleanup"),
+                )
+                .arg(
+                    Arg::with_name("no-verify")
+                        .short("n")
+                        .long("no-verify")
+                        .help("Bypasses the pre-commit and commit-msg hooks"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .conflicts_with("paths")
+                        .help("Commits modifications and deletions of tracked files only"),
+                )
+                .arg(
+                    Arg::with_name("amend")
+                        .long("amend")
+                        .help("Replaces the tip of the current branch"),
+                )
+                .arg(
+                    Arg::with_name("reset-author")
+                        .long("reset-author")
+                        .requires("amend")
+                        .help("Takes the author of an amended commit from the committer"),
+                )
+                .arg(
+                    Arg::with_name("allow-empty")
+                        .long("allow-empty")
+                        .help("Allows a commit with the same tree as its parent"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .multiple(true)
+                        .help("Commits only these paths, leaving the rest as in HEAD"),
                 ),
+            SubCommand::with_name("reset")
+                .about("Reset current HEAD to the specified state")
+                .arg(
+                    Arg::with_name("soft")
+                        .long("soft")
+                        .conflicts_with_all(&["mixed", "hard"])
+                        .help("Only moves the current branch"),
+                )
+                .arg(
+                    Arg::with_name("mixed")
+                        .long("mixed")
+                        .conflicts_with("hard")
+                        .help("Resets the index but not the workspace (default)"),
+                )
+                .arg(
+                    Arg::with_name("hard")
+                        .long("hard")
+                        .help("Resets the index and workspace"),
+                )
+                .arg(
+                    Arg::with_name("revision")
+                        .takes_value(true)
+                        .help("Commit to reset to; defaults to HEAD"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .multiple(true)
+                        .last(true)
+                        .help("Paths to reset within the index"),
+                ),
+            SubCommand::with_name("stash")
+                .about("Stash the changes in a dirty working directory away")
+                .subcommands(vec![
+                    SubCommand::with_name("push")
+                        .about("Save local modifications to a new stash entry")
+                        .arg(
+                            Arg::with_name("message")
+                                .short("m")
+                                .long("message")
+                                .takes_value(true)
+                                .help("Describes the stash entry"),
+                        ),
+                    SubCommand::with_name("pop")
+                        .about("Apply a stash entry, then remove it from the list")
+                        .arg(stash_entry_arg()),
+                    SubCommand::with_name("apply")
+                        .about("Apply a stash entry to the working directory")
+                        .arg(stash_entry_arg()),
+                    SubCommand::with_name("list").about("List the stash entries"),
+                    SubCommand::with_name("drop")
+                        .about("Remove a stash entry from the list")
+                        .arg(stash_entry_arg()),
+                    SubCommand::with_name("show")
+                        .about("Show the changes recorded in a stash entry")
+                        .arg(
+                            Arg::with_name("patch")
+                                .short("p")
+                                .long("patch")
+                                .help("Shows the changes as a patch"),
+                        )
+                        .arg(stash_entry_arg()),
+                ]),
+            sequence_subcommand(
+                "cherry-pick",
+                "Apply the changes introduced by some existing commits",
+            )
+            .arg(
+                Arg::with_name("x")
+                    .short("x")
+                    .help("Records the original commit within the message"),
+            ),
+            sequence_subcommand("revert", "Revert some existing commits"),
+            SubCommand::with_name("rebase")
+                .about("Reapply commits on top of another base tip")
+                .arg(
+                    Arg::with_name("continue")
+                        .long("continue")
+                        .conflicts_with_all(&["skip", "abort", "upstream"])
+                        .help("Continues after resolving conflicts"),
+                )
+                .arg(
+                    Arg::with_name("skip")
+                        .long("skip")
+                        .conflicts_with_all(&["abort", "upstream"])
+                        .help("Skips the commit which could not be applied"),
+                )
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .conflicts_with("upstream")
+                        .help("Cancels the rebase, returning to the original branch"),
+                )
+                .arg(
+                    Arg::with_name("interactive")
+                        .short("i")
+                        .long("interactive")
+                        .help("Edits the todo list (with GIT_SEQUENCE_EDITOR) before rebasing"),
+                )
+                .arg(
+                    Arg::with_name("onto")
+                        .long("onto")
+                        .takes_value(true)
+                        .help("The commit to replay onto; defaults to <upstream>"),
+                )
+                .arg(
+                    Arg::with_name("upstream")
+                        .required_unless_one(&["continue", "skip", "abort"])
+                        .help("Commits reachable from here are not replayed"),
+                ),
+            SubCommand::with_name("show")
+                .about("Show various types of objects")
+                .arg(
+                    Arg::with_name("objects")
+                        .multiple(true)
+                        .default_value("HEAD")
+                        .help("Objects to show, such as \"HEAD\" or \"HEAD~1:path\""),
+                ),
+            SubCommand::with_name("fsck")
+                .about("Verify the connectivity and validity of the objects in the database")
+                .arg(
+                    Arg::with_name("no-dangling")
+                        .long("no-dangling")
+                        .help("Omits objects which are unreachable and unreferenced"),
+                ),
+            SubCommand::with_name("prune")
+                .about("Prune all unreachable objects from the object database")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Lists the objects which would be removed"),
+                )
+                .arg(
+                    Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .help("Lists the objects which are removed"),
+                )
+                .arg(
+                    Arg::with_name("expire")
+                        .long("expire")
+                        .takes_value(true)
+                        .help("Only prunes objects older than this; defaults to gc.pruneExpire"),
+                ),
+            SubCommand::with_name("gc")
+                .about("Cleanup unnecessary files")
+                .arg(
+                    Arg::with_name("prune")
+                        .long("prune")
+                        .takes_value(true)
+                        .help("Prunes objects older than this; defaults to gc.pruneExpire"),
+                ),
+            SubCommand::with_name("submodule")
+                .about("Initialize, update or inspect submodules")
+                .subcommands(vec![
+                    SubCommand::with_name("add")
+                        .about("Add the repository at <url> as a submodule")
+                        .arg(
+                            Arg::with_name("url")
+                                .required(true)
+                                .help("The (local) repository to clone"),
+                        )
+                        .arg(
+                            Arg::with_name("path")
+                                .help("Where to clone it; defaults to the name of <url>"),
+                        ),
+                    SubCommand::with_name("init")
+                        .about("Register submodules within .git/config")
+                        .arg(submodule_paths_arg()),
+                    SubCommand::with_name("update")
+                        .about("Clone missing submodules and check out the recorded commits")
+                        .arg(
+                            Arg::with_name("init")
+                                .long("init")
+                                .help("Registers uninitialized submodules first"),
+                        )
+                        .arg(submodule_paths_arg()),
+                    SubCommand::with_name("status")
+                        .about("Show the commit checked out by each submodule")
+                        .arg(submodule_paths_arg()),
+                    SubCommand::with_name("foreach")
+                        .about("Run a shell command within each checked-out submodule")
+                        .arg(
+                            Arg::with_name("command")
+                                .required(true)
+                                .help("The command, run with \"sh -c\""),
+                        ),
+                ]),
         ])
         .get_matches();
 
+    let _span =
+        tracing::info_span!("command", name = args.subcommand_name().unwrap_or("")).entered();
     match args.subcommand() {
         ("init", Some(args)) => {
+            let global = global_config()?;
+            // A bare "--shared" means "group".
+            let shared = match args.value_of("shared") {
+                Some(shared) => shared.parse()?,
+                None if args.is_present("shared") => Shared::Group,
+                None => Shared::Umask,
+            };
+            let template = args
+                .value_of("template")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("GIT_TEMPLATE_DIR").map(PathBuf::from))
+                .or_else(|| global.get("init.templatedir").map(PathBuf::from));
             let args = InitArgs {
                 path: args.value_of("path"),
                 cwd: env::current_dir()?,
+                bare: args.is_present("bare"),
+                initial_branch: args.value_of("initial-branch"),
+                default_branch: global.get("init.defaultbranch").map(String::from),
+                template,
+                shared,
+                separate_git_dir: args.value_of("separate-git-dir"),
             };
             init(args)?;
         }
+        ("clone", Some(args)) => {
+            let args = CloneArgs {
+                cwd: env::current_dir()?,
+                source: args.value_of("repository").unwrap(),
+                directory: args.value_of("directory"),
+                local: args.is_present("local"),
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
+                time: chrono::Local::now().into(),
+            };
+            clone(args)?;
+        }
+        ("fetch", Some(args)) => {
+            let tags = if args.is_present("tags") {
+                Some(fetch::TagMode::All)
+            } else if args.is_present("no-tags") {
+                Some(fetch::TagMode::None)
+            } else {
+                None
+            };
+            let args = FetchArgs {
+                cwd: env::current_dir()?,
+                remote: args.value_of("remote"),
+                refspecs: args
+                    .values_of("refspecs")
+                    .map(|v| v.collect())
+                    .unwrap_or_default(),
+                options: fetch::Options {
+                    force: args.is_present("force"),
+                    tags,
+                },
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
+                time: chrono::Local::now().into(),
+            };
+            fetch(args)?;
+        }
+        ("push", Some(args)) => {
+            // A bare "--force-with-lease" covers every ref pushed.
+            let mut leases = args
+                .values_of("force-with-lease")
+                .map(|v| v.map(|lease| lease.parse()).collect::<Result<Vec<_>>>())
+                .transpose()?
+                .unwrap_or_default();
+            if args.is_present("force-with-lease") && leases.is_empty() {
+                leases.push("".parse()?);
+            }
+            let args = PushArgs {
+                cwd: env::current_dir()?,
+                remote: args.value_of("remote"),
+                refspecs: args
+                    .values_of("refspecs")
+                    .map(|v| v.collect())
+                    .unwrap_or_default(),
+                options: push::Options {
+                    force: args.is_present("force"),
+                    leases,
+                    no_verify: args.is_present("no-verify"),
+                },
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
+                time: chrono::Local::now().into(),
+            };
+            push(args)?;
+        }
+        ("upload-pack", Some(args)) => {
+            let args = UploadPackArgs {
+                cwd: env::current_dir()?,
+                directory: args.value_of("directory").unwrap(),
+                protocol: env::var("GIT_PROTOCOL").ok(),
+                options: server_options(args),
+            };
+            upload_pack(args)?;
+        }
+        ("receive-pack", Some(args)) => {
+            let args = ReceivePackArgs {
+                cwd: env::current_dir()?,
+                directory: args.value_of("directory").unwrap(),
+                options: server_options(args),
+            };
+            receive_pack(args)?;
+        }
+        ("http-backend", Some(_)) => {
+            let args = HttpBackendArgs {
+                vars: env::vars().collect(),
+            };
+            http_backend(args)?;
+        }
+        ("serve", Some(args)) => {
+            let args = ServeArgs {
+                cwd: env::current_dir()?,
+                http: args.value_of("http").unwrap(),
+                directory: args.value_of("directory"),
+            };
+            serve(args)?;
+        }
         ("commit", Some(args)) => {
+            let cleanup = match args.value_of("cleanup") {
+                Some(cleanup) => cleanup.to_string(),
+                None => config_value("commit.cleanup")?.unwrap_or_default(),
+            };
+            // As in git, the template may be relative to the user's home.
+            let template = config_value("commit.template")?.map(|template| {
+                match (template.strip_prefix("~/"), env::var_os("HOME")) {
+                    (Some(path), Some(home)) => PathBuf::from(home).join(path),
+                    _ => PathBuf::from(template),
+                }
+            });
             let args = CommitArgs {
                 cwd: env::current_dir()?,
-                message: args.value_of("message"),
-                name: env::var("GIT_AUTHOR_NAME")?,
-                email: env::var("GIT_AUTHOR_EMAIL")?,
+                message: args
+                    .values_of("message")
+                    .map(|v| v.collect())
+                    .unwrap_or_default(),
+                file: args.value_of("file"),
+                template,
+                edit: if args.is_present("edit") {
+                    Some(true)
+                } else if args.is_present("no-edit") {
+                    Some(false)
+                } else {
+                    None
+                },
+                cleanup: if cleanup.is_empty() {
+                    editor::Cleanup::default()
+                } else {
+                    cleanup.parse()?
+                },
+                comment_char: match config_value("core.commentChar")? {
+                    Some(comment_char) => comment_char.parse()?,
+                    None => editor::CommentChar::default(),
+                },
+                editor: commit_editor()?,
+                no_verify: args.is_present("no-verify"),
+                all: args.is_present("all"),
+                paths: args
+                    .values_of("paths")
+                    .map(|v| v.collect())
+                    .unwrap_or_default(),
+                amend: args.is_present("amend"),
+                reset_author: args.is_present("reset-author"),
+                allow_empty: args.is_present("allow-empty"),
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
                 time: chrono::Local::now().into(),
             };
             commit(args)?;
         }
+        ("reset", Some(args)) => {
+            let mode = if args.is_present("soft") {
+                ResetMode::Soft
+            } else if args.is_present("hard") {
+                ResetMode::Hard
+            } else {
+                ResetMode::Mixed
+            };
+            let args = ResetArgs {
+                cwd: env::current_dir()?,
+                mode,
+                revision: args.value_of("revision"),
+                paths: args
+                    .values_of("paths")
+                    .map(|v| v.collect())
+                    .unwrap_or_default(),
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
+                time: chrono::Local::now().into(),
+            };
+            reset(args)?;
+        }
+        ("stash", Some(args)) => {
+            let entry = |args: &clap::ArgMatches| -> Result<usize> {
+                stash::parse_index(args.value_of("stash").unwrap_or("0"))
+            };
+            let command = match args.subcommand() {
+                ("push", Some(args)) => StashCommand::Push {
+                    message: args.value_of("message"),
+                },
+                ("pop", Some(args)) => StashCommand::Pop {
+                    index: entry(args)?,
+                },
+                ("apply", Some(args)) => StashCommand::Apply {
+                    index: entry(args)?,
+                },
+                ("list", Some(_)) => StashCommand::List,
+                ("drop", Some(args)) => StashCommand::Drop {
+                    index: entry(args)?,
+                },
+                ("show", Some(args)) => StashCommand::Show {
+                    index: entry(args)?,
+                    patch: args.is_present("patch"),
+                },
+                _ => StashCommand::Push { message: None },
+            };
+            let args = StashArgs {
+                cwd: env::current_dir()?,
+                command,
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
+                time: chrono::Local::now().into(),
+            };
+            stash(args)?;
+        }
+        ("cherry-pick", Some(args)) => cherry_pick(sequence_args(args)?)?,
+        ("revert", Some(args)) => revert(sequence_args(args)?)?,
+        ("rebase", Some(args)) => {
+            let command = if args.is_present("continue") {
+                RebaseCommand::Continue
+            } else if args.is_present("skip") {
+                RebaseCommand::Skip
+            } else if args.is_present("abort") {
+                RebaseCommand::Abort
+            } else {
+                RebaseCommand::Start {
+                    upstream: args.value_of("upstream").unwrap_or_default(),
+                    onto: args.value_of("onto"),
+                    interactive: args.is_present("interactive"),
+                }
+            };
+            let args = RebaseArgs {
+                cwd: env::current_dir()?,
+                command,
+                editor: commit_editor()?,
+                sequence_editor: editor::sequence_editor(
+                    config_value("sequence.editor")?.as_deref(),
+                    &commit_editor()?,
+                ),
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
+                time: chrono::Local::now().into(),
+            };
+            rebase(args)?;
+        }
+        ("show", Some(args)) => {
+            let args = ShowArgs {
+                cwd: env::current_dir()?,
+                objects: args.values_of("objects").unwrap().collect(),
+            };
+            show(args)?;
+        }
+        ("fsck", Some(args)) => {
+            let args = FsckArgs {
+                cwd: env::current_dir()?,
+                dangling: !args.is_present("no-dangling"),
+            };
+            fsck(args)?;
+        }
+        ("prune", Some(args)) => {
+            let args = PruneArgs {
+                cwd: env::current_dir()?,
+                expire: args.value_of("expire"),
+                dry_run: args.is_present("dry-run"),
+                verbose: args.is_present("verbose"),
+            };
+            prune(args)?;
+        }
+        ("gc", Some(args)) => {
+            let args = GcArgs {
+                cwd: env::current_dir()?,
+                prune: args.value_of("prune"),
+            };
+            gc(args)?;
+        }
+        ("submodule", Some(args)) => {
+            let command = match args.subcommand() {
+                ("add", Some(args)) => SubmoduleCommand::Add {
+                    url: args.value_of("url").unwrap(),
+                    path: args.value_of("path"),
+                },
+                ("init", Some(args)) => SubmoduleCommand::Init {
+                    paths: submodule_paths(args),
+                },
+                ("update", Some(args)) => SubmoduleCommand::Update {
+                    init: args.is_present("init"),
+                    paths: submodule_paths(args),
+                },
+                ("foreach", Some(args)) => SubmoduleCommand::Foreach {
+                    command: args.value_of("command").unwrap(),
+                },
+                ("status", Some(args)) => SubmoduleCommand::Status {
+                    paths: submodule_paths(args),
+                },
+                _ => SubmoduleCommand::Status { paths: vec![] },
+            };
+            let args = SubmoduleArgs {
+                cwd: env::current_dir()?,
+                command,
+                name: env_var("GIT_AUTHOR_NAME")?,
+                email: env_var("GIT_AUTHOR_EMAIL")?,
+                time: chrono::Local::now().into(),
+            };
+            submodule(args)?;
+        }
         _ => eprintln!("Unknown command, try 'rit help'"),
     }
 
@@ -0,0 +1,484 @@
+use crate::database::{Blob, Database, Storable};
+use crate::diff::{diff, lines, Edit};
+use crate::entry::{Entry, Mode};
+use crate::error::{Error, Result};
+use crate::index::{Index, IndexEntry};
+use crate::workspace::{Workspace, WorkspacePath};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+/// The versions of a path which could not be merged automatically.
+#[derive(Debug)]
+pub struct Conflict {
+    pub base: Option<Entry>,
+    pub ours: Option<Entry>,
+    pub theirs: Option<Entry>,
+}
+
+/// The outcome of a three-way merge of trees.
+pub struct MergeResult {
+    /// The merged contents of each path. Conflicted paths hold the version
+    /// which should be left in the workspace (with conflict markers, for
+    /// content conflicts).
+    pub entries: BTreeMap<PathBuf, Entry>,
+    /// Paths which require manual resolution.
+    pub conflicts: BTreeMap<PathBuf, Conflict>,
+    /// Progress messages, in the style git prints while merging.
+    pub messages: Vec<String>,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Performs a three-way merge of flattened trees (as returned by
+/// `Database::load_tree_list`), applying the changes from `base` to
+/// `theirs` onto `ours`.
+///
+/// Merged blobs (including those containing conflict markers) are written
+/// to the database. The labels are used within conflict markers.
+pub fn merge_trees(
+    database: &Database,
+    base: &BTreeMap<PathBuf, Entry>,
+    ours: &BTreeMap<PathBuf, Entry>,
+    theirs: &BTreeMap<PathBuf, Entry>,
+    ours_label: &str,
+    theirs_label: &str,
+) -> Result<MergeResult> {
+    let mut result = MergeResult {
+        entries: BTreeMap::new(),
+        conflicts: BTreeMap::new(),
+        messages: Vec::new(),
+    };
+
+    let paths: BTreeSet<&PathBuf> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let resolved = if o == t || b == t {
+            o.cloned()
+        } else if b == o {
+            t.cloned()
+        } else {
+            match (o, t) {
+                (Some(o), Some(t)) => {
+                    let (entry, clean) = merge_entries(
+                        database,
+                        path,
+                        b,
+                        (o, t),
+                        (ours_label, theirs_label),
+                        &mut result.messages,
+                    )?;
+                    if !clean {
+                        result
+                            .conflicts
+                            .insert(path.clone(), conflict(b, Some(o), Some(t)));
+                    }
+                    Some(entry)
+                }
+                (Some(o), None) => {
+                    result.messages.push(format!(
+                        "CONFLICT (modify/delete): {} deleted in {} and modified in {}. \
+                         Version {} of {} left in tree.",
+                        path.display(),
+                        theirs_label,
+                        ours_label,
+                        ours_label,
+                        path.display()
+                    ));
+                    result
+                        .conflicts
+                        .insert(path.clone(), conflict(b, Some(o), None));
+                    Some(o.clone())
+                }
+                (None, Some(t)) => {
+                    result.messages.push(format!(
+                        "CONFLICT (modify/delete): {} deleted in {} and modified in {}. \
+                         Version {} of {} left in tree.",
+                        path.display(),
+                        ours_label,
+                        theirs_label,
+                        theirs_label,
+                        path.display()
+                    ));
+                    result
+                        .conflicts
+                        .insert(path.clone(), conflict(b, None, Some(t)));
+                    Some(t.clone())
+                }
+                (None, None) => None,
+            }
+        };
+        if let Some(entry) = resolved {
+            result.entries.insert(path.clone(), entry);
+        }
+    }
+
+    // A path cannot be both a file and a directory.
+    for path in result.entries.keys() {
+        if let Some(parent) = path
+            .ancestors()
+            .skip(1)
+            .find(|parent| result.entries.contains_key(*parent))
+        {
+            return Err(Error::Conflict(format!(
+                "Merge conflict: {} is both a file and a directory containing {}",
+                parent.display(),
+                path.display()
+            )));
+        }
+    }
+    Ok(result)
+}
+
+fn conflict(base: Option<&Entry>, ours: Option<&Entry>, theirs: Option<&Entry>) -> Conflict {
+    Conflict {
+        base: base.cloned(),
+        ours: ours.cloned(),
+        theirs: theirs.cloned(),
+    }
+}
+
+// Merges two modified versions of the same file, returning the merged entry
+// and whether the merge was clean.
+fn merge_entries(
+    database: &Database,
+    path: &std::path::Path,
+    base: Option<&Entry>,
+    (ours, theirs): (&Entry, &Entry),
+    (ours_label, theirs_label): (&str, &str),
+    messages: &mut Vec<String>,
+) -> Result<(Entry, bool)> {
+    // Modes merge like any other value: take whichever side changed.
+    let base_mode = base.map(|b| *b.mode());
+    let (mode, mode_clean) = if ours.mode() == theirs.mode() || base_mode == Some(*theirs.mode()) {
+        (*ours.mode(), true)
+    } else if base_mode == Some(*ours.mode()) {
+        (*theirs.mode(), true)
+    } else {
+        (*ours.mode(), false)
+    };
+    if !mode_clean {
+        messages.push(format!(
+            "CONFLICT (mode): Merge conflict in {}",
+            path.display()
+        ));
+    }
+
+    let (oid, content_clean) = if ours.oid() == theirs.oid() {
+        (ours.oid().clone(), true)
+    } else if [Some(ours), Some(theirs), base]
+        .iter()
+        .any(|entry| entry.map(|entry| *entry.mode()) == Some(Mode::Gitlink))
+    {
+        // Submodule commits cannot be merged here, so keep ours.
+        messages.push(format!(
+            "CONFLICT (submodule): Merge conflict in {}",
+            path.display()
+        ));
+        (ours.oid().clone(), false)
+    } else {
+        messages.push(format!("Auto-merging {}", path.display()));
+        let base_data = match base {
+            Some(base) => database.load_blob(base.oid())?.into_data(),
+            None => Vec::new(),
+        };
+        let ours_data = database.load_blob(ours.oid())?.into_data();
+        let theirs_data = database.load_blob(theirs.oid())?.into_data();
+        let (merged, clean) = merge_content(
+            &base_data,
+            &ours_data,
+            &theirs_data,
+            ours_label,
+            theirs_label,
+        );
+        if !clean {
+            let kind = if base.is_some() { "content" } else { "add/add" };
+            messages.push(format!(
+                "CONFLICT ({}): Merge conflict in {}",
+                kind,
+                path.display()
+            ));
+        }
+        let blob = Blob::new(merged);
+        database.store(&blob)?;
+        (blob.oid(), clean)
+    };
+
+    let entry = Entry::new(WorkspacePath::new(path)?, oid, mode);
+    Ok((entry, mode_clean && content_clean))
+}
+
+/// Performs a line-based three-way merge (diff3) of file contents.
+///
+/// Returns the merged contents, and whether the merge was free of
+/// conflicts. Conflicting regions are surrounded by git-style markers.
+pub fn merge_content(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    ours_label: &str,
+    theirs_label: &str,
+) -> (Vec<u8>, bool) {
+    let (o, a, b) = (lines(base), lines(ours), lines(theirs));
+    let match_a = matches(&o, &a);
+    let match_b = matches(&o, &b);
+
+    let mut merged = Vec::new();
+    let mut clean = true;
+    let (mut oi, mut ai, mut bi) = (0, 0, 0);
+    loop {
+        // Consume lines which are unchanged on both sides.
+        let mut i = 0;
+        while oi + i < o.len() && match_a[oi + i] == Some(ai + i) && match_b[oi + i] == Some(bi + i)
+        {
+            i += 1;
+        }
+        for line in &o[oi..oi + i] {
+            merged.extend_from_slice(line);
+        }
+        oi += i;
+        ai += i;
+        bi += i;
+
+        // Find the next base line which both sides kept, and merge the
+        // regions leading up to it.
+        let next = (oi..o.len()).find_map(|j| match (match_a[j], match_b[j]) {
+            (Some(x), Some(y)) => Some((j, x, y)),
+            _ => None,
+        });
+        let (oj, aj, bj) = next.unwrap_or((o.len(), a.len(), b.len()));
+        let (base_chunk, ours_chunk, theirs_chunk) = (&o[oi..oj], &a[ai..aj], &b[bi..bj]);
+
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            theirs_chunk
+                .iter()
+                .for_each(|l| merged.extend_from_slice(l));
+        } else if theirs_chunk == base_chunk {
+            ours_chunk.iter().for_each(|l| merged.extend_from_slice(l));
+        } else {
+            clean = false;
+            write_conflict(
+                &mut merged,
+                ours_chunk,
+                theirs_chunk,
+                ours_label,
+                theirs_label,
+            );
+        }
+
+        if next.is_none() {
+            break;
+        }
+        oi = oj;
+        ai = aj;
+        bi = bj;
+    }
+    (merged, clean)
+}
+
+// For each line of `base`, returns the index of the matching line within
+// `other`, if the line was retained.
+fn matches(base: &[&[u8]], other: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut result = vec![None; base.len()];
+    for edit in diff(base, other) {
+        if let Edit::Equal(i, j) = edit {
+            result[i] = Some(j);
+        }
+    }
+    result
+}
+
+fn write_conflict(
+    out: &mut Vec<u8>,
+    ours: &[&[u8]],
+    theirs: &[&[u8]],
+    ours_label: &str,
+    theirs_label: &str,
+) {
+    let write_lines = |out: &mut Vec<u8>, chunk: &[&[u8]]| {
+        for line in chunk {
+            out.extend_from_slice(line);
+        }
+        if !out.is_empty() && !out.ends_with(b"\n") {
+            out.push(b'\n');
+        }
+    };
+    out.extend_from_slice(format!("<<<<<<< {}\n", ours_label).as_bytes());
+    write_lines(out, ours);
+    out.extend_from_slice(b"=======\n");
+    write_lines(out, theirs);
+    out.extend_from_slice(format!(">>>>>>> {}\n", theirs_label).as_bytes());
+}
+
+/// Ensures applying `result` over `ours` (the tree the index and workspace
+/// are expected to reflect) won't destroy uncommitted work.
+///
+/// Every path which the merge changes must be unmodified in both the index
+/// and the workspace, and must not be occupied by an untracked file.
+pub fn check_workspace(
+    workspace: &Workspace,
+    threshold: u64,
+    index: &Index,
+    ours: &BTreeMap<PathBuf, Entry>,
+    result: &MergeResult,
+) -> Result<()> {
+    let mut dirty = Vec::new();
+    let mut untracked = Vec::new();
+    let paths: BTreeSet<&PathBuf> = ours.keys().chain(result.entries.keys()).collect();
+    for path in paths {
+        let (before, after) = (ours.get(path), result.entries.get(path));
+        if before == after {
+            continue;
+        }
+        match (index.entry(path), before) {
+            (Some(entry), Some(before)) => {
+                let staged = entry.oid() != before.oid() || entry.mode() != before.mode();
+                if staged || !entry.matches_workspace(workspace, threshold)? {
+                    dirty.push(path.display().to_string());
+                }
+            }
+            (Some(_), None) => dirty.push(path.display().to_string()),
+            (None, _) => {
+                if workspace.full_path(&WorkspacePath::new(path)?).exists() {
+                    untracked.push(path.display().to_string());
+                }
+            }
+        }
+    }
+
+    if !dirty.is_empty() {
+        return Err(Error::Conflict(format!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}\n\
+             Please commit your changes or stash them before you merge.",
+            dirty.join("\n\t")
+        )));
+    }
+    if !untracked.is_empty() {
+        return Err(Error::Conflict(format!(
+            "The following untracked working tree files would be overwritten by merge:\n\t{}\n\
+             Please move or remove them before you merge.",
+            untracked.join("\n\t")
+        )));
+    }
+    Ok(())
+}
+
+/// Writes every path which differs between `ours` and the merge result into
+/// the workspace, removing paths which the merge deleted.
+///
+/// Returns the paths which were written, so that callers may update the
+/// index as they see fit.
+pub fn write_workspace(
+    workspace: &Workspace,
+    database: &Database,
+    ours: &BTreeMap<PathBuf, Entry>,
+    result: &MergeResult,
+) -> Result<Vec<PathBuf>> {
+    for path in ours.keys() {
+        if !result.entries.contains_key(path) {
+            workspace.remove_file(&WorkspacePath::new(path)?)?;
+        }
+    }
+    let mut written = Vec::new();
+    for (path, entry) in &result.entries {
+        if ours.get(path) == Some(entry) {
+            continue;
+        }
+        write_entry(workspace, database, &WorkspacePath::new(path)?, entry)?;
+        written.push(path.clone());
+    }
+    Ok(written)
+}
+
+/// Writes the contents of a tree entry into the workspace at `path`.
+///
+/// Only the directory of a submodule is created; its contents belong to
+/// another repository.
+pub fn write_entry(
+    workspace: &Workspace,
+    database: &Database,
+    path: &WorkspacePath,
+    entry: &Entry,
+) -> Result<()> {
+    if *entry.mode() == Mode::Gitlink {
+        return workspace.write_file(path, &[], Mode::Gitlink);
+    }
+    let blob = database.load_blob(entry.oid())?;
+    workspace.write_file(path, blob.data(), *entry.mode())
+}
+
+/// Records the stages of each conflicted path within the index, replacing
+/// any unconflicted entry.
+pub fn add_conflicts(index: &mut Index, result: &MergeResult) -> Result<()> {
+    for (path, conflict) in &result.conflicts {
+        index.remove(path);
+        for (stage, entry) in [
+            (1, &conflict.base),
+            (2, &conflict.ours),
+            (3, &conflict.theirs),
+        ]
+        .iter()
+        {
+            if let Some(entry) = entry {
+                index.add_conflict(IndexEntry::from_tree_entry_at_stage(entry, *stage)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+        let (merged, clean) = merge_content(
+            base.as_bytes(),
+            ours.as_bytes(),
+            theirs.as_bytes(),
+            "ours",
+            "theirs",
+        );
+        (String::from_utf8(merged).unwrap(), clean)
+    }
+
+    #[test]
+    fn test_merge_content_clean() {
+        assert_eq!(
+            merge("a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n"),
+            ("A\nb\nC\n".to_string(), true)
+        );
+        assert_eq!(
+            merge("a\nb\nc\n", "a\nb\nc\nd\n", "a\nc\n"),
+            ("a\nc\nd\n".to_string(), true)
+        );
+        // Both sides making the same change is not a conflict.
+        assert_eq!(merge("a\n", "b\n", "b\n"), ("b\n".to_string(), true));
+    }
+
+    #[test]
+    fn test_merge_content_conflict() {
+        assert_eq!(
+            merge("a\nb\nc\n", "a\nB\nc\n", "a\nBee\nc\n"),
+            (
+                "a\n<<<<<<< ours\nB\n=======\nBee\n>>>>>>> theirs\nc\n".to_string(),
+                false
+            )
+        );
+        // Missing trailing newlines don't corrupt the markers.
+        assert_eq!(
+            merge("", "ours", "theirs"),
+            (
+                "<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n".to_string(),
+                false
+            )
+        );
+    }
+}
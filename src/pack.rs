@@ -0,0 +1,1183 @@
+//! Reading packs, the format in which objects are transferred between
+//! repositories.
+//!
+//! A pack is a "PACK" header with a version and an object count, followed
+//! by each object (compressed, and possibly stored as a delta against
+//! another object) and a trailing SHA-1 checksum of everything before it.
+//!
+//! Packs kept within a repository, such as those made by `git gc` or
+//! received by a fetch, are read in place through their index (a ".idx"
+//! file). rit writes new objects loosely, so only writes packs to be sent
+//! elsewhere.
+
+use crate::database::{Database, ObjectHasher, ObjectID};
+use crate::error::{Error, Result};
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: &[u8] = b"PACK";
+
+// The lengths of the header and trailing checksum.
+const HEADER_LENGTH: usize = 12;
+const CHECKSUM_LENGTH: usize = 20;
+
+// Object types, as numbered within packs.
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+fn corrupt(message: impl std::fmt::Display) -> Error {
+    Error::CorruptObject(format!("pack: {}", message))
+}
+
+// The object a delta applies to.
+enum Base {
+    // The object starting at an earlier offset within the pack.
+    Offset(usize),
+    // Any object, within the pack or the database.
+    Id(ObjectID),
+}
+
+// A delta waiting for its base object.
+struct Delta {
+    offset: usize,
+    base: Base,
+    data: Vec<u8>,
+}
+
+// An object read from a pack, with any delta resolved.
+struct Resolved {
+    type_name: String,
+    data: Vec<u8>,
+    oid: ObjectID,
+}
+
+// The objects read from a pack so far.
+#[derive(Default)]
+struct Objects {
+    by_offset: HashMap<usize, Resolved>,
+    offsets: HashMap<ObjectID, usize>,
+}
+
+impl Objects {
+    fn insert(&mut self, offset: usize, type_name: String, data: Vec<u8>) {
+        let mut hasher = ObjectHasher::new(&type_name, data.len() as u64);
+        hasher.update(&data);
+        let oid = hasher.finish();
+        self.offsets.insert(oid.clone(), offset);
+        self.by_offset.insert(
+            offset,
+            Resolved {
+                type_name,
+                data,
+                oid,
+            },
+        );
+    }
+}
+
+// Every object within a pack, with its deltas resolved.
+struct Contents {
+    // Where each entry begins and ends, in the order they appear.
+    entries: Vec<(usize, usize)>,
+    objects: Objects,
+    // The delta bases which are not within the pack, but were read from
+    // the database instead.
+    external: Vec<ObjectID>,
+}
+
+impl Contents {
+    // Returns the IDs of the objects, in the order they appear.
+    fn oids(&self) -> Vec<ObjectID> {
+        self.entries
+            .iter()
+            .map(|(offset, _)| self.objects.by_offset[offset].oid.clone())
+            .collect()
+    }
+}
+
+// Reads the variable-length size encoding used by pack entry headers and
+// deltas: seven bits per byte, least significant first, with the top bit
+// set on all but the last byte.
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos).ok_or_else(|| corrupt("truncated size"))?;
+        *pos += 1;
+        if shift > 63 {
+            return Err(corrupt("size overflow"));
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+// The most memory reserved up front for a size read from a pack, which
+// may come from an untrusted remote. Larger objects grow as they are read.
+const MAX_PREALLOC: usize = 1 << 20;
+
+/// Applies a delta to `base`, returning the object it describes.
+///
+/// A delta starts with the sizes of the base and result, followed by
+/// instructions which either copy a range of the base or insert new data.
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    let base_size = read_varint(delta, &mut pos)?;
+    if base_size != base.len() as u64 {
+        return Err(corrupt("delta base size mismatch"));
+    }
+    let size =
+        usize::try_from(read_varint(delta, &mut pos)?).map_err(|_| corrupt("delta too large"))?;
+    let mut result = Vec::with_capacity(size.min(MAX_PREALLOC));
+    let size_mismatch = || corrupt("delta result size mismatch");
+    while let Some(&op) = delta.get(pos) {
+        pos += 1;
+        if op & 0x80 != 0 {
+            // The low four bits select which bytes of the offset follow,
+            // and the next three those of the length.
+            let mut offset = 0usize;
+            let mut length = 0usize;
+            for i in 0..7 {
+                if op & (1 << i) == 0 {
+                    continue;
+                }
+                let byte = *delta.get(pos).ok_or_else(|| corrupt("truncated delta"))?;
+                pos += 1;
+                if i < 4 {
+                    offset |= usize::from(byte) << (8 * i);
+                } else {
+                    length |= usize::from(byte) << (8 * (i - 4));
+                }
+            }
+            if length == 0 {
+                length = 0x10000;
+            }
+            let range = base
+                .get(offset..offset.saturating_add(length))
+                .ok_or_else(|| corrupt("delta copies beyond its base"))?;
+            if range.len() > size - result.len() {
+                return Err(size_mismatch());
+            }
+            result.extend_from_slice(range);
+        } else if op != 0 {
+            let data = delta
+                .get(pos..pos + usize::from(op))
+                .ok_or_else(|| corrupt("truncated delta"))?;
+            if data.len() > size - result.len() {
+                return Err(size_mismatch());
+            }
+            result.extend_from_slice(data);
+            pos += usize::from(op);
+        } else {
+            return Err(corrupt("invalid delta instruction"));
+        }
+    }
+    if result.len() != size {
+        return Err(size_mismatch());
+    }
+    Ok(result)
+}
+
+// The length of the blocks of a delta base which are indexed when looking
+// for copies. Shorter matches are inserted instead.
+const DELTA_BLOCK: usize = 16;
+
+// The most bytes a single delta instruction may insert, or copy.
+const MAX_INSERT: usize = 0x7f;
+const MAX_COPY: usize = 0xff_ffff;
+
+// Appends the variable-length encoding read by `read_varint`.
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Appends instructions inserting `data`.
+fn write_insert(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+// Appends an instruction copying `length` bytes of the base from `offset`,
+// including only the non-zero bytes of each.
+fn write_copy(out: &mut Vec<u8>, offset: usize, length: usize) {
+    let start = out.len();
+    out.push(0x80);
+    for i in 0..4 {
+        let byte = (offset >> (8 * i)) as u8;
+        if byte != 0 {
+            out[start] |= 1 << i;
+            out.push(byte);
+        }
+    }
+    for i in 0..3 {
+        let byte = (length >> (8 * i)) as u8;
+        if byte != 0 {
+            out[start] |= 1 << (4 + i);
+            out.push(byte);
+        }
+    }
+}
+
+/// Creates a delta which `apply_delta` turns from `base` into `target`.
+///
+/// Blocks of the base are indexed, and the target is scanned for them:
+/// matches are extended as far as possible and copied, and everything else
+/// is inserted.
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_varint(&mut delta, base.len() as u64);
+    write_varint(&mut delta, target.len() as u64);
+
+    let mut index: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(DELTA_BLOCK).enumerate() {
+        index.entry(block).or_insert(i * DELTA_BLOCK);
+    }
+
+    let mut pending = 0;
+    let mut pos = 0;
+    while pos + DELTA_BLOCK <= target.len() {
+        let offset = match index.get(&target[pos..pos + DELTA_BLOCK]) {
+            Some(offset) => *offset,
+            None => {
+                pos += 1;
+                continue;
+            }
+        };
+        let length = base[offset..]
+            .iter()
+            .zip(&target[pos..])
+            .take(MAX_COPY)
+            .take_while(|(a, b)| a == b)
+            .count();
+        write_insert(&mut delta, &target[pending..pos]);
+        write_copy(&mut delta, offset, length);
+        pos += length;
+        pending = pos;
+    }
+    write_insert(&mut delta, &target[pending..]);
+    delta
+}
+
+// Returns the name of a non-delta object type.
+fn type_name(kind: u8) -> Result<&'static str> {
+    match kind {
+        OBJ_COMMIT => Ok("commit"),
+        OBJ_TREE => Ok("tree"),
+        OBJ_BLOB => Ok("blob"),
+        OBJ_TAG => Ok("tag"),
+        _ => Err(corrupt(format!("unknown object type {}", kind))),
+    }
+}
+
+// Reads the header of the entry at `pos`, advancing past it to the entry's
+// compressed contents: the type, the size of the contents and, for deltas,
+// the base.
+fn read_entry_header(data: &[u8], pos: &mut usize) -> Result<(u8, u64, Option<Base>)> {
+    let offset = *pos;
+    let first = *data.get(*pos).ok_or_else(|| corrupt("truncated entry"))?;
+    let kind = (first >> 4) & 0x7;
+    let mut size = u64::from(first & 0x0f);
+    *pos += 1;
+    if first & 0x80 != 0 {
+        size |= read_varint(data, pos)? << 4;
+    }
+
+    let base = match kind {
+        OBJ_OFS_DELTA => {
+            // Offsets use a slightly different encoding, in which each
+            // continuation also adds one.
+            let mut byte = *data.get(*pos).ok_or_else(|| corrupt("truncated entry"))?;
+            *pos += 1;
+            let mut distance = usize::from(byte & 0x7f);
+            while byte & 0x80 != 0 {
+                byte = *data.get(*pos).ok_or_else(|| corrupt("truncated entry"))?;
+                *pos += 1;
+                distance = distance
+                    .checked_add(1)
+                    .and_then(|distance| distance.checked_mul(1 << 7))
+                    .ok_or_else(|| corrupt("delta base offset out of range"))?
+                    | usize::from(byte & 0x7f);
+            }
+            let base = offset
+                .checked_sub(distance)
+                .filter(|base| *base >= HEADER_LENGTH)
+                .ok_or_else(|| corrupt("delta base offset out of range"))?;
+            Some(Base::Offset(base))
+        }
+        OBJ_REF_DELTA => {
+            let oid = data
+                .get(*pos..*pos + 20)
+                .ok_or_else(|| corrupt("truncated entry"))?;
+            *pos += 20;
+            Some(Base::Id(ObjectID::from_bytes(oid)?))
+        }
+        _ => None,
+    };
+    Ok((kind, size, base))
+}
+
+// Decompresses the zlib stream at the start of `data`, which must inflate
+// to `size` bytes, returning its contents and compressed length.
+fn inflate(data: &[u8], size: u64) -> Result<(Vec<u8>, usize)> {
+    let mut decoder = ZlibDecoder::new(data);
+    let mut contents = Vec::new();
+    decoder
+        .by_ref()
+        .take(size + 1)
+        .read_to_end(&mut contents)
+        .map_err(|err| corrupt(format!("inflate: {}", err)))?;
+    if contents.len() as u64 != size {
+        return Err(corrupt("object size mismatch"));
+    }
+    Ok((contents, decoder.total_in() as usize))
+}
+
+/// Stores every object within `pack` in `database`, resolving deltas,
+/// and returns their IDs in the order they appear in the pack.
+///
+/// Delta bases may be other objects in the pack or, for packs which refer
+/// to objects by ID, objects already in the database.
+pub fn unpack(database: &Database, pack: &[u8]) -> Result<Vec<ObjectID>> {
+    let contents = read_contents(database, pack)?;
+    for (offset, _) in &contents.entries {
+        let object = &contents.objects.by_offset[offset];
+        database
+            .backend()
+            .write(&object.oid, &object.type_name, &object.data)?;
+    }
+    let oids = contents.oids();
+    tracing::debug!(count = oids.len(), "unpack");
+    Ok(oids)
+}
+
+/// A pack which is ready to be kept within a repository.
+pub struct Indexed {
+    /// The pack, including any delta bases it lacked.
+    pub pack: Vec<u8>,
+    /// The index of the pack, to be stored beside it.
+    pub index: Vec<u8>,
+    /// The IDs of the objects originally within the pack, in the order
+    /// they appear.
+    pub oids: Vec<ObjectID>,
+}
+
+/// Prepares a pack received from another repository to be kept whole,
+/// resolving its deltas to index it.
+///
+/// A "thin" pack, whose deltas refer to bases outside it, is completed by
+/// appending those bases from `database`, as `git index-pack --fix-thin`
+/// does, since packs kept within a repository are read on their own.
+pub fn index(database: &Database, pack: &[u8]) -> Result<Indexed> {
+    let contents = read_contents(database, pack)?;
+    let mut completed = pack[..pack.len() - CHECKSUM_LENGTH].to_vec();
+    let mut entries = Vec::with_capacity(contents.entries.len() + contents.external.len());
+    for (offset, end) in &contents.entries {
+        let oid = contents.objects.by_offset[offset].oid.clone();
+        entries.push((oid, crc32(&pack[*offset..*end]), *offset as u64));
+    }
+    for oid in &contents.external {
+        let offset = completed.len();
+        let (type_name, data) = database.read_raw(oid)?;
+        write_entry_header(&mut completed, kind(&type_name)?, data.len() as u64);
+        deflate(&mut completed, &data)?;
+        entries.push((oid.clone(), crc32(&completed[offset..]), offset as u64));
+    }
+    let count = u32::try_from(entries.len()).map_err(|_| corrupt("too many objects"))?;
+    completed[8..HEADER_LENGTH].copy_from_slice(&count.to_be_bytes());
+    let checksum = Sha1::digest(&completed);
+    completed.extend_from_slice(&checksum);
+
+    entries.sort();
+    tracing::debug!(count, thin = contents.external.len(), "index pack");
+    Ok(Indexed {
+        index: write_index(&entries, &checksum),
+        pack: completed,
+        oids: contents.oids(),
+    })
+}
+
+// Reads every object within a pack, resolving deltas. Bases which are not
+// within the pack are read from `database`.
+fn read_contents(database: &Database, pack: &[u8]) -> Result<Contents> {
+    if pack.len() < HEADER_LENGTH + CHECKSUM_LENGTH || !pack.starts_with(SIGNATURE) {
+        return Err(corrupt("bad signature"));
+    }
+    let (body, checksum) = pack.split_at(pack.len() - CHECKSUM_LENGTH);
+    if Sha1::digest(body).as_slice() != checksum {
+        return Err(corrupt("checksum mismatch"));
+    }
+    let word = |i: usize| u32::from_be_bytes([body[i], body[i + 1], body[i + 2], body[i + 3]]);
+    let version = word(4);
+    if version != 2 && version != 3 {
+        return Err(corrupt(format!("unsupported version {}", version)));
+    }
+    let count = word(8) as usize;
+
+    // Objects are kept in memory, by offset, as they may be delta bases.
+    let mut contents = Contents {
+        entries: Vec::with_capacity(count.min(MAX_PREALLOC)),
+        objects: Objects::default(),
+        external: Vec::new(),
+    };
+    let objects = &mut contents.objects;
+    let mut deltas = Vec::new();
+
+    let mut pos = HEADER_LENGTH;
+    for _ in 0..count {
+        let offset = pos;
+        let (kind, size, base) = read_entry_header(body, &mut pos)?;
+        let (data, length) = inflate(&body[pos..], size)?;
+        pos += length;
+        contents.entries.push((offset, pos));
+        match base {
+            Some(base) => deltas.push(Delta { offset, base, data }),
+            None => objects.insert(offset, type_name(kind)?.to_string(), data),
+        }
+    }
+    if pos != body.len() {
+        return Err(corrupt("trailing data"));
+    }
+
+    // Deltas may depend on other deltas, in any order, so are resolved
+    // until no more can be.
+    while !deltas.is_empty() {
+        let mut unresolved = Vec::new();
+        let pending = deltas.len();
+        for delta in deltas {
+            let base_offset = match &delta.base {
+                Base::Offset(offset) => Some(*offset),
+                Base::Id(oid) => objects.offsets.get(oid).copied(),
+            };
+            let resolved = match base_offset.and_then(|offset| objects.by_offset.get(&offset)) {
+                Some(base) => Some((
+                    base.type_name.clone(),
+                    apply_delta(&base.data, &delta.data)?,
+                )),
+                None => match &delta.base {
+                    Base::Id(oid) if database.contains(oid)? => {
+                        let (type_name, base) = database.read_raw(oid)?;
+                        if !contents.external.contains(oid) {
+                            contents.external.push(oid.clone());
+                        }
+                        Some((type_name, apply_delta(&base, &delta.data)?))
+                    }
+                    _ => None,
+                },
+            };
+            match resolved {
+                Some((type_name, data)) => objects.insert(delta.offset, type_name, data),
+                None => unresolved.push(delta),
+            }
+        }
+        if unresolved.len() == pending {
+            return Err(corrupt(format!("{} unresolved deltas", pending)));
+        }
+        deltas = unresolved;
+    }
+    Ok(contents)
+}
+
+// Reads from a stream, keeping a copy of everything consumed, so that a
+// pack can be taken from a stream which continues after it.
+struct Recorder<'a, R> {
+    inner: &'a mut R,
+    recorded: Vec<u8>,
+}
+
+impl<'a, R: BufRead> Read for Recorder<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl<'a, R: BufRead> BufRead for Recorder<'a, R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        // The buffer was filled by the caller, so this does not read.
+        if let Ok(buffer) = self.inner.fill_buf() {
+            self.recorded.extend_from_slice(&buffer[..amount]);
+        }
+        self.inner.consume(amount);
+    }
+}
+
+/// Reads a single pack from `reader`, leaving anything after it unread,
+/// and returns it whole to be unpacked.
+pub fn read(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let truncated = |_| corrupt("truncated pack");
+    let mut recorder = Recorder {
+        inner: reader,
+        recorded: Vec::new(),
+    };
+    let mut header = [0; HEADER_LENGTH];
+    recorder.read_exact(&mut header).map_err(truncated)?;
+    if !header.starts_with(SIGNATURE) {
+        return Err(corrupt("bad signature"));
+    }
+    let count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+    let mut byte = [0; 1];
+    for _ in 0..count {
+        recorder.read_exact(&mut byte).map_err(truncated)?;
+        let kind = (byte[0] >> 4) & 0x7;
+        let mut size = u64::from(byte[0] & 0x0f);
+        let mut shift = 4;
+        while byte[0] & 0x80 != 0 {
+            recorder.read_exact(&mut byte).map_err(truncated)?;
+            if shift > 63 {
+                return Err(corrupt("size overflow"));
+            }
+            size |= u64::from(byte[0] & 0x7f) << shift;
+            shift += 7;
+        }
+        match kind {
+            OBJ_OFS_DELTA => loop {
+                recorder.read_exact(&mut byte).map_err(truncated)?;
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+            },
+            OBJ_REF_DELTA => recorder.read_exact(&mut [0; 20]).map_err(truncated)?,
+            _ => (),
+        }
+        // Reading to the end of the stream consumes its checksum too.
+        let mut decoder = ZlibDecoder::new(&mut recorder).take(size + 1);
+        let inflated = std::io::copy(&mut decoder, &mut std::io::sink())
+            .map_err(|err| corrupt(format!("inflate: {}", err)))?;
+        if inflated != size {
+            return Err(corrupt("object size mismatch"));
+        }
+    }
+    recorder
+        .read_exact(&mut [0; CHECKSUM_LENGTH])
+        .map_err(truncated)?;
+    Ok(recorder.recorded)
+}
+
+// Returns the number of a non-delta object type within packs.
+fn kind(type_name: &str) -> Result<u8> {
+    match type_name {
+        "commit" => Ok(OBJ_COMMIT),
+        "tree" => Ok(OBJ_TREE),
+        "blob" => Ok(OBJ_BLOB),
+        "tag" => Ok(OBJ_TAG),
+        _ => Err(corrupt(format!("unknown type {}", type_name))),
+    }
+}
+
+// Appends the compressed contents of an entry.
+fn deflate(out: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    let mut encoder = ZlibEncoder::new(out, Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+// Returns the CRC-32 of an entry, as recorded in indexes.
+fn crc32(entry: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(entry);
+    crc.sum()
+}
+
+// Appends the header of a pack entry: its type and size, four bits of
+// which fit in the first byte.
+fn write_entry_header(out: &mut Vec<u8>, kind: u8, size: u64) {
+    let mut byte = (kind << 4) | (size & 0x0f) as u8;
+    let rest = size >> 4;
+    if rest > 0 {
+        byte |= 0x80;
+    }
+    out.push(byte);
+    if rest > 0 {
+        write_varint(out, rest);
+    }
+}
+
+/// Writes a pack of `objects`.
+///
+/// Objects with an entry in `bases` are stored as deltas against that
+/// base, when smaller. Bases are not included in the pack, which makes it
+/// "thin": the receiver must already have them.
+pub fn write(
+    database: &Database,
+    objects: &[ObjectID],
+    bases: &HashMap<ObjectID, ObjectID>,
+) -> Result<Vec<u8>> {
+    let mut pack = Vec::new();
+    pack.extend_from_slice(SIGNATURE);
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    let count = u32::try_from(objects.len()).map_err(|_| corrupt("too many objects"))?;
+    pack.extend_from_slice(&count.to_be_bytes());
+
+    let mut deltas = 0;
+    for oid in objects {
+        let (type_name, data) = database.read_raw(oid)?;
+        let kind = kind(&type_name)?;
+        let delta = match bases.get(oid) {
+            Some(base) => {
+                let (_, base_data) = database.read_raw(base)?;
+                let delta = create_delta(&base_data, &data);
+                Some((base, delta)).filter(|(_, delta)| delta.len() < data.len() / 2)
+            }
+            None => None,
+        };
+        let contents = match &delta {
+            Some((base, delta)) => {
+                deltas += 1;
+                write_entry_header(&mut pack, OBJ_REF_DELTA, delta.len() as u64);
+                pack.extend_from_slice(base.as_bytes());
+                delta
+            }
+            None => {
+                write_entry_header(&mut pack, kind, data.len() as u64);
+                &data
+            }
+        };
+        deflate(&mut pack, contents)?;
+    }
+    let checksum = Sha1::digest(&pack);
+    pack.extend_from_slice(&checksum);
+    tracing::debug!(count, deltas, size = pack.len(), "write pack");
+    Ok(pack)
+}
+
+// The start of a version 2 pack index, before its version.
+const INDEX_SIGNATURE: &[u8] = b"\xfftOc";
+
+// The length of the fan-out table at the start of an index: the number of
+// objects whose IDs begin with each byte or less.
+const FANOUT_LENGTH: usize = 256 * 4;
+
+// Set on offsets within an index which are instead positions within its
+// table of 64-bit offsets.
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+/// The index of a pack (a ".idx" file), giving the offset and CRC-32 of
+/// each object within it.
+///
+/// Version 2 indexes, as written by git since 1.5.2, are supported: the
+/// header and fan-out table are followed by the sorted IDs, their CRC-32s,
+/// their offsets and the pack's checksum, then a checksum of the index.
+#[derive(Debug)]
+pub struct Index {
+    oids: Vec<ObjectID>,
+    crcs: Vec<u32>,
+    offsets: Vec<u64>,
+    pack_checksum: Vec<u8>,
+}
+
+impl Index {
+    /// Parses an index, verifying its checksum.
+    pub fn parse(data: &[u8]) -> Result<Index> {
+        let corrupt = |message| Error::CorruptObject(format!("pack index: {}", message));
+        if data.len() < 8 + FANOUT_LENGTH + 2 * CHECKSUM_LENGTH
+            || !data.starts_with(INDEX_SIGNATURE)
+        {
+            return Err(corrupt("bad signature"));
+        }
+        let (body, checksum) = data.split_at(data.len() - CHECKSUM_LENGTH);
+        if Sha1::digest(body).as_slice() != checksum {
+            return Err(corrupt("checksum mismatch"));
+        }
+        let word = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        if word(4) != 2 {
+            return Err(corrupt("unsupported version"));
+        }
+        let count = word(8 + FANOUT_LENGTH - 4) as usize;
+        let oids_start = 8 + FANOUT_LENGTH;
+        let crcs_start = oids_start + count * 20;
+        let offsets_start = crcs_start + count * 4;
+        let large_start = offsets_start + count * 4;
+        let trailer_start = body.len() - CHECKSUM_LENGTH;
+        if large_start > trailer_start || !(trailer_start - large_start).is_multiple_of(8) {
+            return Err(corrupt("truncated"));
+        }
+
+        let mut index = Index {
+            oids: Vec::with_capacity(count),
+            crcs: Vec::with_capacity(count),
+            offsets: Vec::with_capacity(count),
+            pack_checksum: body[trailer_start..].to_vec(),
+        };
+        for i in 0..count {
+            let oid = &data[oids_start + i * 20..oids_start + (i + 1) * 20];
+            index.oids.push(ObjectID::from_bytes(oid)?);
+            index.crcs.push(word(crcs_start + i * 4));
+            let offset = word(offsets_start + i * 4);
+            let offset = if offset & LARGE_OFFSET == 0 {
+                u64::from(offset)
+            } else {
+                let at = large_start + (offset & !LARGE_OFFSET) as usize * 8;
+                let bytes = data
+                    .get(at..at + 8)
+                    .filter(|_| at + 8 <= trailer_start)
+                    .ok_or_else(|| corrupt("large offset out of range"))?;
+                u64::from_be_bytes(<[u8; 8]>::try_from(bytes).expect("eight bytes"))
+            };
+            index.offsets.push(offset);
+        }
+        if index.oids.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(corrupt("object IDs not sorted"));
+        }
+        Ok(index)
+    }
+
+    /// Returns the IDs of the objects within the pack, sorted.
+    pub fn oids(&self) -> &[ObjectID] {
+        &self.oids
+    }
+
+    /// Returns the checksum which the indexed pack ends with.
+    pub fn pack_checksum(&self) -> &[u8] {
+        &self.pack_checksum
+    }
+
+    // Returns the position of an object within the index.
+    fn position(&self, oid: &ObjectID) -> Option<usize> {
+        self.oids.binary_search(oid).ok()
+    }
+}
+
+// Writes a version 2 index of a pack's entries, given as their ID, CRC-32
+// and offset, sorted by ID.
+fn write_index(entries: &[(ObjectID, u32, u64)], pack_checksum: &[u8]) -> Vec<u8> {
+    let mut index = INDEX_SIGNATURE.to_vec();
+    index.extend_from_slice(&2u32.to_be_bytes());
+    let mut count = 0;
+    for byte in 0..=255u8 {
+        count += entries[count..]
+            .iter()
+            .take_while(|(oid, _, _)| oid.as_bytes()[0] == byte)
+            .count();
+        index.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+    for (oid, _, _) in entries {
+        index.extend_from_slice(oid.as_bytes());
+    }
+    for (_, crc, _) in entries {
+        index.extend_from_slice(&crc.to_be_bytes());
+    }
+    // Offsets which do not fit in 31 bits refer to a table of 64-bit
+    // offsets after the others.
+    let mut large = Vec::new();
+    for (_, _, offset) in entries {
+        let offset = match u32::try_from(*offset) {
+            Ok(offset) if offset & LARGE_OFFSET == 0 => offset,
+            _ => {
+                large.extend_from_slice(&offset.to_be_bytes());
+                LARGE_OFFSET | (large.len() / 8 - 1) as u32
+            }
+        };
+        index.extend_from_slice(&offset.to_be_bytes());
+    }
+    index.extend_from_slice(&large);
+    index.extend_from_slice(pack_checksum);
+    let checksum = Sha1::digest(&index);
+    index.extend_from_slice(&checksum);
+    index
+}
+
+/// A pack kept within a repository, alongside its index, from which
+/// objects are read individually.
+#[derive(Debug)]
+pub struct Pack {
+    path: PathBuf,
+    data: Vec<u8>,
+    index: Index,
+}
+
+impl Pack {
+    /// Opens the pack at `path` (ending ".pack") and the index beside it
+    /// (ending ".idx").
+    pub fn open(path: &Path) -> Result<Pack> {
+        let index = Index::parse(&std::fs::read(path.with_extension("idx"))?)?;
+        let data = std::fs::read(path)?;
+        if data.len() < HEADER_LENGTH + CHECKSUM_LENGTH || !data.starts_with(SIGNATURE) {
+            return Err(corrupt("bad signature"));
+        }
+        Ok(Pack {
+            path: path.to_path_buf(),
+            data,
+            index,
+        })
+    }
+
+    /// Returns the location of the pack.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the pack's index.
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Returns true if the pack holds the object `oid`.
+    pub fn contains(&self, oid: &ObjectID) -> bool {
+        self.index.position(oid).is_some()
+    }
+
+    /// Reads the type name and contents of an object, or None if the pack
+    /// does not hold it.
+    pub fn read(&self, oid: &ObjectID) -> Result<Option<(String, Vec<u8>)>> {
+        match self.index.position(oid) {
+            Some(position) => self.read_at(self.index.offsets[position]).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Reads the object at an offset, following the chain of deltas to its
+    // base and then applying each of them in turn.
+    fn read_at(&self, offset: u64) -> Result<(String, Vec<u8>)> {
+        let body = &self.data[..self.data.len() - CHECKSUM_LENGTH];
+        let mut offset = usize::try_from(offset).map_err(|_| corrupt("offset out of range"))?;
+        let mut deltas: Vec<Vec<u8>> = Vec::new();
+        loop {
+            if offset < HEADER_LENGTH || offset >= body.len() {
+                return Err(corrupt("offset out of range"));
+            }
+            // Chains longer than the pack must loop.
+            if deltas.len() > self.index.oids.len() {
+                return Err(corrupt("delta chain loops"));
+            }
+            let mut pos = offset;
+            let (kind, size, base) = read_entry_header(body, &mut pos)?;
+            let (data, _) = inflate(&body[pos..], size)?;
+            offset = match base {
+                None => {
+                    let mut object = (type_name(kind)?.to_string(), data);
+                    for delta in deltas.iter().rev() {
+                        object.1 = apply_delta(&object.1, delta)?;
+                    }
+                    return Ok(object);
+                }
+                Some(Base::Offset(base)) => base,
+                // Packs kept in a repository are never thin.
+                Some(Base::Id(oid)) => match self.index.position(&oid) {
+                    Some(position) => usize::try_from(self.index.offsets[position])
+                        .map_err(|_| corrupt("offset out of range"))?,
+                    None => return Err(corrupt(format!("missing delta base {}", oid.as_str()))),
+                },
+            };
+            deltas.push(data);
+        }
+    }
+
+    /// Checks the pack against its checksum and index, returning a
+    /// description of each problem found, as `git fsck` reports them.
+    ///
+    /// Each entry's CRC-32 must match the index, and each object must hash
+    /// to the ID the index gives it.
+    pub fn verify(&self) -> Vec<String> {
+        let name = self.path.display();
+        let (body, checksum) = self.data.split_at(self.data.len() - CHECKSUM_LENGTH);
+        let mut problems = Vec::new();
+        if Sha1::digest(body).as_slice() != checksum {
+            problems.push(format!("{} SHA1 checksum mismatch", name));
+        }
+        if checksum != self.index.pack_checksum.as_slice() {
+            problems.push(format!("packfile {} does not match index", name));
+            return problems;
+        }
+
+        // Each entry ends where the next begins.
+        let mut ends: Vec<u64> = self.index.offsets.clone();
+        ends.push(body.len() as u64);
+        ends.sort_unstable();
+        for (i, oid) in self.index.oids.iter().enumerate() {
+            let offset = self.index.offsets[i];
+            let end = ends[ends.partition_point(|end| *end <= offset)..]
+                .first()
+                .copied()
+                .unwrap_or(offset);
+            let entry = usize::try_from(offset)
+                .ok()
+                .zip(usize::try_from(end).ok())
+                .and_then(|(start, end)| body.get(start..end));
+            if entry.is_none_or(|entry| crc32(entry) != self.index.crcs[i]) {
+                problems.push(format!(
+                    "index CRC mismatch for object {} from {} at offset {}",
+                    oid.as_str(),
+                    name,
+                    offset
+                ));
+                continue;
+            }
+            let valid = match self.read_at(offset) {
+                Ok((type_name, data)) => {
+                    let mut hasher = ObjectHasher::new(&type_name, data.len() as u64);
+                    hasher.update(&data);
+                    hasher.finish() == *oid
+                }
+                Err(_) => false,
+            };
+            if !valid {
+                problems.push(format!("packed {} from {} is corrupt", oid.as_str(), name));
+            }
+        }
+        problems
+    }
+}
+
+/// Lists the packs within `dir` (such as ".git/objects/pack") which have
+/// an index, sorted by name.
+pub fn list(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "pack")
+            && path.with_extension("idx").is_file()
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Blob, Storable};
+    use crate::test_helpers::git_with_input;
+    use anyhow::Result;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_apply_delta() -> Result<()> {
+        let base = b"hello, world";
+        // Base size 12, result size 11: copy "hello" then insert " rit!".
+        let delta = [
+            12,
+            10,
+            0x80 | 0x01 | 0x10,
+            0,
+            5,
+            5,
+            b' ',
+            b'r',
+            b'i',
+            b't',
+            b'!',
+        ];
+        assert_eq!(apply_delta(base, &delta[..])?, b"hello rit!");
+        assert!(apply_delta(b"short", &delta[..]).is_err());
+        // Copies must lie within the base.
+        assert!(apply_delta(base, &[12, 20, 0x80 | 0x01 | 0x10, 4, 20][..]).is_err());
+        // The result size is checked rather than trusted, whether it is
+        // too large (here 2^62) or too small.
+        let mut huge = vec![12];
+        write_varint(&mut huge, 1 << 62);
+        huge.extend_from_slice(&delta[2..]);
+        assert!(apply_delta(base, &huge).is_err());
+        assert!(apply_delta(base, &[12, 4, 0x80 | 0x01 | 0x10, 0, 5][..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_delta() -> Result<()> {
+        let base: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let mut target = b"prefix".to_vec();
+        target.extend_from_slice(&base[1000..90_000]);
+        target.extend_from_slice(b"middle");
+        target.extend_from_slice(&base[..40]);
+        let delta = create_delta(&base, &target);
+        assert!(delta.len() < 100);
+        assert_eq!(apply_delta(&base, &delta)?, target);
+
+        // Unrelated contents are inserted whole.
+        let delta = create_delta(b"abc", b"a different object");
+        assert_eq!(apply_delta(b"abc", &delta)?, b"a different object");
+        assert_eq!(apply_delta(b"", &create_delta(b"", b""))?, b"");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_thin() -> Result<()> {
+        let sender = Database::in_memory();
+        let base = Blob::new(b"shared contents\n".repeat(100));
+        let mut changed = base.data().to_vec();
+        changed.extend_from_slice(b"one more line\n");
+        let changed = Blob::new(changed);
+        let other = Blob::new(b"other".to_vec());
+        for blob in [&base, &changed, &other].iter() {
+            sender.store(*blob)?;
+        }
+
+        let objects = vec![changed.oid(), other.oid()];
+        let bases = vec![(changed.oid(), base.oid())].into_iter().collect();
+        let pack = write(&sender, &objects, &bases)?;
+        assert!(pack.len() < 100);
+
+        // The receiver needs the base to resolve the delta.
+        let receiver = Database::in_memory();
+        assert!(unpack(&receiver, &pack).is_err());
+        receiver.store(&base)?;
+        assert_eq!(unpack(&receiver, &pack)?, objects);
+        assert_eq!(receiver.load_blob(&changed.oid())?.data(), changed.data());
+
+        // Packs are completed with their bases to be kept.
+        let indexed = index(&receiver, &pack)?;
+        assert_eq!(indexed.oids, objects);
+        let dir = TempDir::new("test_write_thin")?;
+        let path = dir.path().join("pack-thin.pack");
+        std::fs::write(&path, &indexed.pack)?;
+        std::fs::write(path.with_extension("idx"), &indexed.index)?;
+        let kept = Pack::open(&path)?;
+        assert_eq!(kept.index().oids().len(), 3);
+        assert_eq!(
+            kept.read(&changed.oid())?.unwrap().1,
+            changed.data().to_vec()
+        );
+        assert_eq!(kept.read(&base.oid())?.unwrap().1, base.data().to_vec());
+        assert!(kept.verify().is_empty());
+        let status = Command::new("git")
+            .args(["verify-pack", path.to_str().unwrap()])
+            .status()?;
+        assert!(status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack() -> Result<()> {
+        let dir = TempDir::new("test_pack")?;
+        let path = dir.path();
+        git_with_input(path, &["init", "-q"], b"");
+        // Similar versions of a file, so that git stores deltas.
+        let mut contents = String::new();
+        for i in 0..20 {
+            contents.push_str(&format!("line {} of a file which grows over time\n", i));
+            std::fs::write(path.join("file.txt"), &contents)?;
+            git_with_input(path, &["add", "file.txt"], b"");
+            git_with_input(path, &["commit", "-q", "-m", &format!("commit {}", i)], b"");
+        }
+        git_with_input(path, &["tag", "-a", "v1", "-m", "v1"], b"");
+        let listed = git_with_input(path, &["rev-list", "--objects", "--all"], b"");
+        let listed = String::from_utf8(listed)?;
+        let expected: Vec<ObjectID> = listed
+            .lines()
+            .map(|line| line.split(' ').next().unwrap().parse())
+            .collect::<Result<_, _>>()?;
+        let input = listed
+            .lines()
+            .map(|line| format!("{}\n", line.split(' ').next().unwrap()))
+            .collect::<String>();
+
+        for args in [
+            &["pack-objects", "--stdout", "--delta-base-offset"][..],
+            &["pack-objects", "--stdout"][..],
+        ]
+        .iter()
+        {
+            let pack = git_with_input(path, args, input.as_bytes());
+            // Packs are read from streams which continue after them.
+            let mut stream = pack.clone();
+            stream.extend_from_slice(b"0000");
+            let mut reader = std::io::BufReader::with_capacity(64, stream.as_slice());
+            assert_eq!(read(&mut reader)?, pack);
+            let mut rest = vec![];
+            reader.read_to_end(&mut rest)?;
+            assert_eq!(rest, b"0000");
+
+            let database = Database::in_memory();
+            let mut oids = unpack(&database, &pack)?;
+            oids.sort();
+            let mut sorted = expected.clone();
+            sorted.sort();
+            assert_eq!(oids, sorted);
+            for oid in &expected {
+                let contents = git_with_input(path, &["cat-file", "-p", &oid.as_str()], b"");
+                let (type_name, data) = database.read_raw(oid)?;
+                if type_name != "tree" {
+                    assert_eq!(data, contents);
+                }
+            }
+
+            let mut corrupted = pack.clone();
+            let middle = corrupted.len() / 2;
+            corrupted[middle] ^= 0xff;
+            assert!(unpack(&Database::in_memory(), &corrupted).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_file() -> Result<()> {
+        let dir = TempDir::new("test_pack_file")?;
+        let path = dir.path();
+        git_with_input(path, &["init", "-q"], b"");
+        let mut contents = String::new();
+        for i in 0..20 {
+            contents.push_str(&format!("line {} of a file which grows over time\n", i));
+            std::fs::write(path.join("file.txt"), &contents)?;
+            git_with_input(path, &["add", "file.txt"], b"");
+            git_with_input(path, &["commit", "-q", "-m", &format!("commit {}", i)], b"");
+        }
+        let listed = git_with_input(path, &["rev-list", "--objects", "--all"], b"");
+        let listed = String::from_utf8(listed)?;
+        let input = listed
+            .lines()
+            .map(|line| format!("{}\n", line.split(' ').next().unwrap()))
+            .collect::<String>();
+
+        for (prefix, args) in [("offset", &["--delta-base-offset"][..]), ("ref", &[][..])].iter() {
+            let prefix = path.join(prefix);
+            let prefix = prefix.to_str().unwrap();
+            let mut pack_args = vec!["pack-objects", "-q"];
+            pack_args.extend_from_slice(args);
+            pack_args.push(prefix);
+            let name = git_with_input(path, &pack_args, input.as_bytes());
+            let name = String::from_utf8(name)?;
+            let pack_path = PathBuf::from(format!("{}-{}.pack", prefix, name.trim()));
+
+            let pack = Pack::open(&pack_path)?;
+            assert_eq!(pack.index().oids().len(), listed.lines().count());
+            for line in listed.lines() {
+                let hex = &line[..40];
+                let kind = String::from_utf8(git_with_input(path, &["cat-file", "-t", hex], b""))?;
+                let contents = git_with_input(path, &["cat-file", kind.trim(), hex], b"");
+                let (type_name, data) = pack.read(&hex.parse()?)?.unwrap();
+                assert_eq!(type_name, kind.trim());
+                assert_eq!(data, contents);
+            }
+            assert!(pack.read(&Blob::new(b"absent".to_vec()).oid())?.is_none());
+            assert!(pack.verify().is_empty());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,768 @@
+//! The client side of git's wire protocol.
+//!
+//! Messages are framed as "pkt-lines": four hexadecimal digits giving the
+//! length of the line (including the digits themselves), followed by its
+//! contents. The special lengths 0000, 0001 and 0002 mark the end of a
+//! message, a section within it and a stateless response respectively.
+//!
+//! Fetches use protocol version 2, served by `git-upload-pack`. Pushes use
+//! the original protocol, served by `git-receive-pack`, as version 2 does
+//! not cover them. Either server is run as a local process with the
+//! repository path as its argument; the ssh and file:// transports work the
+//! same way, only differing in how the process is started.
+
+use crate::database::ObjectID;
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+
+/// The program run to serve fetches, unless configured otherwise.
+pub const DEFAULT_UPLOAD_PACK: &str = "git-upload-pack";
+
+/// The program run to receive pushes, unless configured otherwise.
+pub const DEFAULT_RECEIVE_PACK: &str = "git-receive-pack";
+
+// The object ID standing for a missing reference.
+pub(crate) const NULL_OID: &str = "0000000000000000000000000000000000000000";
+
+// The largest pkt-line, including its length.
+pub(crate) const MAX_PACKET_LENGTH: usize = 65520;
+
+// The number of "have" lines sent in each round of negotiation.
+const HAVES_PER_ROUND: usize = 32;
+
+// The sideband channels within the packfile section.
+pub(crate) const BAND_DATA: u8 = 1;
+pub(crate) const BAND_PROGRESS: u8 = 2;
+pub(crate) const BAND_ERROR: u8 = 3;
+
+/// The agent capability's value, which identifies this implementation.
+pub(crate) fn agent() -> String {
+    format!("rit/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// A single pkt-line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet {
+    Data(Vec<u8>),
+    /// "0000", which ends a message.
+    Flush,
+    /// "0001", which separates sections of a message.
+    Delimiter,
+    /// "0002", which ends a response in stateless connections.
+    ResponseEnd,
+}
+
+pub(crate) fn protocol_error(message: impl std::fmt::Display) -> Error {
+    Error::Fatal(format!("protocol error: {}", message))
+}
+
+/// Reads a single pkt-line from `reader`.
+pub fn read_packet(reader: &mut impl Read) -> Result<Packet> {
+    let mut length = [0; 4];
+    reader.read_exact(&mut length).map_err(|err| {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            Error::Fatal("the remote end hung up unexpectedly".to_string())
+        } else {
+            err.into()
+        }
+    })?;
+    let length = std::str::from_utf8(&length)
+        .ok()
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+        .ok_or_else(|| protocol_error("bad line length character"))?;
+    match length {
+        0 => Ok(Packet::Flush),
+        1 => Ok(Packet::Delimiter),
+        2 => Ok(Packet::ResponseEnd),
+        3 => Err(protocol_error(format!("bad line length {}", length))),
+        _ if length > MAX_PACKET_LENGTH => {
+            Err(protocol_error(format!("bad line length {}", length)))
+        }
+        _ => {
+            let mut data = vec![0; length - 4];
+            reader.read_exact(&mut data)?;
+            Ok(Packet::Data(data))
+        }
+    }
+}
+
+/// Writes `data` to `writer` as a single pkt-line.
+pub fn write_packet(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    if data.len() + 4 > MAX_PACKET_LENGTH {
+        return Err(protocol_error("packet too long"));
+    }
+    write!(writer, "{:04x}", data.len() + 4)?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+pub fn write_flush(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(b"0000")?;
+    Ok(())
+}
+
+pub fn write_delimiter(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(b"0001")?;
+    Ok(())
+}
+
+// Converts a data packet to a line of text, without its trailing newline.
+// Server errors, sent as "ERR <message>", are returned as errors.
+pub(crate) fn packet_line(data: Vec<u8>) -> Result<String> {
+    let mut line = String::from_utf8(data).map_err(|_| protocol_error("invalid UTF-8"))?;
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    match line.strip_prefix("ERR ") {
+        Some(message) => Err(Error::Fatal(format!("remote error: {}", message))),
+        None => Ok(line),
+    }
+}
+
+// Reads a line of text, or None at a flush packet.
+pub(crate) fn read_line(reader: &mut impl Read) -> Result<Option<String>> {
+    match read_packet(reader)? {
+        Packet::Data(data) => packet_line(data).map(Some),
+        Packet::Flush => Ok(None),
+        packet => Err(protocol_error(format!("unexpected {:?}", packet))),
+    }
+}
+
+/// A reference advertised by the server in response to `ls-refs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRef {
+    pub name: String,
+    pub oid: ObjectID,
+    /// The reference a symbolic reference (such as HEAD) points at.
+    pub symref_target: Option<String>,
+    /// The object an annotated tag points at.
+    pub peeled: Option<ObjectID>,
+}
+
+impl FromStr for RemoteRef {
+    type Err = Error;
+
+    /// Parses a line of the form
+    /// "{OID} {NAME}[ symref-target:{TARGET}][ peeled:{OID}]".
+    fn from_str(line: &str) -> Result<Self> {
+        let invalid = || protocol_error(format!("unexpected ls-refs line '{}'", line));
+        let mut words = line.split(' ');
+        let oid = words.next().ok_or_else(invalid)?.parse()?;
+        let name = words.next().ok_or_else(invalid)?.to_string();
+        let mut remote_ref = RemoteRef {
+            name,
+            oid,
+            symref_target: None,
+            peeled: None,
+        };
+        for attribute in words {
+            if let Some(target) = attribute.strip_prefix("symref-target:") {
+                remote_ref.symref_target = Some(target.to_string());
+            } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                remote_ref.peeled = Some(peeled.parse()?);
+            }
+        }
+        Ok(remote_ref)
+    }
+}
+
+// The ends of a server process's pipes.
+type Pipes = (Child, Option<BufWriter<ChildStdin>>, BufReader<ChildStdout>);
+
+// Runs `program` on the repository at `path`, requesting `protocol` (such
+// as "version=2") if given.
+//
+// As in git, the program is run by the shell so that it may include
+// arguments.
+fn spawn(program: &str, path: &Path, protocol: Option<&str>) -> Result<Pipes> {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(format!("{} \"$@\"", program))
+        .arg(program)
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    if let Some(protocol) = protocol {
+        command.env("GIT_PROTOCOL", protocol);
+    }
+    let mut child = command.spawn()?;
+    let input = child.stdin.take().map(BufWriter::new);
+    let output = child.stdout.take().map(BufReader::new);
+    let output = output.ok_or_else(|| protocol_error("no output from server"))?;
+    Ok((child, input, output))
+}
+
+// Waits for a server to exit, which it does once its input is closed.
+fn finish(child: &mut Child) -> Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::Fatal(
+            "the remote end hung up unexpectedly".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// A connection to a `git-upload-pack` process speaking protocol v2.
+pub struct Connection {
+    child: Child,
+    input: Option<BufWriter<ChildStdin>>,
+    output: BufReader<ChildStdout>,
+    capabilities: Vec<String>,
+}
+
+impl Connection {
+    /// Runs `program` on the repository at `path`, and reads the
+    /// capabilities it advertises.
+    pub fn spawn(program: &str, path: &Path) -> Result<Self> {
+        let (child, input, output) = spawn(program, path, Some("version=2"))?;
+        let mut connection = Connection {
+            child,
+            input,
+            output,
+            capabilities: vec![],
+        };
+        connection.read_capabilities()?;
+        Ok(connection)
+    }
+
+    fn read_capabilities(&mut self) -> Result<()> {
+        match read_line(&mut self.output)? {
+            Some(version) if version == "version 2" => (),
+            Some(version) => {
+                return Err(protocol_error(format!(
+                    "unsupported protocol '{}'",
+                    version
+                )))
+            }
+            None => return Err(protocol_error("empty capability advertisement")),
+        }
+        while let Some(capability) = read_line(&mut self.output)? {
+            self.capabilities.push(capability);
+        }
+        tracing::debug!(capabilities = ?self.capabilities, "connect");
+        Ok(())
+    }
+
+    /// Returns the capabilities advertised by the server, such as
+    /// "ls-refs" or "fetch=shallow".
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    /// Returns the value of the capability `name`, which is empty for
+    /// capabilities without one, or None if it was not advertised.
+    pub fn capability(&self, name: &str) -> Option<&str> {
+        self.capabilities.iter().find_map(|capability| {
+            let mut parts = capability.splitn(2, '=');
+            if parts.next() == Some(name) {
+                Some(parts.next().unwrap_or(""))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn input(&mut self) -> Result<&mut BufWriter<ChildStdin>> {
+        self.input
+            .as_mut()
+            .ok_or_else(|| protocol_error("connection closed"))
+    }
+
+    // Sends the command `name` with the arguments `args`.
+    fn send_command(&mut self, name: &str, args: &[String]) -> Result<()> {
+        if self.capability(name).is_none() {
+            return Err(Error::Fatal(format!(
+                "the server does not support '{}'",
+                name
+            )));
+        }
+        let send_agent = self.capability("agent").is_some();
+        let input = self.input()?;
+        write_packet(input, format!("command={}\n", name).as_bytes())?;
+        if send_agent {
+            let agent = format!("agent={}\n", agent());
+            write_packet(input, agent.as_bytes())?;
+        }
+        write_delimiter(input)?;
+        for arg in args {
+            write_packet(input, format!("{}\n", arg).as_bytes())?;
+        }
+        write_flush(input)?;
+        input.flush()?;
+        Ok(())
+    }
+
+    /// Lists the server's references whose names start with any of
+    /// `prefixes` (or all of them, if there are none), including HEAD.
+    pub fn ls_refs(&mut self, prefixes: &[&str]) -> Result<Vec<RemoteRef>> {
+        let mut args = vec!["symrefs".to_string(), "peel".to_string()];
+        args.extend(
+            prefixes
+                .iter()
+                .map(|prefix| format!("ref-prefix {}", prefix)),
+        );
+        self.send_command("ls-refs", &args)?;
+
+        let mut refs = vec![];
+        while let Some(line) = read_line(&mut self.output)? {
+            refs.push(line.parse()?);
+        }
+        Ok(refs)
+    }
+
+    /// Fetches `wants` and every object they reference, returning them as a
+    /// pack. Objects reachable from `haves` are left out, once the server
+    /// acknowledges that it has them too.
+    ///
+    /// The `haves` are sent a few at a time, most recent first, until the
+    /// server is ready to send the pack or they run out. Progress messages
+    /// from the server are passed to `progress` a line at a time, each
+    /// ending with "\n" or "\r".
+    pub fn fetch(
+        &mut self,
+        wants: &[ObjectID],
+        haves: &[ObjectID],
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<Vec<u8>> {
+        let mut common: Vec<&ObjectID> = vec![];
+        let mut remaining = haves.iter();
+        loop {
+            let round: Vec<&ObjectID> = remaining.by_ref().take(HAVES_PER_ROUND).collect();
+            let done = remaining.len() == 0;
+
+            // Tags pointing at the objects sent are included, so that
+            // they can be followed.
+            let mut args = vec!["ofs-delta".to_string(), "include-tag".to_string()];
+            args.extend(wants.iter().map(|oid| format!("want {}", oid.as_str())));
+            args.extend(
+                common
+                    .iter()
+                    .chain(round.iter())
+                    .map(|oid| format!("have {}", oid.as_str())),
+            );
+            if done {
+                args.push("done".to_string());
+            }
+            self.send_command("fetch", &args)?;
+
+            if !done {
+                let (acknowledged, ready) = self.read_acknowledgments()?;
+                tracing::debug!(acknowledged = acknowledged.len(), ready, "negotiate");
+                common.extend(round.into_iter().filter(|oid| acknowledged.contains(oid)));
+                if !ready {
+                    continue;
+                }
+            }
+            return self.read_packfile(progress);
+        }
+    }
+
+    // Reads the acknowledgments section, returning the acknowledged objects
+    // and whether the server is ready to send the pack (in which case the
+    // section ends with a delimiter rather than a flush).
+    fn read_acknowledgments(&mut self) -> Result<(Vec<ObjectID>, bool)> {
+        match read_line(&mut self.output)? {
+            Some(header) if header == "acknowledgments" => (),
+            other => {
+                return Err(protocol_error(format!(
+                    "expected acknowledgments, got {:?}",
+                    other
+                )))
+            }
+        }
+        let mut acknowledged = vec![];
+        let mut ready = false;
+        loop {
+            let line = match read_packet(&mut self.output)? {
+                Packet::Data(data) => packet_line(data)?,
+                Packet::Flush => return Ok((acknowledged, false)),
+                Packet::Delimiter if ready => return Ok((acknowledged, true)),
+                packet => return Err(protocol_error(format!("unexpected {:?}", packet))),
+            };
+            if let Some(oid) = line.strip_prefix("ACK ") {
+                acknowledged.push(oid.parse()?);
+            } else if line == "ready" {
+                ready = true;
+            } else if line != "NAK" {
+                return Err(protocol_error(format!("unexpected '{}'", line)));
+            }
+        }
+    }
+
+    // Skips any sections before "packfile", then reads the pack from the
+    // sideband.
+    fn read_packfile(&mut self, progress: &mut dyn FnMut(&str)) -> Result<Vec<u8>> {
+        loop {
+            match read_line(&mut self.output)? {
+                Some(header) if header == "packfile" => break,
+                Some(header) => {
+                    tracing::debug!(section = %header, "skip");
+                    loop {
+                        match read_packet(&mut self.output)? {
+                            Packet::Data(_) => (),
+                            Packet::Delimiter => break,
+                            packet => {
+                                return Err(protocol_error(format!("unexpected {:?}", packet)))
+                            }
+                        }
+                    }
+                }
+                None => return Err(protocol_error("no packfile in response")),
+            }
+        }
+
+        let mut pack = vec![];
+        let mut message = String::new();
+        loop {
+            let data = match read_packet(&mut self.output)? {
+                Packet::Data(data) => data,
+                Packet::Flush => break,
+                packet => return Err(protocol_error(format!("unexpected {:?}", packet))),
+            };
+            let (band, data) = data
+                .split_first()
+                .ok_or_else(|| protocol_error("empty sideband packet"))?;
+            match *band {
+                BAND_DATA => pack.extend_from_slice(data),
+                BAND_PROGRESS => {
+                    message.push_str(&String::from_utf8_lossy(data));
+                    while let Some(end) = message.find(['\n', '\r']) {
+                        progress(&message[..=end]);
+                        message.drain(..=end);
+                    }
+                }
+                BAND_ERROR => {
+                    let error = String::from_utf8_lossy(data);
+                    return Err(Error::Fatal(format!("remote error: {}", error.trim_end())));
+                }
+                band => return Err(protocol_error(format!("bad sideband {}", band))),
+            }
+        }
+        if !message.is_empty() {
+            progress(&message);
+        }
+        tracing::debug!(size = pack.len(), "fetch");
+        Ok(pack)
+    }
+
+    /// Ends the session, waiting for the server to exit.
+    pub fn close(mut self) -> Result<()> {
+        if let Some(mut input) = self.input.take() {
+            write_flush(&mut input)?;
+            input.flush()?;
+        }
+        finish(&mut self.child)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Closing its input lets the server exit.
+        self.input.take();
+        let _ = self.child.wait();
+    }
+}
+
+/// A request to change a reference on the server, from `old` to `new`.
+/// A missing `old` means the reference must not exist yet, and a missing
+/// `new` deletes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefCommand {
+    pub name: String,
+    pub old: Option<ObjectID>,
+    pub new: Option<ObjectID>,
+}
+
+/// The server's response to a push.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PushReport {
+    /// Why the pack could not be stored, if it could not.
+    pub unpack_error: Option<String>,
+    /// The references which were not updated, with the reason for each.
+    pub rejected: BTreeMap<String, String>,
+}
+
+/// A connection to a `git-receive-pack` process.
+pub struct PushConnection {
+    child: Child,
+    input: Option<BufWriter<ChildStdin>>,
+    output: BufReader<ChildStdout>,
+    refs: BTreeMap<String, ObjectID>,
+    capabilities: Vec<String>,
+}
+
+impl PushConnection {
+    /// Runs `program` on the repository at `path`, and reads the
+    /// references and capabilities it advertises.
+    pub fn spawn(program: &str, path: &Path) -> Result<Self> {
+        let (child, input, output) = spawn(program, path, None)?;
+        let mut connection = PushConnection {
+            child,
+            input,
+            output,
+            refs: BTreeMap::new(),
+            capabilities: vec![],
+        };
+        connection.read_advertisement()?;
+        Ok(connection)
+    }
+
+    // Reads lines of the form "{OID} {NAME}", the first of which is
+    // followed by "\0" and the capabilities. A repository without
+    // references advertises a null ID named "capabilities^{}".
+    fn read_advertisement(&mut self) -> Result<()> {
+        while let Packet::Data(data) = read_packet(&mut self.output)? {
+            let line = packet_line(data)?;
+            let line = match line.split_once('\0') {
+                Some((line, capabilities)) => {
+                    self.capabilities = capabilities
+                        .split(' ')
+                        .filter(|capability| !capability.is_empty())
+                        .map(|capability| capability.to_string())
+                        .collect();
+                    line
+                }
+                None => &line,
+            };
+            let (oid, name) = line
+                .split_once(' ')
+                .ok_or_else(|| protocol_error(format!("unexpected '{}'", line)))?;
+            if oid != NULL_OID && !name.starts_with("shallow") {
+                self.refs.insert(name.to_string(), oid.parse()?);
+            }
+        }
+        tracing::debug!(refs = self.refs.len(), capabilities = ?self.capabilities, "connect");
+        Ok(())
+    }
+
+    /// Returns the server's references, by full name.
+    pub fn refs(&self) -> &BTreeMap<String, ObjectID> {
+        &self.refs
+    }
+
+    /// Returns true if the server advertised the capability `name`, such
+    /// as "delete-refs".
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|capability| capability.split('=').next() == Some(name))
+    }
+
+    /// Sends `commands` followed by `pack` (which must hold every object
+    /// the new values need), and returns the server's report.
+    ///
+    /// Without any commands, the session ends without a report.
+    pub fn push(mut self, commands: &[RefCommand], pack: Option<&[u8]>) -> Result<PushReport> {
+        let report_status = self.has_capability("report-status");
+        let mut requested = vec![];
+        if report_status {
+            requested.push("report-status".to_string());
+        }
+        if self.has_capability("agent") {
+            requested.push(format!("agent={}", agent()));
+        }
+
+        let mut input = self
+            .input
+            .take()
+            .ok_or_else(|| protocol_error("connection closed"))?;
+        for (i, command) in commands.iter().enumerate() {
+            let oid = |oid: &Option<ObjectID>| match oid {
+                Some(oid) => oid.as_str(),
+                None => NULL_OID.to_string(),
+            };
+            let mut line = format!(
+                "{} {} {}",
+                oid(&command.old),
+                oid(&command.new),
+                command.name
+            );
+            // Capabilities follow the first command.
+            if i == 0 {
+                line.push('\0');
+                line.push_str(&requested.join(" "));
+            }
+            line.push('\n');
+            write_packet(&mut input, line.as_bytes())?;
+        }
+        write_flush(&mut input)?;
+        if let (false, Some(pack)) = (commands.is_empty(), pack) {
+            input.write_all(pack)?;
+        }
+        input.flush()?;
+        drop(input);
+
+        let mut report = PushReport::default();
+        if !commands.is_empty() && report_status {
+            let unpack = read_line(&mut self.output)?
+                .ok_or_else(|| protocol_error("missing unpack status"))?;
+            match unpack.strip_prefix("unpack ") {
+                Some("ok") => (),
+                Some(error) => report.unpack_error = Some(error.to_string()),
+                None => return Err(protocol_error(format!("unexpected '{}'", unpack))),
+            }
+            while let Some(line) = read_line(&mut self.output)? {
+                if let Some(rejected) = line.strip_prefix("ng ") {
+                    let (name, reason) = rejected.split_once(' ').unwrap_or((rejected, ""));
+                    report.rejected.insert(name.to_string(), reason.to_string());
+                } else if !line.starts_with("ok ") {
+                    return Err(protocol_error(format!("unexpected '{}'", line)));
+                }
+            }
+        }
+        finish(&mut self.child)?;
+        Ok(report)
+    }
+}
+
+impl Drop for PushConnection {
+    fn drop(&mut self) {
+        // Closing its input without sending any commands lets the server
+        // exit.
+        if let Some(mut input) = self.input.take() {
+            let _ = write_flush(&mut input).and_then(|_| Ok(input.flush()?));
+        }
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::git;
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    fn commit(dir: &Path, message: &str) {
+        std::fs::write(dir.join("file.txt"), message).unwrap();
+        git(dir, &["add", "file.txt"]);
+        git(
+            dir,
+            &[
+                "-c",
+                "user.name=A",
+                "-c",
+                "user.email=a@b",
+                "commit",
+                "-m",
+                message,
+            ],
+        );
+    }
+
+    fn rev_parse(dir: &Path, revision: &str) -> ObjectID {
+        git(dir, &["rev-parse", revision]).trim().parse().unwrap()
+    }
+
+    // Returns the number of objects in a pack, from its header.
+    fn pack_count(pack: &[u8]) -> u32 {
+        assert_eq!(&pack[..4], b"PACK");
+        u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]])
+    }
+
+    #[test]
+    fn test_packets() -> Result<()> {
+        let mut buffer = vec![];
+        write_packet(&mut buffer, b"command=ls-refs\n")?;
+        write_delimiter(&mut buffer)?;
+        write_flush(&mut buffer)?;
+        assert_eq!(buffer, b"0014command=ls-refs\n00010000");
+
+        let mut reader = &buffer[..];
+        assert_eq!(
+            read_packet(&mut reader)?,
+            Packet::Data(b"command=ls-refs\n".to_vec())
+        );
+        assert_eq!(read_packet(&mut reader)?, Packet::Delimiter);
+        assert_eq!(read_packet(&mut reader)?, Packet::Flush);
+        assert!(read_packet(&mut reader).is_err());
+        assert!(read_packet(&mut &b"00zz"[..]).is_err());
+        assert!(read_packet(&mut &b"0003"[..]).is_err());
+        assert!(packet_line(b"ERR access denied\n".to_vec()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_remote_ref() -> Result<()> {
+        let oid = "ce013625030ba8dba906f756967f9e9ca394464a";
+        let remote_ref: RemoteRef =
+            format!("{} HEAD symref-target:refs/heads/main", oid).parse()?;
+        assert_eq!(remote_ref.name, "HEAD");
+        assert_eq!(remote_ref.oid.as_str(), oid);
+        assert_eq!(remote_ref.symref_target.as_deref(), Some("refs/heads/main"));
+        assert_eq!(remote_ref.peeled, None);
+        assert!("HEAD".parse::<RemoteRef>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ls_refs_and_fetch() -> Result<()> {
+        let dir = TempDir::new("test_protocol")?;
+        let path = dir.path();
+        git(path, &["init", "-q", "-b", "main"]);
+        commit(path, "first");
+        let first = rev_parse(path, "HEAD");
+        git(
+            path,
+            &[
+                "-c",
+                "user.name=A",
+                "-c",
+                "user.email=a@b",
+                "tag",
+                "-a",
+                "v1",
+                "-m",
+                "v1",
+            ],
+        );
+        commit(path, "second");
+        let second = rev_parse(path, "HEAD");
+
+        let mut connection = Connection::spawn(DEFAULT_UPLOAD_PACK, path)?;
+        assert!(connection.capability("ls-refs").is_some());
+        assert!(connection.capability("fetch").is_some());
+        assert_eq!(connection.capability("unknown"), None);
+
+        let refs = connection.ls_refs(&[])?;
+        let names: Vec<&str> = refs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["HEAD", "refs/heads/main", "refs/tags/v1"]);
+        assert_eq!(refs[0].oid, second);
+        assert_eq!(refs[0].symref_target.as_deref(), Some("refs/heads/main"));
+        assert_eq!(refs[2].oid, rev_parse(path, "v1"));
+        assert_eq!(refs[2].peeled.as_ref(), Some(&first));
+        let tags = connection.ls_refs(&["refs/tags/"])?;
+        assert_eq!(tags, vec![refs[2].clone()]);
+
+        // With nothing in common, everything is sent: two commits, two
+        // trees, two blobs and the tag pointing at the first commit.
+        let mut messages = vec![];
+        let pack = connection.fetch(std::slice::from_ref(&second), &[], &mut |m| {
+            messages.push(m.to_string())
+        })?;
+        assert_eq!(pack_count(&pack), 7);
+        assert!(messages.iter().all(|m| m.ends_with(['\n', '\r'])));
+
+        // Once the server acknowledges the first commit, only the second
+        // commit's objects are sent. Unknown haves are ignored.
+        let unknown: ObjectID = "ce013625030ba8dba906f756967f9e9ca394464a".parse()?;
+        let mut haves = vec![unknown; HAVES_PER_ROUND];
+        haves.push(first.clone());
+        let pack = connection.fetch(std::slice::from_ref(&second), &haves, &mut |_| ())?;
+        assert_eq!(pack_count(&pack), 3);
+        // The same, when the server can stop negotiating early.
+        haves.rotate_right(1);
+        let pack = connection.fetch(&[second], &haves, &mut |_| ())?;
+        assert_eq!(pack_count(&pack), 3);
+
+        let missing = "0000000000000000000000000000000000000001".parse()?;
+        assert!(connection.fetch(&[missing], &[], &mut |_| ()).is_err());
+        Ok(())
+    }
+}
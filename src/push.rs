@@ -0,0 +1,684 @@
+//! Pushing objects and references to another repository.
+//!
+//! Local references are mapped to the remote's by refspecs, and each
+//! update is checked against the references the remote advertises. The
+//! objects the remote lacks are sent as a thin pack, along with commands
+//! which the remote applies only if its references are still as expected.
+
+use crate::author::Author;
+use crate::database::{Database, ObjectID};
+use crate::error::{Error, Result};
+use crate::fetch::Remote;
+use crate::gc;
+use crate::hooks;
+use crate::pack;
+use crate::protocol::{PushConnection, RefCommand, NULL_OID};
+use crate::refs::{short_name, HEAD};
+use crate::refspec::Refspec;
+use crate::repository::Repository;
+use crate::revision;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::str::FromStr;
+
+// The width of the summary column of update lines, as for fetch.
+const SUMMARY_WIDTH: usize = 17;
+
+// The reflog message of remote-tracking references updated by a push.
+const TRACKING_MESSAGE: &str = "update by push";
+
+/// A "--force-with-lease" check, which allows a remote reference to be
+/// overwritten only while it still has the value expected of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    /// The remote reference the lease covers, or None for every reference
+    /// pushed.
+    pub name: Option<String>,
+    /// The revision the remote reference is expected to have, or the empty
+    /// string if it must not exist. None expects the value of its
+    /// remote-tracking reference.
+    pub expect: Option<String>,
+}
+
+impl FromStr for Lease {
+    type Err = Error;
+
+    /// Parses the value of "--force-with-lease", which is either empty,
+    /// "<name>" or "<name>:<expect>".
+    fn from_str(value: &str) -> Result<Self> {
+        if value.is_empty() {
+            return Ok(Lease {
+                name: None,
+                expect: None,
+            });
+        }
+        let (name, expect) = match value.split_once(':') {
+            Some((name, expect)) => (name, Some(expect.to_string())),
+            None => (value, None),
+        };
+        if name.is_empty() {
+            return Err(Error::Fatal(format!("cannot parse lease '{}'", value)));
+        }
+        Ok(Lease {
+            name: Some(name.to_string()),
+            expect,
+        })
+    }
+}
+
+impl Lease {
+    // Returns true if the lease covers the remote reference `name`.
+    fn covers(&self, name: &str) -> Result<bool> {
+        match &self.name {
+            Some(covered) => Ok(covered.parse::<Refspec>()?.matches(name)),
+            None => Ok(true),
+        }
+    }
+}
+
+/// Options controlling a push.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Allows updates which are not fast-forwards, as if every refspec
+    /// started with "+".
+    pub force: bool,
+    /// Allows updates which are not fast-forwards only if the remote
+    /// references are as expected.
+    pub leases: Vec<Lease>,
+    /// Skips the pre-push hook.
+    pub no_verify: bool,
+}
+
+/// The outcome of pushing a single reference.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The remote reference was created.
+    New,
+    /// The remote reference already matched.
+    UpToDate,
+    FastForward,
+    /// The remote reference was moved by force.
+    Forced,
+    Deleted,
+    /// The update was not sent, for the reason given, such as
+    /// "non-fast-forward".
+    Rejected(&'static str),
+    /// The remote refused the update, for the reason given.
+    RemoteRejected(String),
+}
+
+/// A single reference pushed (or not).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefUpdate {
+    /// The local side of the refspec, as shown, or None for a deletion.
+    pub local: Option<String>,
+    /// The full name of the remote reference.
+    pub remote: String,
+    pub old: Option<ObjectID>,
+    pub new: Option<ObjectID>,
+    pub status: Status,
+}
+
+// Runs the pre-push hook, passing a line for each reference about to be
+// sent: "<local ref> <local oid> <remote ref> <remote oid>".
+fn run_pre_push(repo: &Repository, remote: &Remote, updates: &[RefUpdate]) -> Result<()> {
+    let oid = |oid: &Option<ObjectID>| match oid {
+        Some(oid) => oid.as_str(),
+        None => NULL_OID.to_string(),
+    };
+    let mut input = String::new();
+    for update in updates.iter().filter(|update| update.is_sent()) {
+        let local = match &update.local {
+            Some(local) => repo
+                .refs()
+                .full_name(local)?
+                .unwrap_or_else(|| local.clone()),
+            None => "(delete)".to_string(),
+        };
+        input.push_str(&format!(
+            "{} {} {} {}\n",
+            local,
+            oid(&update.new),
+            update.remote,
+            oid(&update.old)
+        ));
+    }
+    let args = [remote.name(), remote.url()];
+    if !repo
+        .hooks()?
+        .run(hooks::PRE_PUSH, &args, input.as_bytes())?
+    {
+        return Err(Error::Conflict(format!(
+            "failed to push some refs to '{}'",
+            remote.url()
+        )));
+    }
+    Ok(())
+}
+
+// Describes what a reference is, such as "branch" for "refs/heads/main".
+fn kind(name: &str) -> &'static str {
+    if name.starts_with("refs/tags/") {
+        "tag"
+    } else if name.starts_with("refs/heads/") {
+        "branch"
+    } else {
+        "reference"
+    }
+}
+
+impl RefUpdate {
+    /// Returns true if the remote reference was not updated, which is an
+    /// error.
+    pub fn is_rejected(&self) -> bool {
+        matches!(self.status, Status::Rejected(_) | Status::RemoteRejected(_))
+    }
+
+    /// Returns true if the update is reported by default.
+    pub fn is_shown(&self) -> bool {
+        self.status != Status::UpToDate
+    }
+
+    // Returns true if the update changes the remote reference.
+    fn is_sent(&self) -> bool {
+        matches!(
+            self.status,
+            Status::New | Status::FastForward | Status::Forced | Status::Deleted
+        )
+    }
+
+    /// Formats the update as git does, such as
+    /// " * [new branch]      main -> main".
+    pub fn summary(&self) -> String {
+        let range = |separator: &str| match (&self.old, &self.new) {
+            (Some(old), Some(new)) => {
+                format!("{}{}{}", old.as_short_str(), separator, new.as_short_str())
+            }
+            _ => String::new(),
+        };
+        let (code, summary, reason) = match &self.status {
+            Status::New => ('*', format!("[new {}]", kind(&self.remote)), None),
+            Status::UpToDate => ('=', "[up to date]".to_string(), None),
+            Status::FastForward => (' ', range(".."), None),
+            Status::Forced => ('+', range("..."), Some("forced update")),
+            Status::Deleted => ('-', "[deleted]".to_string(), None),
+            Status::Rejected(reason) => ('!', "[rejected]".to_string(), Some(*reason)),
+            Status::RemoteRejected(reason) => {
+                ('!', "[remote rejected]".to_string(), Some(reason.as_str()))
+            }
+        };
+        let mut line = format!(" {} {:<width$} ", code, summary, width = SUMMARY_WIDTH);
+        match &self.local {
+            Some(local) => line.push_str(&format!("{} -> {}", local, short_name(&self.remote))),
+            None => line.push_str(short_name(&self.remote)),
+        }
+        if let Some(reason) = reason {
+            line.push_str(&format!(" ({})", reason));
+        }
+        line
+    }
+}
+
+/// The result of a push.
+pub struct Pushed {
+    /// Every reference pushed, in the order of the refspecs.
+    pub updates: Vec<RefUpdate>,
+    /// The number of objects sent.
+    pub objects: usize,
+}
+
+// A local object (or deletion) to push to a remote reference.
+struct Mapping {
+    local: Option<String>,
+    new: Option<ObjectID>,
+    remote: String,
+    force: bool,
+}
+
+/// Pushes to `remote` according to `refspecs`, or the remote's configured
+/// push refspecs, or otherwise the current branch to the branch of the
+/// same name. Remote-tracking references of updated references are
+/// updated to match, using `identity` in their reflogs.
+pub fn push(
+    repo: &Repository,
+    remote: &Remote,
+    refspecs: &[Refspec],
+    options: &Options,
+    identity: &Author,
+) -> Result<Pushed> {
+    let refspecs = match (refspecs, remote.push_refspecs()) {
+        ([], []) => {
+            let branch = repo.refs().current_ref()?;
+            if branch == HEAD {
+                return Err(Error::Fatal(
+                    "You are not currently on a branch.".to_string(),
+                ));
+            }
+            vec![branch.parse()?]
+        }
+        ([], configured) => configured.to_vec(),
+        (given, _) => given.to_vec(),
+    };
+
+    let connection = PushConnection::spawn(remote.receive_pack(), remote.git_dir())?;
+    let remote_refs = connection.refs().clone();
+    let mappings = map_refspecs(repo, &refspecs, &remote_refs)?;
+
+    let database = repo.database();
+    let mut updates = Vec::with_capacity(mappings.len());
+    for mapping in mappings {
+        let old = remote_refs.get(&mapping.remote).cloned();
+        let status = check(repo, remote, options, &mapping, old.as_ref())?;
+        let status = match status {
+            Status::Deleted if !connection.has_capability("delete-refs") => {
+                Status::Rejected("remote does not support deleting refs")
+            }
+            status => status,
+        };
+        updates.push(RefUpdate {
+            local: mapping.local,
+            remote: mapping.remote,
+            old,
+            new: mapping.new,
+            status,
+        });
+    }
+
+    if !options.no_verify {
+        run_pre_push(repo, remote, &updates)?;
+    }
+
+    let commands: Vec<RefCommand> = updates
+        .iter()
+        .filter(|update| update.is_sent())
+        .map(|update| RefCommand {
+            name: update.remote.clone(),
+            old: update.old.clone(),
+            new: update.new.clone(),
+        })
+        .collect();
+    let tips: Vec<ObjectID> = commands
+        .iter()
+        .filter_map(|command| command.new.clone())
+        .collect();
+    let mut objects = vec![];
+    let pack = if tips.is_empty() {
+        None
+    } else {
+        let known: Vec<ObjectID> = remote_refs
+            .values()
+            .filter_map(|oid| match database.contains(oid) {
+                Ok(true) => Some(Ok(oid.clone())),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<_>>()?;
+        let known = gc::reachable(database, known)?;
+        objects = gc::reachable(database, tips)?
+            .difference(&known)
+            .cloned()
+            .collect();
+        let bases = thin_bases(database, &objects, &known)?;
+        Some(pack::write(database, &objects, &bases)?)
+    };
+    tracing::debug!(commands = commands.len(), objects = objects.len(), "push");
+
+    let report = connection.push(&commands, pack.as_deref())?;
+    for update in updates.iter_mut().filter(|update| update.is_sent()) {
+        if let Some(reason) = report.rejected.get(&update.remote) {
+            update.status = Status::RemoteRejected(reason.clone());
+        } else if let Some(error) = &report.unpack_error {
+            update.status = Status::RemoteRejected(error.clone());
+        }
+    }
+    update_tracking_refs(repo, remote, &updates, identity)?;
+
+    Ok(Pushed {
+        updates,
+        objects: objects.len(),
+    })
+}
+
+// Resolves `refspecs` into the local objects to push and the remote
+// references to update.
+fn map_refspecs(
+    repo: &Repository,
+    refspecs: &[Refspec],
+    remote_refs: &BTreeMap<String, ObjectID>,
+) -> Result<Vec<Mapping>> {
+    let (negative, positive): (Vec<&Refspec>, Vec<&Refspec>) =
+        refspecs.iter().partition(|spec| spec.is_negative());
+    let excluded = |name: &str| negative.iter().any(|spec| spec.matches(name));
+
+    let mut mappings = vec![];
+    let mut local_refs = None;
+    for spec in positive {
+        if spec.is_glob() {
+            if local_refs.is_none() {
+                local_refs = Some(repo.refs().list_refs()?);
+            }
+            for (name, oid) in local_refs.iter().flatten() {
+                if excluded(name) {
+                    continue;
+                }
+                if let Some(remote) = spec.map(name) {
+                    mappings.push(Mapping {
+                        local: Some(short_name(name).to_string()),
+                        new: Some(oid.clone()),
+                        remote,
+                        force: spec.is_force(),
+                    });
+                }
+            }
+            continue;
+        }
+
+        // An empty source, as in ":topic", deletes the destination.
+        if spec.source().is_empty() {
+            let destination = spec.destination().unwrap_or_default();
+            let remote = match remote_name(destination, remote_refs)? {
+                Some(remote) => remote,
+                None => {
+                    return Err(Error::Fatal(format!(
+                        "unable to delete '{}': remote ref does not exist",
+                        destination
+                    )))
+                }
+            };
+            mappings.push(Mapping {
+                local: None,
+                new: None,
+                remote,
+                force: spec.is_force(),
+            });
+            continue;
+        }
+
+        let unmatched =
+            || Error::Fatal(format!("src refspec {} does not match any", spec.source()));
+        let full_name = match spec.source() {
+            HEAD => Some(repo.refs().current_ref()?),
+            source => repo.refs().full_name(source)?,
+        };
+        let new = match &full_name {
+            Some(name) => repo.refs().read_ref(name)?,
+            None => repo.resolve(spec.source()).ok(),
+        }
+        .ok_or_else(unmatched)?;
+        let local = match (spec.source(), &full_name) {
+            (HEAD, _) | (_, None) => spec.source().to_string(),
+            (_, Some(name)) => short_name(name).to_string(),
+        };
+        if full_name.as_deref().is_some_and(excluded) {
+            continue;
+        }
+
+        let remote = match (spec.destination(), &full_name) {
+            (None, Some(name)) if name.starts_with("refs/") => name.clone(),
+            (Some(destination), _) if destination.starts_with("refs/") => destination.to_string(),
+            (Some(destination), _) => match remote_name(destination, remote_refs)? {
+                Some(remote) => remote,
+                None => match &full_name {
+                    Some(name) if name.starts_with("refs/tags/") => {
+                        format!("refs/tags/{}", destination)
+                    }
+                    Some(name) if name.starts_with("refs/heads/") => {
+                        format!("refs/heads/{}", destination)
+                    }
+                    _ => {
+                        return Err(Error::Fatal(format!(
+                            "the destination '{}' is not a full reference name",
+                            destination
+                        )))
+                    }
+                },
+            },
+            (None, _) => {
+                return Err(Error::Fatal(format!(
+                    "the source '{}' needs a destination",
+                    spec.source()
+                )))
+            }
+        };
+        mappings.push(Mapping {
+            local: Some(local),
+            new: Some(new),
+            remote,
+            force: spec.is_force(),
+        });
+    }
+    Ok(mappings)
+}
+
+// Returns the remote reference which the abbreviated `name` refers to, such
+// as "refs/heads/main" for "main", if one exists.
+fn remote_name(name: &str, remote_refs: &BTreeMap<String, ObjectID>) -> Result<Option<String>> {
+    let pattern: Refspec = name.parse()?;
+    Ok(remote_refs
+        .keys()
+        .find(|remote| pattern.matches(remote))
+        .cloned())
+}
+
+// Decides whether the remote reference may be changed from `old`, as
+// git's checks on a push do.
+fn check(
+    repo: &Repository,
+    remote: &Remote,
+    options: &Options,
+    mapping: &Mapping,
+    old: Option<&ObjectID>,
+) -> Result<Status> {
+    let mut force = mapping.force || options.force;
+    for lease in &options.leases {
+        if !lease.covers(&mapping.remote)? {
+            continue;
+        }
+        let expected = match lease.expect.as_deref() {
+            Some("") => None,
+            Some(revision) => Some(repo.resolve(revision)?),
+            None => match remote.tracking_ref(&mapping.remote) {
+                Some(tracking) => repo.refs().read_ref_exact(&tracking)?,
+                None => None,
+            },
+        };
+        if expected.as_ref() != old {
+            return Ok(Status::Rejected("stale info"));
+        }
+        force = true;
+    }
+
+    let new = match (&mapping.new, old) {
+        (None, _) => return Ok(Status::Deleted),
+        (Some(new), Some(old)) if new == old => return Ok(Status::UpToDate),
+        (Some(_), None) => return Ok(Status::New),
+        (Some(new), Some(_)) => new,
+    };
+    let old = old.expect("checked above");
+    let database = repo.database();
+    if mapping.remote.starts_with("refs/tags/") {
+        // Tags only move by force.
+        Ok(if force {
+            Status::Forced
+        } else {
+            Status::Rejected("already exists")
+        })
+    } else if database.contains(old)? && is_fast_forward(database, old, new)? {
+        Ok(Status::FastForward)
+    } else if force {
+        Ok(Status::Forced)
+    } else if !database.contains(old)? {
+        Ok(Status::Rejected("fetch first"))
+    } else {
+        Ok(Status::Rejected("non-fast-forward"))
+    }
+}
+
+// Returns true if `old` and `new` are commits, and `new` descends from
+// `old`.
+fn is_fast_forward(database: &Database, old: &ObjectID, new: &ObjectID) -> Result<bool> {
+    let is_commit = |oid: &ObjectID| -> Result<bool> { Ok(database.read_raw(oid)?.0 == "commit") };
+    Ok(is_commit(old)? && is_commit(new)? && revision::is_ancestor(database, old, new)?)
+}
+
+// Chooses delta bases for the blobs in `objects`: the blob at the same
+// path in a parent commit which the remote already has.
+fn thin_bases(
+    database: &Database,
+    objects: &[ObjectID],
+    known: &BTreeSet<ObjectID>,
+) -> Result<HashMap<ObjectID, ObjectID>> {
+    let sent: BTreeSet<&ObjectID> = objects.iter().collect();
+    let mut bases = HashMap::new();
+    for oid in objects {
+        if database.read_raw(oid)?.0 != "commit" {
+            continue;
+        }
+        let commit = database.load_commit(oid)?;
+        let parents: Vec<&ObjectID> = commit
+            .parents()
+            .iter()
+            .filter(|parent| known.contains(*parent))
+            .collect();
+        if parents.is_empty() {
+            continue;
+        }
+        let files = database.load_tree_list(commit.tree())?;
+        for parent in parents {
+            let parent_files = database.load_tree_list(database.load_commit(parent)?.tree())?;
+            for (path, entry) in &files {
+                if !sent.contains(entry.oid()) || bases.contains_key(entry.oid()) {
+                    continue;
+                }
+                if let Some(base) = parent_files.get(path) {
+                    if base.mode() == entry.mode() && known.contains(base.oid()) {
+                        bases.insert(entry.oid().clone(), base.oid().clone());
+                    }
+                }
+            }
+        }
+    }
+    Ok(bases)
+}
+
+// Makes the remote-tracking references of the updated remote references
+// match them.
+fn update_tracking_refs(
+    repo: &Repository,
+    remote: &Remote,
+    updates: &[RefUpdate],
+    identity: &Author,
+) -> Result<()> {
+    let refs = repo.refs();
+    let mut transaction = refs.transaction();
+    for update in updates.iter().filter(|update| update.is_sent()) {
+        let tracking = match remote.tracking_ref(&update.remote) {
+            Some(tracking) => tracking,
+            None => continue,
+        };
+        match &update.new {
+            Some(new) => {
+                let old = refs.read_ref_exact(&tracking)?;
+                transaction.update(&tracking, old.as_ref(), new, Some(TRACKING_MESSAGE));
+            }
+            None => refs.delete_ref(&tracking)?,
+        }
+    }
+    transaction.commit(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::oid;
+
+    fn update(
+        local: Option<&str>,
+        remote: &str,
+        old: Option<char>,
+        new: Option<char>,
+        status: Status,
+    ) -> RefUpdate {
+        RefUpdate {
+            local: local.map(|local| local.to_string()),
+            remote: remote.to_string(),
+            old: old.map(oid),
+            new: new.map(oid),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let updates = [
+            update(
+                Some("main"),
+                "refs/heads/main",
+                None,
+                Some('a'),
+                Status::New,
+            ),
+            update(Some("v1"), "refs/tags/v1", None, Some('a'), Status::New),
+            update(
+                Some("main"),
+                "refs/heads/main",
+                Some('a'),
+                Some('b'),
+                Status::FastForward,
+            ),
+            update(
+                Some("HEAD"),
+                "refs/heads/topic",
+                Some('a'),
+                Some('b'),
+                Status::Forced,
+            ),
+            update(None, "refs/heads/topic", Some('a'), None, Status::Deleted),
+            update(
+                Some("topic"),
+                "refs/heads/topic",
+                Some('a'),
+                Some('b'),
+                Status::Rejected("non-fast-forward"),
+            ),
+            update(
+                Some("main"),
+                "refs/heads/main",
+                Some('a'),
+                Some('b'),
+                Status::RemoteRejected("hook declined".to_string()),
+            ),
+        ];
+        let lines: Vec<String> = updates.iter().map(|update| update.summary()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                " * [new branch]      main -> main",
+                " * [new tag]         v1 -> v1",
+                "   aaaaaaa..bbbbbbb  main -> main",
+                " + aaaaaaa...bbbbbbb HEAD -> topic (forced update)",
+                " - [deleted]         topic",
+                " ! [rejected]        topic -> topic (non-fast-forward)",
+                " ! [remote rejected] main -> main (hook declined)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_lease() -> Result<()> {
+        assert_eq!(
+            "".parse::<Lease>()?,
+            Lease {
+                name: None,
+                expect: None
+            }
+        );
+        let lease: Lease = "main:HEAD~1".parse()?;
+        assert_eq!(lease.name.as_deref(), Some("main"));
+        assert_eq!(lease.expect.as_deref(), Some("HEAD~1"));
+        assert!(lease.covers("refs/heads/main")?);
+        assert!(!lease.covers("refs/heads/topic")?);
+        assert_eq!("main:".parse::<Lease>()?.expect.as_deref(), Some(""));
+        assert!(":x".parse::<Lease>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use crate::database::{Database, ObjectID};
+use crate::error::{Error, Result};
+use crate::lockfile::LockFile;
+use std::fs::create_dir;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Appended to the todo list presented to the user.
+const TODO_HELP: &str = "
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\", but discard this commit's log message
+# x, exec <command> = run command (the rest of the line) using shell
+# d, drop <commit> = remove commit
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+#
+# If you remove a line here THAT COMMIT WILL BE LOST.
+#
+# However, if you remove everything, the rebase will be aborted.
+#
+";
+
+/// The ways a commit may be replayed by a rebase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Replays the commit as-is.
+    Pick,
+    /// Replays the commit, but edits its message.
+    Reword,
+    /// Melds the commit into its predecessor, combining their messages.
+    Squash,
+    /// Melds the commit into its predecessor, keeping the predecessor's
+    /// message.
+    Fixup,
+    /// Omits the commit.
+    Drop,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Pick => "pick",
+            Action::Reword => "reword",
+            Action::Squash => "squash",
+            Action::Fixup => "fixup",
+            Action::Drop => "drop",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pick" | "p" => Ok(Action::Pick),
+            "reword" | "r" => Ok(Action::Reword),
+            "squash" | "s" => Ok(Action::Squash),
+            "fixup" | "f" => Ok(Action::Fixup),
+            "drop" | "d" => Ok(Action::Drop),
+            _ => Err(Error::Fatal(format!("Invalid command '{}'", s))),
+        }
+    }
+}
+
+/// A single line of the rebase todo list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TodoItem {
+    Commit {
+        action: Action,
+        oid: ObjectID,
+        /// The title of the commit; purely informational.
+        title: String,
+    },
+    /// Runs a shell command.
+    Exec(String),
+}
+
+impl TodoItem {
+    // Parses a line such as "pick <oid> <title>" or "exec <command>".
+    //
+    // Abbreviated object IDs are resolved against `database`.
+    fn parse(line: &str, database: &Database) -> Result<Self> {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        if command == "exec" || command == "x" {
+            if rest.trim().is_empty() {
+                return Err(Error::Fatal(format!(
+                    "Missing command in todo line: {}",
+                    line
+                )));
+            }
+            return Ok(TodoItem::Exec(rest.trim().to_string()));
+        }
+
+        let action = Action::from_str(command)?;
+        let (oid, title) = rest.trim_start().split_once(' ').unwrap_or((rest, ""));
+        let oid = match database.prefix_match(&oid.to_ascii_lowercase())?.as_slice() {
+            [oid] => oid.clone(),
+            [] => {
+                return Err(Error::Fatal(format!(
+                    "Could not parse '{}' in todo line: {}",
+                    oid, line
+                )))
+            }
+            candidates => {
+                return Err(Error::AmbiguousObjectId {
+                    prefix: oid.to_string(),
+                    candidates: candidates.iter().map(|oid| oid.as_short_str()).collect(),
+                })
+            }
+        };
+        Ok(TodoItem::Commit {
+            action,
+            oid,
+            title: title.to_string(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        match self {
+            TodoItem::Commit { action, oid, title } => {
+                format!("{} {} {}\n", action.as_str(), oid.as_short_str(), title)
+            }
+            TodoItem::Exec(command) => format!("exec {}\n", command),
+        }
+    }
+}
+
+/// The state of an in-progress rebase, which may be interrupted by
+/// conflicts and later resumed.
+///
+/// Uses a subset of git's ".git/rebase-merge" layout: "head-name" names the
+/// branch being rebased, "onto" and "orig-head" record the new base and the
+/// original tip, "git-rebase-todo" lists the remaining steps and "done" the
+/// steps already taken. "message" holds the message for a commit which is
+/// awaiting conflict resolution.
+pub struct RebaseState {
+    path: PathBuf,
+}
+
+impl RebaseState {
+    /// Takes a path to the git directory as input.
+    pub fn new<P: AsRef<Path>>(git_path: P) -> Self {
+        RebaseState {
+            path: git_path.as_ref().join("rebase-merge"),
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    /// Records the state of a new rebase.
+    ///
+    /// `head_name` is the full name of the branch being rebased, or
+    /// "detached HEAD".
+    pub fn start(
+        &self,
+        head_name: &str,
+        onto: &ObjectID,
+        orig_head: &ObjectID,
+        todo: &[TodoItem],
+    ) -> Result<()> {
+        if self.in_progress() {
+            return Err(Error::Fatal(
+                "It seems that there is already a rebase in progress.\n\
+                 Use \"rit rebase (--continue | --skip | --abort)\"."
+                    .to_string(),
+            ));
+        }
+        create_dir(&self.path)?;
+        self.write_file("head-name", &format!("{}\n", head_name))?;
+        self.write_file("onto", &format!("{}\n", onto.as_str()))?;
+        self.write_file("orig-head", &format!("{}\n", orig_head.as_str()))?;
+        self.write_file("done", "")?;
+        self.write_todo(todo)
+    }
+
+    pub fn head_name(&self) -> Result<String> {
+        Ok(self.read_file("head-name")?.trim().to_string())
+    }
+
+    pub fn onto(&self) -> Result<ObjectID> {
+        ObjectID::from_str(self.read_file("onto")?.trim())
+    }
+
+    pub fn orig_head(&self) -> Result<ObjectID> {
+        ObjectID::from_str(self.read_file("orig-head")?.trim())
+    }
+
+    /// Returns the location of the todo list, for editing.
+    pub fn todo_path(&self) -> PathBuf {
+        self.path.join("git-rebase-todo")
+    }
+
+    /// Returns the remaining steps, in the order they will be applied.
+    pub fn todo(&self, database: &Database) -> Result<Vec<TodoItem>> {
+        self.parse_list("git-rebase-todo", database)
+    }
+
+    pub fn write_todo(&self, todo: &[TodoItem]) -> Result<()> {
+        let contents: String = todo.iter().map(|item| item.to_line()).collect();
+        self.write_file("git-rebase-todo", &contents)
+    }
+
+    /// Writes the todo list along with instructions on how to edit it.
+    pub fn write_todo_with_help(&self, todo: &[TodoItem], summary: &str) -> Result<()> {
+        let mut contents: String = todo.iter().map(|item| item.to_line()).collect();
+        contents.push_str(&format!("\n# {}\n", summary));
+        contents.push_str(TODO_HELP);
+        self.write_file("git-rebase-todo", &contents)
+    }
+
+    /// Returns the steps which have been taken, oldest first.
+    pub fn done(&self, database: &Database) -> Result<Vec<TodoItem>> {
+        self.parse_list("done", database)
+    }
+
+    pub fn append_done(&self, item: &TodoItem) -> Result<()> {
+        let mut done = self.read_file("done")?;
+        done.push_str(&item.to_line());
+        self.write_file("done", &done)
+    }
+
+    /// Saves the message of a commit which could not be applied cleanly.
+    pub fn save_message(&self, message: &str) -> Result<()> {
+        self.write_file("message", message)
+    }
+
+    /// Returns the message saved by `save_message`, if any.
+    pub fn message(&self) -> Result<Option<String>> {
+        match std::fs::read_to_string(self.path.join("message")) {
+            Ok(message) => Ok(Some(message)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn clear_message(&self) -> Result<()> {
+        match std::fs::remove_file(self.path.join("message")) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the rebase state.
+    pub fn finish(&self) -> Result<()> {
+        match std::fs::remove_dir_all(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn parse_list(&self, name: &str, database: &Database) -> Result<Vec<TodoItem>> {
+        self.read_file(name)?
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| TodoItem::parse(line, database))
+            .collect()
+    }
+
+    fn read_file(&self, name: &str) -> Result<String> {
+        std::fs::read_to_string(self.path.join(name))
+            .map_err(|_| Error::Fatal("No rebase in progress?".to_string()))
+    }
+
+    fn write_file(&self, name: &str, contents: &str) -> Result<()> {
+        let mut lock = LockFile::new(self.path.join(name))?;
+        lock.writer().write_all(contents.as_bytes())?;
+        lock.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Blob, Storable};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let dir = TempDir::new("test_rebase_roundtrip")?;
+        let database = Database::new(dir.path().join("objects"));
+        let first = Blob::new(b"first".to_vec());
+        let second = Blob::new(b"second".to_vec());
+        database.store(&first)?;
+        database.store(&second)?;
+
+        let state = RebaseState::new(dir.path());
+        assert!(!state.in_progress());
+        assert!(state.todo(&database).is_err());
+
+        let todo = vec![
+            TodoItem::Commit {
+                action: Action::Pick,
+                oid: first.oid(),
+                title: "first change".to_string(),
+            },
+            TodoItem::Exec("make test".to_string()),
+            TodoItem::Commit {
+                action: Action::Fixup,
+                oid: second.oid(),
+                title: "second change".to_string(),
+            },
+        ];
+        state.start("refs/heads/topic", &second.oid(), &first.oid(), &todo)?;
+        assert!(state.in_progress());
+        assert!(state
+            .start("refs/heads/topic", &second.oid(), &first.oid(), &todo)
+            .is_err());
+
+        assert_eq!(state.head_name()?, "refs/heads/topic");
+        assert_eq!(state.onto()?, second.oid());
+        assert_eq!(state.orig_head()?, first.oid());
+        assert_eq!(state.todo(&database)?, todo);
+        assert!(state.done(&database)?.is_empty());
+
+        // Edited lists may use abbreviations, and contain comments.
+        state.write_todo_with_help(&todo[..1], "Rebase onto second")?;
+        assert_eq!(state.todo(&database)?, &todo[..1]);
+        std::fs::write(
+            state.todo_path(),
+            format!("f {}\nx true\n# comment\n", &second.oid().as_str()[..6]),
+        )?;
+        assert_eq!(
+            state.todo(&database)?,
+            vec![
+                TodoItem::Commit {
+                    action: Action::Fixup,
+                    oid: second.oid(),
+                    title: String::new(),
+                },
+                TodoItem::Exec("true".to_string()),
+            ]
+        );
+        std::fs::write(state.todo_path(), "edit 0000000\n")?;
+        assert!(state.todo(&database).is_err());
+
+        state.append_done(&todo[0])?;
+        state.append_done(&todo[1])?;
+        assert_eq!(state.done(&database)?, &todo[..2]);
+
+        assert_eq!(state.message()?, None);
+        state.save_message("message\n")?;
+        assert_eq!(state.message()?, Some("message\n".to_string()));
+        state.clear_message()?;
+        assert_eq!(state.message()?, None);
+
+        state.finish()?;
+        assert!(!state.in_progress());
+        Ok(())
+    }
+}
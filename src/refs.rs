@@ -1,8 +1,27 @@
+use crate::author::Author;
 use crate::database::ObjectID;
+use crate::error::{Error, Result};
 use crate::lockfile::LockFile;
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The reference describing the currently checked-out commit.
+pub const HEAD: &str = "HEAD";
+/// The reference recording the previous HEAD before a drastic change.
+pub const ORIG_HEAD: &str = "ORIG_HEAD";
+/// The commit being cherry-picked while conflicts are resolved.
+pub const CHERRY_PICK_HEAD: &str = "CHERRY_PICK_HEAD";
+/// The commit being reverted while conflicts are resolved.
+pub const REVERT_HEAD: &str = "REVERT_HEAD";
+
+// The object ID used by reflogs to describe a missing value.
+const NULL_OID: &str = "0000000000000000000000000000000000000000";
+
+// Prefix used to identify symbolic references.
+const SYMREF_PREFIX: &str = "ref: ";
 
 /// Shorthand names for git object IDs.
 pub struct Refs {
@@ -10,31 +29,640 @@ pub struct Refs {
 }
 
 impl Refs {
-    /// Takes a path to the directory containing references
-    /// as input.
+    /// Takes a path to the git directory as input.
     ///
-    /// As an example, to access ".git/refs/HEAD", the path
-    /// to ".git/refs" would be supplied to this constructor.
+    /// As an example, to access ".git/HEAD", the path
+    /// to ".git" would be supplied to this constructor.
     pub fn new<P: AsRef<Path>>(path: P) -> Refs {
         Refs {
             path: PathBuf::from(path.as_ref()),
         }
     }
 
-    /// Updates the HEAD file, returning an error if it is already in use.
-    pub fn update_head(&self, oid: &ObjectID) -> Result<()> {
-        let mut head = LockFile::new(self.head_path())?;
-        head.writer().write_all(oid.as_str().as_bytes())?;
-        head.commit()
+    /// Updates the reference pointed to by HEAD (or HEAD itself, if it is
+    /// detached), recording the change in the reflog.
+    ///
+    /// Returns an error if the reference is already in use.
+    pub fn update_head(&self, oid: &ObjectID, identity: &Author, message: &str) -> Result<()> {
+        let old = self.read_head().ok();
+        let current = self.current_ref()?;
+
+        self.update_ref(&current, oid)?;
+        self.append_reflog(&current, old.as_ref(), oid, identity, message)?;
+        if current != HEAD {
+            self.append_reflog(HEAD, old.as_ref(), oid, identity, message)?;
+        }
+        Ok(())
     }
 
+    /// Reads the object ID which HEAD ultimately refers to.
     pub fn read_head(&self) -> Result<ObjectID> {
-        let contents = std::fs::read(self.head_path())?;
-        let hex_oid: String = std::str::from_utf8(&contents)?.split_whitespace().collect();
-        ObjectID::from_str(hex_oid)
+        self.read_ref(HEAD)?.ok_or(Error::UnbornHead)
+    }
+
+    /// Returns the full name of the reference which HEAD points to, such as
+    /// "refs/heads/master", or "HEAD" if HEAD is detached.
+    pub fn current_ref(&self) -> Result<String> {
+        let mut name = HEAD.to_string();
+        while let Some(target) = self.read_symref(&name)? {
+            name = target;
+        }
+        Ok(name)
+    }
+
+    /// Resolves a reference by name, following symbolic references.
+    ///
+    /// The name is looked up in the same locations as git does, so
+    /// "master", "heads/master" and "refs/heads/master" are all equivalent.
+    /// Returns `None` if the reference does not exist.
+    pub fn read_ref(&self, name: &str) -> Result<Option<ObjectID>> {
+        match self.full_name(name)? {
+            Some(full_name) => self.read_ref_exact(&full_name),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the full name of the reference which `name` refers to, such
+    /// as "refs/heads/master" for "master", looking in the same locations
+    /// as `read_ref`. Returns `None` if the reference does not exist.
+    pub fn full_name(&self, name: &str) -> Result<Option<String>> {
+        for candidate in [
+            name.to_string(),
+            format!("refs/{}", name),
+            format!("refs/tags/{}", name),
+            format!("refs/heads/{}", name),
+            format!("refs/remotes/{}", name),
+        ]
+        .iter()
+        {
+            if self.read_ref_exact(candidate)?.is_some() {
+                return Ok(Some(candidate.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads a reference by its full name, following symbolic references.
+    /// Unlike `read_ref`, "main" means only the reference named "main".
+    pub fn read_ref_exact(&self, name: &str) -> Result<Option<ObjectID>> {
+        if let Some(target) = self.read_symref(name)? {
+            return self.read_ref_exact(&target);
+        }
+        let path = self.path.join(name);
+        if path.is_file() {
+            // Files such as FETCH_HEAD hold more than an ID; as in git,
+            // only the first word is used.
+            let contents = std::fs::read_to_string(path)?;
+            let oid = contents.split_whitespace().next().unwrap_or("");
+            return Ok(Some(ObjectID::from_str(oid)?));
+        }
+        self.read_packed_ref(name)
+    }
+
+    // Returns the target of `name` if it is a symbolic reference.
+    fn read_symref(&self, name: &str) -> Result<Option<String>> {
+        let path = self.path.join(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(contents
+            .trim()
+            .strip_prefix(SYMREF_PREFIX)
+            .map(|target| target.to_string()))
     }
 
-    fn head_path(&self) -> PathBuf {
-        self.path.join("HEAD")
+    // Looks up a reference within the "packed-refs" file, which git
+    // creates when compacting references.
+    fn read_packed_ref(&self, name: &str) -> Result<Option<ObjectID>> {
+        let path = self.path.join("packed-refs");
+        if !path.is_file() {
+            return Ok(None);
+        }
+        for line in std::fs::read_to_string(path)?.lines() {
+            if line.starts_with('#') || line.starts_with('^') {
+                continue;
+            }
+            if let Some((oid, ref_name)) = line.split_once(' ') {
+                if ref_name == name {
+                    return Ok(Some(ObjectID::from_str(oid)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns every reference beneath "refs/" by full name, along with the
+    /// object it refers to. Loose references take precedence over packed
+    /// ones, and symbolic references which do not resolve are omitted.
+    pub fn list_refs(&self) -> Result<BTreeMap<String, ObjectID>> {
+        let mut refs = BTreeMap::new();
+        let packed = self.path.join("packed-refs");
+        if packed.is_file() {
+            for line in std::fs::read_to_string(packed)?.lines() {
+                if line.starts_with('#') || line.starts_with('^') {
+                    continue;
+                }
+                if let Some((oid, name)) = line.split_once(' ') {
+                    refs.insert(name.to_string(), ObjectID::from_str(oid)?);
+                }
+            }
+        }
+        for name in list_files(&self.path, "refs")? {
+            if let Some(oid) = self.read_ref_exact(&name)? {
+                refs.insert(name, oid);
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Returns the full names of every reference with a reflog, such as
+    /// "HEAD" and "refs/heads/master", sorted.
+    pub fn list_reflogs(&self) -> Result<Vec<String>> {
+        let logs = self.path.join("logs");
+        let mut names = list_files(&logs, "refs")?;
+        if logs.join(HEAD).is_file() {
+            names.push(HEAD.to_string());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Points the reference with the full name `name` at `oid`, without
+    /// following symbolic references or recording a reflog entry.
+    pub fn update_ref(&self, name: &str, oid: &ObjectID) -> Result<()> {
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut lock = LockFile::new(path)?;
+        lock.writer()
+            .write_all(format!("{}\n", oid.as_str()).as_bytes())?;
+        lock.commit()
+    }
+
+    /// Updates the reference with the full name `name` from `old` to `new`
+    /// without recording a reflog entry, where a missing `old` means it
+    /// must not exist yet and a missing `new` deletes it.
+    ///
+    /// Fails without changing anything if the reference does not have the
+    /// value `old`.
+    pub fn compare_and_swap(
+        &self,
+        name: &str,
+        old: Option<&ObjectID>,
+        new: Option<&ObjectID>,
+    ) -> Result<()> {
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut lock = LockFile::new(path)?;
+        if self.read_ref_exact(name)?.as_ref() != old {
+            return Err(Error::Fatal(format!(
+                "cannot lock ref '{}': reference has changed",
+                name
+            )));
+        }
+        match new {
+            Some(new) => {
+                lock.writer()
+                    .write_all(format!("{}\n", new.as_str()).as_bytes())?;
+                lock.commit()
+            }
+            // Dropping the lock removes it.
+            None => self.delete_ref(name),
+        }
+    }
+
+    /// Makes `name` a symbolic reference to the reference named `target`,
+    /// as when attaching HEAD to a branch.
+    pub fn set_symref(&self, name: &str, target: &str) -> Result<()> {
+        let mut lock = LockFile::new(self.path.join(name))?;
+        lock.writer()
+            .write_all(format!("{}{}\n", SYMREF_PREFIX, target).as_bytes())?;
+        lock.commit()
+    }
+
+    /// Points the reference with the full name `name` at `oid`, recording
+    /// the change in the reference's reflog.
+    pub fn update_logged_ref(
+        &self,
+        name: &str,
+        oid: &ObjectID,
+        identity: &Author,
+        message: &str,
+    ) -> Result<()> {
+        let old = self.read_ref_exact(name)?;
+        self.update_ref(name, oid)?;
+        self.append_reflog(name, old.as_ref(), oid, identity, message)
+    }
+
+    /// Starts a set of reference updates which are applied together.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            refs: self,
+            updates: Vec::new(),
+        }
+    }
+
+    /// Deletes the reference with the full name `name`, along with its
+    /// reflog, whether it is loose or packed.
+    pub fn delete_ref(&self, name: &str) -> Result<()> {
+        for path in [self.path.join(name), self.path.join("logs").join(name)].iter() {
+            match std::fs::remove_file(path) {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.delete_packed_ref(name)
+    }
+
+    // Removes a reference from the "packed-refs" file, along with the line
+    // after it holding its peeled object, if any.
+    fn delete_packed_ref(&self, name: &str) -> Result<()> {
+        let path = self.path.join("packed-refs");
+        if !path.is_file() {
+            return Ok(());
+        }
+        let mut lock = LockFile::new(&path)?;
+        let mut contents = String::new();
+        let mut found = false;
+        let mut skip_peeled = false;
+        for line in std::fs::read_to_string(&path)?.lines() {
+            if line.starts_with('^') && skip_peeled {
+                continue;
+            }
+            skip_peeled = line.split_once(' ').map(|(_, ref_name)| ref_name) == Some(name);
+            if skip_peeled {
+                found = true;
+                continue;
+            }
+            contents.push_str(line);
+            contents.push('\n');
+        }
+        if !found {
+            // Dropping the lock removes it.
+            return Ok(());
+        }
+        lock.writer().write_all(contents.as_bytes())?;
+        lock.commit()
+    }
+
+    /// Reads the reflog of the reference with the full name `name`, oldest
+    /// entry first.
+    ///
+    /// A reference without a reflog has no entries.
+    pub fn read_reflog(&self, name: &str) -> Result<Vec<ReflogEntry>> {
+        let path = self.path.join("logs").join(name);
+        if !path.is_file() {
+            return Ok(vec![]);
+        }
+        std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(ReflogEntry::parse)
+            .collect()
+    }
+
+    /// Replaces the reflog of the reference with the full name `name`.
+    pub fn write_reflog(&self, name: &str, entries: &[ReflogEntry]) -> Result<()> {
+        let mut lock = LockFile::new(self.path.join("logs").join(name))?;
+        for entry in entries {
+            lock.writer().write_all(entry.to_line().as_bytes())?;
+        }
+        lock.commit()
+    }
+
+    // Appends an entry to the reflog of the reference `name`.
+    fn append_reflog(
+        &self,
+        name: &str,
+        old: Option<&ObjectID>,
+        new: &ObjectID,
+        identity: &Author,
+        message: &str,
+    ) -> Result<()> {
+        let path = self.path.join("logs").join(name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let entry = ReflogEntry {
+            old: old.cloned(),
+            new: new.clone(),
+            identity: identity.clone(),
+            // Reflog messages are stored on a single line.
+            message: message.lines().next().unwrap_or("").to_string(),
+        };
+
+        let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+        log.write_all(entry.to_line().as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Shortens the full name of a reference for display, as git does: for
+/// example "refs/remotes/origin/main" becomes "origin/main".
+pub fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+// A single update queued within a transaction.
+struct Update {
+    name: String,
+    old: Option<ObjectID>,
+    new: ObjectID,
+    message: Option<String>,
+}
+
+/// A set of reference updates which either all succeed or all fail.
+///
+/// Every reference is locked, and checked against its expected value,
+/// before any is changed. If another process holds a lock or has moved a
+/// reference in the meantime, no reference is updated.
+pub struct Transaction<'a> {
+    refs: &'a Refs,
+    updates: Vec<Update>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Queues an update of the reference with the full name `name` from
+    /// `old` (or None, if it must not exist yet) to `new`. With a
+    /// `message`, the change is recorded in the reference's reflog.
+    pub fn update(
+        &mut self,
+        name: &str,
+        old: Option<&ObjectID>,
+        new: &ObjectID,
+        message: Option<&str>,
+    ) {
+        self.updates.push(Update {
+            name: name.to_string(),
+            old: old.cloned(),
+            new: new.clone(),
+            message: message.map(|message| message.to_string()),
+        });
+    }
+
+    /// Applies every queued update.
+    pub fn commit(self, identity: &Author) -> Result<()> {
+        let mut locks = Vec::with_capacity(self.updates.len());
+        for update in &self.updates {
+            let path = self.refs.path.join(&update.name);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            let mut lock = LockFile::new(path)?;
+            if self.refs.read_ref_exact(&update.name)? != update.old {
+                return Err(Error::Fatal(format!(
+                    "cannot lock ref '{}': reference has changed",
+                    update.name
+                )));
+            }
+            lock.writer()
+                .write_all(format!("{}\n", update.new.as_str()).as_bytes())?;
+            locks.push(lock);
+        }
+        for lock in locks {
+            lock.commit()?;
+        }
+        for update in &self.updates {
+            if let Some(message) = &update.message {
+                self.refs.append_reflog(
+                    &update.name,
+                    update.old.as_ref(),
+                    &update.new,
+                    identity,
+                    message,
+                )?;
+            }
+            tracing::debug!(name = %update.name, new = %update.new.as_str(), "update ref");
+        }
+        Ok(())
+    }
+}
+
+// Returns the names of the files beneath `root/dir`, relative to `root` and
+// separated by "/", such as "refs/heads/master". Lock files are skipped.
+fn list_files(root: &Path, dir: &str) -> Result<Vec<String>> {
+    let path = root.join(dir);
+    if !path.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            names.extend(list_files(root, &name)?);
+        } else if !name.ends_with(".lock") {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// A single recorded update of a reference.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReflogEntry {
+    /// The previous value of the reference, if it existed.
+    pub old: Option<ObjectID>,
+    pub new: ObjectID,
+    pub identity: Author,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    // Entries are formatted as "{OLD} {NEW} {IDENTITY}\t{MESSAGE}".
+    fn parse(line: &str) -> Result<Self> {
+        let invalid = || Error::InvalidRef(format!("reflog entry '{}'", line));
+        let (old, rest) = line.split_once(' ').ok_or_else(invalid)?;
+        let (new, rest) = rest.split_once(' ').ok_or_else(invalid)?;
+        let (identity, message) = rest.split_once('\t').unwrap_or((rest, ""));
+        Ok(ReflogEntry {
+            old: match old {
+                NULL_OID => None,
+                _ => Some(ObjectID::from_str(old)?),
+            },
+            new: ObjectID::from_str(new)?,
+            identity: Author::parse(identity)?,
+            message: message.to_string(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        let old = self
+            .old
+            .as_ref()
+            .map(|oid| oid.as_str())
+            .unwrap_or_else(|| NULL_OID.to_string());
+        format!(
+            "{} {} {}\t{}\n",
+            old,
+            self.new.as_str(),
+            self.identity.to_str(),
+            self.message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::oid;
+    use tempdir::TempDir;
+
+    fn identity() -> Author {
+        Author::new(
+            "Sean".to_string(),
+            "sean@zombo.com".to_string(),
+            chrono::DateTime::parse_from_rfc3339("2021-01-01T01:01:01+00:00").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_update_head_follows_branch() -> Result<()> {
+        let dir = TempDir::new("test_update_head_follows_branch")?;
+        std::fs::write(dir.path().join("HEAD"), "ref: refs/heads/master\n")?;
+        let refs = Refs::new(dir.path());
+
+        assert!(refs.read_head().is_err());
+        assert_eq!(refs.current_ref()?, "refs/heads/master");
+
+        refs.update_head(&oid('a'), &identity(), "commit (initial): first")?;
+        refs.update_head(&oid('b'), &identity(), "commit: second")?;
+
+        assert_eq!(refs.read_head()?, oid('b'));
+        assert_eq!(refs.read_ref("master")?, Some(oid('b')));
+        assert_eq!(refs.read_ref("heads/master")?, Some(oid('b')));
+        assert_eq!(
+            refs.full_name("master")?.as_deref(),
+            Some("refs/heads/master")
+        );
+        assert_eq!(short_name("refs/heads/master"), "master");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("HEAD"))?,
+            "ref: refs/heads/master\n"
+        );
+
+        let log = std::fs::read_to_string(dir.path().join("logs/refs/heads/master"))?;
+        let entries = refs.read_reflog("refs/heads/master")?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].old, None);
+        assert_eq!(entries[0].new, oid('a'));
+        assert_eq!(entries[1].old, Some(oid('a')));
+        assert_eq!(entries[1].message, "commit: second");
+        assert_eq!(entries[1].identity, identity());
+        assert_eq!(std::fs::read_to_string(dir.path().join("logs/HEAD"))?, log);
+        assert_eq!(refs.list_reflogs()?, vec![HEAD, "refs/heads/master"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detached_head() -> Result<()> {
+        let dir = TempDir::new("test_detached_head")?;
+        std::fs::write(dir.path().join("HEAD"), format!("{}\n", oid('a').as_str()))?;
+        let refs = Refs::new(dir.path());
+
+        assert_eq!(refs.current_ref()?, HEAD);
+        refs.update_head(&oid('b'), &identity(), "reset: moving to b")?;
+        assert_eq!(refs.read_head()?, oid('b'));
+        assert!(!dir.path().join("refs/heads/master").exists());
+
+        // Reattaching HEAD makes it follow the branch again.
+        refs.update_ref("refs/heads/master", &oid('c'))?;
+        refs.set_symref(HEAD, "refs/heads/master")?;
+        assert_eq!(refs.current_ref()?, "refs/heads/master");
+        assert_eq!(refs.read_head()?, oid('c'));
+        Ok(())
+    }
+
+    #[test]
+    fn test_packed_refs() -> Result<()> {
+        let dir = TempDir::new("test_packed_refs")?;
+        std::fs::write(
+            dir.path().join("packed-refs"),
+            format!(
+                "# pack-refs with: peeled fully-peeled sorted\n{} refs/heads/topic\n",
+                oid('c').as_str()
+            ),
+        )?;
+        let refs = Refs::new(dir.path());
+        assert_eq!(refs.read_ref("topic")?, Some(oid('c')));
+        assert_eq!(refs.read_ref("missing")?, None);
+
+        // Loose references shadow packed ones.
+        refs.update_ref("refs/heads/topic", &oid('d'))?;
+        refs.update_ref("refs/tags/v1", &oid('e'))?;
+        let listed: Vec<_> = refs.list_refs()?.into_iter().collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("refs/heads/topic".to_string(), oid('d')),
+                ("refs/tags/v1".to_string(), oid('e')),
+            ]
+        );
+
+        // Deleting a reference removes its packed entry too.
+        refs.delete_ref("refs/heads/topic")?;
+        assert_eq!(refs.read_ref("topic")?, None);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("packed-refs"))?,
+            "# pack-refs with: peeled fully-peeled sorted\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction() -> Result<()> {
+        let dir = TempDir::new("test_transaction")?;
+        let refs = Refs::new(dir.path());
+        refs.update_ref("refs/heads/master", &oid('a'))?;
+
+        let mut transaction = refs.transaction();
+        transaction.update(
+            "refs/heads/master",
+            Some(&oid('a')),
+            &oid('b'),
+            Some("fetch"),
+        );
+        transaction.update("refs/tags/v1", None, &oid('c'), None);
+        transaction.commit(&identity())?;
+        assert_eq!(refs.read_ref("master")?, Some(oid('b')));
+        assert_eq!(refs.read_ref("v1")?, Some(oid('c')));
+        assert_eq!(refs.read_reflog("refs/heads/master")?[0].message, "fetch");
+        assert!(refs.read_reflog("refs/tags/v1")?.is_empty());
+
+        // Nothing changes if any reference has moved.
+        let mut transaction = refs.transaction();
+        transaction.update("refs/heads/topic", None, &oid('d'), None);
+        transaction.update("refs/heads/master", Some(&oid('a')), &oid('e'), None);
+        assert!(transaction.commit(&identity()).is_err());
+        assert_eq!(refs.read_ref("topic")?, None);
+        assert_eq!(refs.read_ref("master")?, Some(oid('b')));
+        assert!(!dir.path().join("refs/heads/topic.lock").exists());
+
+        // Single references are swapped, or deleted, in the same way.
+        let master = "refs/heads/master";
+        assert!(refs
+            .compare_and_swap(master, Some(&oid('a')), Some(&oid('e')))
+            .is_err());
+        refs.compare_and_swap(master, Some(&oid('b')), Some(&oid('e')))?;
+        assert_eq!(refs.read_ref("master")?, Some(oid('e')));
+        refs.compare_and_swap(master, Some(&oid('e')), None)?;
+        assert_eq!(refs.read_ref("master")?, None);
+        assert!(!dir.path().join("refs/heads/master.lock").exists());
+
+        // Only the first word of files such as FETCH_HEAD is read.
+        std::fs::write(
+            dir.path().join("FETCH_HEAD"),
+            format!("{}\t\tbranch 'master' of /src\n", oid('f').as_str()),
+        )?;
+        assert_eq!(refs.read_ref("FETCH_HEAD")?, Some(oid('f')));
+        Ok(())
     }
 }
@@ -0,0 +1,225 @@
+//! Refspecs, which map the references of one repository to those of
+//! another, such as "+refs/heads/*:refs/remotes/origin/*".
+
+use crate::error::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+// The prefixes tried, in order, when matching an abbreviated reference name
+// such as "main". Matches git's rules for resolving revisions.
+const ABBREVIATION_RULES: [&str; 5] = ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"];
+
+/// A refspec of the form "[+]<src>[:<dst>]", or "^<src>" for a negative
+/// refspec which excludes the references it matches.
+///
+/// Either both sides contain a single "*" glob, or neither does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    force: bool,
+    negative: bool,
+    source: String,
+    destination: Option<String>,
+}
+
+impl FromStr for Refspec {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = || Error::Fatal(format!("invalid refspec '{}'", spec));
+        let (negative, rest) = match spec.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (force, rest) = match rest.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (source, destination) = match rest.split_once(':') {
+            Some((source, destination)) => (source, Some(destination)),
+            None => (rest, None),
+        };
+
+        let globs = |side: &str| side.matches('*').count();
+        let valid = match destination {
+            _ if globs(source) > 1 => false,
+            // Negative refspecs only name what to exclude.
+            _ if negative => !force && destination.is_none() && !source.is_empty(),
+            Some(destination) => globs(destination) == globs(source),
+            None => !source.is_empty(),
+        };
+        if !valid {
+            return Err(invalid());
+        }
+        Ok(Refspec {
+            force,
+            negative,
+            source: source.to_string(),
+            destination: destination
+                .filter(|destination| !destination.is_empty())
+                .map(|destination| destination.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Refspec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negative {
+            write!(f, "^")?;
+        }
+        if self.force {
+            write!(f, "+")?;
+        }
+        write!(f, "{}", self.source)?;
+        if let Some(destination) = &self.destination {
+            write!(f, ":{}", destination)?;
+        }
+        Ok(())
+    }
+}
+
+impl Refspec {
+    /// Returns true if matching references may be updated even when the
+    /// change is not a fast-forward.
+    pub fn is_force(&self) -> bool {
+        self.force
+    }
+
+    /// Returns true for refspecs such as "^refs/heads/wip", which exclude
+    /// the references they match.
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn is_glob(&self) -> bool {
+        self.source.contains('*')
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn destination(&self) -> Option<&str> {
+        self.destination.as_deref()
+    }
+
+    /// Returns true if the reference with the full name `name` matches the
+    /// source side. Sources which are not full names, such as "main", also
+    /// match "refs/heads/main" (and so on, following git's rules).
+    pub fn matches(&self, name: &str) -> bool {
+        if let Some((prefix, suffix)) = self.source.split_once('*') {
+            return name.len() >= prefix.len() + suffix.len()
+                && name.starts_with(prefix)
+                && name.ends_with(suffix);
+        }
+        !self.source.is_empty()
+            && ABBREVIATION_RULES
+                .iter()
+                .any(|rule| name.strip_prefix(rule) == Some(&self.source))
+    }
+
+    /// Returns the destination which the reference `name` maps to, or None
+    /// if the reference does not match or the refspec has no destination.
+    ///
+    /// With globs, the part of `name` matched by "*" is substituted into the
+    /// destination. Destinations which are not full names are taken to be
+    /// in the same namespace as `name`, such as "refs/tags/" for a tag.
+    pub fn map(&self, name: &str) -> Option<String> {
+        let destination = self.destination.as_deref()?;
+        if !self.matches(name) {
+            return None;
+        }
+        if let Some((prefix, suffix)) = self.source.split_once('*') {
+            let matched = &name[prefix.len()..name.len() - suffix.len()];
+            return Some(destination.replacen('*', matched, 1));
+        }
+        if destination.starts_with("refs/") || destination == "HEAD" {
+            return Some(destination.to_string());
+        }
+        let namespace = if name.starts_with("refs/tags/") {
+            "refs/tags/"
+        } else {
+            "refs/heads/"
+        };
+        Some(format!("{}{}", namespace, destination))
+    }
+
+    /// Returns the prefixes of the reference names which the source side
+    /// may match, which are used to limit the references a server lists.
+    pub fn prefixes(&self) -> Vec<String> {
+        match self.source.split_once('*') {
+            Some((prefix, _)) => vec![prefix.to_string()],
+            None if self.source.starts_with("refs/") => vec![self.source.clone()],
+            None => ABBREVIATION_RULES
+                .iter()
+                .map(|rule| format!("{}{}", rule, self.source))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let spec: Refspec = "+refs/heads/*:refs/remotes/origin/*".parse()?;
+        assert!(spec.is_force());
+        assert!(spec.is_glob());
+        assert!(!spec.is_negative());
+        assert_eq!(spec.source(), "refs/heads/*");
+        assert_eq!(spec.destination(), Some("refs/remotes/origin/*"));
+        assert_eq!(spec.to_string(), "+refs/heads/*:refs/remotes/origin/*");
+
+        let spec: Refspec = "^refs/heads/wip/*".parse()?;
+        assert!(spec.is_negative());
+        assert_eq!(spec.destination(), None);
+
+        let spec: Refspec = "main".parse()?;
+        assert!(!spec.is_force());
+        assert_eq!(spec.destination(), None);
+        assert_eq!("main:".parse::<Refspec>()?, spec);
+
+        for invalid in [
+            "",
+            "refs/heads/*:refs/remotes/origin/main",
+            "refs/*/*:refs/*/*",
+            "^+refs/heads/main",
+            "^refs/heads/main:refs/heads/other",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<Refspec>().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_map() -> Result<()> {
+        let spec: Refspec = "+refs/heads/*:refs/remotes/origin/*".parse()?;
+        assert_eq!(
+            spec.map("refs/heads/feature/x"),
+            Some("refs/remotes/origin/feature/x".to_string())
+        );
+        assert_eq!(spec.map("refs/tags/v1"), None);
+        assert_eq!(spec.prefixes(), vec!["refs/heads/"]);
+
+        let spec: Refspec = "refs/heads/*-wip:refs/wip/*".parse()?;
+        assert!(spec.matches("refs/heads/a-wip"));
+        assert!(!spec.matches("refs/heads/-wi"));
+        assert_eq!(spec.map("refs/heads/a-wip"), Some("refs/wip/a".to_string()));
+
+        // Abbreviated names match as revisions do.
+        let spec: Refspec = "main:topic".parse()?;
+        assert!(spec.matches("refs/heads/main"));
+        assert!(!spec.matches("refs/heads/main2"));
+        assert_eq!(
+            spec.map("refs/heads/main"),
+            Some("refs/heads/topic".to_string())
+        );
+        let spec: Refspec = "v1:v1".parse()?;
+        assert_eq!(spec.map("refs/tags/v1"), Some("refs/tags/v1".to_string()));
+        assert!(spec.prefixes().contains(&"refs/tags/v1".to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,428 @@
+use crate::author::Author;
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::database::{Blob, Database, Object, ObjectID, Storable};
+use crate::entry::Entry;
+use crate::error::{Error, Result};
+use crate::hooks::Hooks;
+use crate::index::Index;
+use crate::init;
+use crate::rebase::RebaseState;
+use crate::refs::{Refs, CHERRY_PICK_HEAD, HEAD, ORIG_HEAD, REVERT_HEAD};
+use crate::revision::{self, Revision};
+use crate::sequencer::Sequencer;
+use crate::tree::Tree;
+use crate::workspace::{Workspace, WorkspacePath};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+// Files at least this large are streamed into the database, unless
+// core.bigFileThreshold says otherwise. Matches git's default.
+const DEFAULT_BIG_FILE_THRESHOLD: u64 = 512 << 20;
+
+/// A repository: a workspace along with the git directory holding its
+/// objects, refs and index.
+///
+/// This is the entry point for using rit as a library. The underlying
+/// `Database`, `Refs` and `Workspace` are also exposed for operations
+/// which are not covered here.
+pub struct Repository {
+    root_path: PathBuf,
+    git_path: PathBuf,
+    database: Database,
+    refs: Refs,
+    workspace: Workspace,
+}
+
+impl Repository {
+    /// Creates an empty repository whose workspace is `path`, returning
+    /// it opened.
+    ///
+    /// Initializing an existing repository leaves its contents intact.
+    pub fn init<P: AsRef<Path>>(path: P) -> Result<Self> {
+        init::init(path.as_ref(), &init::Options::default())?;
+        Repository::open(path)
+    }
+
+    /// Opens the repository whose workspace is `path`. Its git directory
+    /// is "<path>/.git", or wherever a ".git" file names.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let root_path = path.as_ref().to_path_buf();
+        let git_path = match init::git_dir(&root_path) {
+            Some(git_path) => git_path,
+            None => return Err(Error::NotARepository(root_path)),
+        };
+        let config = Config::load(git_path.join("config"))?;
+        let symlinks = config.get_bool("core.symlinks")?.unwrap_or(true);
+        Ok(Repository {
+            database: Database::new(git_path.join("objects")),
+            refs: Refs::new(&git_path),
+            workspace: Workspace::new(&root_path).with_symlinks(symlinks),
+            root_path,
+            git_path,
+        })
+    }
+
+    /// Opens the repository containing `path`, searching its ancestors.
+    pub fn discover<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        path.ancestors()
+            .find(|dir| init::git_dir(dir).is_some())
+            .map(Repository::open)
+            .unwrap_or_else(|| Err(Error::NotARepository(path.to_path_buf())))
+    }
+
+    /// Returns the root of the workspace.
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Returns the location of the git directory, such as "<root>/.git".
+    pub fn git_path(&self) -> &Path {
+        &self.git_path
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    pub fn refs(&self) -> &Refs {
+        &self.refs
+    }
+
+    pub fn workspace(&self) -> &Workspace {
+        &self.workspace
+    }
+
+    /// Reads ".git/config".
+    pub fn config(&self) -> Result<Config> {
+        Config::load(self.git_path.join("config"))
+    }
+
+    /// Returns the hooks commands run: those within ".git/hooks", or the
+    /// directory named by "core.hooksPath".
+    pub fn hooks(&self) -> Result<Hooks> {
+        let dir = match self.config()?.get("core.hooksPath") {
+            Some(path) => self.root_path.join(path),
+            None => self.git_path.join("hooks"),
+        };
+        let index = self.git_path.join("index");
+        Ok(Hooks::new(dir, &self.root_path).with_env("GIT_INDEX_FILE", &index.to_string_lossy()))
+    }
+
+    /// Returns the size (core.bigFileThreshold) from which files are
+    /// streamed into the database rather than read into memory.
+    pub fn big_file_threshold(&self) -> Result<u64> {
+        Ok(self
+            .config()?
+            .get_int("core.bigFileThreshold")?
+            .unwrap_or(DEFAULT_BIG_FILE_THRESHOLD))
+    }
+
+    /// Stores the contents of a workspace file as a blob, returning its ID.
+    /// `threshold` is the result of `big_file_threshold`.
+    ///
+    /// For a submodule, nothing is stored and the ID of the commit it has
+    /// checked out is returned.
+    pub fn store_file(
+        &self,
+        path: &WorkspacePath,
+        metadata: &Metadata,
+        threshold: u64,
+    ) -> Result<ObjectID> {
+        // A submodule is recorded as the commit it has checked out.
+        if metadata.is_dir() {
+            return self.workspace.submodule_head(path)?.ok_or_else(|| {
+                Error::Fatal(format!(
+                    "'{}' does not have a commit checked out",
+                    path.as_partial_path().display()
+                ))
+            });
+        }
+        // Symlinks are stored as their (short) target, so are never
+        // streamed.
+        if metadata.len() < threshold || metadata.file_type().is_symlink() {
+            let blob = Blob::new(self.workspace.read_file(path)?);
+            self.database.store(&blob)?;
+            return Ok(blob.oid());
+        }
+        let mut file = self.workspace.open_file(path)?;
+        self.database
+            .store_stream("blob", metadata.len(), &mut file)
+    }
+
+    /// Returns the number of threads (index.threads) used to hash
+    /// workspace files, where 0 picks one per CPU.
+    pub fn threads(&self) -> Result<usize> {
+        let config = self.config()?;
+        let value = match config.get("index.threads") {
+            Some(value) => value,
+            None => return Ok(0),
+        };
+        if let Ok(threads) = value.parse() {
+            return Ok(threads);
+        }
+        // As in git, "true" picks automatically and "false" disables
+        // threading.
+        Ok(match config.get_bool("index.threads")? {
+            Some(false) => 1,
+            _ => 0,
+        })
+    }
+
+    /// Stores the contents of each of `files` as blobs, spreading the
+    /// work of reading, hashing and compressing over `threads()` threads.
+    ///
+    /// Returns the metadata and ID of each file, in the same order as
+    /// `files`. Directories are skipped, unless they are submodules.
+    pub fn store_files(
+        &self,
+        files: Vec<WorkspacePath>,
+    ) -> Result<Vec<(WorkspacePath, Metadata, ObjectID)>> {
+        let threshold = self.big_file_threshold()?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads()?)
+            .thread_name(|i| format!("th{:02}:store", i + 1))
+            .build()
+            .map_err(|e| Error::Fatal(format!("could not start threads: {}", e)))?;
+        let _span = tracing::info_span!("store_files", count = files.len()).entered();
+        let stored: Vec<Option<_>> = pool.install(|| {
+            files
+                .into_par_iter()
+                .map(|file| {
+                    let metadata = self.workspace.metadata(&file)?;
+                    if metadata.is_dir() && self.workspace.nested_git_dir(&file).is_none() {
+                        tracing::trace!(path = %file.as_partial_path().display(), "ignoring directory");
+                        return Ok(None);
+                    }
+                    tracing::trace!(path = %file.as_partial_path().display(), "reading file");
+                    let oid = self.store_file(&file, &metadata, threshold)?;
+                    Ok(Some((file, metadata, oid)))
+                })
+                .collect::<Result<_>>()
+        })?;
+        Ok(stored.into_iter().flatten().collect())
+    }
+
+    /// Reads the index. Changes are saved with `Index::write`.
+    pub fn load_index(&self) -> Result<Index> {
+        Index::load(self.git_path.join("index"))
+    }
+
+    /// Loads the object named `oid`.
+    pub fn load(&self, oid: &ObjectID) -> Result<Object> {
+        self.database.load(oid)
+    }
+
+    /// Resolves a revision expression, such as "master~2" or
+    /// "HEAD:src/main.rs", to an object ID.
+    pub fn resolve(&self, revision: &str) -> Result<ObjectID> {
+        Revision::parse(revision)?.resolve(&self.refs, &self.database)
+    }
+
+    /// Returns the commit HEAD refers to, or None on an unborn branch.
+    pub fn head(&self) -> Result<Option<ObjectID>> {
+        self.refs.read_ref(HEAD)
+    }
+
+    /// Stores the tree (and subtrees) containing `entries`.
+    pub fn write_tree(&self, entries: Vec<Entry>) -> Result<Tree> {
+        Tree::build(entries, |tree| self.database.store(tree))
+    }
+
+    /// Stores a new commit. No refs are updated; see `Refs::update_head`.
+    pub fn create_commit(
+        &self,
+        parents: Vec<ObjectID>,
+        tree: ObjectID,
+        author: Author,
+        committer: Author,
+        message: String,
+    ) -> Result<Commit> {
+        let commit = Commit::new(parents, tree, author, committer, message);
+        self.database.store(&commit)?;
+        Ok(commit)
+    }
+
+    /// Returns the objects which must be kept, named after what refers to
+    /// them: HEAD, pseudo-references such as ORIG_HEAD, every reference
+    /// and reflog entry, and the commits recorded by an in-progress rebase,
+    /// cherry-pick or revert.
+    ///
+    /// The index also refers to objects, which are not included.
+    pub fn referenced_objects(&self) -> Result<Vec<(String, ObjectID)>> {
+        let mut objects = Vec::new();
+        for name in [HEAD, ORIG_HEAD, CHERRY_PICK_HEAD, REVERT_HEAD].iter() {
+            if let Some(oid) = self.refs.read_ref(name)? {
+                objects.push((name.to_string(), oid));
+            }
+        }
+        objects.extend(self.refs.list_refs()?);
+        for name in self.refs.list_reflogs()? {
+            for entry in self.refs.read_reflog(&name)? {
+                objects.extend(entry.old.map(|oid| (name.clone(), oid)));
+                objects.push((name.clone(), entry.new));
+            }
+        }
+        let rebase = RebaseState::new(&self.git_path);
+        if rebase.in_progress() {
+            objects.push(("rebase onto".to_string(), rebase.onto()?));
+            objects.push(("rebase orig-head".to_string(), rebase.orig_head()?));
+        }
+        let sequencer = Sequencer::new(&self.git_path);
+        if sequencer.in_progress() {
+            objects.push(("sequencer head".to_string(), sequencer.head()?));
+        }
+        Ok(objects)
+    }
+
+    /// Returns every file within a tree, keyed by path. `oid` may also name
+    /// a commit (or a tag), in which case its tree is walked.
+    pub fn walk_tree(&self, oid: &ObjectID) -> Result<BTreeMap<PathBuf, Entry>> {
+        let tree = revision::peel_to_tree(&self.database, oid.clone())?;
+        self.database.load_tree_list(&tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Mode;
+    use crate::index::IndexEntry;
+    use anyhow::Result;
+    use std::fs::create_dir_all;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_init_open_discover() -> Result<()> {
+        let dir = TempDir::new("test_repository")?;
+        assert!(Repository::open(dir.path()).is_err());
+        assert!(Repository::discover(dir.path()).is_err());
+
+        let repo = Repository::init(dir.path())?;
+        assert_eq!(repo.git_path(), dir.path().join(".git"));
+        assert_eq!(repo.head()?, None);
+        assert!(repo.refs().current_ref()?.ends_with("master"));
+
+        let nested = dir.path().join("a/b");
+        create_dir_all(&nested)?;
+        assert!(Repository::open(&nested).is_err());
+        assert_eq!(Repository::discover(&nested)?.root_path(), dir.path());
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_and_walk() -> Result<()> {
+        let dir = TempDir::new("test_repository")?;
+        let repo = Repository::init(dir.path())?;
+
+        let blob = Blob::new(b"hello".to_vec());
+        repo.database().store(&blob)?;
+        let entries = vec![
+            Entry::new(WorkspacePath::new("a.txt")?, blob.oid(), Mode::ReadWrite),
+            Entry::new(
+                WorkspacePath::new("dir/b.txt")?,
+                blob.oid(),
+                Mode::ReadWrite,
+            ),
+        ];
+        let tree = repo.write_tree(entries)?;
+
+        let time = chrono::DateTime::parse_from_rfc3339("2021-01-01T01:01:01+00:00")?;
+        let author = Author::new("Sean".to_string(), "sean@zombo.com".to_string(), time);
+        let commit = repo.create_commit(
+            vec![],
+            tree.oid(),
+            author.clone(),
+            author.clone(),
+            "first\n".to_string(),
+        )?;
+        repo.refs().update_head(&commit.oid(), &author, "commit")?;
+
+        assert_eq!(repo.head()?, Some(commit.oid()));
+        assert_eq!(repo.resolve("master")?, commit.oid());
+        assert_eq!(repo.resolve("HEAD:dir/b.txt")?, blob.oid());
+        match repo.load(&commit.oid())? {
+            Object::Commit(loaded) => assert_eq!(loaded.message(), "first\n"),
+            object => panic!("Unexpected {}", object.type_name()),
+        }
+
+        let files = repo.walk_tree(&commit.oid())?;
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            vec![Path::new("a.txt"), Path::new("dir/b.txt")]
+        );
+        assert_eq!(repo.walk_tree(&tree.oid())?, files);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_big_file() -> Result<()> {
+        let dir = TempDir::new("test_repository")?;
+        let repo = Repository::init(dir.path())?;
+        assert_eq!(repo.big_file_threshold()?, DEFAULT_BIG_FILE_THRESHOLD);
+        std::fs::write(
+            dir.path().join(".git/config"),
+            "[core]\n\tbigFileThreshold = 1k\n",
+        )?;
+        let threshold = repo.big_file_threshold()?;
+        assert_eq!(threshold, 1024);
+
+        for size in [10, 4096].iter() {
+            let path = WorkspacePath::new(format!("{}.bin", size))?;
+            let data = vec![b'x'; *size];
+            std::fs::write(repo.workspace().full_path(&path), &data)?;
+            let metadata = repo.workspace().metadata(&path)?;
+            let oid = repo.store_file(&path, &metadata, threshold)?;
+            assert_eq!(oid, Blob::new(data.clone()).oid());
+            assert_eq!(repo.database().load_blob(&oid)?.into_data(), data);
+        }
+
+        // Comparing a big file with the index streams it through the hasher.
+        let path = WorkspacePath::new("4096.bin")?;
+        let small = repo.workspace().metadata(&WorkspacePath::new("10.bin")?)?;
+        let entry = IndexEntry::new(path.clone(), Blob::new(vec![b'x'; 4096]).oid(), &small);
+        assert!(entry.matches_workspace(repo.workspace(), threshold)?);
+        std::fs::write(repo.workspace().full_path(&path), vec![b'y'; 4096])?;
+        assert!(!entry.matches_workspace(repo.workspace(), threshold)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_files() -> Result<()> {
+        let dir = TempDir::new("test_repository")?;
+        let repo = Repository::init(dir.path())?;
+        let mut files = Vec::new();
+        for i in 0..64 {
+            let path = WorkspacePath::new(format!("file{}.txt", i))?;
+            std::fs::write(repo.workspace().full_path(&path), format!("{}\n", i))?;
+            files.push(path);
+        }
+        create_dir_all(dir.path().join("empty"))?;
+        files.insert(10, WorkspacePath::new("empty")?);
+
+        let config = dir.path().join(".git/config");
+        let mut results = Vec::new();
+        for (setting, threads) in [("4", 4), ("false", 1), ("true", 0)].iter() {
+            std::fs::write(&config, format!("[index]\n\tthreads = {}\n", setting))?;
+            assert_eq!(repo.threads()?, *threads);
+            let stored: Vec<_> = repo
+                .store_files(files.clone())?
+                .into_iter()
+                .map(|(path, _, oid)| (path, oid))
+                .collect();
+            results.push(stored);
+        }
+
+        // The output follows the input order, regardless of threading.
+        assert_eq!(results[0].len(), 64);
+        assert!(results.iter().all(|stored| *stored == results[0]));
+        for (i, (path, oid)) in results[0].iter().enumerate() {
+            assert_eq!(*path, files[if i < 10 { i } else { i + 1 }]);
+            assert_eq!(*oid, Blob::new(format!("{}\n", i).into_bytes()).oid());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+use crate::database::{Database, Object, ObjectID};
+use crate::error::{Error, Result};
+use crate::refs::{Refs, HEAD};
+use std::collections::HashSet;
+
+/// A parsed revision expression, such as "master", "HEAD~2", "abc123^" or
+/// "HEAD:src/main.rs".
+#[derive(Debug, PartialEq, Eq)]
+pub enum Revision {
+    /// A reference name, or an (abbreviated) object ID.
+    Ref(String),
+    /// The Nth parent of a revision ("rev^N"). The 0th parent of a
+    /// revision is the revision itself.
+    Parent(Box<Revision>, usize),
+    /// The Nth generation ancestor of a revision, following first
+    /// parents ("rev~N").
+    Ancestor(Box<Revision>, usize),
+    /// The tree or blob at a path within the tree of a revision
+    /// ("rev:path"). An empty path names the root tree.
+    Path(Box<Revision>, String),
+}
+
+impl Revision {
+    /// Parses a revision expression.
+    pub fn parse(expr: &str) -> Result<Self> {
+        let invalid = || Error::InvalidRevision(expr.to_string());
+
+        // The path may contain any character, so is split off first.
+        if let Some((base, path)) = expr.split_once(':') {
+            if base.is_empty() {
+                return Err(invalid());
+            }
+            let path = path.trim_matches('/').to_string();
+            return Ok(Revision::Path(Box::new(Revision::parse(base)?), path));
+        }
+
+        // Suffixes are parsed right-to-left, so "HEAD~2^" is the first
+        // parent of "HEAD~2".
+        if let Some(index) = expr.rfind(['^', '~']) {
+            let (base, suffix) = expr.split_at(index);
+            let count = &suffix[1..];
+            let count = if count.is_empty() {
+                1
+            } else if count.bytes().all(|b| b.is_ascii_digit()) {
+                count.parse().map_err(|_| invalid())?
+            } else {
+                return Err(invalid());
+            };
+            let base = Box::new(Revision::parse(base)?);
+            return Ok(if suffix.starts_with('^') {
+                Revision::Parent(base, count)
+            } else {
+                Revision::Ancestor(base, count)
+            });
+        }
+
+        match expr {
+            "@" => Ok(Revision::Ref(HEAD.to_string())),
+            _ if Revision::valid_ref_name(expr) => Ok(Revision::Ref(expr.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+
+    // Checks a subset of the rules git applies to reference names
+    // (see "git check-ref-format"). As names become paths within the git
+    // directory, this keeps them from escaping it.
+    pub(crate) fn valid_ref_name(name: &str) -> bool {
+        !name.is_empty()
+            && !name.starts_with('/')
+            && !name.ends_with('/')
+            && name
+                .split('/')
+                .all(|component| !component.starts_with('.') && !component.ends_with(".lock"))
+            && !name.contains("..")
+            && !name.contains("@{")
+            && !name.contains("//")
+            && !name
+                .chars()
+                .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+    }
+
+    /// Resolves the revision to an object ID.
+    pub fn resolve(&self, refs: &Refs, database: &Database) -> Result<ObjectID> {
+        match self {
+            Revision::Ref(name) => Revision::resolve_name(name, refs, database),
+            Revision::Parent(base, n) => {
+                let oid = base.resolve(refs, database)?;
+                if *n == 0 {
+                    return Ok(oid);
+                }
+                let oid = peel_to_commit(database, oid)?;
+                let commit = database.load_commit(&oid)?;
+                commit
+                    .parents()
+                    .get(n - 1)
+                    .cloned()
+                    .ok_or_else(|| Error::UnknownRevision(format!("{}^{}", oid.as_str(), n)))
+            }
+            Revision::Ancestor(base, n) => {
+                let mut oid = peel_to_commit(database, base.resolve(refs, database)?)?;
+                for _ in 0..*n {
+                    let commit = database.load_commit(&oid)?;
+                    oid = commit
+                        .parent()
+                        .cloned()
+                        .ok_or_else(|| Error::UnknownRevision(format!("{}~1", oid.as_str())))?;
+                }
+                Ok(oid)
+            }
+            Revision::Path(base, path) => {
+                let mut oid = peel_to_tree(database, base.resolve(refs, database)?)?;
+                for name in path.split('/').filter(|name| !name.is_empty()) {
+                    let tree = database
+                        .load_tree(&oid)
+                        .map_err(|_| Error::PathNotFound(path.clone()))?;
+                    oid = tree
+                        .entries()
+                        .iter()
+                        .find(|entry| entry.path().as_os_str() == name)
+                        .map(|entry| entry.oid().clone())
+                        .ok_or_else(|| Error::PathNotFound(path.clone()))?;
+                }
+                Ok(oid)
+            }
+        }
+    }
+
+    // Resolves a reference name, falling back to an abbreviated object ID.
+    fn resolve_name(name: &str, refs: &Refs, database: &Database) -> Result<ObjectID> {
+        if let Some(oid) = refs.read_ref(name)? {
+            return Ok(oid);
+        }
+
+        let is_hex = name.bytes().all(|b| b.is_ascii_hexdigit());
+        if is_hex && (4..=40).contains(&name.len()) {
+            let candidates = database.prefix_match(&name.to_ascii_lowercase())?;
+            match candidates.len() {
+                0 => (),
+                1 => return Ok(candidates[0].clone()),
+                _ => {
+                    return Err(Error::AmbiguousObjectId {
+                        prefix: name.to_string(),
+                        candidates: candidates.iter().map(|oid| oid.as_short_str()).collect(),
+                    });
+                }
+            }
+        }
+        Err(Error::UnknownRevision(name.to_string()))
+    }
+}
+
+/// Follows annotated tags until reaching a non-tag object.
+pub fn peel(database: &Database, mut oid: ObjectID) -> Result<ObjectID> {
+    loop {
+        match database.load(&oid)? {
+            Object::Tag(tag) => oid = tag.object().clone(),
+            _ => return Ok(oid),
+        }
+    }
+}
+
+// Peels an object to the commit it names.
+fn peel_to_commit(database: &Database, oid: ObjectID) -> Result<ObjectID> {
+    let oid = peel(database, oid)?;
+    database.load_commit(&oid)?;
+    Ok(oid)
+}
+
+/// Peels an object to the tree it names, which is the tree of a commit.
+pub fn peel_to_tree(database: &Database, oid: ObjectID) -> Result<ObjectID> {
+    let oid = peel(database, oid)?;
+    match database.load(&oid)? {
+        Object::Commit(commit) => Ok(commit.tree().clone()),
+        Object::Tree(_) => Ok(oid),
+        object => Err(object.wrong_type(&oid, "tree")),
+    }
+}
+
+/// Returns the commits reachable from `include` but not from `exclude`,
+/// with parents ordered before their children (as with
+/// "git rev-list --reverse --topo-order exclude..include").
+pub fn commit_range(
+    database: &Database,
+    exclude: Option<&ObjectID>,
+    include: &ObjectID,
+) -> Result<Vec<ObjectID>> {
+    let mut excluded = HashSet::new();
+    let mut pending: Vec<ObjectID> = exclude.into_iter().cloned().collect();
+    while let Some(oid) = pending.pop() {
+        if excluded.insert(oid.clone()) {
+            pending.extend(database.load_commit(&oid)?.parents().iter().cloned());
+        }
+    }
+
+    // A depth-first walk, emitting each commit once all of its parents
+    // have been emitted.
+    let mut range = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(include.clone(), false)];
+    while let Some((oid, expanded)) = stack.pop() {
+        if expanded {
+            range.push(oid);
+            continue;
+        }
+        if excluded.contains(&oid) || !visited.insert(oid.clone()) {
+            continue;
+        }
+        let commit = database.load_commit(&oid)?;
+        stack.push((oid, true));
+        for parent in commit.parents().iter().rev() {
+            stack.push((parent.clone(), false));
+        }
+    }
+    Ok(range)
+}
+
+/// Returns true if `ancestor` is reachable from `descendant` (including
+/// when they are the same commit), so that moving a reference from one to
+/// the other is a fast-forward.
+pub fn is_ancestor(
+    database: &Database,
+    ancestor: &ObjectID,
+    descendant: &ObjectID,
+) -> Result<bool> {
+    let mut visited = HashSet::new();
+    let mut pending = vec![descendant.clone()];
+    while let Some(oid) = pending.pop() {
+        if oid == *ancestor {
+            return Ok(true);
+        }
+        if visited.insert(oid.clone()) {
+            pending.extend(database.load_commit(&oid)?.parents().iter().cloned());
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Author;
+    use crate::commit::Commit;
+    use crate::database::Storable;
+    use anyhow::Result;
+    use std::str::FromStr;
+    use tempdir::TempDir;
+
+    fn named(name: &str) -> Box<Revision> {
+        Box::new(Revision::Ref(name.to_string()))
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(Revision::parse("master")?, *named("master"));
+        assert_eq!(Revision::parse("@")?, *named("HEAD"));
+        assert_eq!(
+            Revision::parse("HEAD^")?,
+            Revision::Parent(named("HEAD"), 1)
+        );
+        assert_eq!(
+            Revision::parse("HEAD^2")?,
+            Revision::Parent(named("HEAD"), 2)
+        );
+        assert_eq!(
+            Revision::parse("HEAD~3")?,
+            Revision::Ancestor(named("HEAD"), 3)
+        );
+        assert_eq!(
+            Revision::parse("topic~2^")?,
+            Revision::Parent(Box::new(Revision::Ancestor(named("topic"), 2)), 1)
+        );
+        assert_eq!(
+            Revision::parse("HEAD~1:src/a b.rs")?,
+            Revision::Path(
+                Box::new(Revision::Ancestor(named("HEAD"), 1)),
+                "src/a b.rs".to_string()
+            )
+        );
+        assert_eq!(
+            Revision::parse("master:")?,
+            Revision::Path(named("master"), String::new())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for expr in [
+            ".hidden",
+            "a..b",
+            "HEAD~x",
+            "^",
+            "bad name",
+            "x.lock",
+            ":path",
+            "refs/.x/y",
+            "refs/x.lock/y",
+        ]
+        .iter()
+        {
+            assert!(Revision::parse(expr).is_err(), "{} should not parse", expr);
+        }
+    }
+
+    #[test]
+    fn test_commit_range() -> Result<()> {
+        let dir = TempDir::new("test_commit_range")?;
+        let database = Database::new(dir.path());
+        let author = Author::new(
+            "Sean".to_string(),
+            "sean@zombo.com".to_string(),
+            chrono::DateTime::parse_from_rfc3339("2021-01-01T01:01:01+00:00")?,
+        );
+        let tree = ObjectID::from_str("4b825dc642cb6eb9a060e54bf8d69288fbee4904")?;
+        let commit = |parents: Vec<&ObjectID>, message: &str| -> Result<ObjectID> {
+            let commit = Commit::new(
+                parents.into_iter().cloned().collect(),
+                tree.clone(),
+                author.clone(),
+                author.clone(),
+                message.to_string(),
+            );
+            database.store(&commit)?;
+            Ok(commit.oid())
+        };
+
+        // a - b - c - e
+        //      \     /
+        //       - d -
+        let a = commit(vec![], "a")?;
+        let b = commit(vec![&a], "b")?;
+        let c = commit(vec![&b], "c")?;
+        let d = commit(vec![&b], "d")?;
+        let e = commit(vec![&c, &d], "e")?;
+
+        assert_eq!(
+            commit_range(&database, None, &b)?,
+            vec![a.clone(), b.clone()]
+        );
+        assert_eq!(
+            commit_range(&database, Some(&b), &e)?,
+            vec![c.clone(), d.clone(), e.clone()]
+        );
+        assert!(is_ancestor(&database, &d, &e)?);
+        assert!(is_ancestor(&database, &a, &a)?);
+        assert!(!is_ancestor(&database, &c, &d)?);
+        assert_eq!(commit_range(&database, Some(&c), &d)?, vec![d]);
+        assert!(commit_range(&database, Some(&e), &a)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use crate::database::ObjectID;
+use crate::error::{Error, Result};
+use crate::lockfile::LockFile;
+use std::fs::create_dir;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The operation applied by a single step of a sequence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Applies the change introduced by a commit.
+    Pick,
+    /// Applies the inverse of the change introduced by a commit.
+    Revert,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Pick => "pick",
+            Action::Revert => "revert",
+        }
+    }
+
+    /// Returns the name of the command performing this action, as used
+    /// within messages.
+    pub fn command_name(&self) -> &'static str {
+        match self {
+            Action::Pick => "cherry-pick",
+            Action::Revert => "revert",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pick" | "p" => Ok(Action::Pick),
+            "revert" => Ok(Action::Revert),
+            _ => Err(Error::Fatal(format!("Invalid sequencer action: {}", s))),
+        }
+    }
+}
+
+/// A single line of the todo list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub action: Action,
+    pub oid: ObjectID,
+    /// The title of the commit; purely informational.
+    pub title: String,
+}
+
+impl Step {
+    pub fn new(action: Action, oid: ObjectID, title: &str) -> Self {
+        Step {
+            action,
+            oid,
+            title: title.to_string(),
+        }
+    }
+
+    // Parses a line of the form "pick <oid> <title>".
+    fn parse(line: &str) -> Result<Self> {
+        let mut fields = line.splitn(3, ' ');
+        let action = Action::from_str(fields.next().unwrap_or(""))?;
+        let oid = fields
+            .next()
+            .ok_or_else(|| Error::Fatal(format!("Missing commit in todo line: {}", line)))?;
+        Ok(Step {
+            action,
+            oid: ObjectID::from_str(oid)?,
+            title: fields.next().unwrap_or("").to_string(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {}\n",
+            self.action.as_str(),
+            self.oid.as_str(),
+            self.title
+        )
+    }
+}
+
+/// Settings which apply to every step of a sequence.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    /// Appends "(cherry picked from commit ...)" to picked commit messages.
+    pub record_origin: bool,
+}
+
+/// The state of an in-progress cherry-pick or revert, which may be
+/// interrupted by conflicts and later resumed.
+///
+/// Uses the same ".git/sequencer" layout as git: "head" records the commit
+/// to return to on abort, "todo" lists the remaining steps and "opts" holds
+/// the options (in git config format). The message for a commit which is
+/// awaiting conflict resolution is kept in ".git/MERGE_MSG".
+pub struct Sequencer {
+    path: PathBuf,
+    message_path: PathBuf,
+}
+
+impl Sequencer {
+    /// Takes a path to the git directory as input.
+    pub fn new<P: AsRef<Path>>(git_path: P) -> Self {
+        Sequencer {
+            path: git_path.as_ref().join("sequencer"),
+            message_path: git_path.as_ref().join("MERGE_MSG"),
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    /// Records the state of a new sequence, which will return to `head` if
+    /// aborted.
+    pub fn start(&self, head: &ObjectID, options: &Options, todo: &[Step]) -> Result<()> {
+        if self.in_progress() {
+            return Err(Error::Fatal(
+                "A cherry-pick or revert is already in progress".to_string(),
+            ));
+        }
+        create_dir(&self.path)?;
+        self.write_file("head", &format!("{}\n", head.as_str()))?;
+        let mut opts = String::from("[options]\n");
+        if options.record_origin {
+            opts.push_str("\trecord-origin = true\n");
+        }
+        self.write_file("opts", &opts)?;
+        self.write_todo(todo)
+    }
+
+    /// Returns the commit which HEAD pointed to when the sequence started.
+    pub fn head(&self) -> Result<ObjectID> {
+        ObjectID::from_str(self.read_file("head")?.trim())
+    }
+
+    pub fn options(&self) -> Result<Options> {
+        let mut options = Options::default();
+        for line in self.read_file("opts")?.lines() {
+            if let Some((key, value)) = line.trim().split_once('=') {
+                if key.trim() == "record-origin" {
+                    options.record_origin = value.trim() == "true";
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    /// Returns the remaining steps, in the order they will be applied.
+    pub fn todo(&self) -> Result<Vec<Step>> {
+        self.read_file("todo")?
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Step::parse)
+            .collect()
+    }
+
+    pub fn write_todo(&self, todo: &[Step]) -> Result<()> {
+        let contents: String = todo.iter().map(|step| step.to_line()).collect();
+        self.write_file("todo", &contents)
+    }
+
+    /// Saves the message of a commit which could not be applied cleanly.
+    pub fn save_message(&self, message: &str) -> Result<()> {
+        std::fs::write(&self.message_path, message)?;
+        Ok(())
+    }
+
+    /// Returns the message saved by `save_message`.
+    pub fn message(&self) -> Result<String> {
+        std::fs::read_to_string(&self.message_path).map_err(|_| {
+            Error::Fatal("No commit message is awaiting conflict resolution".to_string())
+        })
+    }
+
+    /// Removes the sequencer state, including any saved message.
+    pub fn quit(&self) -> Result<()> {
+        match std::fs::remove_file(&self.message_path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        match std::fs::remove_dir_all(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_file(&self, name: &str) -> Result<String> {
+        std::fs::read_to_string(self.path.join(name))
+            .map_err(|_| Error::Fatal("No cherry-pick or revert in progress".to_string()))
+    }
+
+    fn write_file(&self, name: &str, contents: &str) -> Result<()> {
+        let mut lock = LockFile::new(self.path.join(name))?;
+        lock.writer().write_all(contents.as_bytes())?;
+        lock.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::oid;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let dir = TempDir::new("test_sequencer_roundtrip")?;
+        let sequencer = Sequencer::new(dir.path());
+        assert!(!sequencer.in_progress());
+        assert!(sequencer.todo().is_err());
+
+        let todo = vec![
+            Step::new(Action::Pick, oid('a'), "first change"),
+            Step::new(Action::Revert, oid('b'), "second change"),
+        ];
+        let options = Options {
+            record_origin: true,
+        };
+        sequencer.start(&oid('c'), &options, &todo)?;
+        assert!(sequencer.in_progress());
+        assert!(sequencer.start(&oid('c'), &options, &todo).is_err());
+
+        assert_eq!(sequencer.head()?, oid('c'));
+        assert_eq!(sequencer.options()?, options);
+        assert_eq!(sequencer.todo()?, todo);
+
+        sequencer.write_todo(&todo[1..])?;
+        assert_eq!(sequencer.todo()?, &todo[1..]);
+
+        sequencer.save_message("first change\n")?;
+        assert_eq!(sequencer.message()?, "first change\n");
+
+        sequencer.quit()?;
+        assert!(!sequencer.in_progress());
+        assert!(sequencer.message().is_err());
+        Ok(())
+    }
+}
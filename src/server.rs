@@ -0,0 +1,947 @@
+//! The server side of fetching and pushing, as run by `upload-pack` and
+//! `receive-pack` over a client's connection (standard input and output,
+//! for "file://" URLs).
+//!
+//! Fetches use protocol version 2 when the client asks for it through
+//! GIT_PROTOCOL, and version 0 otherwise. Pushes always use version 0, as
+//! in git.
+
+use crate::clone::Source;
+use crate::database::{Database, Object, ObjectID};
+use crate::entry::Mode;
+use crate::error::{Error, Result};
+use crate::gc;
+use crate::pack;
+use crate::protocol::{
+    agent, packet_line, protocol_error, read_line, read_packet, write_delimiter, write_flush,
+    write_packet, Packet, RemoteRef, BAND_DATA, MAX_PACKET_LENGTH, NULL_OID,
+};
+use crate::refs::{Refs, HEAD};
+use crate::revision::{self, Revision};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, Write};
+
+// The capabilities upload-pack advertises in protocol version 0, besides
+// its agent and where HEAD points.
+const UPLOAD_PACK_CAPABILITIES: [&str; 4] =
+    ["side-band-64k", "ofs-delta", "include-tag", "no-progress"];
+
+// The capabilities receive-pack advertises, besides its agent.
+const RECEIVE_PACK_CAPABILITIES: [&str; 3] = ["report-status", "delete-refs", "ofs-delta"];
+
+// The capabilities advertised in protocol version 2.
+const V2_CAPABILITIES: [&str; 4] = ["ls-refs", "fetch", "server-option", "object-format=sha1"];
+
+// The largest sideband packet's payload, after its band number.
+const MAX_BAND_LENGTH: usize = MAX_PACKET_LENGTH - 5;
+
+/// Which parts of a session a server runs. Over HTTP, where each request
+/// is separate, the advertisement and then each request are served alone,
+/// as with git's "--advertise-refs" and "--stateless-rpc".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Only sends the advertisement.
+    pub advertise_refs: bool,
+    /// Skips the advertisement, and ends once the input does.
+    pub stateless_rpc: bool,
+}
+
+// Returns true if the value of GIT_PROTOCOL asks for version 2. It may
+// hold other parameters too, separated by ":".
+pub(crate) fn is_version_2(protocol: Option<&str>) -> bool {
+    protocol.is_some_and(|protocol| {
+        protocol
+            .split(':')
+            .any(|parameter| parameter == "version=2")
+    })
+}
+
+// Returns true once the client has closed the connection.
+fn at_end(input: &mut impl BufRead) -> Result<bool> {
+    Ok(input.fill_buf()?.is_empty())
+}
+
+// Sends `message` to the client as an error, and returns it.
+fn send_error(output: &mut impl Write, message: String) -> Error {
+    let sent = write_packet(output, format!("ERR {}\n", message).as_bytes())
+        .and_then(|_| Ok(output.flush()?));
+    if let Err(e) = sent {
+        return e;
+    }
+    Error::Fatal(message)
+}
+
+// Lists HEAD (if it resolves) and then every reference, with the target
+// of HEAD and the objects annotated tags point at.
+fn list_refs(database: &Database, refs: &Refs) -> Result<Vec<RemoteRef>> {
+    let mut listed = vec![];
+    if let Some(oid) = refs.read_ref_exact(HEAD)? {
+        let target = refs.current_ref()?;
+        listed.push(RemoteRef {
+            name: HEAD.to_string(),
+            oid,
+            symref_target: Some(target).filter(|target| target != HEAD),
+            peeled: None,
+        });
+    }
+    for (name, oid) in refs.list_refs()? {
+        let peeled = revision::peel(database, oid.clone())?;
+        listed.push(RemoteRef {
+            name,
+            peeled: Some(peeled).filter(|peeled| *peeled != oid),
+            oid,
+            symref_target: None,
+        });
+    }
+    Ok(listed)
+}
+
+// Sends a version 0 advertisement: a line per reference, the first of
+// which is followed by "\0" and the capabilities.
+fn advertise(output: &mut impl Write, lines: &[String], capabilities: &[String]) -> Result<()> {
+    let capabilities = capabilities.join(" ");
+    match lines.split_first() {
+        Some((first, rest)) => {
+            write_packet(output, format!("{}\0{}\n", first, capabilities).as_bytes())?;
+            for line in rest {
+                write_packet(output, format!("{}\n", line).as_bytes())?;
+            }
+        }
+        // Without references, the capabilities still need a line.
+        None => {
+            let line = format!("{} capabilities^{{}}\0{}\n", NULL_OID, capabilities);
+            write_packet(output, line.as_bytes())?;
+        }
+    }
+    write_flush(output)?;
+    output.flush()?;
+    Ok(())
+}
+
+// Parses the object ID at the start of `line`, after `prefix`.
+fn parse_oid(line: &str, prefix: &str) -> Result<Option<ObjectID>> {
+    match line.strip_prefix(prefix) {
+        Some(rest) => Ok(Some(rest.split(' ').next().unwrap_or(rest).parse()?)),
+        None => Ok(None),
+    }
+}
+
+// Checks that every want is the tip of an advertised reference.
+fn check_wants(
+    output: &mut impl Write,
+    advertised: &[RemoteRef],
+    wants: &[ObjectID],
+) -> Result<()> {
+    for want in wants {
+        let ours = advertised
+            .iter()
+            .any(|remote_ref| remote_ref.oid == *want || remote_ref.peeled.as_ref() == Some(want));
+        if !ours {
+            return Err(send_error(
+                output,
+                format!("upload-pack: not our ref {}", want.as_str()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Writes a pack of the objects reachable from `wants` but not from
+// `common`. With `include_tag`, annotated tags pointing into the pack are
+// included too.
+fn pack_objects(
+    database: &Database,
+    advertised: &[RemoteRef],
+    wants: &[ObjectID],
+    common: &[ObjectID],
+    include_tag: bool,
+) -> Result<Vec<u8>> {
+    let known = gc::reachable(database, common.iter().cloned())?;
+    let mut objects: BTreeSet<ObjectID> = gc::reachable(database, wants.iter().cloned())?
+        .difference(&known)
+        .cloned()
+        .collect();
+    if include_tag {
+        for remote_ref in advertised {
+            let tagged = match &remote_ref.peeled {
+                Some(peeled) => objects.contains(peeled),
+                None => false,
+            };
+            if tagged && !known.contains(&remote_ref.oid) {
+                let tags = gc::reachable(database, vec![remote_ref.oid.clone()])?;
+                objects.extend(tags.difference(&known).cloned());
+            }
+        }
+    }
+    let objects: Vec<ObjectID> = objects.into_iter().collect();
+    tracing::debug!(objects = objects.len(), "upload-pack");
+    pack::write(database, &objects, &HashMap::new())
+}
+
+// Sends `pack`, split into packets on the data band if `sideband`.
+fn send_pack(output: &mut impl Write, pack: &[u8], sideband: bool) -> Result<()> {
+    if !sideband {
+        output.write_all(pack)?;
+        return Ok(());
+    }
+    for chunk in pack.chunks(MAX_BAND_LENGTH) {
+        let mut data = Vec::with_capacity(chunk.len() + 1);
+        data.push(BAND_DATA);
+        data.extend_from_slice(chunk);
+        write_packet(output, &data)?;
+    }
+    write_flush(output)
+}
+
+/// Serves a fetch from the repository `source`, reading requests from
+/// `input` and writing responses to `output`, until the client is done.
+///
+/// `protocol` is the value of GIT_PROTOCOL, which selects the version.
+pub fn upload_pack(
+    source: &Source,
+    protocol: Option<&str>,
+    options: Options,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<()> {
+    let database = source.database();
+    let refs = source.refs();
+    let advertised = list_refs(&database, &refs)?;
+    if is_version_2(protocol) {
+        return upload_pack_v2(&database, &advertised, options, input, output);
+    }
+
+    let mut capabilities: Vec<String> = UPLOAD_PACK_CAPABILITIES
+        .iter()
+        .map(|capability| capability.to_string())
+        .collect();
+    if let Some(target) = advertised
+        .first()
+        .and_then(|head| head.symref_target.as_ref())
+    {
+        capabilities.push(format!("symref={}:{}", HEAD, target));
+    }
+    capabilities.push(format!("agent={}", agent()));
+    let mut lines = vec![];
+    for remote_ref in &advertised {
+        lines.push(format!("{} {}", remote_ref.oid.as_str(), remote_ref.name));
+        if let Some(peeled) = &remote_ref.peeled {
+            lines.push(format!("{} {}^{{}}", peeled.as_str(), remote_ref.name));
+        }
+    }
+    if !options.stateless_rpc {
+        advertise(output, &lines, &capabilities)?;
+    }
+    if options.advertise_refs || at_end(input)? {
+        return Ok(());
+    }
+
+    // Each want may list capabilities after its ID, though only the first
+    // does in practice.
+    let mut wants = vec![];
+    let mut requested = BTreeSet::new();
+    while let Some(line) = read_line(input)? {
+        match parse_oid(&line, "want ")? {
+            Some(want) => wants.push(want),
+            None => return Err(protocol_error(format!("unexpected '{}'", line))),
+        }
+        requested.extend(line.split(' ').skip(2).map(|word| word.to_string()));
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+    check_wants(output, &advertised, &wants)?;
+
+    // Without multi_ack, only the first common object is acknowledged, and
+    // rounds without one are answered with "NAK".
+    let mut common = vec![];
+    loop {
+        match read_line(input)? {
+            None => {
+                if common.is_empty() {
+                    write_packet(output, b"NAK\n")?;
+                }
+                output.flush()?;
+                // A stateless client sends its next round separately.
+                if at_end(input)? {
+                    return Ok(());
+                }
+            }
+            Some(line) if line == "done" => break,
+            Some(line) => {
+                let have = parse_oid(&line, "have ")?
+                    .ok_or_else(|| protocol_error(format!("unexpected '{}'", line)))?;
+                if database.contains(&have)? {
+                    if common.is_empty() {
+                        write_packet(output, format!("ACK {}\n", have.as_str()).as_bytes())?;
+                    }
+                    common.push(have);
+                }
+            }
+        }
+    }
+    if common.is_empty() {
+        write_packet(output, b"NAK\n")?;
+    }
+
+    let include_tag = requested.contains("include-tag");
+    let pack = pack_objects(&database, &advertised, &wants, &common, include_tag)?;
+    send_pack(output, &pack, requested.contains("side-band-64k"))?;
+    output.flush()?;
+    Ok(())
+}
+
+// A protocol version 2 request: a command and its arguments.
+struct Request {
+    command: String,
+    args: Vec<String>,
+}
+
+// Reads a request of the form "command=<name>", capabilities, a delimiter
+// and arguments, ending with a flush. A flush alone ends the session.
+fn read_request(input: &mut impl BufRead) -> Result<Option<Request>> {
+    let mut lines = vec![];
+    let mut args = None;
+    loop {
+        match read_packet(input)? {
+            Packet::Data(data) => lines.push(packet_line(data)?),
+            Packet::Delimiter if args.is_none() => args = Some(lines.len()),
+            Packet::Flush if lines.is_empty() => return Ok(None),
+            Packet::Flush => break,
+            packet => return Err(protocol_error(format!("unexpected {:?}", packet))),
+        }
+    }
+    let args = lines.split_off(args.unwrap_or(lines.len()));
+    let command = lines
+        .first()
+        .and_then(|line| line.strip_prefix("command="))
+        .ok_or_else(|| protocol_error("missing command"))?
+        .to_string();
+    Ok(Some(Request { command, args }))
+}
+
+// Serves protocol version 2: the capabilities are advertised, and then
+// each request answered in turn.
+fn upload_pack_v2(
+    database: &Database,
+    advertised: &[RemoteRef],
+    options: Options,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<()> {
+    if !options.stateless_rpc {
+        write_packet(output, b"version 2\n")?;
+        write_packet(output, format!("agent={}\n", agent()).as_bytes())?;
+        for capability in V2_CAPABILITIES.iter() {
+            write_packet(output, format!("{}\n", capability).as_bytes())?;
+        }
+        write_flush(output)?;
+        output.flush()?;
+    }
+    if options.advertise_refs {
+        return Ok(());
+    }
+
+    while !at_end(input)? {
+        let request = match read_request(input)? {
+            Some(request) => request,
+            None => break,
+        };
+        match request.command.as_str() {
+            "ls-refs" => ls_refs(advertised, &request.args, output)?,
+            "fetch" => fetch(database, advertised, &request.args, output)?,
+            command => return Err(send_error(output, format!("invalid command '{}'", command))),
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+// Lists the references whose names start with any "ref-prefix" given.
+fn ls_refs(advertised: &[RemoteRef], args: &[String], output: &mut impl Write) -> Result<()> {
+    let symrefs = args.iter().any(|arg| arg == "symrefs");
+    let peel = args.iter().any(|arg| arg == "peel");
+    let prefixes: Vec<&str> = args
+        .iter()
+        .filter_map(|arg| arg.strip_prefix("ref-prefix "))
+        .collect();
+    for remote_ref in advertised {
+        if !prefixes.is_empty()
+            && !prefixes
+                .iter()
+                .any(|prefix| remote_ref.name.starts_with(prefix))
+        {
+            continue;
+        }
+        let mut line = format!("{} {}", remote_ref.oid.as_str(), remote_ref.name);
+        if let (true, Some(target)) = (symrefs, &remote_ref.symref_target) {
+            line.push_str(&format!(" symref-target:{}", target));
+        }
+        if let (true, Some(peeled)) = (peel, &remote_ref.peeled) {
+            line.push_str(&format!(" peeled:{}", peeled.as_str()));
+        }
+        line.push('\n');
+        write_packet(output, line.as_bytes())?;
+    }
+    write_flush(output)
+}
+
+// Answers a round of negotiation, sending the pack once the client is
+// done or any common object is found.
+fn fetch(
+    database: &Database,
+    advertised: &[RemoteRef],
+    args: &[String],
+    output: &mut impl Write,
+) -> Result<()> {
+    let mut wants = vec![];
+    let mut common = vec![];
+    let mut done = false;
+    let mut include_tag = false;
+    for arg in args {
+        if let Some(want) = parse_oid(arg, "want ")? {
+            wants.push(want);
+        } else if let Some(have) = parse_oid(arg, "have ")? {
+            if database.contains(&have)? {
+                common.push(have);
+            }
+        } else {
+            match arg.as_str() {
+                "done" => done = true,
+                "include-tag" => include_tag = true,
+                "thin-pack" | "ofs-delta" | "no-progress" => (),
+                _ => return Err(send_error(output, format!("unexpected line: '{}'", arg))),
+            }
+        }
+    }
+    check_wants(output, advertised, &wants)?;
+
+    if !done {
+        write_packet(output, b"acknowledgments\n")?;
+        if common.is_empty() {
+            write_packet(output, b"NAK\n")?;
+            return write_flush(output);
+        }
+        for oid in &common {
+            write_packet(output, format!("ACK {}\n", oid.as_str()).as_bytes())?;
+        }
+        write_packet(output, b"ready\n")?;
+        write_delimiter(output)?;
+    }
+    write_packet(output, b"packfile\n")?;
+    let pack = pack_objects(database, advertised, &wants, &common, include_tag)?;
+    send_pack(output, &pack, true)
+}
+
+// A reference update requested by a push.
+struct Command {
+    name: String,
+    old: Option<ObjectID>,
+    new: Option<ObjectID>,
+}
+
+// Parses an object ID in a command, where the null ID stands for none.
+fn parse_command_oid(oid: &str) -> Result<Option<ObjectID>> {
+    if oid == NULL_OID {
+        Ok(None)
+    } else {
+        Ok(Some(oid.parse()?))
+    }
+}
+
+/// Serves a push to the repository `source`, reading the commands and pack
+/// from `input`, updating references and reporting the outcome of each to
+/// `output`.
+///
+/// Updating the checked out branch of a repository with a workspace is
+/// refused, as by git's default "receive.denyCurrentBranch".
+pub fn receive_pack(
+    source: &Source,
+    options: Options,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<()> {
+    let database = source.database();
+    let refs = source.refs();
+    let lines: Vec<String> = refs
+        .list_refs()?
+        .iter()
+        .map(|(name, oid)| format!("{} {}", oid.as_str(), name))
+        .collect();
+    let mut capabilities: Vec<String> = RECEIVE_PACK_CAPABILITIES
+        .iter()
+        .map(|capability| capability.to_string())
+        .collect();
+    capabilities.push(format!("agent={}", agent()));
+    if !options.stateless_rpc {
+        advertise(output, &lines, &capabilities)?;
+    }
+    if options.advertise_refs || at_end(input)? {
+        return Ok(());
+    }
+
+    let mut commands = vec![];
+    let mut requested = vec![];
+    while let Some(line) = read_line(input)? {
+        let line = match line.split_once('\0') {
+            Some((line, capabilities)) => {
+                requested.extend(capabilities.split(' ').map(|word| word.to_string()));
+                line.to_string()
+            }
+            None => line,
+        };
+        let words: Vec<&str> = line.splitn(3, ' ').collect();
+        match words[..] {
+            [old, new, name] => commands.push(Command {
+                name: name.to_string(),
+                old: parse_command_oid(old)?,
+                new: parse_command_oid(new)?,
+            }),
+            _ => return Err(protocol_error(format!("unexpected '{}'", line))),
+        }
+    }
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    // Only deletions come without a pack.
+    let unpacked = if commands.iter().any(|command| command.new.is_some()) {
+        pack::read(input).and_then(|pack| database.store_pack(&pack))
+    } else {
+        Ok(vec![])
+    };
+    let (received, unpack_error) = match unpacked {
+        Ok(oids) => (oids.into_iter().collect(), None),
+        Err(e) => (HashSet::new(), Some(e.to_string())),
+    };
+    let current = refs.current_ref()?;
+    let deny_non_fast_forwards = source
+        .config()?
+        .get_bool("receive.denyNonFastForwards")?
+        .unwrap_or(false);
+
+    let mut statuses = vec![];
+    for command in &commands {
+        let non_fast_forward = || match (&command.old, &command.new) {
+            (Some(old), Some(new)) if command.name.starts_with("refs/heads/") => {
+                Ok(!revision::is_ancestor(&database, old, new)?)
+            }
+            _ => Ok::<_, Error>(false),
+        };
+        let error = if unpack_error.is_some() {
+            Some("unpacker error")
+        } else if !command.name.starts_with("refs/") || !Revision::valid_ref_name(&command.name) {
+            Some("funny refname")
+        } else if !source.is_bare() && command.name == current {
+            Some("branch is currently checked out")
+        } else if match &command.new {
+            Some(new) => !is_connected(&database, new, &received)?,
+            None => false,
+        } {
+            Some("missing necessary objects")
+        } else if deny_non_fast_forwards && non_fast_forward()? {
+            Some("non-fast-forward")
+        } else {
+            refs.compare_and_swap(&command.name, command.old.as_ref(), command.new.as_ref())
+                .err()
+                .map(|_| "failed to update ref")
+        };
+        tracing::debug!(name = %command.name, ?error, "receive-pack");
+        statuses.push(error);
+    }
+
+    if requested
+        .iter()
+        .any(|capability| capability == "report-status")
+    {
+        let unpack = match &unpack_error {
+            Some(error) => format!("unpack {}\n", error),
+            None => "unpack ok\n".to_string(),
+        };
+        write_packet(output, unpack.as_bytes())?;
+        for (command, error) in commands.iter().zip(statuses) {
+            let line = match error {
+                Some(error) => format!("ng {} {}\n", command.name, error),
+                None => format!("ok {}\n", command.name),
+            };
+            write_packet(output, line.as_bytes())?;
+        }
+        write_flush(output)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+// Returns true if every object reachable from `tip` exists. The objects
+// just `received` are walked, down to those which already existed (and so
+// are complete, as the repository was).
+fn is_connected(database: &Database, tip: &ObjectID, received: &HashSet<ObjectID>) -> Result<bool> {
+    let mut visited = HashSet::new();
+    let mut pending = vec![tip.clone()];
+    while let Some(oid) = pending.pop() {
+        if !visited.insert(oid.clone()) {
+            continue;
+        }
+        if !database.contains(&oid)? {
+            return Ok(false);
+        }
+        if !received.contains(&oid) {
+            continue;
+        }
+        match database.load(&oid)? {
+            Object::Commit(commit) => {
+                pending.push(commit.tree().clone());
+                pending.extend(commit.parents().iter().cloned());
+            }
+            Object::Tree(tree) => pending.extend(
+                tree.entries()
+                    .iter()
+                    .filter(|entry| *entry.mode() != Mode::Gitlink)
+                    .map(|entry| entry.oid().clone()),
+            ),
+            Object::Tag(tag) => pending.push(tag.object().clone()),
+            Object::Blob(_) => (),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::git;
+    use anyhow::Result;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use tempdir::TempDir;
+
+    // Creates a repository with two commits on "main", and an annotated
+    // tag on the first.
+    fn create_repo(dir: &Path) {
+        git(dir, &["init", "-q", "-b", "main"]);
+        for contents in ["first", "second"].iter() {
+            std::fs::write(dir.join("file.txt"), contents).unwrap();
+            git(dir, &["add", "file.txt"]);
+            git(dir, &["commit", "-q", "-m", contents]);
+            if *contents == "first" {
+                git(dir, &["tag", "-a", "v1", "-m", "v1"]);
+            }
+        }
+    }
+
+    fn request(lines: &[Option<&str>]) -> Result<Vec<u8>> {
+        let mut input = vec![];
+        for line in lines {
+            match line {
+                Some("") => write_delimiter(&mut input)?,
+                Some(line) => write_packet(&mut input, format!("{}\n", line).as_bytes())?,
+                None => write_flush(&mut input)?,
+            }
+        }
+        Ok(input)
+    }
+
+    // Reads text lines up to the next flush.
+    fn read_lines(output: &mut &[u8]) -> Result<Vec<String>> {
+        let mut lines = vec![];
+        while let Some(line) = read_line(output)? {
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    // Reads a pack sent on the data band, up to the next flush.
+    fn read_sideband_pack(output: &mut &[u8]) -> Result<Vec<u8>> {
+        let mut pack = vec![];
+        while let Packet::Data(data) = read_packet(output)? {
+            assert_eq!(data[0], BAND_DATA);
+            pack.extend_from_slice(&data[1..]);
+        }
+        Ok(pack)
+    }
+
+    #[test]
+    fn test_upload_pack_v2() -> Result<()> {
+        let dir = TempDir::new("test_upload_pack")?;
+        create_repo(dir.path());
+        let source = Source::locate(".", dir.path())?;
+        let main = git(dir.path(), &["rev-parse", "main"]);
+        let first = git(dir.path(), &["rev-parse", "main~1"]);
+        let (main, first) = (main.trim(), first.trim());
+
+        let input = request(&[
+            Some("command=ls-refs"),
+            Some(""),
+            Some("symrefs"),
+            Some("ref-prefix HEAD"),
+            Some("ref-prefix refs/heads/"),
+            None,
+            Some("command=fetch"),
+            Some(""),
+            Some(&format!("want {}", main)),
+            Some(&format!("have {}", first)),
+            Some("include-tag"),
+            None,
+            None,
+        ])?;
+        let mut output = vec![];
+        upload_pack(
+            &source,
+            Some("version=2"),
+            Options::default(),
+            &mut input.as_slice(),
+            &mut output,
+        )?;
+
+        let mut output = output.as_slice();
+        let capabilities = read_lines(&mut output)?;
+        assert_eq!(capabilities[0], "version 2");
+        assert!(capabilities.contains(&"fetch".to_string()));
+        assert_eq!(
+            read_lines(&mut output)?,
+            vec![
+                format!("{} HEAD symref-target:refs/heads/main", main),
+                format!("{} refs/heads/main", main),
+            ]
+        );
+
+        // Having the first commit, only the second is sent: the commit,
+        // its tree and the changed file.
+        assert_eq!(read_line(&mut output)?.as_deref(), Some("acknowledgments"));
+        assert_eq!(read_line(&mut output)?, Some(format!("ACK {}", first)));
+        assert_eq!(read_line(&mut output)?.as_deref(), Some("ready"));
+        assert_eq!(read_packet(&mut output)?, Packet::Delimiter);
+        assert_eq!(read_line(&mut output)?.as_deref(), Some("packfile"));
+        let pack = read_sideband_pack(&mut output)?;
+        assert!(output.is_empty());
+        let database = Database::in_memory();
+        assert_eq!(pack::unpack(&database, &pack)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_pack_v0() -> Result<()> {
+        let dir = TempDir::new("test_upload_pack")?;
+        create_repo(dir.path());
+        let source = Source::locate(dir.path().to_str().unwrap(), dir.path())?;
+        let main = git(dir.path(), &["rev-parse", "main"]);
+        let main = main.trim();
+
+        let input = request(&[
+            Some(&format!("want {} side-band-64k include-tag", main)),
+            None,
+            Some("have 1111111111111111111111111111111111111111"),
+            None,
+            Some("done"),
+        ])?;
+        let mut output = vec![];
+        upload_pack(
+            &source,
+            None,
+            Options::default(),
+            &mut input.as_slice(),
+            &mut output,
+        )?;
+
+        // The advertisement matches git's, apart from the capabilities.
+        let mut output = output.as_slice();
+        let mut advertised = read_lines(&mut output)?;
+        let (first, capabilities) = advertised[0].split_once('\0').unwrap();
+        assert!(capabilities.contains("symref=HEAD:refs/heads/main"));
+        advertised[0] = first.to_string();
+        let expected = git(dir.path(), &["show-ref", "--head", "-d"]);
+        assert_eq!(advertised, expected.lines().collect::<Vec<_>>());
+
+        // Nothing is in common, so everything is sent, including the tag.
+        assert_eq!(read_line(&mut output)?.as_deref(), Some("NAK"));
+        assert_eq!(read_line(&mut output)?.as_deref(), Some("NAK"));
+        let pack = read_sideband_pack(&mut output)?;
+        let database = Database::in_memory();
+        let objects = git(dir.path(), &["rev-list", "--objects", "--all"]);
+        assert_eq!(
+            pack::unpack(&database, &pack)?.len(),
+            objects.lines().count()
+        );
+
+        // Only advertised objects may be fetched.
+        let input = request(&[Some(&format!("want {}", "2".repeat(40))), None])?;
+        let mut output = vec![];
+        assert!(upload_pack(
+            &source,
+            None,
+            Options::default(),
+            &mut input.as_slice(),
+            &mut output
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_receive_pack() -> Result<()> {
+        let dir = TempDir::new("test_receive_pack")?;
+        let work = dir.path().join("work");
+        let bare = dir.path().join("bare.git");
+        std::fs::create_dir(&work)?;
+        create_repo(&work);
+        git(dir.path(), &["init", "-q", "--bare", "bare.git"]);
+        let source = Source::locate("bare.git", dir.path())?;
+        let main = git(&work, &["rev-parse", "main"]);
+        let main = main.trim();
+
+        let mut input = request(&[
+            Some(&format!(
+                "{} {} refs/heads/main\0report-status",
+                NULL_OID, main
+            )),
+            Some(&format!("{} {} refs/heads/stale", main, main)),
+            None,
+        ])?;
+        let objects = git(&work, &["rev-list", "--objects", "main"]);
+        input.extend_from_slice(&pack_objects(&work, &objects));
+        let mut output = vec![];
+        receive_pack(
+            &source,
+            Options::default(),
+            &mut input.as_slice(),
+            &mut output,
+        )?;
+
+        let mut output = output.as_slice();
+        let advertised = read_lines(&mut output)?;
+        assert!(advertised[0].starts_with(&format!("{} capabilities^{{}}\0", NULL_OID)));
+        assert_eq!(
+            read_lines(&mut output)?,
+            vec![
+                "unpack ok",
+                "ok refs/heads/main",
+                "ng refs/heads/stale failed to update ref",
+            ]
+        );
+        assert_eq!(git(&bare, &["rev-parse", "main"]).trim(), main);
+        assert_eq!(git(&bare, &["fsck"]), "");
+        // The pack is kept whole.
+        let counts = git(&bare, &["count-objects", "-v"]);
+        assert!(counts.starts_with("count: 0\n"));
+        assert!(counts.contains("\npacks: 1\n"));
+
+        // Deletions need no pack.
+        let input = request(&[
+            Some(&format!(
+                "{} {} refs/heads/main\0report-status",
+                main, NULL_OID
+            )),
+            None,
+        ])?;
+        let mut output = vec![];
+        receive_pack(
+            &source,
+            Options::default(),
+            &mut input.as_slice(),
+            &mut output,
+        )?;
+        assert!(git(&bare, &["for-each-ref"]).is_empty());
+        Ok(())
+    }
+
+    // Packs the objects whose IDs start each line of `objects`.
+    fn pack_objects(dir: &Path, objects: &str) -> Vec<u8> {
+        let objects: String = objects
+            .lines()
+            .map(|line| format!("{}\n", &line[..40]))
+            .collect();
+        let output = Command::new("git")
+            .args(["pack-objects", "--stdout"])
+            .current_dir(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(objects.as_bytes())?;
+                child.wait_with_output()
+            })
+            .unwrap();
+        output.stdout
+    }
+
+    // Sends a single update to receive-pack, followed by `pack`, returning
+    // its report.
+    fn push(source: &Source, old: &str, new: &str, name: &str, pack: &[u8]) -> Result<Vec<String>> {
+        let mut input = request(&[
+            Some(&format!("{} {} {}\0report-status", old, new, name)),
+            None,
+        ])?;
+        input.extend_from_slice(pack);
+        let mut output = vec![];
+        let options = Options {
+            stateless_rpc: true,
+            ..Options::default()
+        };
+        receive_pack(source, options, &mut input.as_slice(), &mut output)?;
+        read_lines(&mut output.as_slice())
+    }
+
+    #[test]
+    fn test_receive_pack_refuses() -> Result<()> {
+        let dir = TempDir::new("test_receive_pack_refuses")?;
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work)?;
+        create_repo(&work);
+        git(dir.path(), &["init", "-q", "--bare", "bare.git"]);
+        let source = Source::locate("bare.git", dir.path())?;
+        let main = git(&work, &["rev-parse", "main"]);
+        let main = main.trim();
+        let first = git(&work, &["rev-parse", "main~"]);
+        let first = first.trim();
+        let empty = pack::write(&Database::in_memory(), &[], &HashMap::new())?;
+
+        // Names which would escape the repository, or are otherwise
+        // invalid, are refused.
+        for name in ["refs/../../escaped", "refs/heads/x.lock", "refs/heads/.x"].iter() {
+            assert_eq!(
+                push(&source, NULL_OID, main, name, &empty)?,
+                vec![
+                    "unpack ok".to_string(),
+                    format!("ng {} funny refname", name)
+                ]
+            );
+        }
+        assert!(!dir.path().join("escaped").exists());
+
+        // A tip whose history is incomplete is refused.
+        let commit_only = pack_objects(&work, main);
+        assert_eq!(
+            push(&source, NULL_OID, main, "refs/heads/main", &commit_only)?,
+            vec!["unpack ok", "ng refs/heads/main missing necessary objects"]
+        );
+        assert!(git(dir.path(), &["-C", "bare.git", "for-each-ref"]).is_empty());
+
+        let objects = git(&work, &["rev-list", "--objects", "main"]);
+        let pack = pack_objects(&work, &objects);
+        assert_eq!(
+            push(&source, NULL_OID, main, "refs/heads/main", &pack)?,
+            vec!["unpack ok", "ok refs/heads/main"]
+        );
+
+        // With receive.denyNonFastForwards, branches may not be rewound.
+        git(
+            dir.path(),
+            &[
+                "-C",
+                "bare.git",
+                "config",
+                "receive.denyNonFastForwards",
+                "true",
+            ],
+        );
+        assert_eq!(
+            push(&source, main, first, "refs/heads/main", &empty)?,
+            vec!["unpack ok", "ng refs/heads/main non-fast-forward"]
+        );
+        assert_eq!(
+            push(&source, NULL_OID, first, "refs/tags/old", &empty)?,
+            vec!["unpack ok", "ok refs/tags/old"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use crate::author::Author;
+use crate::database::ObjectID;
+use crate::error::{Error, Result};
+use crate::refs::{ReflogEntry, Refs};
+
+/// The reference pointing at the most recent stash entry.
+///
+/// As in git, the reflog of this reference holds the stack of entries.
+pub const STASH: &str = "refs/stash";
+
+/// The stack of saved (uncommitted) changes.
+pub struct Stash<'a> {
+    refs: &'a Refs,
+}
+
+impl<'a> Stash<'a> {
+    pub fn new(refs: &'a Refs) -> Self {
+        Stash { refs }
+    }
+
+    /// Returns every stash entry, newest ("stash@{0}") first.
+    pub fn list(&self) -> Result<Vec<ReflogEntry>> {
+        let mut entries = self.refs.read_reflog(STASH)?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Returns the commit saved as entry `n` of the stack.
+    pub fn get(&self, n: usize) -> Result<ObjectID> {
+        let entries = self.list()?;
+        if entries.is_empty() {
+            return Err(Error::Fatal("No stash entries found.".to_string()));
+        }
+        entries
+            .get(n)
+            .map(|entry| entry.new.clone())
+            .ok_or_else(|| Error::InvalidRef(format!("stash@{{{}}}", n)))
+    }
+
+    /// Pushes a stash commit onto the top of the stack.
+    pub fn push(&self, oid: &ObjectID, identity: &Author, message: &str) -> Result<()> {
+        self.refs.update_logged_ref(STASH, oid, identity, message)
+    }
+
+    /// Removes entry `n` from the stack, returning the commit it held.
+    pub fn drop(&self, n: usize) -> Result<ObjectID> {
+        let oid = self.get(n)?;
+        let mut entries = self.list()?;
+        entries.remove(n);
+        entries.reverse();
+
+        if entries.is_empty() {
+            self.refs.delete_ref(STASH)?;
+            return Ok(oid);
+        }
+
+        // Keep the log consistent, with each entry following its
+        // predecessor.
+        let mut previous = None;
+        for entry in entries.iter_mut() {
+            entry.old = previous;
+            previous = Some(entry.new.clone());
+        }
+        self.refs.write_reflog(STASH, &entries)?;
+        if let Some(top) = entries.last() {
+            self.refs.update_ref(STASH, &top.new)?;
+        }
+        Ok(oid)
+    }
+}
+
+/// Parses a reference to a stash entry, either as "stash@{N}" or "N".
+pub fn parse_index(s: &str) -> Result<usize> {
+    let index = s
+        .strip_prefix("stash@{")
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or(s);
+    index.parse().map_err(|_| Error::InvalidRef(s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::oid;
+    use tempdir::TempDir;
+
+    fn identity() -> Author {
+        Author::new(
+            "Sean".to_string(),
+            "sean@zombo.com".to_string(),
+            chrono::DateTime::parse_from_rfc3339("2021-01-01T01:01:01+00:00").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_parse_index() -> Result<()> {
+        assert_eq!(parse_index("stash@{2}")?, 2);
+        assert_eq!(parse_index("0")?, 0);
+        assert!(parse_index("stash@{x}").is_err());
+        assert!(parse_index("stash@{1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_and_drop() -> Result<()> {
+        let dir = TempDir::new("test_stash_push_and_drop")?;
+        let refs = Refs::new(dir.path());
+        let stash = Stash::new(&refs);
+        assert!(stash.get(0).is_err());
+
+        stash.push(&oid('a'), &identity(), "WIP on master: first")?;
+        stash.push(&oid('b'), &identity(), "WIP on master: second")?;
+        stash.push(&oid('c'), &identity(), "WIP on master: third")?;
+
+        let messages: Vec<String> = stash.list()?.into_iter().map(|e| e.message).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "WIP on master: third",
+                "WIP on master: second",
+                "WIP on master: first"
+            ]
+        );
+        assert_eq!(stash.get(1)?, oid('b'));
+        assert!(stash.get(3).is_err());
+
+        // Dropping from the middle leaves the top in place.
+        assert_eq!(stash.drop(1)?, oid('b'));
+        assert_eq!(refs.read_ref(STASH)?, Some(oid('c')));
+        // Dropping the top moves the reference.
+        assert_eq!(stash.drop(0)?, oid('c'));
+        assert_eq!(refs.read_ref(STASH)?, Some(oid('a')));
+        assert_eq!(stash.list()?.len(), 1);
+
+        assert_eq!(stash.drop(0)?, oid('a'));
+        assert_eq!(refs.read_ref(STASH)?, None);
+        assert!(stash.list()?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,694 @@
+use crate::database::{object_header, ObjectHasher, ObjectID};
+use crate::error::{Error, Result};
+use crate::pack::{self, Pack};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, rename, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+// The amount of data read at once when streaming an object.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// An iterator over the IDs of the objects within a store.
+pub type ObjectIDs<'a> = Box<dyn Iterator<Item = Result<ObjectID>> + 'a>;
+
+/// A backend holding the contents of a `Database`.
+///
+/// Objects are identified by their ID and hold a type name (such as "blob")
+/// along with their uncompressed contents. Stores only ever add objects:
+/// writing an object which already exists does nothing.
+pub trait ObjectStore: Send + Sync {
+    /// Returns true if the store holds the object `oid`.
+    fn has(&self, oid: &ObjectID) -> Result<bool>;
+
+    /// Reads the type name and contents of an object, failing with
+    /// `Error::ObjectNotFound` if it does not exist.
+    fn read(&self, oid: &ObjectID) -> Result<(String, Vec<u8>)>;
+
+    /// Adds an object, whose ID has already been computed as `oid`.
+    fn write(&self, oid: &ObjectID, type_name: &str, data: &[u8]) -> Result<()>;
+
+    /// Adds an object whose `size` bytes of contents are read from
+    /// `reader`, returning its ID.
+    ///
+    /// By default the contents are read into memory; stores which can
+    /// hash and save data as it arrives should override this.
+    fn write_stream(&self, type_name: &str, size: u64, reader: &mut dyn Read) -> Result<ObjectID> {
+        let mut data = Vec::new();
+        reader.take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(truncated(size, data.len() as u64));
+        }
+        let mut hasher = ObjectHasher::new(type_name, size);
+        hasher.update(&data);
+        let oid = hasher.finish();
+        self.write(&oid, type_name, &data)?;
+        Ok(oid)
+    }
+
+    /// Keeps a pack and its index (as made by `pack::index`) whole,
+    /// returning false if the store cannot, in which case each object must
+    /// be written instead.
+    fn write_pack(&self, pack: &[u8], index: &[u8]) -> Result<bool> {
+        let _ = (pack, index);
+        Ok(false)
+    }
+
+    /// Iterates over the IDs of every object in the store, in no
+    /// particular order.
+    fn iter(&self) -> Result<ObjectIDs<'_>>;
+
+    /// Removes an object. Removing an object which does not exist, or
+    /// which is packed, does nothing.
+    fn remove(&self, oid: &ObjectID) -> Result<()>;
+
+    /// Returns true if the object is kept within a pack, from which it
+    /// cannot be removed alone.
+    fn is_packed(&self, oid: &ObjectID) -> Result<bool> {
+        let _ = oid;
+        Ok(false)
+    }
+
+    /// Returns when an object was last written, if the store records it.
+    fn modified(&self, oid: &ObjectID) -> Result<Option<SystemTime>> {
+        let _ = oid;
+        Ok(None)
+    }
+
+    /// Removes temporary files which were last modified before `expire`,
+    /// returning how many were removed. These are left behind when a
+    /// process dies while writing an object.
+    fn remove_stale(&self, expire: SystemTime) -> Result<usize> {
+        let _ = expire;
+        Ok(0)
+    }
+
+    /// Returns all object IDs in the store beginning with the hexadecimal
+    /// `prefix`, sorted.
+    fn prefix_match(&self, prefix: &str) -> Result<Vec<ObjectID>> {
+        let mut matches = Vec::new();
+        for oid in self.iter()? {
+            let oid = oid?;
+            if oid.as_str().starts_with(prefix) {
+                matches.push(oid);
+            }
+        }
+        matches.sort();
+        Ok(matches)
+    }
+}
+
+// Returns the error for a stream which ended before `expected` bytes.
+fn truncated(expected: u64, actual: u64) -> Error {
+    Error::CorruptObject(format!(
+        "short read while streaming object: expected {} bytes, got {}",
+        expected, actual
+    ))
+}
+
+// The prefix of the names of objects which are being written, as used by
+// both rit and git.
+const TEMP_PREFIX: &str = "tmp_obj_";
+
+/// Stores each object in its own zlib-compressed file, named by its ID
+/// beneath a directory such as ".git/objects".
+pub struct LooseStore {
+    root: PathBuf,
+}
+
+impl LooseStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        LooseStore {
+            root: PathBuf::from(path.as_ref()),
+        }
+    }
+
+    // Returns the on-disk location of a loose object.
+    fn object_path(&self, oid: &ObjectID) -> PathBuf {
+        let object_id = oid.as_str();
+        self.root.join(&object_id[0..2]).join(&object_id[2..])
+    }
+
+    // Returns a new, unique path for an object which is being written.
+    fn temp_path(&self) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        self.root.join(format!(
+            "{}{}_{}",
+            TEMP_PREFIX,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    // Moves a fully written temporary file into place as the object `oid`.
+    fn install(&self, tmp_path: &Path, oid: &ObjectID) -> Result<()> {
+        let final_path = self.object_path(oid);
+        // Someone else already made this object. Since the object store
+        // *should* be immutable, this means it already has the content we want
+        // too.
+        if final_path.exists() {
+            std::fs::remove_file(tmp_path)?;
+            return Ok(());
+        }
+        // First two characters of the object ID form a directory.
+        // The latter characters of the object ID form the regular file name.
+        create_dir_all(final_path.parent().expect("object paths have a parent"))?;
+        rename(tmp_path, final_path)?;
+        Ok(())
+    }
+
+    // Lists the objects within one of the two-character fan-out
+    // directories.
+    fn read_fanout(&self, prefix: &str) -> Result<Vec<ObjectID>> {
+        let dir = self.root.join(prefix);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut oids = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let suffix = entry?.file_name();
+            // Skips temporary files, and anything else which is not an
+            // object.
+            if let Ok(oid) = ObjectID::from_str(&format!("{}{}", prefix, suffix.to_string_lossy()))
+            {
+                oids.push(oid);
+            }
+        }
+        Ok(oids)
+    }
+}
+
+impl ObjectStore for LooseStore {
+    fn has(&self, oid: &ObjectID) -> Result<bool> {
+        Ok(self.object_path(oid).is_file())
+    }
+
+    fn read(&self, oid: &ObjectID) -> Result<(String, Vec<u8>)> {
+        let compressed = std::fs::read(self.object_path(oid))
+            .map_err(|_| Error::ObjectNotFound(oid.as_str()))?;
+        let mut decoder = flate2::read::ZlibDecoder::new(compressed.as_slice());
+        let mut content = Vec::new();
+        decoder.read_to_end(&mut content)?;
+
+        // Objects are stored as "{TYPE} {SIZE}\0{DATA}".
+        let corrupt = |reason| Error::CorruptObject(format!("{} in {}", reason, oid.as_str()));
+        let header_end = content
+            .iter()
+            .position(|b| *b == b'\0')
+            .ok_or_else(|| corrupt("missing header"))?;
+        let data = content.split_off(header_end + 1);
+        let (type_name, size) = std::str::from_utf8(&content[..header_end])
+            .ok()
+            .and_then(|header| header.split_once(' '))
+            .ok_or_else(|| corrupt("invalid header"))?;
+        if size.parse::<usize>().ok() != Some(data.len()) {
+            return Err(corrupt("incorrect length"));
+        }
+        Ok((type_name.to_string(), data))
+    }
+
+    fn write(&self, oid: &ObjectID, type_name: &str, data: &[u8]) -> Result<()> {
+        if self.has(oid)? {
+            return Ok(());
+        }
+        create_dir_all(&self.root)?;
+        let tmp_path = self.temp_path();
+
+        // Create the file exclusively so we won't clobber anyone else
+        // generating this object. Use zlib to compress the file so it uses
+        // less on-disk storage.
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        let mut encoder = flate2::write::ZlibEncoder::new(file, flate2::Compression::fast());
+        encoder.write_all(object_header(type_name, data.len() as u64).as_bytes())?;
+        encoder.write_all(data)?;
+        encoder.finish()?;
+
+        self.install(&tmp_path, oid)
+    }
+
+    // Hashes and compresses the contents as they are read, so only a chunk
+    // is held in memory at once. The ID is only known at the end, so the
+    // object is written to a temporary file and then renamed.
+    fn write_stream(&self, type_name: &str, size: u64, reader: &mut dyn Read) -> Result<ObjectID> {
+        create_dir_all(&self.root)?;
+        let tmp_path = self.temp_path();
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        let mut encoder = flate2::write::ZlibEncoder::new(file, flate2::Compression::fast());
+        let mut hasher = ObjectHasher::new(type_name, size);
+
+        let mut copy = || -> Result<()> {
+            encoder.write_all(object_header(type_name, size).as_bytes())?;
+            let mut reader = reader.take(size);
+            let mut buffer = vec![0; CHUNK_SIZE];
+            let mut total = 0;
+            loop {
+                let n = match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                hasher.update(&buffer[..n]);
+                encoder.write_all(&buffer[..n])?;
+                total += n as u64;
+            }
+            if total != size {
+                return Err(truncated(size, total));
+            }
+            encoder.try_finish()?;
+            Ok(())
+        };
+        if let Err(err) = copy() {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        drop(encoder);
+
+        let oid = hasher.finish();
+        self.install(&tmp_path, &oid)?;
+        Ok(oid)
+    }
+
+    fn iter(&self) -> Result<ObjectIDs<'_>> {
+        if !self.root.is_dir() {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let mut prefixes = Vec::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+                prefixes.push(name);
+            }
+        }
+        let oids = prefixes
+            .into_iter()
+            .map(move |prefix| self.read_fanout(&prefix))
+            .flat_map(|oids| match oids {
+                Ok(oids) => oids.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(err) => vec![Err(err)],
+            });
+        Ok(Box::new(oids))
+    }
+
+    // Only reads the fan-out directory which may contain matches.
+    fn prefix_match(&self, prefix: &str) -> Result<Vec<ObjectID>> {
+        if prefix.len() < 2 {
+            return Ok(vec![]);
+        }
+        let mut matches: Vec<ObjectID> = self
+            .read_fanout(&prefix[0..2])?
+            .into_iter()
+            .filter(|oid| oid.as_str().starts_with(prefix))
+            .collect();
+        matches.sort();
+        Ok(matches)
+    }
+
+    // Also removes the fan-out directory, once it is empty.
+    fn remove(&self, oid: &ObjectID) -> Result<()> {
+        let path = self.object_path(oid);
+        match std::fs::remove_file(&path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+
+    fn modified(&self, oid: &ObjectID) -> Result<Option<SystemTime>> {
+        let metadata = std::fs::metadata(self.object_path(oid))
+            .map_err(|_| Error::ObjectNotFound(oid.as_str()))?;
+        Ok(Some(metadata.modified()?))
+    }
+
+    // Temporary files are written to the root, though older versions of
+    // git also wrote them within the fan-out directories.
+    fn remove_stale(&self, expire: SystemTime) -> Result<usize> {
+        if !self.root.is_dir() {
+            return Ok(0);
+        }
+        let mut dirs = vec![self.root.clone()];
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && entry.file_name().len() == 2 {
+                dirs.push(entry.path());
+            }
+        }
+        let mut count = 0;
+        for dir in dirs {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let stale = entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX)
+                    && entry.metadata()?.modified()? < expire;
+                if stale {
+                    std::fs::remove_file(entry.path())?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Stores objects beneath a directory such as ".git/objects" as git does:
+/// loosely, as `LooseStore` does, and within the packs in its "pack"
+/// directory.
+///
+/// Packs are only read: new objects are always written loosely. Packs are
+/// opened when first needed, and the directory is scanned again whenever
+/// an object is not found, in case another process has added a pack.
+pub struct DiskStore {
+    loose: LooseStore,
+    pack_dir: PathBuf,
+    packs: RwLock<Vec<Pack>>,
+}
+
+impl DiskStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        DiskStore {
+            loose: LooseStore::new(&path),
+            pack_dir: path.as_ref().join("pack"),
+            packs: RwLock::new(Vec::new()),
+        }
+    }
+
+    // Opens any packs which have appeared since the directory was last
+    // scanned, returning true if there were any. Packs which cannot be
+    // opened are skipped, as `git fsck` reports them.
+    fn refresh(&self) -> Result<bool> {
+        let mut packs = self.packs.write().unwrap();
+        let mut added = false;
+        for path in pack::list(&self.pack_dir)? {
+            if packs.iter().any(|pack| pack.path() == path) {
+                continue;
+            }
+            match Pack::open(&path) {
+                Ok(pack) => {
+                    packs.push(pack);
+                    added = true;
+                }
+                Err(err) => tracing::warn!(error = %err, "skipping unreadable pack"),
+            }
+        }
+        Ok(added)
+    }
+
+    // Reads an object from whichever pack holds it, if any.
+    fn read_packed(&self, oid: &ObjectID) -> Result<Option<(String, Vec<u8>)>> {
+        for pass in 0..2 {
+            if pass == 1 && !self.refresh()? {
+                break;
+            }
+            for pack in self.packs.read().unwrap().iter() {
+                if let Some(object) = pack.read(oid)? {
+                    return Ok(Some(object));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl ObjectStore for DiskStore {
+    fn has(&self, oid: &ObjectID) -> Result<bool> {
+        Ok(self.loose.has(oid)? || self.is_packed(oid)?)
+    }
+
+    fn read(&self, oid: &ObjectID) -> Result<(String, Vec<u8>)> {
+        match self.loose.read(oid) {
+            Err(Error::ObjectNotFound(_)) => self
+                .read_packed(oid)?
+                .ok_or_else(|| Error::ObjectNotFound(oid.as_str())),
+            result => result,
+        }
+    }
+
+    fn write(&self, oid: &ObjectID, type_name: &str, data: &[u8]) -> Result<()> {
+        if self.has(oid)? {
+            return Ok(());
+        }
+        self.loose.write(oid, type_name, data)
+    }
+
+    fn write_stream(&self, type_name: &str, size: u64, reader: &mut dyn Read) -> Result<ObjectID> {
+        self.loose.write_stream(type_name, size, reader)
+    }
+
+    // Packs are named after their checksum, as git does. The index is
+    // installed last, as packs are only read once it exists.
+    fn write_pack(&self, pack: &[u8], index: &[u8]) -> Result<bool> {
+        let checksum = &pack[pack.len().saturating_sub(20)..];
+        let path = self
+            .pack_dir
+            .join(format!("pack-{}.pack", hex::encode(checksum)));
+        if path.with_extension("idx").is_file() {
+            return Ok(true);
+        }
+        create_dir_all(&self.pack_dir)?;
+        for (data, path) in [(pack, path.clone()), (index, path.with_extension("idx"))].iter() {
+            let tmp_path = self.pack_dir.join(format!(
+                "tmp_{}",
+                path.file_name()
+                    .expect("packs have a name")
+                    .to_string_lossy()
+            ));
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)?;
+            let written = file.write_all(data).and_then(|_| file.sync_all());
+            if let Err(e) = written.and_then(|_| rename(&tmp_path, path)) {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e.into());
+            }
+        }
+        tracing::debug!(path = %path.display(), "write pack");
+        Ok(true)
+    }
+
+    // Objects may be both loose and packed, or in several packs, but are
+    // only listed once.
+    fn iter(&self) -> Result<ObjectIDs<'_>> {
+        self.refresh()?;
+        let mut oids = self.loose.iter()?.collect::<Result<BTreeSet<_>>>()?;
+        for pack in self.packs.read().unwrap().iter() {
+            oids.extend(pack.index().oids().iter().cloned());
+        }
+        Ok(Box::new(oids.into_iter().map(Ok)))
+    }
+
+    fn remove(&self, oid: &ObjectID) -> Result<()> {
+        self.loose.remove(oid)
+    }
+
+    fn is_packed(&self, oid: &ObjectID) -> Result<bool> {
+        for pass in 0..2 {
+            if pass == 1 && !self.refresh()? {
+                break;
+            }
+            if self
+                .packs
+                .read()
+                .unwrap()
+                .iter()
+                .any(|pack| pack.contains(oid))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Packed objects are as old as their pack, which is never pruned.
+    fn modified(&self, oid: &ObjectID) -> Result<Option<SystemTime>> {
+        if !self.loose.has(oid)? && self.is_packed(oid)? {
+            return Ok(None);
+        }
+        self.loose.modified(oid)
+    }
+
+    fn remove_stale(&self, expire: SystemTime) -> Result<usize> {
+        self.loose.remove_stale(expire)
+    }
+
+    fn prefix_match(&self, prefix: &str) -> Result<Vec<ObjectID>> {
+        self.refresh()?;
+        let mut matches: BTreeSet<ObjectID> =
+            self.loose.prefix_match(prefix)?.into_iter().collect();
+        for pack in self.packs.read().unwrap().iter() {
+            let oids = pack.index().oids();
+            let start = oids.partition_point(|oid| oid.as_str().as_str() < prefix);
+            matches.extend(
+                oids[start..]
+                    .iter()
+                    .take_while(|oid| oid.as_str().starts_with(prefix))
+                    .cloned(),
+            );
+        }
+        Ok(matches.into_iter().collect())
+    }
+}
+
+/// Holds objects in memory, for repositories which never need to reach
+/// the disk (such as within tests).
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: RwLock<BTreeMap<ObjectID, (String, Vec<u8>)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn has(&self, oid: &ObjectID) -> Result<bool> {
+        Ok(self.objects.read().unwrap().contains_key(oid))
+    }
+
+    fn read(&self, oid: &ObjectID) -> Result<(String, Vec<u8>)> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(oid)
+            .cloned()
+            .ok_or_else(|| Error::ObjectNotFound(oid.as_str()))
+    }
+
+    fn write(&self, oid: &ObjectID, type_name: &str, data: &[u8]) -> Result<()> {
+        self.objects
+            .write()
+            .unwrap()
+            .entry(oid.clone())
+            .or_insert_with(|| (type_name.to_string(), data.to_vec()));
+        Ok(())
+    }
+
+    fn iter(&self) -> Result<ObjectIDs<'_>> {
+        let oids: Vec<ObjectID> = self.objects.read().unwrap().keys().cloned().collect();
+        Ok(Box::new(oids.into_iter().map(Ok)))
+    }
+
+    fn remove(&self, oid: &ObjectID) -> Result<()> {
+        self.objects.write().unwrap().remove(oid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Blob, Storable};
+    use tempdir::TempDir;
+
+    // Exercises the behavior shared by every store.
+    fn check_store(store: &dyn ObjectStore) -> Result<()> {
+        let hello = Blob::new(b"hello\n".to_vec());
+        let world = Blob::new(b"world\n".to_vec());
+        assert!(!store.has(&hello.oid())?);
+        assert!(matches!(
+            store.read(&hello.oid()),
+            Err(Error::ObjectNotFound(_))
+        ));
+        assert_eq!(store.iter()?.count(), 0);
+
+        store.write(&hello.oid(), "blob", hello.data())?;
+        store.write(&world.oid(), "blob", world.data())?;
+        // Writing an existing object is a no-op.
+        store.write(&hello.oid(), "blob", hello.data())?;
+
+        assert!(store.has(&hello.oid())?);
+        assert_eq!(
+            store.read(&hello.oid())?,
+            ("blob".to_string(), b"hello\n".to_vec())
+        );
+        let mut oids = store.iter()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(oids.len(), 2);
+        oids.sort();
+        let mut expected = vec![hello.oid(), world.oid()];
+        expected.sort();
+        assert_eq!(oids, expected);
+
+        // Streamed objects have the same ID as those written at once.
+        let big = Blob::new(vec![b'x'; 3 * CHUNK_SIZE + 1]);
+        let oid =
+            store.write_stream("blob", big.data().len() as u64, &mut big.data().as_slice())?;
+        assert_eq!(oid, big.oid());
+        assert_eq!(store.read(&oid)?.1, *big.data());
+        assert!(store
+            .write_stream("blob", 10, &mut b"short".as_ref())
+            .is_err());
+        assert_eq!(store.iter()?.count(), 3);
+
+        // "ce013625..." is "hello\n".
+        assert_eq!(store.prefix_match("ce0136")?, vec![hello.oid()]);
+        assert!(store.prefix_match("ce0137")?.is_empty());
+
+        store.remove(&world.oid())?;
+        store.remove(&world.oid())?;
+        assert!(!store.has(&world.oid())?);
+        assert_eq!(store.iter()?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_loose_store() -> Result<()> {
+        let dir = TempDir::new("test_loose_store")?;
+        check_store(&LooseStore::new(dir.path()))?;
+        // The failed stream leaves no temporary files behind.
+        let names: Vec<_> = std::fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        assert!(names.iter().all(|name| name.len() == 2));
+        // Objects are stored as "{2 hex digits}/{38 hex digits}".
+        assert!(dir
+            .path()
+            .join("ce/013625030ba8dba906f756967f9e9ca394464a")
+            .is_file());
+
+        // Only temporary files older than the expiry are removed.
+        let store = LooseStore::new(dir.path());
+        std::fs::write(dir.path().join("tmp_obj_1_0"), "partial")?;
+        std::fs::write(dir.path().join("ce/tmp_obj_2_0"), "partial")?;
+        let past = SystemTime::now() - std::time::Duration::from_secs(60);
+        assert_eq!(store.remove_stale(past)?, 0);
+        let future = SystemTime::now() + std::time::Duration::from_secs(60);
+        assert_eq!(store.remove_stale(future)?, 2);
+        assert!(!dir.path().join("tmp_obj_1_0").exists());
+        assert!(store
+            .modified(&Blob::new(b"hello\n".to_vec()).oid())?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_store() -> Result<()> {
+        let dir = TempDir::new("test_disk_store")?;
+        let store = DiskStore::new(dir.path());
+        check_store(&store)?;
+        // Loose objects can be removed, so are never counted as packed.
+        let hello = Blob::new(b"hello\n".to_vec()).oid();
+        assert!(!store.is_packed(&hello)?);
+        assert!(store.modified(&hello)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_store() -> Result<()> {
+        check_store(&MemoryStore::new())
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use std::path::{Path, PathBuf};
+
+/// The file, at the root of the workspace, describing each submodule.
+pub const GITMODULES: &str = ".gitmodules";
+
+/// A submodule, as described by ".gitmodules".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Submodule {
+    /// Identifies the submodule within config files; usually its path.
+    pub name: String,
+    /// The location of the submodule, relative to the workspace root.
+    pub path: PathBuf,
+    /// Where the submodule is cloned from. Relative URLs (beginning "./"
+    /// or "../") are relative to the superproject.
+    pub url: String,
+}
+
+impl Submodule {
+    /// Returns the config variable holding a setting of this submodule,
+    /// such as "submodule.<name>.url".
+    pub fn variable(&self, key: &str) -> String {
+        format!("submodule.{}.{}", self.name, key)
+    }
+
+    /// Resolves the URL against the root of the superproject's workspace.
+    ///
+    /// Only local paths are supported.
+    pub fn resolve_url(&self, root: &Path) -> PathBuf {
+        resolve_url(root, &self.url)
+    }
+}
+
+/// Resolves a submodule URL, which is a local path, against the root of the
+/// superproject's workspace.
+pub fn resolve_url(root: &Path, url: &str) -> PathBuf {
+    let path = root.join(url);
+    std::fs::canonicalize(&path).unwrap_or(path)
+}
+
+/// The submodules described by a ".gitmodules" file.
+pub struct Submodules {
+    path: PathBuf,
+    config: Config,
+}
+
+impl Submodules {
+    /// Reads the ".gitmodules" file within the workspace at `root`, which
+    /// is treated as empty if missing.
+    pub fn load<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let path = root.as_ref().join(GITMODULES);
+        Ok(Submodules {
+            config: Config::load(&path)?,
+            path,
+        })
+    }
+
+    /// Returns every submodule, in the order they are described.
+    ///
+    /// Entries without a path or URL are ignored, as in git.
+    pub fn list(&self) -> Vec<Submodule> {
+        self.config
+            .subsections("submodule")
+            .into_iter()
+            .filter_map(|name| {
+                let get = |key| self.config.get(&format!("submodule.{}.{}", name, key));
+                Some(Submodule {
+                    name: name.to_string(),
+                    path: PathBuf::from(get("path")?),
+                    url: get("url")?.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the submodule at `path`, relative to the workspace root.
+    pub fn find_by_path(&self, path: &Path) -> Option<Submodule> {
+        self.list().into_iter().find(|s| s.path == path)
+    }
+
+    /// Describes a new submodule, saving ".gitmodules".
+    pub fn add(&mut self, submodule: &Submodule) -> Result<()> {
+        if self.find_by_path(&submodule.path).is_some() {
+            return Err(Error::Fatal(format!(
+                "'{}' already exists in {}",
+                submodule.path.display(),
+                GITMODULES
+            )));
+        }
+        let path = submodule
+            .path
+            .to_str()
+            .ok_or_else(|| Error::invalid_path(&submodule.path, "not valid unicode"))?;
+        self.config.set(&submodule.variable("path"), path)?;
+        self.config
+            .set(&submodule.variable("url"), &submodule.url)?;
+        self.config.save(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_add_and_list() -> Result<()> {
+        let dir = TempDir::new("test_submodule")?;
+        std::fs::write(
+            dir.path().join(GITMODULES),
+            "[submodule \"lib\"]\n\
+             \tpath = vendor/lib\n\
+             \turl = ../lib.git\n\
+             [submodule \"broken\"]\n\
+             \tpath = broken\n",
+        )?;
+        let mut submodules = Submodules::load(dir.path())?;
+        let lib = Submodule {
+            name: "lib".to_string(),
+            path: PathBuf::from("vendor/lib"),
+            url: "../lib.git".to_string(),
+        };
+        assert_eq!(submodules.list(), vec![lib.clone()]);
+        assert_eq!(
+            submodules.find_by_path(Path::new("vendor/lib")),
+            Some(lib.clone())
+        );
+        assert_eq!(lib.variable("url"), "submodule.lib.url");
+
+        let other = Submodule {
+            name: "other".to_string(),
+            path: PathBuf::from("other"),
+            url: "/src/other".to_string(),
+        };
+        submodules.add(&other)?;
+        assert!(submodules.add(&other).is_err());
+        assert_eq!(
+            Submodules::load(dir.path())?.list(),
+            vec![lib, other.clone()]
+        );
+        assert_eq!(other.resolve_url(dir.path()), PathBuf::from("/src/other"));
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use crate::author::Author;
+use crate::database::{ObjectID, Storable};
+use crate::error::{Error, Result};
+use std::str::FromStr;
+
+/// An annotated tag, which names another object along with a message.
+pub struct Tag {
+    object: ObjectID,
+    name: String,
+    tagger: Option<Author>,
+    message: String,
+    data: Vec<u8>,
+}
+
+impl Tag {
+    /// Parses a tag from its stored representation.
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let text = std::str::from_utf8(&data)
+            .map_err(|_| Error::CorruptObject("tag is not valid UTF-8".to_string()))?;
+        let (headers, message) = text.split_once("\n\n").unwrap_or((text, ""));
+
+        let mut object = None;
+        let mut has_type = false;
+        let mut name = None;
+        let mut tagger = None;
+        for line in headers.lines() {
+            let (key, value) = match line.split_once(' ') {
+                Some(header) if !line.starts_with(' ') => header,
+                _ => continue,
+            };
+            match key {
+                "object" => object = Some(ObjectID::from_str(value)?),
+                "type" => has_type = true,
+                "tag" => name = Some(value.to_string()),
+                // Very old tags have no tagger.
+                "tagger" => tagger = Some(Author::parse(value)?),
+                _ => (),
+            }
+        }
+
+        let missing = |header| Error::CorruptObject(format!("tag is missing a {}", header));
+        if !has_type {
+            return Err(missing("type"));
+        }
+        Ok(Tag {
+            object: object.ok_or_else(|| missing("object"))?,
+            name: name.ok_or_else(|| missing("tag"))?,
+            tagger,
+            message: message.to_string(),
+            data,
+        })
+    }
+
+    /// Returns the ID of the tagged object.
+    pub fn object(&self) -> &ObjectID {
+        &self.object
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn tagger(&self) -> Option<&Author> {
+        self.tagger.as_ref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Storable for Tag {
+    fn type_name(&self) -> &str {
+        "tag"
+    }
+    fn data(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        // As written by "git tag -a".
+        let data = b"object ce013625030ba8dba906f756967f9e9ca394464a\n\
+                     type commit\n\
+                     tag v1.0\n\
+                     tagger Sean <sean@zombo.com> 1609462861 +0000\n\
+                     \n\
+                     Release\n";
+        let tag = Tag::parse(data.to_vec())?;
+        assert_eq!(
+            tag.object().as_str(),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+        assert_eq!(tag.name(), "v1.0");
+        assert_eq!(
+            tag.tagger().map(|tagger| tagger.to_str()),
+            Some("Sean <sean@zombo.com> 1609462861 +0000".to_string())
+        );
+        assert_eq!(tag.message(), "Release\n");
+        assert_eq!(tag.type_name(), "tag");
+
+        assert!(Tag::parse(b"type commit\ntag v1\n\n".to_vec()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+//! Helpers shared by the unit tests.
+
+use crate::database::ObjectID;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Runs git within `dir`, returning its output as text.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    String::from_utf8_lossy(&git_with_input(dir, args, b"")).into_owned()
+}
+
+/// Runs git within `dir` with `input` on its standard input, returning its
+/// output. Commits are made by a fixed identity at a fixed date.
+pub fn git_with_input(dir: &Path, args: &[&str], input: &[u8]) -> Vec<u8> {
+    let mut child = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "A")
+        .env("GIT_AUTHOR_EMAIL", "a@b")
+        .env("GIT_AUTHOR_DATE", "2021-01-01T01:01:01+00:00")
+        .env("GIT_COMMITTER_NAME", "A")
+        .env("GIT_COMMITTER_EMAIL", "a@b")
+        .env("GIT_COMMITTER_DATE", "2021-01-01T01:01:01+00:00")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "git {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+/// Returns the object ID made of 40 repetitions of `c`.
+pub fn oid(c: char) -> ObjectID {
+    c.to_string().repeat(40).parse().unwrap()
+}
@@ -0,0 +1,591 @@
+use crate::error::{Error, Result};
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Enables trace output (as with git) for every event.
+pub const GIT_TRACE: &str = "GIT_TRACE";
+/// Enables timing output for every span, such as the running command.
+pub const GIT_TRACE_PERFORMANCE: &str = "GIT_TRACE_PERFORMANCE";
+/// Enables trace2-style JSON events, one per line.
+pub const GIT_TRACE2_EVENT: &str = "GIT_TRACE2_EVENT";
+
+// The target of the event recorded by `exit`.
+const EXIT_TARGET: &str = "rit::exit";
+
+/// A destination for trace output.
+pub enum Sink {
+    Stderr,
+    File(Mutex<File>),
+}
+
+impl Sink {
+    /// Interprets the value of a variable such as GIT_TRACE: "1", "2" and
+    /// "true" write to stderr, while an absolute path is appended to.
+    /// Returns None if tracing is disabled.
+    pub fn parse(name: &str, value: &str) -> Result<Option<Self>> {
+        match value.to_ascii_lowercase().as_str() {
+            "" | "0" | "false" => Ok(None),
+            "1" | "2" | "true" => Ok(Some(Sink::Stderr)),
+            _ if value.starts_with('/') => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(value)
+                    .map_err(|e| {
+                        Error::Fatal(format!("could not open '{}' for tracing: {}", value, e))
+                    })?;
+                Ok(Some(Sink::File(Mutex::new(file))))
+            }
+            _ => Err(Error::Fatal(format!(
+                "unknown trace value for '{}': {}",
+                name, value
+            ))),
+        }
+    }
+
+    // Writes a single line. Failures are ignored, as tracing must not
+    // interfere with the command being traced.
+    fn write_line(&self, line: &str) {
+        let line = format!("{}\n", line);
+        match self {
+            Sink::Stderr => {
+                let _ = std::io::stderr().write_all(line.as_bytes());
+            }
+            Sink::File(file) => {
+                if let Ok(mut file) = file.lock() {
+                    let _ = file.write_all(line.as_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Installs a global subscriber writing to the targets named by the
+/// GIT_TRACE, GIT_TRACE_PERFORMANCE and GIT_TRACE2_EVENT environment
+/// variables. Nothing is installed if none are set.
+pub fn init() -> Result<()> {
+    let sink = |name| match std::env::var(name) {
+        Ok(value) => Sink::parse(name, &value),
+        Err(_) => Ok(None),
+    };
+    let trace = sink(GIT_TRACE)?;
+    let performance = sink(GIT_TRACE_PERFORMANCE)?;
+    let event = sink(GIT_TRACE2_EVENT)?;
+    if trace.is_none() && performance.is_none() && event.is_none() {
+        return Ok(());
+    }
+
+    let argv: Vec<String> = std::env::args().collect();
+    tracing::subscriber::set_global_default(subscriber(trace, performance, event, &argv))
+        .map_err(|e| Error::Fatal(format!("could not initialize tracing: {}", e)))
+}
+
+/// Builds a subscriber writing to the given targets. `argv` is recorded by
+/// the trace2 "start" event.
+pub fn subscriber(
+    trace: Option<Sink>,
+    performance: Option<Sink>,
+    event: Option<Sink>,
+    argv: &[String],
+) -> impl Subscriber + Send + Sync {
+    tracing_subscriber::registry()
+        .with(trace.map(|sink| TraceLayer { sink }))
+        .with(performance.map(|sink| PerformanceLayer { sink }))
+        .with(event.map(|sink| Trace2Layer::new(sink, argv)))
+}
+
+/// Records the exit status of the process, which ends the trace2 session.
+pub fn exit(code: i32) {
+    tracing::info!(target: EXIT_TARGET, code, "exit");
+}
+
+// A value recorded on an event or span.
+#[derive(Clone)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl Value {
+    fn to_json(&self) -> String {
+        match self {
+            Value::Str(s) => json_string(s),
+            Value::Int(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Str(s) => f.write_str(s),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+// The message and fields recorded on an event or span.
+#[derive(Clone, Default)]
+struct Fields {
+    message: String,
+    values: Vec<(&'static str, Value)>,
+}
+
+impl Fields {
+    fn from_event(event: &Event<'_>) -> Self {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        fields
+    }
+
+    // Formats the fields as "key=value" pairs, separated by spaces.
+    fn to_pairs(&self) -> String {
+        let pairs: Vec<String> = self
+            .values
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        pairs.join(" ")
+    }
+
+    fn to_json(&self) -> String {
+        let pairs: Vec<String> = self
+            .values
+            .iter()
+            .map(|(key, value)| format!("{}:{}", json_string(key), value.to_json()))
+            .collect();
+        format!("{{{}}}", pairs.join(","))
+    }
+
+    fn push(&mut self, field: &Field, value: Value) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else if let Some(existing) = self.values.iter_mut().find(|(k, _)| *k == field.name()) {
+            // Values recorded after a span is created replace earlier ones.
+            existing.1 = value;
+        } else {
+            self.values.push((field.name(), value));
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, Value::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.push(field, Value::Int(value)),
+            Err(_) => self.push(field, Value::Str(value.to_string())),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, Value::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, Value::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push(field, Value::Str(format!("{:?}", value)));
+    }
+}
+
+// Per-span state, shared by each layer.
+struct SpanData {
+    start: Instant,
+    fields: Fields,
+}
+
+// Records the start time and fields of a new span, if not already done by
+// another layer.
+fn record_span<S>(attrs: &Attributes<'_>, id: &Id, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let span = ctx.span(id).expect("new span is registered");
+    let mut extensions = span.extensions_mut();
+    if extensions.get_mut::<SpanData>().is_none() {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        extensions.insert(SpanData {
+            start: Instant::now(),
+            fields,
+        });
+    }
+}
+
+// Adds values recorded after a span was created.
+fn update_span<S>(id: &Id, values: &Record<'_>, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let span = ctx.span(id).expect("recorded span is registered");
+    let mut extensions = span.extensions_mut();
+    if let Some(data) = extensions.get_mut::<SpanData>() {
+        values.record(&mut data.fields);
+    }
+}
+
+// Returns the elapsed time and fields of a span.
+fn span_data<S>(id: &Id, ctx: &Context<'_, S>) -> Option<(f64, Fields)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let span = ctx.span(id)?;
+    let extensions = span.extensions();
+    let data = extensions.get::<SpanData>()?;
+    Some((data.start.elapsed().as_secs_f64(), data.fields.clone()))
+}
+
+// Formats the start of a GIT_TRACE style line: the local time followed by
+// the source location.
+fn line_prefix(metadata: &Metadata<'_>) -> String {
+    let location = format!(
+        "{}:{}",
+        metadata.file().unwrap_or("?"),
+        metadata.line().unwrap_or(0)
+    );
+    format!(
+        "{} {:<24}",
+        chrono::Local::now().format("%H:%M:%S%.6f"),
+        location
+    )
+}
+
+// Writes every event, as with GIT_TRACE.
+struct TraceLayer {
+    sink: Sink,
+}
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let fields = Fields::from_event(event);
+        let mut line = format!(
+            "{} trace: {}",
+            line_prefix(event.metadata()),
+            fields.message
+        );
+        if !fields.values.is_empty() {
+            let _ = write!(line, " {}", fields.to_pairs());
+        }
+        self.sink.write_line(&line);
+    }
+}
+
+// Writes the duration of every span, as with GIT_TRACE_PERFORMANCE.
+struct PerformanceLayer {
+    sink: Sink,
+}
+
+impl<S> Layer<S> for PerformanceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        record_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        update_span(id, values, &ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let metadata = match ctx.metadata(&id) {
+            Some(metadata) => metadata,
+            None => return,
+        };
+        if let Some((elapsed, fields)) = span_data(&id, &ctx) {
+            let mut line = format!(
+                "{} performance: {:.9} s: {}",
+                line_prefix(metadata),
+                elapsed,
+                metadata.name()
+            );
+            if !fields.values.is_empty() {
+                let _ = write!(line, " {}", fields.to_pairs());
+            }
+            self.sink.write_line(&line);
+        }
+    }
+}
+
+// Writes trace2-style JSON events, as with GIT_TRACE2_EVENT.
+//
+// Spans become "region_enter" and "region_leave" events, and other events
+// become "data_json" events whose value holds their fields.
+struct Trace2Layer {
+    sink: Sink,
+    // Identifies the process within the trace.
+    sid: String,
+    start: Instant,
+}
+
+impl Trace2Layer {
+    fn new(sink: Sink, argv: &[String]) -> Self {
+        let now = chrono::Utc::now();
+        let layer = Trace2Layer {
+            sink,
+            sid: format!(
+                "{}-P{:08x}",
+                now.format("%Y%m%dT%H%M%S%.6fZ"),
+                std::process::id()
+            ),
+            start: Instant::now(),
+        };
+        let here = (file!(), line!());
+        layer.write(
+            "version",
+            here,
+            &format!(
+                "\"evt\":\"3\",\"exe\":{}",
+                json_string(env!("CARGO_PKG_VERSION"))
+            ),
+        );
+        let argv: Vec<String> = argv.iter().map(|arg| json_string(arg)).collect();
+        layer.write(
+            "start",
+            here,
+            &format!("\"t_abs\":0.000000,\"argv\":[{}]", argv.join(",")),
+        );
+        layer
+    }
+
+    // Writes an event with the common fields, followed by `extra` (a list
+    // of JSON object members).
+    fn write(&self, event: &str, (file, line): (&str, u32), extra: &str) {
+        let thread = std::thread::current();
+        let mut json = format!(
+            "{{\"event\":{},\"sid\":{},\"thread\":{},\"time\":{},\"file\":{},\"line\":{}",
+            json_string(event),
+            json_string(&self.sid),
+            json_string(thread.name().unwrap_or("main")),
+            json_string(
+                &chrono::Utc::now()
+                    .format("%Y-%m-%dT%H:%M:%S%.6fZ")
+                    .to_string()
+            ),
+            json_string(file),
+            line
+        );
+        if !extra.is_empty() {
+            json.push(',');
+            json.push_str(extra);
+        }
+        json.push('}');
+        self.sink.write_line(&json);
+    }
+}
+
+// Returns the source location of a callsite.
+fn location(metadata: &'static Metadata<'static>) -> (&'static str, u32) {
+    (metadata.file().unwrap_or(""), metadata.line().unwrap_or(0))
+}
+
+impl<S> Layer<S> for Trace2Layer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        record_span(attrs, id, &ctx);
+        let nesting = ctx.span_scope(id).map(|scope| scope.count()).unwrap_or(1);
+        let metadata = attrs.metadata();
+        let fields = span_data(id, &ctx)
+            .map(|(_, fields)| fields)
+            .unwrap_or_default();
+        self.write(
+            "region_enter",
+            location(metadata),
+            &format!(
+                "\"nesting\":{},\"category\":{},\"label\":{},\"msg\":{}",
+                nesting,
+                json_string(metadata.target()),
+                json_string(metadata.name()),
+                fields.to_json()
+            ),
+        );
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        update_span(id, values, &ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let metadata = match ctx.metadata(&id) {
+            Some(metadata) => metadata,
+            None => return,
+        };
+        let nesting = ctx.span_scope(&id).map(|scope| scope.count()).unwrap_or(1);
+        if let Some((elapsed, _)) = span_data(&id, &ctx) {
+            self.write(
+                "region_leave",
+                location(metadata),
+                &format!(
+                    "\"t_rel\":{:.6},\"nesting\":{},\"category\":{},\"label\":{}",
+                    elapsed,
+                    nesting,
+                    json_string(metadata.target()),
+                    json_string(metadata.name())
+                ),
+            );
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let fields = Fields::from_event(event);
+        let t_abs = self.start.elapsed().as_secs_f64();
+        if metadata.target() == EXIT_TARGET {
+            let code = match fields.values.first() {
+                Some((_, Value::Int(code))) => *code,
+                _ => 0,
+            };
+            self.write(
+                "exit",
+                location(metadata),
+                &format!("\"t_abs\":{:.6},\"code\":{}", t_abs, code),
+            );
+            return;
+        }
+        self.write(
+            "data_json",
+            location(metadata),
+            &format!(
+                "\"t_abs\":{:.6},\"category\":{},\"key\":{},\"value\":{}",
+                t_abs,
+                json_string(metadata.target()),
+                json_string(&fields.message),
+                fields.to_json()
+            ),
+        );
+    }
+}
+
+// Quotes a string for use within JSON.
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_sink() -> Result<()> {
+        assert!(Sink::parse(GIT_TRACE, "")?.is_none());
+        assert!(Sink::parse(GIT_TRACE, "0")?.is_none());
+        assert!(Sink::parse(GIT_TRACE, "false")?.is_none());
+        assert!(matches!(Sink::parse(GIT_TRACE, "1")?, Some(Sink::Stderr)));
+        assert!(matches!(
+            Sink::parse(GIT_TRACE, "TRUE")?,
+            Some(Sink::Stderr)
+        ));
+        assert!(Sink::parse(GIT_TRACE, "relative/path").is_err());
+
+        let dir = TempDir::new("test_trace")?;
+        let path = dir.path().join("trace.log");
+        let sink = Sink::parse(GIT_TRACE, path.to_str().unwrap())?;
+        assert!(matches!(sink, Some(Sink::File(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscriber() -> Result<()> {
+        let dir = TempDir::new("test_trace")?;
+        let open = |name: &str| -> Result<(std::path::PathBuf, Option<Sink>)> {
+            let path = dir.path().join(name);
+            let sink = Sink::parse(GIT_TRACE, path.to_str().unwrap())?;
+            Ok((path, sink))
+        };
+        let (trace_path, trace) = open("trace")?;
+        let (performance_path, performance) = open("performance")?;
+        let (event_path, event) = open("event")?;
+        let argv = vec!["rit".to_string(), "say \"hi\"".to_string()];
+
+        let subscriber = subscriber(trace, performance, event, &argv);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("command", name = "commit");
+            let _entered = span.enter();
+            tracing::debug!(oid = "ce01362", size = 3, "read");
+            drop(_entered);
+            drop(span);
+            exit(1);
+        });
+
+        let trace = std::fs::read_to_string(trace_path)?;
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("trace: read oid=ce01362 size=3"));
+        assert!(lines[1].ends_with("trace: exit code=1"));
+
+        let performance = std::fs::read_to_string(performance_path)?;
+        assert_eq!(performance.lines().count(), 1);
+        assert!(performance.contains(" s: command name=commit"));
+
+        let events = std::fs::read_to_string(event_path)?;
+        let names: Vec<&str> = events
+            .lines()
+            .map(|line| {
+                let start = line.find(':').unwrap() + 2;
+                let end = start + line[start..].find('"').unwrap();
+                &line[start..end]
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "version",
+                "start",
+                "region_enter",
+                "data_json",
+                "region_leave",
+                "exit"
+            ]
+        );
+        assert!(events.contains(r#""argv":["rit","say \"hi\""]"#));
+        assert!(events.contains(r#""value":{"oid":"ce01362","size":3}"#));
+        assert!(events.contains(r#""code":1}"#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+}
@@ -1,10 +1,11 @@
-use crate::database::Storable;
-use crate::entry::Entry;
-use crate::workspace::{Workspace, WorkspacePath};
-use anyhow::{anyhow, Result};
+use crate::database::{ObjectID, Storable};
+use crate::entry::{Entry, Mode};
+use crate::error::{Error, Result};
+use crate::workspace::WorkspacePath;
 use lazy_init::Lazy;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 // A single component of a path - should have no parents or separators.
 //
@@ -15,9 +16,11 @@ use std::path::{Path, PathBuf};
 struct Component(PathBuf);
 
 impl Component {
-    fn new<P: AsRef<Path>>(p: P) -> Self {
-        assert_eq!(p.as_ref().parent().unwrap(), Path::new(""));
-        Component(p.as_ref().into())
+    fn new<P: AsRef<Path>>(p: P) -> Result<Self> {
+        if p.as_ref().parent() != Some(Path::new("")) {
+            return Err(Error::invalid_path(p.as_ref(), "not a single component"));
+        }
+        Ok(Component(p.as_ref().into()))
     }
 }
 
@@ -27,7 +30,7 @@ impl Component {
 #[derive(Debug)]
 enum Node {
     Tree(TreeNode),
-    Entry(WorkspacePath),
+    Entry(Entry),
 }
 
 #[derive(Default, Debug)]
@@ -44,25 +47,24 @@ impl TreeNode {
     //   parents: ["a", "b", "c"], entry: "a/b/c/d.txt"
     // By calling "add_entry", intermediate nodes are created, such that:
     //   a -> b -> c -> d.txt
-    fn add_entry(
-        &mut self,
-        workspace: &Workspace,
-        parents: &[Component],
-        entry: &WorkspacePath,
-    ) -> Result<()> {
+    fn add_entry(&mut self, parents: &[Component], entry: Entry) -> Result<()> {
         if parents.is_empty() {
             // We have accessed the TreeNode storing the entry. Add away!
-            let basename = entry.as_partial_path().file_name().unwrap();
-            let node = if workspace.metadata(&entry)?.is_dir() {
-                Node::Tree(TreeNode::default())
-            } else {
-                Node::Entry(entry.clone())
-            };
-            let old = self.map.insert(Component::new(basename), node);
-            assert!(
-                old.is_none(),
-                "We kicked something out to insert this entry!"
+            let basename = entry
+                .path()
+                .file_name()
+                .ok_or_else(|| Error::invalid_path(entry.path(), "no file name"))?;
+            let component = Component::new(basename)?;
+            if self.map.contains_key(&component) {
+                return Err(Error::invalid_path(entry.path(), "duplicate entry"));
+            }
+            // Within a tree, entries are only identified by their basename.
+            let entry = Entry::new(
+                WorkspacePath::new(basename)?,
+                entry.oid().clone(),
+                *entry.mode(),
             );
+            self.map.insert(component, Node::Entry(entry));
         } else {
             // We need to do some tree traversal to reach the entry.
             match self.map.get_mut(&parents[0]) {
@@ -70,20 +72,53 @@ impl TreeNode {
                 // the entry to that node, instead of this one.
                 Some(node) => {
                     match node {
-                        Node::Tree(node) => node.add_entry(workspace, &parents[1..], entry)?,
-                        Node::Entry(_) => panic!("Parsed a directory as a file?"),
+                        Node::Tree(node) => node.add_entry(&parents[1..], entry)?,
+                        Node::Entry(_) => {
+                            return Err(Error::invalid_path(
+                                entry.path(),
+                                "a parent directory is also a file",
+                            ))
+                        }
                     };
                 }
                 // No intermediate node exists, but one SHOULD exist here.
                 None => {
                     let mut node = TreeNode::default();
-                    node.add_entry(workspace, &parents[1..], entry)?;
+                    node.add_entry(&parents[1..], entry)?;
                     self.map.insert(parents[0].clone(), Node::Tree(node));
                 }
             };
         }
         Ok(())
     }
+
+    // Converts the TreeNode into a Tree, depth-first.
+    //
+    // Every subtree is passed to `store` before the tree containing it is
+    // created, since the parent needs the ID of each of its children.
+    fn into_tree<F>(self, store: &mut F) -> Result<Tree>
+    where
+        F: FnMut(&Tree) -> Result<()>,
+    {
+        let mut entries = Vec::new();
+        for (component, node) in self.map {
+            let entry = match node {
+                Node::Entry(entry) => entry,
+                Node::Tree(node) => {
+                    let tree = node.into_tree(store)?;
+                    Entry::new(
+                        WorkspacePath::new(component.0)?,
+                        tree.oid(),
+                        Mode::Directory,
+                    )
+                }
+            };
+            entries.push(entry);
+        }
+        let tree = Tree::new(entries);
+        store(&tree)?;
+        Ok(tree)
+    }
 }
 
 /// Implements a git tree object, a storable list of entries.
@@ -95,57 +130,90 @@ pub struct Tree {
 }
 
 impl Tree {
-    // XXX this should just become the "new" method...
-    pub fn build(workspace: &Workspace, mut entries: Vec<WorkspacePath>) -> Result<Self> {
-        entries.sort();
-
-        // 'entries' is full paths relative to workspace root
+    /// Builds a hierarchy of trees from a list of entries, whose paths are
+    /// relative to the workspace root.
+    ///
+    /// Each tree in the hierarchy (including the root) is passed to `store`,
+    /// children before their parents. The root tree is returned.
+    pub fn build<F>(entries: Vec<Entry>, mut store: F) -> Result<Self>
+    where
+        F: FnMut(&Tree) -> Result<()>,
+    {
         let mut root = TreeNode::default();
 
-        for entry in &entries {
-            let path = entry.as_partial_path();
-            println!("Tree::build entry: {}", path.display());
-
-            let parents: Vec<Component> = entry
-                .as_partial_path()
-                .iter()
-                .map(|p| Component::new(&p))
-                .collect();
-            println!("  parents: {:#?}", parents);
-            root.add_entry(workspace, &parents[..parents.len() - 1], entry)?;
+        for entry in entries {
+            let parents = match entry.path().parent() {
+                Some(parent) => parent.iter().map(Component::new).collect::<Result<_>>()?,
+                None => Vec::new(),
+            };
+            root.add_entry(&parents, entry)?;
+        }
+        root.into_tree(&mut store)
+    }
+
+    /// Parses a tree from its stored representation.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let corrupt = || Error::CorruptObject("invalid tree entry".to_string());
+        let mut entries = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            // Entry format: "{MODE} {NAME}\0{OID}"
+            let space = rest.iter().position(|b| *b == b' ').ok_or_else(corrupt)?;
+            let mode = Mode::from_str(std::str::from_utf8(&rest[..space]).map_err(|_| corrupt())?)?;
+            rest = &rest[space + 1..];
+
+            let nul = rest.iter().position(|b| *b == b'\0').ok_or_else(corrupt)?;
+            let name = std::str::from_utf8(&rest[..nul]).map_err(|_| corrupt())?;
+            rest = &rest[nul + 1..];
+
+            let oid_len = <sha1::Sha1 as sha1::Digest>::output_size();
+            if rest.len() < oid_len {
+                return Err(corrupt());
+            }
+            let oid = ObjectID::from_bytes(&rest[..oid_len])?;
+            rest = &rest[oid_len..];
+
+            entries.push(Entry::new(WorkspacePath::new(name)?, oid, mode));
         }
-        println!("Root: {:#?}", root);
-        Err(anyhow!("nah"))
+        Ok(Tree::new(entries))
     }
 
     pub fn serialize(&self) -> Vec<u8> {
         self.entries
             .iter()
-            .map(|entry| {
-                println!("Tree entry path: {}", entry.path().display());
+            .flat_map(|entry| {
                 // Entry format: "{MODE} {NAME}\0{OID}"
-                vec![
+                [
                     format!("{} ", entry.mode().as_str()).as_bytes(),
                     entry.path_bytes(),
-                    &[b'\0'],
+                    b"\0",
                     entry.oid().as_bytes(),
                 ]
-                .iter()
-                .map(|slice| slice.to_vec())
-                .flatten()
-                .collect::<Vec<u8>>()
+                .concat()
             })
-            .flatten()
             .collect::<Vec<u8>>()
     }
 
     pub fn new(mut entries: Vec<Entry>) -> Tree {
-        entries.sort();
+        // Git sorts trees by name, but compares directories as if they had
+        // a trailing slash.
+        entries.sort_by_cached_key(|entry| {
+            let mut key = entry.path_bytes().to_vec();
+            if *entry.mode() == Mode::Directory {
+                key.push(b'/');
+            }
+            key
+        });
         Tree {
             entries,
             data: Lazy::new(),
         }
     }
+
+    /// Returns the entries of this tree, in sorted order.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
 }
 
 impl Storable for Tree {
@@ -154,6 +222,86 @@ impl Storable for Tree {
     }
 
     fn data(&self) -> &Vec<u8> {
-        &self.data.get_or_create(|| self.serialize())
+        self.data.get_or_create(|| self.serialize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, mode: Mode) -> Entry {
+        Entry::new(
+            WorkspacePath::new(path).unwrap(),
+            ObjectID::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap(),
+            mode,
+        )
+    }
+
+    #[test]
+    fn test_build_nested() -> Result<()> {
+        let mut stored = Vec::new();
+        let root = Tree::build(
+            vec![
+                entry("a/b/c.txt", Mode::ReadWrite),
+                entry("a/d.txt", Mode::ReadWriteExecute),
+                entry("e.txt", Mode::ReadWrite),
+            ],
+            |tree| {
+                stored.push(tree.oid());
+                Ok(())
+            },
+        )?;
+
+        // Trees "a/b", "a", and the root.
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored.last(), Some(&root.oid()));
+
+        let names: Vec<&Path> = root.entries().iter().map(|e| e.path()).collect();
+        assert_eq!(names, vec![Path::new("a"), Path::new("e.txt")]);
+        assert_eq!(*root.entries()[0].mode(), Mode::Directory);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        let build = |entries| Tree::build(entries, |_| Ok(()));
+        let duplicate = vec![
+            entry("a.txt", Mode::ReadWrite),
+            entry("a.txt", Mode::ReadWrite),
+        ];
+        assert!(matches!(build(duplicate), Err(Error::InvalidPath { .. })));
+        let file_and_dir = vec![
+            entry("a", Mode::ReadWrite),
+            entry("a/b.txt", Mode::ReadWrite),
+        ];
+        assert!(matches!(
+            build(file_and_dir),
+            Err(Error::InvalidPath { .. })
+        ));
+    }
+
+    #[test]
+    fn test_directory_ordering() {
+        // "foo" as a directory sorts as "foo/", which follows "foo.txt".
+        let tree = Tree::new(vec![
+            entry("foo", Mode::Directory),
+            entry("foo.txt", Mode::ReadWrite),
+        ]);
+        let names: Vec<&Path> = tree.entries().iter().map(|e| e.path()).collect();
+        assert_eq!(names, vec![Path::new("foo.txt"), Path::new("foo")]);
+    }
+
+    #[test]
+    fn test_parse_roundtrip() -> Result<()> {
+        let tree = Tree::new(vec![
+            entry("dir", Mode::Directory),
+            entry("file.txt", Mode::ReadWrite),
+            entry("run.sh", Mode::ReadWriteExecute),
+        ]);
+        let parsed = Tree::parse(tree.data())?;
+        assert_eq!(parsed.entries(), tree.entries());
+        assert_eq!(parsed.oid(), tree.oid());
+        Ok(())
     }
 }
@@ -1,9 +1,15 @@
-use anyhow::{anyhow, Result};
-use std::fs::Metadata;
+use crate::database::ObjectID;
+use crate::entry::Mode;
+use crate::error::{Error, Result};
+use crate::refs::{Refs, HEAD};
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, Metadata, Permissions};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 /// A file path, relative to the workspace origin.
-#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct WorkspacePath {
     path: PathBuf,
 }
@@ -21,7 +27,10 @@ impl WorkspacePath {
         // which would cause issues when paths are embedded within
         // the object store.
         if path.as_ref().is_absolute() {
-            return Err(anyhow!("Absolute paths disallowed"));
+            return Err(Error::invalid_path(
+                path.as_ref(),
+                "absolute paths are disallowed",
+            ));
         }
         Ok(WorkspacePath {
             path: PathBuf::from(path.as_ref()),
@@ -30,36 +39,192 @@ impl WorkspacePath {
 
     /// Returns the partial path (within some workspace) of the file.
     pub fn as_partial_path(&self) -> &Path {
-        &self.path.as_path()
+        self.path.as_path()
     }
 }
 
 pub struct Workspace {
     root: PathBuf,
+    // Whether symlinks are checked out as links (core.symlinks), rather
+    // than as regular files holding the link target.
+    symlinks: bool,
 }
 
 impl Workspace {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Workspace {
             root: PathBuf::from(path.as_ref()),
+            symlinks: true,
         }
     }
 
+    /// Sets whether symlinks are checked out as links (the default), or
+    /// as regular files holding the link target.
+    pub fn with_symlinks(mut self, symlinks: bool) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn symlinks(&self) -> bool {
+        self.symlinks
+    }
+
     /// Access the full path of a file within the workspace.
     pub fn full_path(&self, sub_path: &WorkspacePath) -> PathBuf {
         self.root.join(sub_path.as_partial_path())
     }
 
     /// Read the entirety of a file within the workspace.
+    ///
+    /// For a symlink, this is the target of the link.
     pub fn read_file(&self, path: &WorkspacePath) -> Result<Vec<u8>> {
         let real_path = self.full_path(path);
-        std::fs::read(real_path).map_err(|e| anyhow!(e))
+        if std::fs::symlink_metadata(&real_path)?
+            .file_type()
+            .is_symlink()
+        {
+            let target = std::fs::read_link(real_path)?;
+            return Ok(target.into_os_string().into_vec());
+        }
+        Ok(std::fs::read(real_path)?)
     }
 
-    /// Read a file's metadata within the workspace.
+    /// Opens a file within the workspace for reading, such as when it is
+    /// too large to read at once.
+    pub fn open_file(&self, path: &WorkspacePath) -> Result<std::fs::File> {
+        Ok(std::fs::File::open(self.full_path(path))?)
+    }
+
+    /// Identifies the mode of a file from its metadata, given the mode it
+    /// was last `recorded` with (such as in the index).
+    ///
+    /// Without core.symlinks, a symlink stays one even though it was
+    /// checked out as a regular file. Directories are only recorded when
+    /// they hold submodules, so are always `Mode::Gitlink`.
+    pub fn mode(&self, metadata: &Metadata, recorded: Option<Mode>) -> Mode {
+        if metadata.is_dir() {
+            return Mode::Gitlink;
+        }
+        match recorded {
+            Some(Mode::Symlink) if !self.symlinks && Mode::Symlink.matches_metadata(metadata) => {
+                Mode::Symlink
+            }
+            _ => Mode::from_metadata(metadata),
+        }
+    }
+
+    /// Returns the git directory of the repository nested at `path`, such
+    /// as a submodule, if there is one.
+    ///
+    /// The ".git" of a submodule may be a directory, or a file naming the
+    /// directory ("gitdir: <path>").
+    pub fn nested_git_dir(&self, path: &WorkspacePath) -> Option<PathBuf> {
+        let dot_git = self.full_path(path).join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+        let contents = std::fs::read_to_string(&dot_git).ok()?;
+        let target = contents.trim().strip_prefix("gitdir:")?.trim();
+        Some(dot_git.parent()?.join(target))
+    }
+
+    /// Returns the commit checked out by the repository nested at `path`,
+    /// or None if there is no repository (or it has no commits).
+    pub fn submodule_head(&self, path: &WorkspacePath) -> Result<Option<ObjectID>> {
+        match self.nested_git_dir(path) {
+            Some(git_dir) => Refs::new(git_dir).read_ref(HEAD),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a file's metadata within the workspace. Symlinks are not
+    /// followed.
     pub fn metadata(&self, path: &WorkspacePath) -> Result<Metadata> {
         let real_path = self.full_path(path);
-        std::fs::metadata(real_path).map_err(|e| anyhow!(e))
+        Ok(std::fs::symlink_metadata(real_path)?)
+    }
+
+    /// Replaces the contents of a file within the workspace, creating any
+    /// missing parent directories.
+    ///
+    /// Anything occupying the path (or one of its parents) which is not a
+    /// directory is removed first. For `Mode::Symlink`, `data` is the
+    /// target of the link.
+    pub fn write_file(&self, path: &WorkspacePath, data: &[u8], mode: Mode) -> Result<()> {
+        let real_path = self.full_path(path);
+
+        // Clear out files which would prevent the parents from being
+        // created, such as "a" when writing "a/b".
+        for parent in path.as_partial_path().ancestors().skip(1) {
+            let real_parent = self.root.join(parent);
+            if real_parent == self.root {
+                continue;
+            }
+            if let Ok(metadata) = std::fs::symlink_metadata(&real_parent) {
+                if !metadata.is_dir() {
+                    std::fs::remove_file(&real_parent)?;
+                }
+            }
+        }
+        if let Some(parent) = real_path.parent() {
+            create_dir_all(parent)?;
+        }
+        // Links are never written through, so are replaced like files.
+        if let Ok(metadata) = std::fs::symlink_metadata(&real_path) {
+            if metadata.is_dir() {
+                if mode != Mode::Gitlink {
+                    std::fs::remove_dir_all(&real_path)?;
+                }
+            } else if metadata.file_type().is_symlink()
+                || mode == Mode::Symlink
+                || mode == Mode::Gitlink
+            {
+                std::fs::remove_file(&real_path)?;
+            }
+        }
+
+        // The contents of a submodule are checked out separately.
+        if mode == Mode::Gitlink {
+            create_dir_all(&real_path)?;
+            return Ok(());
+        }
+        if mode == Mode::Symlink && self.symlinks {
+            std::os::unix::fs::symlink(OsStr::from_bytes(data), &real_path)?;
+            return Ok(());
+        }
+        std::fs::write(&real_path, data)?;
+        let permissions = match mode {
+            Mode::ReadWriteExecute => 0o755,
+            _ => 0o644,
+        };
+        std::fs::set_permissions(&real_path, Permissions::from_mode(permissions))?;
+        Ok(())
+    }
+
+    /// Removes a file from the workspace, along with any parent directories
+    /// which become empty as a result.
+    ///
+    /// Removing a file which does not exist is not an error.
+    pub fn remove_file(&self, path: &WorkspacePath) -> Result<()> {
+        let real_path = self.full_path(path);
+        match std::fs::symlink_metadata(&real_path) {
+            // A submodule is only removed if it is empty (as when it was
+            // never checked out), so that no work is lost.
+            Ok(metadata) if metadata.is_dir() => {
+                let _ = std::fs::remove_dir(&real_path);
+            }
+            Ok(_) => std::fs::remove_file(&real_path)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        for parent in path.as_partial_path().ancestors().skip(1) {
+            let real_parent = self.root.join(parent);
+            // Stop at the first directory which still has contents.
+            if real_parent == self.root || std::fs::remove_dir(&real_parent).is_err() {
+                break;
+            }
+        }
+        Ok(())
     }
 
     /// Returns a list of files within the workspace, all relative to the
@@ -92,11 +257,15 @@ impl Workspace {
                     let entry_path = WorkspacePath::new(base.join(entry.file_name()))?;
                     let file_type = match entry.file_type() {
                         Ok(file_type) => file_type,
-                        Err(e) => return Err(anyhow!(e)),
+                        Err(e) => return Err(Error::from(e)),
                     };
 
                     if Workspace::ignored(&entry_path) {
                         Ok(vec![])
+                    } else if file_type.is_dir() && self.nested_git_dir(&entry_path).is_some() {
+                        // Nested repositories (such as submodules) are
+                        // tracked as a whole, not by their contents.
+                        Ok(vec![entry_path])
                     } else if file_type.is_dir() {
                         let mut nested_entries = self.list_files_r(Some(&entry_path))?;
                         nested_entries.push(entry_path);
@@ -147,7 +316,7 @@ mod tests {
                     let _ = File::create(self.0.path().join(path.as_ref()))?;
                 }
                 TestPath::Dir(path) => {
-                    let _ = create_dir(self.0.path().join(path.as_ref()))?;
+                    create_dir(self.0.path().join(path.as_ref()))?;
                 }
             };
             Ok(())